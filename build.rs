@@ -1,4 +1,15 @@
+use std::{env, fs, fs::File, path::Path, path::PathBuf};
+
+/// PNG exports of the UI backgrounds from `ui/Vindskrivare.sketch`, one full screen each.
+const UI_ASSET_DIR: &str = "ui/export";
+
+/// Must match `DISPLAY_W` and `DISPLAY_H` in `src/ui.rs`.
+const DISPLAY_W: u32 = 240;
+const DISPLAY_H: u32 = 280;
+
 fn main() {
+    compress_ui_assets();
+
     // By default, Cargo will re-run a build script whenever
     // any file in the project changes. By specifying `memory.x`
     // here, we ensure the build script is only re-run when
@@ -10,3 +21,68 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 }
+
+/// Convert each PNG in `ui/export` to little endian RGB565 and deflate it into OUT_DIR, where
+/// `asset::compressed_asset!` picks it up. The images are nearly all gradients and flat colour, so
+/// this shrinks them to about a quarter of their raw size.
+fn compress_ui_assets() {
+    println!("cargo:rerun-if-changed={UI_ASSET_DIR}");
+
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    for entry in fs::read_dir(UI_ASSET_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|e| e.to_str()) != Some("png") {
+            continue;
+        }
+
+        let raw = png_to_rgb565(&path);
+        let compressed = miniz_oxide::deflate::compress_to_vec(&raw, 10);
+
+        let name = path.file_stem().unwrap().to_str().unwrap();
+        fs::write(out_dir.join(format!("{name}.deflate")), compressed).unwrap();
+    }
+}
+
+/// Decode a full-screen PNG into RGB565 pixels, two bytes each, little endian. Any transparency
+/// is composited onto black, which is what's behind everything on the display.
+fn png_to_rgb565(path: &Path) -> Vec<u8> {
+    let mut decoder = png::Decoder::new(File::open(path).unwrap());
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+
+    let mut reader = decoder
+        .read_info()
+        .unwrap_or_else(|e| panic!("{} isn't a valid PNG: {e}", path.display()));
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).unwrap();
+
+    assert!(
+        info.width == DISPLAY_W && info.height == DISPLAY_H,
+        "{} is {}x{}, but UI images have to be {DISPLAY_W}x{DISPLAY_H}",
+        path.display(),
+        info.width,
+        info.height,
+    );
+
+    let channels = info.color_type.samples();
+    let mut out = Vec::with_capacity((DISPLAY_W * DISPLAY_H * 2) as usize);
+
+    for pixel in buf[..info.buffer_size()].chunks_exact(channels) {
+        let (r, g, b, a) = match info.color_type {
+            png::ColorType::Grayscale => (pixel[0], pixel[0], pixel[0], 255),
+            png::ColorType::GrayscaleAlpha => (pixel[0], pixel[0], pixel[0], pixel[1]),
+            png::ColorType::Rgb => (pixel[0], pixel[1], pixel[2], 255),
+            png::ColorType::Rgba => (pixel[0], pixel[1], pixel[2], pixel[3]),
+            png::ColorType::Indexed => unreachable!("palettes are expanded by the decoder"),
+        };
+
+        let over_black = |c: u8| ((c as u16 * a as u16) / 255) as u8;
+        let rgb565 = ((over_black(r) as u16 >> 3) << 11)
+            | ((over_black(g) as u16 >> 2) << 5)
+            | (over_black(b) as u16 >> 3);
+
+        out.extend_from_slice(&rgb565.to_le_bytes());
+    }
+
+    out
+}
@@ -0,0 +1,175 @@
+//! An in-memory MQTT broker for running the firmware's protocol logic on the host.
+//!
+//! The broker keeps retained messages, remembers what the device has subscribed to and queues up
+//! anything published to those topics for the device to receive, which is all the protocol relies
+//! on. Sessions and wildcards aren't modelled, and QoS only as far as acknowledging publishes.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use vindskrivare_protocol::{Ack, Transport};
+
+/// One message as it went through the broker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+/// The broker refused a request, as set up with [`Broker::fail_after`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Refused;
+
+#[derive(Default)]
+pub struct Broker {
+    retained: BTreeMap<String, Vec<u8>>,
+    /// Everything the device has published, in order.
+    published: Vec<Publish>,
+    /// What the device has subscribed to, in order.
+    subscriptions: Vec<String>,
+    /// Messages waiting for the device to receive them.
+    deliveries: VecDeque<Publish>,
+    /// How many more requests from the device succeed before they start failing.
+    requests_left: Option<usize>,
+    /// Whether the broker's ACL denies everything the device publishes.
+    denying: bool,
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A connection for the device to use.
+    pub fn client(&mut self) -> Client<'_> {
+        Client { broker: self }
+    }
+
+    /// Refuse every request from the device after the next `requests`.
+    pub fn fail_after(&mut self, requests: usize) {
+        self.requests_left = Some(requests);
+    }
+
+    /// Deny everything the device publishes from now on, or stop denying it. Denied publishes are
+    /// dropped, and only an acknowledged one hears about it.
+    pub fn deny_publishes(&mut self, denying: bool) {
+        self.denying = denying;
+    }
+
+    /// Publish a message from someone else, such as Home Assistant.
+    pub fn inject(&mut self, topic: &str, payload: &[u8], retain: bool) {
+        self.route(Publish {
+            topic: topic.into(),
+            payload: payload.into(),
+            retain,
+        });
+    }
+
+    /// The next message for the device, if there is one.
+    pub fn next_delivery(&mut self) -> Option<Publish> {
+        self.deliveries.pop_front()
+    }
+
+    pub fn retained(&self, topic: &str) -> Option<&[u8]> {
+        self.retained.get(topic).map(Vec::as_slice)
+    }
+
+    pub fn published(&self) -> &[Publish] {
+        &self.published
+    }
+
+    pub fn subscriptions(&self) -> &[String] {
+        &self.subscriptions
+    }
+
+    fn route(&mut self, message: Publish) {
+        if message.retain {
+            // An empty retained message clears the topic, like a real broker.
+            if message.payload.is_empty() {
+                self.retained.remove(&message.topic);
+            } else {
+                self.retained
+                    .insert(message.topic.clone(), message.payload.clone());
+            }
+        }
+
+        if self.subscriptions.contains(&message.topic) {
+            self.deliveries.push_back(message);
+        }
+    }
+
+    fn request(&mut self) -> Result<(), Refused> {
+        match &mut self.requests_left {
+            Some(0) => Err(Refused),
+            Some(left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// The device's connection to a [`Broker`].
+pub struct Client<'b> {
+    broker: &'b mut Broker,
+}
+
+impl Transport for Client<'_> {
+    type Error = Refused;
+
+    async fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), Refused> {
+        self.publish_acknowledged(topic, payload, retain).await?;
+        Ok(())
+    }
+
+    async fn publish_acknowledged(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<Ack, Refused> {
+        self.broker.request()?;
+        if self.broker.denying {
+            return Ok(Ack::NotAuthorized);
+        }
+
+        let message = Publish {
+            topic: topic.into(),
+            payload: payload.into(),
+            retain,
+        };
+        self.broker.published.push(message.clone());
+        self.broker.route(message);
+        Ok(Ack::Accepted)
+    }
+
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Refused> {
+        self.broker.request()?;
+        self.broker.subscriptions.push(topic.into());
+
+        // New subscribers get whatever's retained on the topic straight away.
+        if let Some(payload) = self.broker.retained.get(topic) {
+            self.broker.deliveries.push_back(Publish {
+                topic: topic.into(),
+                payload: payload.clone(),
+                retain: true,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Run a future that never has to wait, which is all of them against the mock broker.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+
+    match future.as_mut().poll(&mut cx) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("the mock broker never makes anything wait"),
+    }
+}
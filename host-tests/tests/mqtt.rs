@@ -0,0 +1,581 @@
+use std::sync::LazyLock;
+
+use host_tests::{block_on, Broker, Refused};
+use vindskrivare_protocol::{
+    self as protocol, Access, AccessChange, Ack, Command, Ignored, Message, Remote, TooLong,
+    TopicBuilder, Topics,
+};
+
+static TOPICS: LazyLock<Topics<'static>> =
+    LazyLock::new(|| build(TopicBuilder::new("homeassistant", "office")));
+
+static WITH_EXTRAS: LazyLock<Topics<'static>> = LazyLock::new(|| {
+    build(
+        TopicBuilder::new("homeassistant", "office")
+            .pressure(Some("weather/pressure"))
+            .presence(Some("office/occupancy")),
+    )
+});
+
+/// Lay the topics out in a buffer that lives for the rest of the tests.
+fn build(builder: TopicBuilder<'static>) -> Topics<'static> {
+    let buf = Box::leak(Box::new([0u8; 1024]));
+    builder.build(buf).unwrap()
+}
+
+const MESSAGES: [Message; 18] = [
+    Message::Discovery,
+    Message::State,
+    Message::DisplayState,
+    Message::ModeState,
+    Message::ProfileState,
+    Message::Alert,
+    Message::Crash,
+    Message::Report,
+    Message::Cpu,
+    Message::Automation,
+    Message::Event,
+    Message::Rules,
+    Message::Render,
+    Message::Traffic,
+    Message::AlarmLog,
+    Message::Diagnostics,
+    Message::Button,
+    Message::Availability,
+];
+
+/// Connect the device to a fresh broker the way the firmware does.
+fn connected(topics: &Topics) -> Broker {
+    let mut broker = Broker::new();
+    block_on(protocol::subscribe_all(&mut broker.client(), topics)).unwrap();
+    broker
+}
+
+#[test]
+fn topic_layout() {
+    assert_eq!(TOPICS.discovery, "homeassistant/device/office/config");
+    assert_eq!(TOPICS.state, "/vindskrivare/office/state");
+    assert_eq!(TOPICS.display_state, "/vindskrivare/office/display");
+    assert_eq!(TOPICS.display_set, "/vindskrivare/office/display/set");
+    assert_eq!(TOPICS.mode_state, "/vindskrivare/office/mode");
+    assert_eq!(TOPICS.mode_set, "/vindskrivare/office/mode/set");
+    assert_eq!(TOPICS.profile_state, "/vindskrivare/office/profile");
+    assert_eq!(TOPICS.profile_set, "/vindskrivare/office/profile/set");
+    assert_eq!(TOPICS.alert, "/vindskrivare/office/alert");
+    assert_eq!(TOPICS.crash, "/vindskrivare/office/crash");
+    assert_eq!(TOPICS.report, "/vindskrivare/office/report");
+    assert_eq!(TOPICS.cpu, "/vindskrivare/office/cpu");
+    assert_eq!(TOPICS.automation, "/vindskrivare/office/automation");
+    assert_eq!(TOPICS.event, "/vindskrivare/office/event");
+    assert_eq!(TOPICS.rules_state, "/vindskrivare/office/rules");
+    assert_eq!(TOPICS.rules_set, "/vindskrivare/office/rules/set");
+    assert_eq!(TOPICS.render, "/vindskrivare/office/render");
+    assert_eq!(TOPICS.traffic, "/vindskrivare/office/traffic");
+    assert_eq!(TOPICS.alarm_log, "/vindskrivare/office/alarms");
+    assert_eq!(TOPICS.alarm_log_dump, "/vindskrivare/office/alarms/dump");
+    assert_eq!(TOPICS.availability, "/vindskrivare/office/availability");
+    assert_eq!(TOPICS.command, "/vindskrivare/office/cmd");
+    assert_eq!(TOPICS.diagnostics, "/vindskrivare/office/diagnostics");
+    assert_eq!(TOPICS.button, "/vindskrivare/office/button");
+}
+
+#[test]
+fn namespace_prefixes_the_device_topics() {
+    let topics = build(
+        TopicBuilder::new("homeassistant", "office")
+            .namespace(Some("home-a/"))
+            .pressure(Some("weather/pressure")),
+    );
+    assert_eq!(
+        topics.discovery,
+        "home-a/homeassistant/device/office/config"
+    );
+    assert_eq!(topics.state, "home-a/vindskrivare/office/state");
+    assert_eq!(topics.display_set, "home-a/vindskrivare/office/display/set");
+    assert_eq!(topics.mode_set, "home-a/vindskrivare/office/mode/set");
+    assert_eq!(topics.cpu, "home-a/vindskrivare/office/cpu");
+
+    // Other devices' topics are theirs to name.
+    assert_eq!(topics.pressure, Some("weather/pressure"));
+
+    // With or without the trailing slash, and an empty namespace is no namespace.
+    let without_slash =
+        build(TopicBuilder::new("homeassistant", "office").namespace(Some("home-a")));
+    assert_eq!(without_slash.discovery, topics.discovery);
+    assert_eq!(without_slash.state, topics.state);
+    let empty = build(TopicBuilder::new("homeassistant", "office").namespace(Some("")));
+    assert_eq!(empty, *TOPICS);
+}
+
+#[test]
+fn topics_that_dont_fit_are_refused() {
+    let mut buf = [0u8; 64];
+    assert_eq!(
+        TopicBuilder::new("homeassistant", "office").build(&mut buf),
+        Err(TooLong)
+    );
+}
+
+#[test]
+fn every_message_has_its_own_topic() {
+    for (i, a) in MESSAGES.iter().enumerate() {
+        for b in &MESSAGES[i + 1..] {
+            assert_ne!(TOPICS.of(*a), TOPICS.of(*b), "{a:?} and {b:?}");
+        }
+    }
+}
+
+#[test]
+fn alerts_events_presses_and_dumps_are_not_retained() {
+    let mut broker = connected(&TOPICS);
+
+    for message in MESSAGES {
+        block_on(protocol::publish(
+            &mut broker.client(),
+            &TOPICS,
+            message,
+            b"{}",
+        ))
+        .unwrap();
+    }
+
+    for publish in broker.published() {
+        let event = publish.topic == TOPICS.alert
+            || publish.topic == TOPICS.event
+            || publish.topic == TOPICS.button
+            || publish.topic == TOPICS.alarm_log;
+        assert_eq!(publish.retain, !event, "{publish:?}");
+    }
+    assert_eq!(broker.retained(TOPICS.state), Some(&b"{}"[..]));
+    assert_eq!(broker.retained(TOPICS.alert), None);
+    assert_eq!(broker.retained(TOPICS.event), None);
+    assert_eq!(broker.retained(TOPICS.button), None);
+}
+
+#[test]
+fn subscribes_to_command_topics() {
+    let broker = connected(&TOPICS);
+    assert_eq!(
+        broker.subscriptions(),
+        [
+            TOPICS.display_set,
+            TOPICS.mode_set,
+            TOPICS.profile_set,
+            TOPICS.rules_set,
+            TOPICS.alarm_log_dump,
+            TOPICS.command
+        ]
+    );
+
+    let broker = connected(&WITH_EXTRAS);
+    assert_eq!(
+        broker.subscriptions(),
+        [
+            "weather/pressure",
+            "office/occupancy",
+            WITH_EXTRAS.display_set,
+            WITH_EXTRAS.mode_set,
+            WITH_EXTRAS.profile_set,
+            WITH_EXTRAS.rules_set,
+            WITH_EXTRAS.alarm_log_dump,
+            WITH_EXTRAS.command
+        ]
+    );
+}
+
+#[test]
+fn subscribing_stops_at_the_first_refusal() {
+    let mut broker = Broker::new();
+    broker.fail_after(1);
+
+    let result = block_on(protocol::subscribe_all(&mut broker.client(), &TOPICS));
+
+    assert_eq!(result, Err(Refused));
+    assert_eq!(broker.subscriptions(), [TOPICS.display_set]);
+}
+
+#[test]
+fn display_command_round_trip() {
+    let mut broker = connected(&TOPICS);
+
+    broker.inject(TOPICS.display_set, b"pm2_5_off", false);
+    let delivery = broker.next_delivery().expect("command delivered");
+    assert_eq!(
+        TOPICS.parse(&delivery.topic, &delivery.payload),
+        Ok(Command::Display {
+            key: "pm2_5",
+            on: false
+        })
+    );
+
+    // The firmware answers a settings change by publishing the new display state.
+    let state = br#"{"pm2_5":"OFF"}"#;
+    block_on(protocol::publish(
+        &mut broker.client(),
+        &TOPICS,
+        Message::DisplayState,
+        state,
+    ))
+    .unwrap();
+    assert_eq!(broker.retained(TOPICS.display_state), Some(&state[..]));
+    assert_eq!(broker.next_delivery(), None);
+}
+
+#[test]
+fn mode_command_round_trip() {
+    let mut broker = connected(&TOPICS);
+
+    broker.inject(TOPICS.mode_set, b"away\n", false);
+    let delivery = broker.next_delivery().expect("command delivered");
+    assert_eq!(
+        TOPICS.parse(&delivery.topic, &delivery.payload),
+        Ok(Command::Mode("away"))
+    );
+
+    block_on(protocol::publish(
+        &mut broker.client(),
+        &TOPICS,
+        Message::ModeState,
+        b"away",
+    ))
+    .unwrap();
+    assert_eq!(broker.retained(TOPICS.mode_state), Some(&b"away"[..]));
+}
+
+#[test]
+fn profile_command_round_trip() {
+    let mut broker = connected(&TOPICS);
+
+    broker.inject(TOPICS.profile_set, b"low_power", false);
+    let delivery = broker.next_delivery().expect("command delivered");
+    assert_eq!(
+        TOPICS.parse(&delivery.topic, &delivery.payload),
+        Ok(Command::Profile("low_power"))
+    );
+
+    block_on(protocol::publish(
+        &mut broker.client(),
+        &TOPICS,
+        Message::ProfileState,
+        b"low_power",
+    ))
+    .unwrap();
+    assert_eq!(
+        broker.retained(TOPICS.profile_state),
+        Some(&b"low_power"[..])
+    );
+}
+
+#[test]
+fn rules_command_round_trip() {
+    let mut broker = connected(&TOPICS);
+
+    let rules = br#"[{"metric":"pm2_5","above":50,"for":60,"action":"buzz"}]"#;
+    broker.inject(TOPICS.rules_set, rules, false);
+    let delivery = broker.next_delivery().expect("command delivered");
+    assert_eq!(
+        TOPICS.parse(&delivery.topic, &delivery.payload),
+        Ok(Command::Rules(core::str::from_utf8(rules).unwrap()))
+    );
+
+    block_on(protocol::publish(
+        &mut broker.client(),
+        &TOPICS,
+        Message::Rules,
+        rules,
+    ))
+    .unwrap();
+    assert_eq!(broker.retained(TOPICS.rules_state), Some(&rules[..]));
+}
+
+#[test]
+fn alarm_log_dump_round_trip() {
+    let mut broker = connected(&TOPICS);
+
+    broker.inject(TOPICS.alarm_log_dump, b"", false);
+    let delivery = broker.next_delivery().expect("command delivered");
+    assert_eq!(
+        TOPICS.parse(&delivery.topic, &delivery.payload),
+        Ok(Command::DumpAlarmLog)
+    );
+
+    let log = br#"[{"boot":3,"started_s":120,"metric":"pm2_5","health":"warning","peak":31.5,"duration_s":600}]"#;
+    block_on(protocol::publish(
+        &mut broker.client(),
+        &TOPICS,
+        Message::AlarmLog,
+        log,
+    ))
+    .unwrap();
+    assert_eq!(broker.retained(TOPICS.alarm_log), None);
+    assert_eq!(
+        broker.published().last().map(|p| &p.payload[..]),
+        Some(&log[..])
+    );
+}
+
+#[test]
+fn remote_command_round_trip() {
+    let mut broker = connected(&TOPICS);
+
+    for remote in Remote::ALL {
+        broker.inject(TOPICS.command, remote.key().as_bytes(), false);
+        let delivery = broker.next_delivery().expect("command delivered");
+        assert_eq!(
+            TOPICS.parse(&delivery.topic, &delivery.payload),
+            Ok(Command::Remote(remote))
+        );
+    }
+
+    // Clearing a retained command, so it isn't carried out again on the next connect.
+    broker.inject(TOPICS.command, b"restart", true);
+    broker.next_delivery();
+    assert_eq!(
+        block_on(protocol::clear_command(&mut broker.client(), &TOPICS)),
+        Ok(Ack::Accepted)
+    );
+    assert_eq!(broker.retained(TOPICS.command), None);
+    let delivery = broker.next_delivery().expect("clear delivered");
+    assert_eq!(
+        TOPICS.parse(&delivery.topic, &delivery.payload),
+        Err(Ignored::Empty)
+    );
+
+    assert_eq!(
+        TOPICS.parse(TOPICS.command, b"self_destruct"),
+        Err(Ignored::BadPayload)
+    );
+}
+
+/// Connect the way the firmware does, and carry out any command that arrives the way it does,
+/// returning the commands that were carried out.
+fn session(broker: &mut Broker) -> Vec<Remote> {
+    block_on(protocol::subscribe_all(&mut broker.client(), &TOPICS)).unwrap();
+
+    let mut carried_out = Vec::new();
+    while let Some(delivery) = broker.next_delivery() {
+        if let Ok(Command::Remote(remote)) = TOPICS.parse(&delivery.topic, &delivery.payload) {
+            let taken = block_on(protocol::take_remote(&mut broker.client(), &TOPICS, remote));
+            carried_out.extend(taken.unwrap());
+        }
+    }
+    carried_out
+}
+
+#[test]
+fn retained_restart_runs_once() {
+    let mut broker = Broker::new();
+    broker.inject(TOPICS.command, b"restart", true);
+
+    assert_eq!(session(&mut broker), [Remote::Restart]);
+    assert_eq!(broker.retained(TOPICS.command), None);
+    // Coming back up after the restart.
+    assert_eq!(session(&mut broker), []);
+}
+
+#[test]
+fn retained_restart_that_cant_be_cleared_is_ignored() {
+    let mut broker = Broker::new();
+    broker.inject(TOPICS.command, b"restart", true);
+    broker.deny_publishes(true);
+
+    // Were it carried out, each of these would be another restart.
+    for _ in 0..3 {
+        assert_eq!(session(&mut broker), []);
+        assert_eq!(broker.retained(TOPICS.command), Some(&b"restart"[..]));
+    }
+
+    // Let back in, the next time it arrives it's cleared and carried out, once.
+    broker.deny_publishes(false);
+    assert_eq!(session(&mut broker), [Remote::Restart]);
+    assert_eq!(session(&mut broker), []);
+}
+
+#[test]
+fn availability_is_kept_for_home_assistant() {
+    let mut broker = connected(&TOPICS);
+
+    block_on(protocol::publish(
+        &mut broker.client(),
+        &TOPICS,
+        Message::Availability,
+        protocol::ONLINE,
+    ))
+    .unwrap();
+    assert_eq!(broker.retained(TOPICS.availability), Some(protocol::ONLINE));
+
+    // The broker publishing the will, retained like the message it replaces.
+    broker.inject(
+        TOPICS.availability,
+        protocol::OFFLINE,
+        Message::Availability.retain(),
+    );
+    assert_eq!(
+        broker.retained(TOPICS.availability),
+        Some(protocol::OFFLINE)
+    );
+}
+
+#[test]
+fn denied_probe_goes_read_only() {
+    let mut broker = connected(&TOPICS);
+    broker.deny_publishes(true);
+    let mut access = Access::new();
+
+    let change = block_on(protocol::probe(
+        &mut broker.client(),
+        &TOPICS,
+        &mut access,
+        100,
+    ));
+    assert_eq!(change, Ok(Some(AccessChange::ReadOnly)));
+    assert!(access.read_only());
+    assert_eq!(broker.retained(TOPICS.availability), None);
+
+    // Denied again on reconnecting, it's still read-only, and isn't news.
+    let change = block_on(protocol::probe(
+        &mut broker.client(),
+        &TOPICS,
+        &mut access,
+        200,
+    ));
+    assert_eq!(change, Ok(None));
+    assert!(access.read_only());
+}
+
+#[test]
+fn read_only_probes_again_once_the_retry_is_due() {
+    let mut broker = connected(&TOPICS);
+    broker.deny_publishes(true);
+    let mut access = Access::new();
+    block_on(protocol::probe(
+        &mut broker.client(),
+        &TOPICS,
+        &mut access,
+        100,
+    ))
+    .unwrap();
+
+    assert!(!access.probe_due(100));
+    assert!(!access.probe_due(100 + protocol::READ_ONLY_RETRY_SECS - 1));
+    assert!(access.probe_due(100 + protocol::READ_ONLY_RETRY_SECS));
+
+    // Still denied, so it waits the whole retry again from now.
+    let now = 100 + protocol::READ_ONLY_RETRY_SECS;
+    block_on(protocol::probe(
+        &mut broker.client(),
+        &TOPICS,
+        &mut access,
+        now,
+    ))
+    .unwrap();
+    assert!(!access.probe_due(now + protocol::READ_ONLY_RETRY_SECS - 1));
+}
+
+#[test]
+fn accepted_probe_leaves_read_only() {
+    let mut broker = connected(&TOPICS);
+    broker.deny_publishes(true);
+    let mut access = Access::new();
+    block_on(protocol::probe(
+        &mut broker.client(),
+        &TOPICS,
+        &mut access,
+        100,
+    ))
+    .unwrap();
+
+    broker.deny_publishes(false);
+    let now = 100 + protocol::READ_ONLY_RETRY_SECS;
+    let change = block_on(protocol::probe(
+        &mut broker.client(),
+        &TOPICS,
+        &mut access,
+        now,
+    ));
+    assert_eq!(change, Ok(Some(AccessChange::Restored)));
+    assert!(!access.read_only());
+    assert!(!access.probe_due(now + protocol::READ_ONLY_RETRY_SECS));
+    assert_eq!(broker.retained(TOPICS.availability), Some(protocol::ONLINE));
+}
+
+#[test]
+fn accepted_probe_on_connect_changes_nothing() {
+    let mut broker = connected(&TOPICS);
+    let mut access = Access::new();
+
+    let change = block_on(protocol::probe(
+        &mut broker.client(),
+        &TOPICS,
+        &mut access,
+        0,
+    ));
+    assert_eq!(change, Ok(None));
+    assert!(!access.read_only());
+    assert_eq!(broker.retained(TOPICS.availability), Some(protocol::ONLINE));
+}
+
+#[test]
+fn denied_publishes_are_dropped_quietly() {
+    let mut broker = connected(&TOPICS);
+    broker.deny_publishes(true);
+
+    let result = block_on(protocol::publish(
+        &mut broker.client(),
+        &TOPICS,
+        Message::State,
+        b"{}",
+    ));
+    assert_eq!(result, Ok(()));
+    assert!(broker.published().is_empty());
+}
+
+#[test]
+fn retained_inputs_arrive_on_connect() {
+    let mut broker = Broker::new();
+    broker.inject("weather/pressure", b"987.5", true);
+    broker.inject("office/occupancy", b"OFF", true);
+
+    block_on(protocol::subscribe_all(&mut broker.client(), &WITH_EXTRAS)).unwrap();
+
+    let pressure = broker.next_delivery().unwrap();
+    assert_eq!(
+        WITH_EXTRAS.parse(&pressure.topic, &pressure.payload),
+        Ok(Command::Pressure(987.5))
+    );
+    let presence = broker.next_delivery().unwrap();
+    assert_eq!(
+        WITH_EXTRAS.parse(&presence.topic, &presence.payload),
+        Ok(Command::Presence(false))
+    );
+    assert_eq!(broker.next_delivery(), None);
+}
+
+#[test]
+fn nonsense_is_ignored() {
+    assert_eq!(
+        TOPICS.parse(TOPICS.display_set, b"pm2_5"),
+        Err(Ignored::BadPayload)
+    );
+    assert_eq!(
+        TOPICS.parse(TOPICS.display_set, &[0xff, 0xfe]),
+        Err(Ignored::BadPayload)
+    );
+    assert_eq!(
+        WITH_EXTRAS.parse("weather/pressure", b"high"),
+        Err(Ignored::BadPayload)
+    );
+    assert_eq!(
+        WITH_EXTRAS.parse("office/occupancy", b"maybe"),
+        Err(Ignored::BadPayload)
+    );
+    assert_eq!(
+        TOPICS.parse(TOPICS.state, b"{}"),
+        Err(Ignored::UnknownTopic)
+    );
+    // Without a presence topic configured, nothing is mistaken for one.
+    assert_eq!(
+        TOPICS.parse("office/occupancy", b"on"),
+        Err(Ignored::UnknownTopic)
+    );
+}
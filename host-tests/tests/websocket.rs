@@ -0,0 +1,166 @@
+use vindskrivare_protocol::websocket::{
+    accept_key, base64, sha1, FrameReader, OPCODE_CLOSE, OPCODE_TEXT,
+};
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_PING: u8 = 0x9;
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn encoded(bytes: &[u8]) -> String {
+    let mut out = vec![0u8; bytes.len().div_ceil(3) * 4];
+    base64(bytes, &mut out);
+    String::from_utf8(out).unwrap()
+}
+
+/// A masked frame, as a client sends them.
+fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(0x80 | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    let mask = [0x37, 0xfa, 0x21, 0x3d];
+    frame.extend(mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    frame
+}
+
+/// Feed `bytes` in pieces of `chunk` bytes, collecting the opcodes seen.
+fn opcodes(bytes: &[u8], chunk: usize) -> Vec<u8> {
+    let mut reader = FrameReader::new();
+    let mut seen = Vec::new();
+    for piece in bytes.chunks(chunk) {
+        reader.feed(piece, |opcode| seen.push(opcode));
+    }
+    seen
+}
+
+#[test]
+fn accept_key_matches_rfc_6455_example() {
+    let mut buf = [0u8; 60];
+    assert_eq!(
+        accept_key("dGhlIHNhbXBsZSBub25jZQ==", &mut buf),
+        Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+    );
+}
+
+#[test]
+fn accept_key_ignores_surrounding_whitespace() {
+    let mut buf = [0u8; 60];
+    assert_eq!(
+        accept_key(" dGhlIHNhbXBsZSBub25jZQ==\r", &mut buf),
+        Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+    );
+}
+
+#[test]
+fn accept_key_refuses_keys_too_long_for_the_buffer() {
+    let mut buf = [0u8; 60];
+    assert_eq!(accept_key(&"A".repeat(40), &mut buf), None);
+}
+
+#[test]
+fn sha1_matches_known_digests() {
+    assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(
+        hex(&sha1(b"abc")),
+        "a9993e364706816aba3e25717850c26c9cd0d89d"
+    );
+    // 56 bytes, so the length needs a second padding block.
+    assert_eq!(
+        hex(&sha1(
+            b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+        )),
+        "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+    );
+    assert_eq!(
+        hex(&sha1(&[b'a'; 1000])),
+        "291e9a6c66994949b57ba5e650361e98fc36b1ba"
+    );
+}
+
+#[test]
+fn base64_pads_every_remainder() {
+    assert_eq!(encoded(b"f"), "Zg==");
+    assert_eq!(encoded(b"fo"), "Zm8=");
+    assert_eq!(encoded(b"foo"), "Zm9v");
+    assert_eq!(encoded(b"foobar"), "Zm9vYmFy");
+    assert_eq!(encoded(&[0xfb, 0xff]), "+/8=");
+}
+
+#[test]
+fn frames_in_one_read_are_all_seen() {
+    let mut bytes = client_frame(true, OPCODE_TEXT, b"hello");
+    bytes.extend(client_frame(true, OPCODE_PING, b""));
+    bytes.extend(client_frame(true, OPCODE_CLOSE, &1000u16.to_be_bytes()));
+
+    assert_eq!(
+        opcodes(&bytes, bytes.len()),
+        [OPCODE_TEXT, OPCODE_PING, OPCODE_CLOSE]
+    );
+}
+
+#[test]
+fn headers_split_across_reads_are_put_back_together() {
+    let mut bytes = client_frame(true, OPCODE_TEXT, &[b'x'; 300]);
+    bytes.extend(client_frame(true, OPCODE_CLOSE, b""));
+
+    for chunk in 1..=8 {
+        assert_eq!(
+            opcodes(&bytes, chunk),
+            [OPCODE_TEXT, OPCODE_CLOSE],
+            "{chunk}"
+        );
+    }
+}
+
+#[test]
+fn payloads_that_look_like_a_close_are_skipped() {
+    // A payload that reads as a run of close headers if anything looks inside it.
+    let mut bytes = client_frame(true, OPCODE_TEXT, &[0; 200]);
+    bytes[8..].fill(0x88);
+    bytes.extend(client_frame(true, OPCODE_PING, b""));
+
+    assert_eq!(opcodes(&bytes, 16), [OPCODE_TEXT, OPCODE_PING]);
+}
+
+#[test]
+fn fragmented_messages_and_interleaved_control_frames() {
+    let mut bytes = client_frame(false, OPCODE_TEXT, b"hel");
+    bytes.extend(client_frame(true, OPCODE_PING, b"?"));
+    bytes.extend(client_frame(false, OPCODE_CONTINUATION, b"lo "));
+    bytes.extend(client_frame(true, OPCODE_CONTINUATION, b"there"));
+    bytes.extend(client_frame(true, OPCODE_CLOSE, b""));
+
+    for chunk in [1, 3, 7, bytes.len()] {
+        assert_eq!(
+            opcodes(&bytes, chunk),
+            [
+                OPCODE_TEXT,
+                OPCODE_PING,
+                OPCODE_CONTINUATION,
+                OPCODE_CONTINUATION,
+                OPCODE_CLOSE
+            ],
+            "{chunk}"
+        );
+    }
+}
+
+#[test]
+fn long_lengths_are_skipped_across_many_reads() {
+    let mut bytes = client_frame(true, OPCODE_TEXT, &vec![0x88; 70_000]);
+    bytes.extend(client_frame(true, OPCODE_CLOSE, b""));
+
+    assert_eq!(opcodes(&bytes, 128), [OPCODE_TEXT, OPCODE_CLOSE]);
+}
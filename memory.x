@@ -1,6 +1,17 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* The bootloader (see bootloader/) is in the first 24K, then its state, then the firmware  */
+    /* and the partition updates are downloaded to, which has to be a page bigger, see ota.rs.  */
+    /* Must match bootloader/memory.x.                                                          */
+    BOOTLOADER_STATE : ORIGIN = 0x10006000, LENGTH = 4K
+    FLASH : ORIGIN = 0x10007000, LENGTH = 848K
+    DFU : ORIGIN = 0x100DB000, LENGTH = 852K
+    /* The last 64K of flash is reserved for persistent storage, see storage.rs, and the 256K */
+    /* below that (from 0x101B0000) for the Wi-Fi chip's firmware, see wifi_blobs.rs          */
+
+    /* The first 16K of RAM is kept across resets, see retained.rs and telemetry.rs. The */
+    /* bootloader leaves it alone too, so it has to match bootloader/memory.x.            */
+    RETAINED : ORIGIN = 0x20000000, LENGTH = 16K
 
     /* Pick one of the two options for RAM layout     */
 
@@ -8,10 +19,23 @@ MEMORY {
     /* Reasonable, unless you are doing something     */
     /* really particular with DMA or other concurrent */
     /* access that would benefit from striping        */
-    RAM   : ORIGIN = 0x20000000, LENGTH = 264K
+    RAM   : ORIGIN = 0x20004000, LENGTH = 248K
 
     /* OPTION B: Keep the unstriped sections separate */
-    /* RAM: ORIGIN = 0x20000000, LENGTH = 256K        */
+    /* RAM: ORIGIN = 0x20004000, LENGTH = 240K        */
     /* SCRATCH_A: ORIGIN = 0x20040000, LENGTH = 4K    */
     /* SCRATCH_B: ORIGIN = 0x20041000, LENGTH = 4K    */
 }
+
+/* Like .uninit, nothing in here is zeroed or initialised at startup. */
+SECTIONS {
+    .retained (NOLOAD) : ALIGN(4) {
+        *(.retained .retained.*);
+    } > RETAINED
+} INSERT AFTER .uninit;
+
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOT2);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
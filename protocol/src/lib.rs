@@ -0,0 +1,576 @@
+//! The MQTT side of the firmware that doesn't care how messages get to the broker: which topics
+//! there are, which messages are retained, what's subscribed to, and what the commands that arrive
+//! mean. [`websocket`] does the same for the `/live` WebSocket.
+//!
+//! It has no dependencies, so the host tests (`cargo host-test`) can run it against a mock broker
+//! without building any of the embedded stack.
+
+#![no_std]
+
+pub mod websocket;
+
+/// Every topic the device publishes or subscribes to.
+///
+/// Laid out once at startup with a [`TopicBuilder`], so nothing has to be formatted per message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Topics<'a> {
+    pub discovery: &'a str,
+    pub state: &'a str,
+    pub display_state: &'a str,
+    pub display_set: &'a str,
+    pub mode_state: &'a str,
+    pub mode_set: &'a str,
+    pub profile_state: &'a str,
+    pub profile_set: &'a str,
+    pub alert: &'a str,
+    pub crash: &'a str,
+    pub report: &'a str,
+    pub cpu: &'a str,
+    pub automation: &'a str,
+    pub event: &'a str,
+    pub rules_state: &'a str,
+    pub rules_set: &'a str,
+    pub render: &'a str,
+    pub traffic: &'a str,
+    pub alarm_log: &'a str,
+    pub alarm_log_dump: &'a str,
+    pub availability: &'a str,
+    pub command: &'a str,
+    pub diagnostics: &'a str,
+    pub button: &'a str,
+    /// Somebody else's topic with the ambient pressure in hPa, if there is one.
+    pub pressure: Option<&'a str>,
+    /// Somebody else's topic saying whether anyone's in the room, if there is one.
+    pub presence: Option<&'a str>,
+}
+
+/// The device's own topics, after the namespace. The discovery topic is `<base>/device/<id>/config`
+/// and the rest are `/vindskrivare/<id>/<suffix>`.
+const DEVICE_SUFFIXES: [&str; 23] = [
+    "state",
+    "display",
+    "display/set",
+    "mode",
+    "mode/set",
+    "alert",
+    "crash",
+    "report",
+    "cpu",
+    "automation",
+    "profile",
+    "profile/set",
+    "event",
+    "rules",
+    "rules/set",
+    "render",
+    "traffic",
+    "alarms",
+    "alarms/dump",
+    "availability",
+    "cmd",
+    "diagnostics",
+    "button",
+];
+
+/// The topics didn't fit in the buffer they were being built in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooLong;
+
+/// Lays out the topics for a device.
+///
+/// With a namespace (e.g. `home-a`), every one of the device's own topics, discovery included,
+/// goes under it, so devices in several homes can share a broker without treading on each other.
+/// The pressure and presence topics belong to something else, so they're left as they are.
+#[derive(Debug, Clone, Copy)]
+pub struct TopicBuilder<'a> {
+    discovery_base: &'a str,
+    identifier: &'a str,
+    namespace: Option<&'a str>,
+    pressure: Option<&'a str>,
+    presence: Option<&'a str>,
+}
+
+impl<'a> TopicBuilder<'a> {
+    pub const fn new(discovery_base: &'a str, identifier: &'a str) -> Self {
+        Self {
+            discovery_base,
+            identifier,
+            namespace: None,
+            pressure: None,
+            presence: None,
+        }
+    }
+
+    /// Put the device's topics under `namespace`. `None` (or an empty namespace) leaves them at
+    /// the top level.
+    pub const fn namespace(mut self, namespace: Option<&'a str>) -> Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub const fn pressure(mut self, topic: Option<&'a str>) -> Self {
+        self.pressure = topic;
+        self
+    }
+
+    pub const fn presence(mut self, topic: Option<&'a str>) -> Self {
+        self.presence = topic;
+        self
+    }
+
+    /// Write the topics into `buf` and hand back the full set, borrowing from it.
+    pub fn build<'b>(&self, buf: &'b mut [u8]) -> Result<Topics<'b>, TooLong>
+    where
+        'a: 'b,
+    {
+        let prefix = self.namespace.unwrap_or("").trim_end_matches('/');
+        let mut writer = Writer { buf, len: 0 };
+
+        // Where each topic ends up in the buffer: discovery first, then the device suffixes.
+        let mut spans = [(0, 0); DEVICE_SUFFIXES.len() + 1];
+
+        let start = writer.len;
+        if !prefix.is_empty() {
+            writer.push(&[prefix, "/"])?;
+        }
+        writer.push(&[self.discovery_base, "/device/", self.identifier, "/config"])?;
+        spans[0] = (start, writer.len);
+
+        for (span, suffix) in spans[1..].iter_mut().zip(DEVICE_SUFFIXES) {
+            let start = writer.len;
+            writer.push(&[prefix, "/vindskrivare/", self.identifier, "/", suffix])?;
+            *span = (start, writer.len);
+        }
+
+        let buf: &'b [u8] = writer.buf;
+        let topic = |(start, end): (usize, usize)| {
+            // Only ever whole `str`s were copied in, so this can't fail.
+            core::str::from_utf8(&buf[start..end]).unwrap_or("")
+        };
+
+        Ok(Topics {
+            discovery: topic(spans[0]),
+            state: topic(spans[1]),
+            display_state: topic(spans[2]),
+            display_set: topic(spans[3]),
+            mode_state: topic(spans[4]),
+            mode_set: topic(spans[5]),
+            alert: topic(spans[6]),
+            crash: topic(spans[7]),
+            report: topic(spans[8]),
+            cpu: topic(spans[9]),
+            automation: topic(spans[10]),
+            profile_state: topic(spans[11]),
+            profile_set: topic(spans[12]),
+            event: topic(spans[13]),
+            rules_state: topic(spans[14]),
+            rules_set: topic(spans[15]),
+            render: topic(spans[16]),
+            traffic: topic(spans[17]),
+            alarm_log: topic(spans[18]),
+            alarm_log_dump: topic(spans[19]),
+            availability: topic(spans[20]),
+            command: topic(spans[21]),
+            diagnostics: topic(spans[22]),
+            button: topic(spans[23]),
+            pressure: self.pressure,
+            presence: self.presence,
+        })
+    }
+}
+
+/// Appends to a byte buffer, failing rather than truncating.
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn push(&mut self, parts: &[&str]) -> Result<(), TooLong> {
+        for part in parts {
+            let end = self.len + part.len();
+            self.buf
+                .get_mut(self.len..end)
+                .ok_or(TooLong)?
+                .copy_from_slice(part.as_bytes());
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+/// The messages the device publishes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    Discovery,
+    State,
+    DisplayState,
+    ModeState,
+    ProfileState,
+    Alert,
+    Crash,
+    Report,
+    Cpu,
+    /// A ready-made Home Assistant automation using the device's entities, as YAML.
+    Automation,
+    /// Something the device noticed happening, e.g. somebody cooking.
+    Event,
+    /// The rules the device is running, as JSON.
+    Rules,
+    /// The gist of what's on screen, for other displays to mirror.
+    Render,
+    /// A day's network traffic, by subsystem.
+    Traffic,
+    /// The log of past alarms, when it's asked for.
+    AlarmLog,
+    /// How the device itself is doing: signal strength, uptime, free RAM and its address.
+    Diagnostics,
+    /// A press of the device's button, for Home Assistant automations.
+    Button,
+    /// Whether the device is connected: [`ONLINE`], or [`OFFLINE`] from the broker once it's
+    /// dropped off.
+    Availability,
+}
+
+/// Published on the availability topic once connected.
+pub const ONLINE: &[u8] = b"online";
+
+/// Left with the broker as the device's will when it connects, so it's published on the
+/// availability topic if the device drops off without disconnecting, e.g. losing power.
+pub const OFFLINE: &[u8] = b"offline";
+
+impl Message {
+    /// Whether the broker should keep the message for anyone who subscribes later.
+    ///
+    /// Everything describes the device's current state except alerts, events and button presses,
+    /// and the alarm log, which is only sent when asked for: a new subscriber (or Home Assistant
+    /// restarting) shouldn't be told about one that's long over, or answered a question it didn't
+    /// ask.
+    pub const fn retain(&self) -> bool {
+        !matches!(
+            self,
+            Message::Alert | Message::Event | Message::Button | Message::AlarmLog
+        )
+    }
+}
+
+/// A message from the broker, made sense of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command<'a> {
+    /// The ambient pressure, in hPa.
+    Pressure(f32),
+    /// Whether anyone's in the room.
+    Presence(bool),
+    /// Show or hide something on the display. The key is a metric's key, or `guest`.
+    Display { key: &'a str, on: bool },
+    /// Switch to the mode with this key.
+    Mode(&'a str),
+    /// Switch to the power profile with this key.
+    Profile(&'a str),
+    /// Replace the rules with these, as JSON. Checked by the device, not here.
+    Rules(&'a str),
+    /// Publish the alarm log. The payload doesn't matter.
+    DumpAlarmLog,
+    /// Do something once, from the command topic.
+    Remote(Remote),
+}
+
+/// The one-off commands sent to the command topic, by their payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Remote {
+    /// Restart the device (`restart`).
+    Restart,
+    /// Clean the sensor's fan now rather than waiting for the weekly clean (`fan_clean`).
+    FanClean,
+    /// Turn the screen off until the button's next pressed (`display_off`).
+    DisplayOff,
+    /// Announce the device to Home Assistant again (`republish_discovery`).
+    RepublishDiscovery,
+}
+
+impl Remote {
+    pub const ALL: [Remote; 4] = [
+        Remote::Restart,
+        Remote::FanClean,
+        Remote::DisplayOff,
+        Remote::RepublishDiscovery,
+    ];
+
+    /// The payload that asks for this command.
+    pub const fn key(&self) -> &'static str {
+        match self {
+            Remote::Restart => "restart",
+            Remote::FanClean => "fan_clean",
+            Remote::DisplayOff => "display_off",
+            Remote::RepublishDiscovery => "republish_discovery",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Remote> {
+        Remote::ALL.into_iter().find(|remote| remote.key() == key)
+    }
+}
+
+/// Why a message from the broker was ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ignored {
+    /// Not one of the topics we subscribe to.
+    UnknownTopic,
+    /// One of ours, but the payload didn't make sense.
+    BadPayload,
+    /// An empty command, which is what's left once a retained one has been cleared.
+    Empty,
+}
+
+impl<'a> Topics<'a> {
+    /// The topic a message is published on.
+    pub const fn of(&self, message: Message) -> &'a str {
+        match message {
+            Message::Discovery => self.discovery,
+            Message::State => self.state,
+            Message::DisplayState => self.display_state,
+            Message::ModeState => self.mode_state,
+            Message::ProfileState => self.profile_state,
+            Message::Alert => self.alert,
+            Message::Crash => self.crash,
+            Message::Report => self.report,
+            Message::Cpu => self.cpu,
+            Message::Automation => self.automation,
+            Message::Event => self.event,
+            Message::Rules => self.rules_state,
+            Message::Render => self.render,
+            Message::Traffic => self.traffic,
+            Message::AlarmLog => self.alarm_log,
+            Message::Diagnostics => self.diagnostics,
+            Message::Button => self.button,
+            Message::Availability => self.availability,
+        }
+    }
+
+    /// Everything to subscribe to after connecting, in order.
+    pub fn subscriptions(&self) -> impl Iterator<Item = &'a str> {
+        [
+            self.pressure,
+            self.presence,
+            Some(self.display_set),
+            Some(self.mode_set),
+            Some(self.profile_set),
+            Some(self.rules_set),
+            Some(self.alarm_log_dump),
+            Some(self.command),
+        ]
+        .into_iter()
+        .flatten()
+    }
+
+    /// Work out what a message on one of our subscriptions is asking for.
+    pub fn parse<'p>(&self, topic: &str, payload: &'p [u8]) -> Result<Command<'p>, Ignored> {
+        let payload = core::str::from_utf8(payload)
+            .map_err(|_| Ignored::BadPayload)?
+            .trim();
+
+        if Some(topic) == self.pressure {
+            return payload
+                .parse()
+                .map(Command::Pressure)
+                .map_err(|_| Ignored::BadPayload);
+        }
+
+        if Some(topic) == self.presence {
+            return parse_switch(payload)
+                .map(Command::Presence)
+                .ok_or(Ignored::BadPayload);
+        }
+
+        if topic == self.display_set {
+            // Payloads look like `pm2_5_on` or `guest_off`.
+            return if let Some(key) = payload.strip_suffix("_on") {
+                Ok(Command::Display { key, on: true })
+            } else if let Some(key) = payload.strip_suffix("_off") {
+                Ok(Command::Display { key, on: false })
+            } else {
+                Err(Ignored::BadPayload)
+            };
+        }
+
+        if topic == self.mode_set {
+            return Ok(Command::Mode(payload));
+        }
+
+        if topic == self.profile_set {
+            return Ok(Command::Profile(payload));
+        }
+
+        if topic == self.rules_set {
+            return Ok(Command::Rules(payload));
+        }
+
+        if topic == self.alarm_log_dump {
+            return Ok(Command::DumpAlarmLog);
+        }
+
+        if topic == self.command {
+            if payload.is_empty() {
+                return Err(Ignored::Empty);
+            }
+            return Remote::from_key(payload)
+                .map(Command::Remote)
+                .ok_or(Ignored::BadPayload);
+        }
+
+        Err(Ignored::UnknownTopic)
+    }
+}
+
+/// Read an on/off payload the way Home Assistant and most other things write them.
+fn parse_switch(payload: &str) -> Option<bool> {
+    match payload {
+        "on" | "ON" | "true" | "1" => Some(true),
+        "off" | "OFF" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Something that can talk to an MQTT broker: the real client on the device, or a mock in tests.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    type Error;
+
+    async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<(), Self::Error>;
+
+    /// Publish at QoS 1 and wait for the broker to acknowledge it. A broker quietly drops a QoS 0
+    /// publish that its ACL denies, so this is the only way to hear about it.
+    async fn publish_acknowledged(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<Ack, Self::Error>;
+
+    async fn subscribe(&mut self, topic: &str) -> Result<(), Self::Error>;
+}
+
+/// What the broker made of an acknowledged publish.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ack {
+    Accepted,
+    /// The broker's ACL doesn't let us publish there.
+    NotAuthorized,
+}
+
+/// How long to stay read-only after the broker denies a publish, before probing it again.
+pub const READ_ONLY_RETRY_SECS: u64 = 15 * 60;
+
+/// Whether the broker's letting us publish. A broker can accept the connection and still deny
+/// every publish, and reconnecting won't change its mind, so instead the device goes read-only:
+/// it stops publishing, and probes every `READ_ONLY_RETRY_SECS` to see if it's been let back in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Access {
+    /// When the broker last denied a publish, in seconds since boot.
+    denied_at: Option<u64>,
+}
+
+/// Going read-only, or coming back from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessChange {
+    ReadOnly,
+    Restored,
+}
+
+impl Access {
+    pub const fn new() -> Self {
+        Self { denied_at: None }
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.denied_at.is_some()
+    }
+
+    /// Whether it's time to see if a broker that's denied us has changed its mind.
+    pub fn probe_due(&self, now: u64) -> bool {
+        self.denied_at
+            .is_some_and(|at| now.saturating_sub(at) >= READ_ONLY_RETRY_SECS)
+    }
+
+    /// Take in what the broker made of a publish, saying if that changes anything.
+    pub fn record(&mut self, ack: Ack, now: u64) -> Option<AccessChange> {
+        let was_read_only = self.read_only();
+        self.denied_at = match ack {
+            Ack::Accepted => None,
+            Ack::NotAuthorized => Some(now),
+        };
+        match (was_read_only, self.read_only()) {
+            (false, true) => Some(AccessChange::ReadOnly),
+            (true, false) => Some(AccessChange::Restored),
+            _ => None,
+        }
+    }
+}
+
+/// Publish a message on its topic, retained or not as it should be.
+pub async fn publish<T: Transport>(
+    transport: &mut T,
+    topics: &Topics<'_>,
+    message: Message,
+    payload: &[u8],
+) -> Result<(), T::Error> {
+    transport
+        .publish(topics.of(message), payload, message.retain())
+        .await
+}
+
+/// Say we're online, acknowledged, as a probe of whether the broker lets us publish, and take in
+/// its answer. Also replaces the will from last time, if that was published.
+pub async fn probe<T: Transport>(
+    transport: &mut T,
+    topics: &Topics<'_>,
+    access: &mut Access,
+    now: u64,
+) -> Result<Option<AccessChange>, T::Error> {
+    let ack = transport
+        .publish_acknowledged(topics.availability, ONLINE, Message::Availability.retain())
+        .await?;
+    Ok(access.record(ack, now))
+}
+
+/// Clear the command topic, so a command that was retained isn't carried out again every time the
+/// device connects. Brokers drop a retained message when an empty one is retained in its place.
+/// It's always sent, and acknowledged, even while read-only: that's the only way to know it went.
+pub async fn clear_command<T: Transport>(
+    transport: &mut T,
+    topics: &Topics<'_>,
+) -> Result<Ack, T::Error> {
+    transport
+        .publish_acknowledged(topics.command, b"", true)
+        .await
+}
+
+/// Clear a command that's arrived, and say whether to carry it out. It's only carried out once
+/// it's been cleared, as otherwise it might still be retained: a `restart` would then be received
+/// again after the restart, and restart the device over and over.
+pub async fn take_remote<T: Transport>(
+    transport: &mut T,
+    topics: &Topics<'_>,
+    remote: Remote,
+) -> Result<Option<Remote>, T::Error> {
+    Ok(match clear_command(transport, topics).await? {
+        Ack::Accepted => Some(remote),
+        Ack::NotAuthorized => None,
+    })
+}
+
+/// Subscribe to everything we listen to, stopping at the first failure.
+pub async fn subscribe_all<T: Transport>(
+    transport: &mut T,
+    topics: &Topics<'_>,
+) -> Result<(), T::Error> {
+    for topic in topics.subscriptions() {
+        transport.subscribe(topic).await?;
+    }
+    Ok(())
+}
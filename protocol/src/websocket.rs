@@ -0,0 +1,182 @@
+//! The parts of the `/live` WebSocket that don't need a socket: the handshake's accept key, and
+//! following the frames a client sends however the reads happen to split them.
+
+/// Appended to the client's key to prove the server understood the handshake, per RFC 6455.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Frame opcodes.
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_CLOSE: u8 = 0x8;
+
+/// The longest a frame header can be: two bytes, a 64-bit length and a mask key.
+const MAX_HEADER: usize = 14;
+
+/// The `Sec-WebSocket-Accept` for a client's key: the base64 of the SHA-1 of it and the GUID.
+/// `buf` is just somewhere to put them together.
+pub fn accept_key<'b>(key: &str, buf: &'b mut [u8; 60]) -> Option<&'b str> {
+    let key = key.trim().as_bytes();
+    let len = key.len() + HANDSHAKE_GUID.len();
+    if len > buf.len() {
+        return None;
+    }
+    buf[..key.len()].copy_from_slice(key);
+    buf[key.len()..len].copy_from_slice(HANDSHAKE_GUID.as_bytes());
+
+    let digest = sha1(&buf[..len]);
+    let encoded = &mut buf[..28];
+    base64(&digest, encoded);
+    core::str::from_utf8(encoded).ok()
+}
+
+/// Follows the frames coming from a client. Only the headers matter to the server, so payloads,
+/// and the fragments of messages split over several frames, are skipped over as they arrive.
+#[derive(Debug, Default)]
+pub struct FrameReader {
+    /// The header of the frame being read, as much of it as has arrived.
+    header: [u8; MAX_HEADER],
+    have: usize,
+    /// How much of the current frame's payload is still to come.
+    skip: u64,
+}
+
+impl FrameReader {
+    pub const fn new() -> Self {
+        Self {
+            header: [0; MAX_HEADER],
+            have: 0,
+            skip: 0,
+        }
+    }
+
+    /// Take the next bytes from the client, calling `frame` with the opcode of each frame once its
+    /// whole header is in. Headers split across reads are held on to until the rest turns up.
+    pub fn feed(&mut self, mut bytes: &[u8], mut frame: impl FnMut(u8)) {
+        while !bytes.is_empty() {
+            if self.skip > 0 {
+                let n = bytes
+                    .len()
+                    .min(usize::try_from(self.skip).unwrap_or(usize::MAX));
+                self.skip -= n as u64;
+                bytes = &bytes[n..];
+                continue;
+            }
+
+            // Two bytes say how long the rest of the header is.
+            let want = match self.have {
+                0 | 1 => 2,
+                _ => header_len(&self.header),
+            };
+            let n = (want - self.have).min(bytes.len());
+            self.header[self.have..self.have + n].copy_from_slice(&bytes[..n]);
+            self.have += n;
+            bytes = &bytes[n..];
+            if self.have < want || (want == 2 && header_len(&self.header) > 2) {
+                continue;
+            }
+
+            self.skip = payload_len(&self.header);
+            self.have = 0;
+            frame(self.header[0] & 0x0F);
+        }
+    }
+}
+
+/// How long a header is, given its first two bytes.
+fn header_len(header: &[u8; MAX_HEADER]) -> usize {
+    let extended = match header[1] & 0x7F {
+        126 => 2,
+        127 => 8,
+        _ => 0,
+    };
+    let mask = if header[1] & 0x80 != 0 { 4 } else { 0 };
+    2 + extended + mask
+}
+
+/// The payload length from a complete header.
+fn payload_len(header: &[u8; MAX_HEADER]) -> u64 {
+    match header[1] & 0x7F {
+        126 => u16::from_be_bytes([header[2], header[3]]) as u64,
+        127 => u64::from_be_bytes(header[2..10].try_into().unwrap()),
+        len => len as u64,
+    }
+}
+
+/// The SHA-1 digest of `data`. Only the handshake uses it, so it's built for size, not speed.
+pub fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut blocks = data.chunks_exact(64);
+    for block in &mut blocks {
+        sha1_block(&mut h, block);
+    }
+
+    // The rest, a 1 bit, zeros, and the length in bits, filling one or two more blocks.
+    let rest = blocks.remainder();
+    let mut tail = [0u8; 128];
+    tail[..rest.len()].copy_from_slice(rest);
+    tail[rest.len()] = 0x80;
+    let tail_len = if rest.len() < 56 { 64 } else { 128 };
+    tail[tail_len - 8..tail_len].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+    for block in tail[..tail_len].chunks_exact(64) {
+        sha1_block(&mut h, block);
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, word) in digest.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn sha1_block(h: &mut [u32; 5], block: &[u8]) {
+    let mut w = [0u32; 80];
+    for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e] = *h;
+    for (i, word) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19 => ((b & c) | (!b & d), 0x5A827999),
+            20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+            _ => (b ^ c ^ d, 0xCA62C1D6),
+        };
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*word);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+
+    for (h, v) in h.iter_mut().zip([a, b, c, d, e]) {
+        *h = h.wrapping_add(v);
+    }
+}
+
+/// Standard base64, padded. `out` must be exactly the right length for `bytes`.
+pub fn base64(bytes: &[u8], out: &mut [u8]) {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    for (chunk, out) in bytes.chunks(3).zip(out.chunks_exact_mut(4)) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for (i, c) in out.iter_mut().enumerate() {
+            *c = match i <= chunk.len() {
+                true => ALPHABET[(n >> (18 - 6 * i)) as usize & 63],
+                false => b'=',
+            };
+        }
+    }
+}
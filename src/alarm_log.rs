@@ -0,0 +1,220 @@
+//! A log of the last `LOG_LEN` alarms, kept in flash so it survives a reboot: which metric went
+//! over its threshold, how bad it got, and for how long.
+//!
+//! Each entry is added once the metric's back under its warning threshold, so one that's still
+//! over doesn't show up yet. New entries are written to flash in batches, as every write wears the
+//! same sector: see `save_if_due`. There's no clock, so entries are stamped with the uptime they
+//! started at and a boot number, which goes up by one each time the device restarts after
+//! something's been logged.
+//!
+//! The log's served at `/alarms` by the HTTP server, published to the alarm log topic when anything
+//! is sent to its dump topic, and the latest few are shown on the diagnostics page.
+
+use core::cell::{Cell, RefCell};
+
+use defmt::{error, info};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, Vec};
+use serde::Serialize;
+
+use crate::metric::Metric;
+use crate::sen55::Health;
+use crate::storage::{self, Slot};
+
+/// How many entries are kept. The oldest make way for new ones.
+pub const LOG_LEN: usize = 50;
+
+/// Unsaved entries are written once there are this many of them...
+const SAVE_AFTER_ENTRIES: u8 = 8;
+
+/// ...or the oldest has waited this long.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Boot (2) + uptime (4) + metric (1) + health (1) + peak (4) + duration (4).
+const ENTRY_LEN: usize = 16;
+
+/// The boot count (2), the number of entries (1), then the entries, oldest first.
+const STORED_LEN: usize = 3 + LOG_LEN * ENTRY_LEN;
+
+/// One alarm, from when its metric went over the warning threshold to when it came back under.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Entry {
+    /// Which boot it happened in.
+    pub boot: u16,
+    /// The uptime it started at, in seconds.
+    pub started_s: u32,
+    #[serde(serialize_with = "metric_key")]
+    pub metric: Metric,
+    /// The worst it got, `warning` or `dangerous`.
+    #[serde(serialize_with = "health_name")]
+    pub health: Health,
+    /// The highest value it reached.
+    pub peak: f32,
+    pub duration_s: u32,
+}
+
+fn metric_key<S: serde::Serializer>(metric: &Metric, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(metric.key())
+}
+
+fn health_name<S: serde::Serializer>(health: &Health, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(health.name())
+}
+
+impl Entry {
+    fn to_bytes(self) -> [u8; ENTRY_LEN] {
+        let mut bytes = [0u8; ENTRY_LEN];
+        bytes[0..2].copy_from_slice(&self.boot.to_le_bytes());
+        bytes[2..6].copy_from_slice(&self.started_s.to_le_bytes());
+        bytes[6] = self.metric as u8;
+        bytes[7] = match self.health {
+            Health::Dangerous => 2,
+            _ => 1,
+        };
+        bytes[8..12].copy_from_slice(&self.peak.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.duration_s.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Entry> {
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        Some(Entry {
+            boot: u16::from_le_bytes([bytes[0], bytes[1]]),
+            started_s: u32_at(2),
+            metric: *Metric::ALL.get(usize::from(bytes[6]))?,
+            health: match bytes[7] {
+                2 => Health::Dangerous,
+                _ => Health::Warning,
+            },
+            peak: f32::from_bits(u32_at(8)),
+            duration_s: u32_at(12),
+        })
+    }
+}
+
+struct Log {
+    entries: Deque<Entry, LOG_LEN>,
+    /// This boot's number, one more than the last one with anything logged.
+    boot: u16,
+    /// How many entries have been added since the log was last saved, and when the first was.
+    unsaved: u8,
+    unsaved_since: Option<Instant>,
+}
+
+static LOG: Mutex<ThreadModeRawMutex, RefCell<Log>> = Mutex::new(RefCell::new(Log {
+    entries: Deque::new(),
+    boot: 0,
+    unsaved: 0,
+    unsaved_since: None,
+}));
+
+/// Set when somebody's asked over MQTT for the log.
+static DUMP_REQUESTED: Mutex<ThreadModeRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// This boot's number, for stamping entries.
+pub fn boot() -> u16 {
+    LOG.lock(|log| log.borrow().boot)
+}
+
+/// Read the log from flash. Called once at startup.
+pub async fn load() {
+    let mut bytes = [0u8; STORED_LEN];
+    if let Err(e) = storage::load(Slot::AlarmLog, &mut bytes).await {
+        info!("No alarm log ({})", e);
+        return;
+    }
+
+    let count = usize::from(bytes[2]).min(LOG_LEN);
+    LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        log.boot = u16::from_le_bytes([bytes[0], bytes[1]]).wrapping_add(1);
+        for chunk in bytes[3..].chunks_exact(ENTRY_LEN).take(count) {
+            if let Some(entry) = Entry::from_bytes(chunk) {
+                _ = log.entries.push_back(entry);
+            }
+        }
+        info!("Loaded {} alarm log entries", log.entries.len());
+    });
+}
+
+/// Add an alarm that's just ended. It's saved by the next `save`, or `save_if_due` once enough have
+/// built up.
+pub fn record(entry: Entry) {
+    LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        if log.entries.is_full() {
+            log.entries.pop_front();
+        }
+        _ = log.entries.push_back(entry);
+        log.unsaved = log.unsaved.saturating_add(1);
+        log.unsaved_since.get_or_insert_with(Instant::now);
+    });
+}
+
+/// Write the log to flash if enough has been added since it last was, or it's been a while. Called
+/// after every reading, so it mustn't write each time an entry's added.
+pub async fn save_if_due() {
+    let due = LOG.lock(|log| {
+        let log = log.borrow();
+        log.unsaved >= SAVE_AFTER_ENTRIES
+            || log
+                .unsaved_since
+                .is_some_and(|since| since.elapsed() > SAVE_INTERVAL)
+    });
+    if due {
+        save().await;
+    }
+}
+
+/// Write the log to flash, if anything's been added since it last was. Called before a deliberate
+/// restart, so nothing waiting for `save_if_due` is lost.
+pub async fn save() {
+    let mut bytes = [0u8; STORED_LEN];
+    let saving = LOG.lock(|log| {
+        let mut log = log.borrow_mut();
+        if log.unsaved == 0 {
+            return false;
+        }
+        log.unsaved = 0;
+        log.unsaved_since = None;
+
+        bytes[0..2].copy_from_slice(&log.boot.to_le_bytes());
+        bytes[2] = log.entries.len() as u8;
+        for (chunk, entry) in bytes[3..]
+            .chunks_exact_mut(ENTRY_LEN)
+            .zip(log.entries.iter())
+        {
+            chunk.copy_from_slice(&entry.to_bytes());
+        }
+        true
+    });
+
+    if saving {
+        if let Err(e) = storage::save(Slot::AlarmLog, &bytes).await {
+            error!("Couldn't save the alarm log: {}", e);
+        }
+    }
+}
+
+/// The log, newest first.
+pub fn entries() -> Vec<Entry, LOG_LEN> {
+    LOG.lock(|log| log.borrow().entries.iter().rev().copied().collect())
+}
+
+/// The log as a JSON array, newest first, into `out`. `None` if it doesn't fit.
+pub fn serialize(out: &mut [u8]) -> Option<usize> {
+    serde_json_core::to_slice(&entries(), out).ok()
+}
+
+/// Ask for the log to be published, the next chance there is.
+pub fn request_dump() {
+    DUMP_REQUESTED.lock(|r| r.set(true));
+}
+
+/// Whether the log's been asked for, clearing the request.
+pub fn take_dump_request() -> bool {
+    DUMP_REQUESTED.lock(|r| r.replace(false))
+}
@@ -0,0 +1,196 @@
+//! Which metrics are over their thresholds right now, and since when, for the alarms page.
+//!
+//! The page joins the carousel while two or more metrics are over at once, when the overall health
+//! alone doesn't say what's wrong. A press while it's up dismisses it, until another metric goes
+//! over or one that was poor turns dangerous.
+//!
+//! An alarm only ends once its metric's clearly back under the warning threshold, `HYSTERESIS`
+//! below it, so a reading hovering at the threshold doesn't start and end one every poll. The whole
+//! alarm then goes in the log, see `alarm_log`, unless it was over too quickly to matter.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+use crate::alarm_log;
+use crate::metric::Metric;
+use crate::sen55::{Health, Readings};
+
+/// The most alarms there can be, one per metric.
+pub const MAX_ALARMS: usize = Metric::ALL.len();
+
+/// How far under the warning threshold, as a fraction of it, a metric has to fall to end its alarm.
+const HYSTERESIS: f32 = 0.1;
+
+/// Alarms shorter than this aren't logged.
+const MIN_LOGGED: Duration = Duration::from_secs(60);
+
+/// One metric over its threshold.
+#[derive(Debug, Clone, Copy)]
+pub struct Alarm {
+    pub metric: Metric,
+    pub health: Health,
+    pub value: f32,
+    /// The threshold it's over.
+    pub threshold: f32,
+    /// When it went over, or from poor to dangerous.
+    pub since: Instant,
+    /// When it first went over, however it's been since.
+    began: Instant,
+    /// The worst and highest it's been since then, for the log.
+    worst: Health,
+    peak: f32,
+}
+
+impl Alarm {
+    fn log_entry(&self) -> alarm_log::Entry {
+        let began = self.began.as_secs().min(u64::from(u32::MAX)) as u32;
+        alarm_log::Entry {
+            boot: alarm_log::boot(),
+            started_s: began,
+            metric: self.metric,
+            health: self.worst,
+            peak: self.peak,
+            duration_s: self.began.elapsed().as_secs() as u32,
+        }
+    }
+}
+
+struct Tracker {
+    alarms: [Option<Alarm>; MAX_ALARMS],
+    /// The alarms that were up when the page was dismissed, see `Tracker::signature`.
+    dismissed: u32,
+}
+
+static TRACKER: Mutex<ThreadModeRawMutex, RefCell<Tracker>> = Mutex::new(RefCell::new(Tracker {
+    alarms: [None; MAX_ALARMS],
+    dismissed: 0,
+}));
+
+impl Tracker {
+    /// The alarms as bits: one for each metric over warning, and another for each over danger.
+    fn signature(&self) -> u32 {
+        self.alarms.iter().flatten().fold(0, |bits, alarm| {
+            let bit = 1 << (alarm.metric as u32 * 2);
+            match alarm.health {
+                Health::Dangerous => bits | bit | bit << 1,
+                _ => bits | bit,
+            }
+        })
+    }
+}
+
+/// Take new readings into account. Metrics with no value keep whatever alarm they had.
+pub fn update(readings: &Readings) {
+    TRACKER.lock(|t| {
+        let mut t = t.borrow_mut();
+        let now = Instant::now();
+
+        for metric in Metric::ALL {
+            let (Some(value), Some(thresholds)) =
+                (metric.value(readings), metric.descriptor().thresholds)
+            else {
+                continue;
+            };
+
+            let slot = &mut t.alarms[metric as usize];
+            *slot = match (metric.health(value), *slot) {
+                (Some(health @ (Health::Warning | Health::Dangerous)), previous) => {
+                    let threshold = match health {
+                        Health::Dangerous => thresholds.danger,
+                        _ => thresholds.warning,
+                    };
+                    let alarm = Alarm {
+                        metric,
+                        health,
+                        value,
+                        threshold,
+                        since: now,
+                        began: now,
+                        worst: health,
+                        peak: value,
+                    };
+                    Some(match previous {
+                        Some(previous) => Alarm {
+                            since: match rank(&previous.health) == rank(&health) {
+                                true => previous.since,
+                                false => now,
+                            },
+                            began: previous.began,
+                            worst: match rank(&previous.worst) > rank(&health) {
+                                true => previous.worst,
+                                false => health,
+                            },
+                            peak: previous.peak.max(value),
+                            ..alarm
+                        },
+                        None => alarm,
+                    })
+                }
+                // Back under, but not by enough to be sure, so it's still a warning.
+                (_, Some(previous)) if value > thresholds.warning * (1.0 - HYSTERESIS) => {
+                    Some(Alarm {
+                        health: Health::Warning,
+                        value,
+                        threshold: thresholds.warning,
+                        since: match previous.health {
+                            Health::Warning => previous.since,
+                            _ => now,
+                        },
+                        ..previous
+                    })
+                }
+                // It's over, so it goes in the log.
+                (_, Some(previous)) => {
+                    if previous.began.elapsed() >= MIN_LOGGED {
+                        alarm_log::record(previous.log_entry());
+                    }
+                    None
+                }
+                (_, None) => None,
+            };
+        }
+
+        // Anything dismissed is forgotten once it's cleared, so it can come back.
+        let signature = t.signature();
+        t.dismissed &= signature;
+    });
+}
+
+/// The alarms up now, dangerous first, then the longest-standing.
+pub fn active() -> Vec<Alarm, MAX_ALARMS> {
+    let mut alarms: Vec<Alarm, MAX_ALARMS> =
+        TRACKER.lock(|t| t.borrow().alarms.iter().flatten().copied().collect());
+    alarms.sort_unstable_by_key(|alarm| (core::cmp::Reverse(rank(&alarm.health)), alarm.since));
+    alarms
+}
+
+/// Whether the alarms page should be in the carousel: there's more than one alarm up, and they
+/// aren't all ones that were dismissed.
+pub fn wanted() -> bool {
+    TRACKER.lock(|t| {
+        let t = t.borrow();
+        let signature = t.signature();
+        t.alarms.iter().flatten().count() > 1 && signature & !t.dismissed != 0
+    })
+}
+
+/// Take the page out of the carousel until there's something new to see.
+pub fn dismiss() {
+    TRACKER.lock(|t| {
+        let mut t = t.borrow_mut();
+        let signature = t.signature();
+        t.dismissed = signature;
+    });
+}
+
+const fn rank(health: &Health) -> u8 {
+    match health {
+        Health::Ok => 0,
+        Health::Warning => 1,
+        Health::Dangerous => 2,
+    }
+}
@@ -0,0 +1,86 @@
+//! Keeps track of which components were last announced to Home Assistant, so any that a firmware
+//! update (or a change to `STATE_FIELDS`) drops can be removed, rather than left behind as stale
+//! entities. Home Assistant only removes a component from a device when it's sent with nothing but
+//! its platform.
+
+use defmt::{info, warn};
+
+use crate::hass::{DiscoveryComponent, DiscoveryMessage};
+use crate::storage::{self, Slot};
+
+/// Plenty for a `<key>:<platform>` line per component.
+const RECORD_LEN: usize = 1024;
+
+/// The components in a discovery message, as `<key>:<platform>` lines padded out with zeroes.
+#[derive(PartialEq, Eq)]
+pub struct Announced {
+    bytes: [u8; RECORD_LEN],
+}
+
+impl Announced {
+    /// The components in `discovery`, or `None` if there are too many to keep track of.
+    pub fn of(discovery: &DiscoveryMessage) -> Option<Self> {
+        let mut bytes = [0; RECORD_LEN];
+        let mut len = 0;
+
+        for (key, component) in discovery.components.iter() {
+            for part in [*key, ":", component.platform, "\n"] {
+                let end = len + part.len();
+                bytes.get_mut(len..end)?.copy_from_slice(part.as_bytes());
+                len = end;
+            }
+        }
+
+        Some(Self { bytes })
+    }
+
+    /// The components announced last time, if there's a record of them.
+    pub async fn load() -> Option<Self> {
+        let mut bytes = [0; RECORD_LEN];
+
+        match storage::load(Slot::Announced, &mut bytes).await {
+            Ok(()) => Some(Self { bytes }),
+            Err(e) => {
+                info!("No record of announced components ({})", e);
+                None
+            }
+        }
+    }
+
+    pub async fn save(&self) {
+        if let Err(e) = storage::save(Slot::Announced, &self.bytes).await {
+            warn!("Couldn't save the announced components: {}", e);
+        }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        let len = self
+            .bytes
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(RECORD_LEN);
+
+        core::str::from_utf8(&self.bytes[..len])
+            .unwrap_or("")
+            .lines()
+            .filter_map(|line| line.rsplit_once(':'))
+    }
+
+    /// Add a removal to `discovery` for each component in this record that it no longer has.
+    pub fn retire_stale<'a>(&'a self, discovery: &mut DiscoveryMessage<'a>) {
+        for (key, platform) in self.entries() {
+            if discovery.components.contains_key(&key) {
+                continue;
+            }
+
+            info!("Removing stale component {}", key);
+            if discovery
+                .components
+                .insert(key, DiscoveryComponent::retired(platform))
+                .is_err()
+            {
+                warn!("No room in the discovery message to remove {}", key);
+            }
+        }
+    }
+}
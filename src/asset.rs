@@ -0,0 +1,237 @@
+//! Full-screen background images, stored compressed in flash.
+//!
+//! `build.rs` converts everything in `ui/export` to RGB565 and deflates it into `OUT_DIR`. Drawing
+//! an image inflates it a chunk at a time and streams the pixels straight to the display, so the
+//! decompressed image is never held in RAM.
+//!
+//! There's one inflater, with its window, shared by every image. It carries on from wherever the
+//! last draw left off, and the window still holds the last few dozen rows, so drawing parts of an
+//! image from the top down (as the readings page draws its tiles) inflates it only once.
+
+use core::cell::RefCell;
+use core::ptr;
+
+use defmt::error;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embedded_graphics::image::ImageDrawable;
+use embedded_graphics::pixelcolor::raw::RawU16;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::{DrawTarget, OriginDimensions, Point, Size};
+use embedded_graphics::primitives::Rectangle;
+use miniz_oxide::inflate::core::{decompress, DecompressorOxide};
+use miniz_oxide::inflate::TINFLStatus;
+
+/// Deflate looks back up to 32KB, so the output buffer has to be at least that big. It wraps
+/// around, so it must also be a power of two.
+const WINDOW_SIZE: usize = 32 * 1024;
+
+/// The window's far too big for the stack, and only the UI worker draws, so there's just the one.
+static INFLATER: Mutex<ThreadModeRawMutex, RefCell<Inflater>> =
+    Mutex::new(RefCell::new(Inflater::new()));
+
+/// Include one of the compressed `ui/export` images by its name without the extension.
+macro_rules! compressed_asset {
+    ($name:literal) => {
+        include_bytes!(concat!(env!("OUT_DIR"), "/", $name, ".deflate"))
+    };
+}
+
+pub(crate) use compressed_asset;
+
+/// A deflated RGB565 (little endian) image.
+pub struct CompressedImage {
+    data: &'static [u8],
+    size: Size,
+}
+
+impl CompressedImage {
+    pub const fn new(data: &'static [u8], width: u32, height: u32) -> Self {
+        Self {
+            data,
+            size: Size::new(width, height),
+        }
+    }
+}
+
+impl OriginDimensions for CompressedImage {
+    fn size(&self) -> Size {
+        self.size
+    }
+}
+
+impl ImageDrawable for CompressedImage {
+    type Color = Rgb565;
+
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        let area = Rectangle::new(Point::zero(), self.size);
+
+        INFLATER.lock(|inflater| {
+            let mut inflater = inflater.borrow_mut();
+            inflater.seek(self.data, 0);
+
+            let pixels = (0..area.size.width * area.size.height).map_while(|_| inflater.pixel());
+            target.fill_contiguous(&area, pixels)
+        })
+    }
+
+    fn draw_sub_image<D>(&self, target: &mut D, area: &Rectangle) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        let area = area.intersection(&Rectangle::new(Point::zero(), self.size));
+        let width = self.size.width as usize;
+        let area_width = area.size.width as usize;
+        let first = area.top_left.y as usize * width + area.top_left.x as usize;
+
+        INFLATER.lock(|inflater| {
+            let mut inflater = inflater.borrow_mut();
+            inflater.seek(self.data, first);
+
+            // Deflate can't seek, so the rest of each row has to be inflated to get to the next.
+            let pixels = (0..area_width * area.size.height as usize).map_while(|i| {
+                if i > 0 && i % area_width == 0 {
+                    inflater.skip(width - area_width);
+                }
+                inflater.pixel()
+            });
+
+            target.fill_contiguous(&Rectangle::new(Point::zero(), area.size), pixels)
+        })
+    }
+}
+
+/// Inflates an image on demand, handing out one pixel at a time.
+struct Inflater {
+    /// The image being inflated.
+    data: &'static [u8],
+    in_pos: usize,
+    /// Made the first time an image is drawn.
+    state: Option<DecompressorOxide>,
+    window: [u8; WINDOW_SIZE],
+    /// Where in the window the decompressor writes next.
+    out_pos: usize,
+    /// Where in the window the next unread byte is.
+    read_pos: usize,
+    /// How many bytes have been decompressed but not read yet.
+    available: usize,
+    /// How many bytes have been decompressed in all.
+    produced: usize,
+    /// Which pixel of the image `read_pos` is on.
+    next_pixel: usize,
+    done: bool,
+}
+
+impl Inflater {
+    const fn new() -> Self {
+        Self {
+            data: &[],
+            in_pos: 0,
+            state: None,
+            window: [0; WINDOW_SIZE],
+            out_pos: 0,
+            read_pos: 0,
+            available: 0,
+            produced: 0,
+            next_pixel: 0,
+            done: true,
+        }
+    }
+
+    /// Get ready to hand out `data`'s pixels from number `pixel` on. That's cheap if it's further
+    /// on than the last draw got to, or not far behind it, and means starting over if not.
+    fn seek(&mut self, data: &'static [u8], pixel: usize) {
+        if !ptr::eq(data, self.data) || (pixel < self.next_pixel && !self.rewind(pixel)) {
+            self.restart(data);
+        }
+        self.skip(pixel - self.next_pixel);
+    }
+
+    /// Step back to `pixel` if it's still in the window.
+    fn rewind(&mut self, pixel: usize) -> bool {
+        let back = (self.next_pixel - pixel) * 2;
+        if back > self.produced.min(WINDOW_SIZE) - self.available {
+            return false;
+        }
+
+        self.read_pos = (self.read_pos + WINDOW_SIZE - back) % WINDOW_SIZE;
+        self.available += back;
+        self.next_pixel = pixel;
+        true
+    }
+
+    fn restart(&mut self, data: &'static [u8]) {
+        self.state.get_or_insert_with(DecompressorOxide::new).init();
+        self.data = data;
+        self.in_pos = 0;
+        self.out_pos = 0;
+        self.read_pos = 0;
+        self.available = 0;
+        self.produced = 0;
+        self.next_pixel = 0;
+        self.done = false;
+    }
+
+    fn skip(&mut self, pixels: usize) {
+        for _ in 0..pixels {
+            if self.pixel().is_none() {
+                break;
+            }
+        }
+    }
+
+    fn pixel(&mut self) -> Option<Rgb565> {
+        let low = self.next_byte()?;
+        let high = self.next_byte()?;
+        self.next_pixel += 1;
+        Some(RawU16::new(u16::from_le_bytes([low, high])).into())
+    }
+
+    fn next_byte(&mut self) -> Option<u8> {
+        while self.available == 0 {
+            if self.done {
+                return None;
+            }
+            self.inflate();
+        }
+
+        let byte = self.window[self.read_pos];
+        self.read_pos = (self.read_pos + 1) % WINDOW_SIZE;
+        self.available -= 1;
+        Some(byte)
+    }
+
+    /// Decompress as much as will fit before the end of the window. Only called once everything
+    /// already in the window has been read, so nothing unread is overwritten.
+    fn inflate(&mut self) {
+        let Some(state) = &mut self.state else {
+            self.done = true;
+            return;
+        };
+
+        let (status, consumed, produced) = decompress(
+            state,
+            &self.data[self.in_pos..],
+            &mut self.window,
+            self.out_pos,
+            0,
+        );
+
+        self.in_pos += consumed;
+        self.out_pos = (self.out_pos + produced) % WINDOW_SIZE;
+        self.available += produced;
+        self.produced += produced;
+
+        match status {
+            TINFLStatus::HasMoreOutput => {}
+            TINFLStatus::Done => self.done = true,
+            other => {
+                error!("Couldn't decompress image: {}", other as i8);
+                self.done = true;
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! A ready-made Home Assistant automation, published (retained) as YAML so it can be pasted
+//! straight into Home Assistant: turn an air purifier on when the PM2.5 is high, and off again once
+//! it's settled.
+//!
+//! Home Assistant names entities after the device and component names, so the entity IDs here are
+//! worked out the same way from what was announced in the discovery message.
+
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::config::{self, Component};
+use crate::device_config;
+use crate::hass::DiscoveryMessage;
+
+/// Turn the purifier on above this much PM2.5 (µg/m³, the same as the warning threshold)...
+const ON_ABOVE: f32 = 25.0;
+const ON_AFTER: &str = "00:05:00";
+
+/// ...and off again once it's been below this for a while.
+const OFF_BELOW: f32 = 10.0;
+const OFF_AFTER: &str = "00:15:00";
+
+pub type Yaml = String<1024>;
+
+/// The automation for the device in `discovery`, or `None` if PM2.5 isn't announced (e.g. it's
+/// been left out of the state message).
+pub fn build(discovery: &DiscoveryMessage) -> Option<Yaml> {
+    let pm2_5 = discovery.components.get(&Component::Pm2_5.id())?;
+
+    let mut sensor = String::<64>::new();
+    write!(sensor, "{}.", pm2_5.platform).ok()?;
+    slugify(&mut sensor, discovery.device.name)?;
+    sensor.push('_').ok()?;
+    slugify(&mut sensor, pm2_5.name)?;
+
+    let name = &device_config::get().name;
+    let purifier = config::HASS_PURIFIER_ENTITY;
+
+    let mut yaml = Yaml::new();
+    write!(
+        yaml,
+        "- alias: \"{name}: purifier on when PM2.5 is high\"\n  \
+           triggers:\n    \
+             - trigger: numeric_state\n      \
+               entity_id: {sensor}\n      \
+               above: {ON_ABOVE}\n      \
+               for: \"{ON_AFTER}\"\n  \
+           actions:\n    \
+             - action: homeassistant.turn_on\n      \
+               target:\n        \
+                 entity_id: {purifier}\n\
+         - alias: \"{name}: purifier off when PM2.5 is low\"\n  \
+           triggers:\n    \
+             - trigger: numeric_state\n      \
+               entity_id: {sensor}\n      \
+               below: {OFF_BELOW}\n      \
+               for: \"{OFF_AFTER}\"\n  \
+           actions:\n    \
+             - action: homeassistant.turn_off\n      \
+               target:\n        \
+                 entity_id: {purifier}\n"
+    )
+    .ok()?;
+
+    Some(yaml)
+}
+
+/// Append `name` the way Home Assistant turns names into entity IDs: lower case, with each run of
+/// anything else replaced by a single underscore.
+fn slugify<const N: usize>(out: &mut String<N>, name: &str) -> Option<()> {
+    let mut gap = false;
+    let mut any = false;
+
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            if gap && any {
+                out.push('_').ok()?;
+            }
+            out.push(c.to_ascii_lowercase()).ok()?;
+            gap = false;
+            any = true;
+        } else {
+            gap = true;
+        }
+    }
+
+    Some(())
+}
@@ -1,46 +1,75 @@
-/// General purpose O(1) rolling average calculator.
-/// Keeps track of the last L values and provides a rolling average of them.
+use embassy_time::Duration;
+
+/// General purpose rolling average calculator, weighted by time.
+/// Keeps track of the last L values, each with how long it had been since the one before, and
+/// provides an average of them over a stretch of time.
+///
+/// Each value stands in for the gap before it, so when a read fails the next value covers for it,
+/// and values from before a long gap fall out of the window rather than being stretched over it.
+/// Only the gaps are kept, never when the values were taken, so the averages still make sense
+/// after a warm reboot resets the clock.
+///
+/// A value can be missing (the sensor had nothing to report), in which case its gap still counts
+/// towards the window but not towards the average.
+#[derive(Clone)]
 pub struct Hysterysiser<const L: usize> {
-    values: [f32; L],
+    values: [Option<f32>; L],
+    /// How long it had been since the previous value when each one was pushed.
+    gaps: [Duration; L],
     index: usize,
-    sum: usize,
-    ready: bool,
+    /// How many values have been pushed, up to L.
+    count: usize,
 }
 
 impl<const L: usize> Hysterysiser<L> {
     pub fn new() -> Self {
         Self {
-            values: [0.0; L],
+            values: [None; L],
+            gaps: [Duration::from_ticks(0); L],
             index: 0,
-            sum: 0,
-            ready: false,
+            count: 0,
         }
     }
 
-    /// Push a new value into the readings to be avaeraged.
-    pub fn push(&mut self, value: f32) {
-        // Subtract the oldest value from the sum, then swap it for the new value and re-add it to the sum.
-        // This way we don't have to iterate over the whole array to calculate the average every time.
-        self.sum -= self.values[self.index] as usize;
+    /// Push a new value into the readings to be averaged, `gap` after the last one.
+    pub fn push(&mut self, value: Option<f32>, gap: Duration) {
         self.values[self.index] = value;
-        self.sum += value as usize;
-
-        // Set the ready flag if we've filled the array for the first time.
-        // This means that the average will be of good quality from now on.
-        if !self.ready && self.index == L - 1 {
-            self.ready = true;
-        }
+        self.gaps[self.index] = gap;
 
+        self.count = (self.count + 1).min(L);
         self.index = (self.index + 1) % L;
     }
 
-    /// Get the rolling average of the last L values, or
-    /// None if there aren't enough readings yet.
-    pub fn average(&self) -> Option<f32> {
-        if !self.ready {
-            return None;
+    /// Get the average over the `window` up to the latest value, or None if the values don't go
+    /// back that far yet (unless there are already L of them) or none of them in the window are
+    /// there.
+    ///
+    /// Each value counts for the gap before it, but for no more than `max_gap`: a few missed reads
+    /// are made up for by the value after them, but the rest of a longer gap isn't counted at all.
+    pub fn average(&self, window: Duration, max_gap: Duration) -> Option<f32> {
+        let mut elapsed = Duration::from_ticks(0);
+        let mut weighted = 0.0;
+        let mut covered = Duration::from_ticks(0);
+
+        for back in 0..self.count {
+            let slot = (self.index + L - 1 - back) % L;
+            let gap = self.gaps[slot];
+
+            if let Some(value) = self.values[slot] {
+                let weight = gap.min(max_gap).min(window - elapsed);
+                weighted += value * weight.as_micros() as f32;
+                covered += weight;
+            }
+
+            elapsed += gap;
+            if elapsed >= window {
+                break;
+            }
         }
 
-        Some(self.sum as f32 / L as f32)
+        if elapsed < window && self.count < L {
+            return None;
+        }
+        (covered > Duration::from_ticks(0)).then(|| weighted / covered.as_micros() as f32)
     }
 }
@@ -0,0 +1,240 @@
+//! Backgrounds for the readings page.
+//!
+//! The original backgrounds are bitmaps exported from the Sketch file in `ui/`. As an alternative,
+//! the same layout can be drawn from primitives in one of a few colour themes, which makes it
+//! possible to restyle the page without regenerating any assets. Set `READINGS_THEME` at build
+//! time to pick one.
+
+use embedded_graphics::image::ImageDrawable;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::{
+    DrawTarget, DrawTargetExt, OriginDimensions, Point, Primitive, RgbColor, Size,
+};
+use embedded_graphics::primitives::{CornerRadii, PrimitiveStyle, Rectangle, RoundedRectangle};
+use embedded_graphics::Drawable;
+use u8g2_fonts::types::{FontColor, HorizontalAlignment, VerticalPosition};
+use u8g2_fonts::FontRenderer;
+
+use crate::asset::CompressedImage;
+use crate::config;
+use crate::metric::Metric;
+use crate::pages::TILES;
+use crate::sen55::Health;
+use crate::settings::{self, TemperatureUnit};
+use crate::ui::{DISPLAY_H, DISPLAY_W};
+
+/// Colours for a drawn background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub background: Rgb565,
+    pub panel: Rgb565,
+    pub label: Rgb565,
+    pub value: Rgb565,
+    pub ok: Rgb565,
+    pub warning: Rgb565,
+    pub dangerous: Rgb565,
+}
+
+impl Theme {
+    pub const DARK: Theme = Theme {
+        background: Rgb565::BLACK,
+        panel: Rgb565::new(4, 8, 6),
+        label: Rgb565::new(20, 40, 20),
+        value: Rgb565::WHITE,
+        ok: Rgb565::new(4, 48, 12),
+        warning: Rgb565::new(31, 44, 0),
+        dangerous: Rgb565::new(31, 8, 4),
+    };
+
+    pub const LIGHT: Theme = Theme {
+        background: Rgb565::new(29, 59, 29),
+        panel: Rgb565::WHITE,
+        label: Rgb565::new(12, 24, 12),
+        value: Rgb565::BLACK,
+        ok: Rgb565::new(2, 40, 8),
+        warning: Rgb565::new(28, 36, 0),
+        dangerous: Rgb565::new(28, 4, 2),
+    };
+
+    /// The theme set by `READINGS_THEME`, or `None` to use the bitmaps.
+    pub fn from_config() -> Option<Theme> {
+        match config::READINGS_THEME {
+            Some("dark") => Some(Theme::DARK),
+            Some("light") => Some(Theme::LIGHT),
+            _ => None,
+        }
+    }
+
+    fn health(&self, health: &Health) -> Rgb565 {
+        match health {
+            Health::Ok => self.ok,
+            Health::Warning => self.warning,
+            Health::Dangerous => self.dangerous,
+        }
+    }
+}
+
+// Panels sit around each value, with the label along the top.
+const PANEL_PAD_X: i32 = 6;
+const PANEL_PAD_TOP: i32 = 20;
+const PANEL_SIZE: Size = Size::new(78, 50);
+const PANEL_RADIUS: u32 = 6;
+
+// A bar down the left shows the health at a glance, with it spelled out along the bottom.
+const HEALTH_BAR: Rectangle = Rectangle::new(Point::new(14, 8), Size::new(12, 242));
+const HEALTH_TEXT_Y: i32 = 258;
+
+/// The readings page background, drawn from primitives rather than a bitmap.
+pub struct DrawnBackground {
+    theme: Theme,
+    health: Health,
+}
+
+impl DrawnBackground {
+    pub const fn new(theme: Theme, health: Health) -> Self {
+        Self { theme, health }
+    }
+
+    /// Draw whatever part of the background falls inside the target's bounding box. Anything
+    /// that doesn't is skipped rather than clipped, so drawing a single tile stays cheap.
+    fn draw_within<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Rgb565>,
+    {
+        let visible = target.bounding_box();
+        let overlaps = |r: &Rectangle| !r.intersection(&visible).is_zero_sized();
+
+        target.fill_solid(&visible, self.theme.background)?;
+
+        if overlaps(&HEALTH_BAR) {
+            RoundedRectangle::with_equal_corners(HEALTH_BAR, Size::new(6, 6))
+                .into_styled(PrimitiveStyle::with_fill(self.theme.health(&self.health)))
+                .draw(target)?;
+        }
+
+        let label_font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_6x13_tf>();
+
+        for (metric, pos) in TILES {
+            let panel = Rectangle::new(pos - Point::new(PANEL_PAD_X, PANEL_PAD_TOP), PANEL_SIZE);
+            if !overlaps(&panel) {
+                continue;
+            }
+
+            RoundedRectangle::new(panel, CornerRadii::new(Size::new_equal(PANEL_RADIUS)))
+                .into_styled(PrimitiveStyle::with_fill(self.theme.panel))
+                .draw(target)?;
+
+            // u8g2 wraps the target's error in its own, which can't be returned from here. The
+            // fills above would already have failed on a broken target anyway.
+            _ = label_font.render(
+                label(metric),
+                pos - Point::new(0, PANEL_PAD_TOP - 3),
+                VerticalPosition::Top,
+                FontColor::Transparent(self.theme.label),
+                target,
+            );
+        }
+
+        let status = Rectangle::new(
+            Point::new(0, HEALTH_TEXT_Y),
+            Size::new(DISPLAY_W, DISPLAY_H - HEALTH_TEXT_Y as u32),
+        );
+        if overlaps(&status) {
+            _ = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_helvB12_tf>().render_aligned(
+                match self.health {
+                    Health::Ok => "Air OK",
+                    Health::Warning => "Air poor",
+                    Health::Dangerous => "Air bad",
+                },
+                Point::new(DISPLAY_W as i32 / 2, HEALTH_TEXT_Y),
+                VerticalPosition::Top,
+                HorizontalAlignment::Center,
+                FontColor::Transparent(self.theme.health(&self.health)),
+                target,
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl OriginDimensions for DrawnBackground {
+    fn size(&self) -> Size {
+        Size::new(DISPLAY_W, DISPLAY_H)
+    }
+}
+
+impl ImageDrawable for DrawnBackground {
+    type Color = Rgb565;
+
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        self.draw_within(&mut target.clipped(&self.bounding_box()))
+    }
+
+    fn draw_sub_image<D>(&self, target: &mut D, area: &Rectangle) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        self.draw_within(&mut target.translated(-area.top_left).clipped(area))
+    }
+}
+
+/// The background behind the readings, whichever kind it is.
+pub enum ReadingsBackground {
+    Bitmap(&'static CompressedImage),
+    Drawn(DrawnBackground),
+}
+
+impl ReadingsBackground {
+    /// Colour to draw the values in, so they stand out against the panels.
+    pub fn value_color(&self) -> Rgb565 {
+        match self {
+            ReadingsBackground::Bitmap(_) => Rgb565::WHITE,
+            ReadingsBackground::Drawn(drawn) => drawn.theme.value,
+        }
+    }
+}
+
+impl OriginDimensions for ReadingsBackground {
+    fn size(&self) -> Size {
+        match self {
+            ReadingsBackground::Bitmap(image) => image.size(),
+            ReadingsBackground::Drawn(drawn) => drawn.size(),
+        }
+    }
+}
+
+impl ImageDrawable for ReadingsBackground {
+    type Color = Rgb565;
+
+    fn draw<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        match self {
+            ReadingsBackground::Bitmap(image) => image.draw(target),
+            ReadingsBackground::Drawn(drawn) => drawn.draw(target),
+        }
+    }
+
+    fn draw_sub_image<D>(&self, target: &mut D, area: &Rectangle) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        match self {
+            ReadingsBackground::Bitmap(image) => image.draw_sub_image(target, area),
+            ReadingsBackground::Drawn(drawn) => drawn.draw_sub_image(target, area),
+        }
+    }
+}
+
+/// Labels for the drawn background. The bitmaps have their own baked in.
+fn label(metric: Metric) -> &'static str {
+    match (metric, settings::get().temperature_unit) {
+        (Metric::Temperature, TemperatureUnit::Fahrenheit) => "Temp \u{b0}F",
+        _ => metric.descriptor().label,
+    }
+}
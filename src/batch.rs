@@ -0,0 +1,192 @@
+//! Batches the pixels drawn one at a time into as few window writes as possible.
+//!
+//! Text and icons are drawn pixel by pixel, and the driver sets the display's window afresh for
+//! every pixel it's handed, which makes for a lot of tiny SPI transactions. [`Batched`] sits
+//! between the pages and the driver, and gathers each run of pixels drawn in a row (and each block
+//! of runs of the same width, one under the other) into a single window that's filled in one go.
+//!
+//! Batches never outlive the call they're drawn in, so there's nothing left to flush and the
+//! driver can still be used directly (through `Deref`) in between. Images and solid fills are
+//! already drawn a window at a time, so they're passed straight through.
+
+use core::ops::{Deref, DerefMut};
+
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::{Dimensions, DrawTarget, Point, Size};
+use embedded_graphics::primitives::Rectangle;
+use embedded_graphics::Pixel;
+use heapless::Vec;
+use portable_atomic::{AtomicU32, Ordering};
+use serde::Serialize;
+
+/// The most pixels gathered into one window, two bytes each on the stack.
+const BATCH_PIXELS: usize = 1024;
+
+static PIXELS: AtomicU32 = AtomicU32::new(0);
+static WRITES: AtomicU32 = AtomicU32::new(0);
+
+/// How well batching's doing, for the debug console.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct BatchSnapshot {
+    /// Pixels drawn one at a time since boot.
+    pub pixels: u32,
+    /// The windows they were written to the display in.
+    pub writes: u32,
+}
+
+pub fn snapshot() -> BatchSnapshot {
+    BatchSnapshot {
+        pixels: PIXELS.load(Ordering::Relaxed),
+        writes: WRITES.load(Ordering::Relaxed),
+    }
+}
+
+/// A display that batches what's drawn on it a pixel at a time. See the module docs.
+pub struct Batched<D> {
+    inner: D,
+}
+
+impl<D> Batched<D> {
+    pub const fn new(inner: D) -> Self {
+        Self { inner }
+    }
+}
+
+impl<D> Deref for Batched<D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        &self.inner
+    }
+}
+
+impl<D> DerefMut for Batched<D> {
+    fn deref_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+}
+
+impl<D: Dimensions> Dimensions for Batched<D> {
+    fn bounding_box(&self) -> Rectangle {
+        self.inner.bounding_box()
+    }
+}
+
+impl<D: DrawTarget<Color = Rgb565>> DrawTarget for Batched<D> {
+    type Color = Rgb565;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Rgb565>>,
+    {
+        let bounds = self.inner.bounding_box();
+        let mut batch = Batch::new();
+
+        // Pixels off screen are dropped, as the driver would, so a window never runs off it.
+        for Pixel(point, color) in pixels.into_iter().filter(|p| bounds.contains(p.0)) {
+            PIXELS.fetch_add(1, Ordering::Relaxed);
+            if !batch.push(point, color) {
+                batch.flush(&mut self.inner)?;
+                batch.push(point, color);
+            }
+        }
+
+        batch.flush(&mut self.inner)
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Rgb565>,
+    {
+        self.inner.fill_contiguous(area, colors)
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Rgb565) -> Result<(), Self::Error> {
+        self.inner.fill_solid(area, color)
+    }
+
+    fn clear(&mut self, color: Rgb565) -> Result<(), Self::Error> {
+        self.inner.clear(color)
+    }
+}
+
+/// Pixels gathered into a window: whole rows of the same width from `origin` down, then part of
+/// another.
+struct Batch {
+    origin: Point,
+    /// How wide the rows are, once the first one's finished.
+    width: Option<u32>,
+    /// Row by row, left to right.
+    pixels: Vec<Rgb565, BATCH_PIXELS>,
+}
+
+impl Batch {
+    const fn new() -> Self {
+        Self {
+            origin: Point::zero(),
+            width: None,
+            pixels: Vec::new(),
+        }
+    }
+
+    /// Add a pixel if it carries on where the batch left off, either along the row or at the
+    /// start of the next one. Returns false if it doesn't, or the batch is full.
+    fn push(&mut self, point: Point, color: Rgb565) -> bool {
+        let n = self.pixels.len() as u32;
+        if n == 0 {
+            self.origin = point;
+        } else {
+            let carries_on = match self.width {
+                Some(width) => point == self.offset(n % width, n / width),
+                None if point == self.offset(n, 0) => true,
+                None if point == self.offset(0, 1) => {
+                    self.width = Some(n);
+                    true
+                }
+                None => false,
+            };
+            if !carries_on {
+                return false;
+            }
+        }
+
+        self.pixels.push(color).is_ok()
+    }
+
+    fn offset(&self, x: u32, y: u32) -> Point {
+        self.origin + Point::new(x as i32, y as i32)
+    }
+
+    /// Write the batch out, as one window for its whole rows and another for any row it's part
+    /// way through, and start again.
+    fn flush<D: DrawTarget<Color = Rgb565>>(&mut self, target: &mut D) -> Result<(), D::Error> {
+        let n = self.pixels.len() as u32;
+        if n == 0 {
+            return Ok(());
+        }
+
+        let width = self.width.unwrap_or(n);
+        let rows = n / width;
+        let whole = (rows * width) as usize;
+
+        if rows > 0 {
+            WRITES.fetch_add(1, Ordering::Relaxed);
+            target.fill_contiguous(
+                &Rectangle::new(self.origin, Size::new(width, rows)),
+                self.pixels[..whole].iter().copied(),
+            )?;
+        }
+        if whole < self.pixels.len() {
+            WRITES.fetch_add(1, Ordering::Relaxed);
+            target.fill_contiguous(
+                &Rectangle::new(self.offset(0, rows), Size::new(n - whole as u32, 1)),
+                self.pixels[whole..].iter().copied(),
+            )?;
+        }
+
+        self.pixels.clear();
+        self.width = None;
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+use defmt::{info, Format};
+use embassy_futures::select::{select, Either};
+use embassy_rp::gpio::Input;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::{MQTT_BUTTON_CHANNEL, UI_BUTTON_CHANNEL};
+
+/// Ignore any bouncing for this long after the button changes state.
+const DEBOUNCE: Duration = Duration::from_millis(30);
+
+/// Holding the button for at least this long counts as a long press.
+const LONG_PRESS: Duration = Duration::from_millis(800);
+
+/// A second press starting within this long of letting go of the first makes a double press.
+const DOUBLE_PRESS_GAP: Duration = Duration::from_millis(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ButtonEvent {
+    Press,
+    LongPress,
+    /// Two short presses in quick succession.
+    DoublePress,
+    /// The rotary encoder (see `encoder`) turned one step clockwise.
+    Clockwise,
+    CounterClockwise,
+}
+
+impl ButtonEvent {
+    /// The event types announced to Home Assistant for the button, see `event_type`.
+    pub const EVENT_TYPES: [&'static str; 3] = ["press", "long_press", "double_press"];
+
+    /// What the press is called in Home Assistant, or `None` for the encoder, which isn't passed on.
+    pub const fn event_type(&self) -> Option<&'static str> {
+        match self {
+            ButtonEvent::Press => Some(Self::EVENT_TYPES[0]),
+            ButtonEvent::LongPress => Some(Self::EVENT_TYPES[1]),
+            ButtonEvent::DoublePress => Some(Self::EVENT_TYPES[2]),
+            ButtonEvent::Clockwise | ButtonEvent::CounterClockwise => None,
+        }
+    }
+}
+
+/// Watches the button (active low, wired between the pin and ground) and sends presses to the UI,
+/// and to Home Assistant as events.
+///
+/// Long presses are reported as soon as the threshold is reached, rather than on release, so the
+/// user gets feedback without having to guess when to let go. A short press isn't reported until
+/// `DOUBLE_PRESS_GAP` after it's let go, in case it's the first of a double press.
+#[embassy_executor::task]
+pub async fn worker(mut button: Input<'static>) {
+    info!("started button worker");
+
+    loop {
+        button.wait_for_falling_edge().await;
+        Timer::after(DEBOUNCE).await;
+
+        // Bounced, or too short a blip to be a real press.
+        if button.is_high() {
+            continue;
+        }
+
+        let event = match select(button.wait_for_high(), Timer::after(LONG_PRESS)).await {
+            Either::First(()) => second_press(&mut button).await,
+            Either::Second(()) => ButtonEvent::LongPress,
+        };
+
+        info!("Button: {}", event);
+        if UI_BUTTON_CHANNEL.try_send(event).is_err() {
+            info!("UI isn't listening for buttons, dropping press");
+        }
+        // Without a broker nobody's listening, and the channel just fills up.
+        _ = MQTT_BUTTON_CHANNEL.try_send((event, Instant::now()));
+
+        // Wait for release before looking for the next press.
+        button.wait_for_high().await;
+        Timer::after(DEBOUNCE).await;
+    }
+}
+
+/// After a short press has been let go, see whether another one follows it closely enough to make
+/// a double press. The second press counts however long it's held.
+async fn second_press(button: &mut Input<'static>) -> ButtonEvent {
+    Timer::after(DEBOUNCE).await;
+
+    match select(
+        button.wait_for_falling_edge(),
+        Timer::after(DOUBLE_PRESS_GAP),
+    )
+    .await
+    {
+        Either::First(()) => {
+            Timer::after(DEBOUNCE).await;
+            match button.is_low() {
+                true => ButtonEvent::DoublePress,
+                false => ButtonEvent::Press,
+            }
+        }
+        Either::Second(()) => ButtonEvent::Press,
+    }
+}
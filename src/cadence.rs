@@ -0,0 +1,97 @@
+//! Adaptive publish cadence: publish quickly while the readings are on the move, and only now and
+//! then while they're steady, so a spike is seen promptly without flooding the broker the rest of
+//! the time.
+//!
+//! Set with `PUBLISH_CADENCE` as `<min>/<max>` in seconds. Readings are published once they've
+//! moved far enough from the last published ones (see `step`), but never sooner than `min` apart,
+//! and at least every `max` regardless. Without it, every reading is published.
+
+use defmt::warn;
+use embassy_time::{Duration, Instant};
+
+use crate::config;
+use crate::metric::Metric;
+use crate::sen55::Readings;
+
+/// The bounds set by `PUBLISH_CADENCE`.
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    min: Duration,
+    max: Duration,
+}
+
+impl Bounds {
+    /// Parse `PUBLISH_CADENCE`, e.g. `10/300`.
+    fn from_config() -> Option<Bounds> {
+        let (min, max) = config::PUBLISH_CADENCE?.split_once('/')?;
+        let (Ok(min), Ok(max)) = (min.trim().parse::<u64>(), max.trim().parse::<u64>()) else {
+            warn!("Couldn't parse PUBLISH_CADENCE, publishing every reading");
+            return None;
+        };
+
+        if min > max {
+            warn!("PUBLISH_CADENCE needs min <= max, publishing every reading");
+            return None;
+        }
+
+        Some(Bounds {
+            min: Duration::from_secs(min),
+            max: Duration::from_secs(max),
+        })
+    }
+}
+
+/// Decides when the next set of readings is worth publishing.
+pub struct Cadence {
+    bounds: Option<Bounds>,
+    /// The last readings published, and when.
+    last: Option<(Instant, Readings)>,
+}
+
+impl Cadence {
+    pub fn from_config() -> Self {
+        Self {
+            bounds: Bounds::from_config(),
+            last: None,
+        }
+    }
+
+    /// Whether `readings` should be published now. Nothing is published sooner than `floor` after
+    /// the last time, whatever the cadence says.
+    pub fn due(&self, readings: &Readings, floor: Duration) -> bool {
+        let Some((at, last)) = &self.last else {
+            return true;
+        };
+
+        let elapsed = at.elapsed();
+        if elapsed < floor {
+            return false;
+        }
+
+        let Some(bounds) = self.bounds else {
+            return true;
+        };
+
+        if elapsed >= bounds.max {
+            return true;
+        }
+
+        elapsed >= bounds.min && moved(last, readings)
+    }
+
+    /// Record that `readings` were published.
+    pub fn published(&mut self, readings: Readings) {
+        self.last = Some((Instant::now(), readings));
+    }
+}
+
+/// Whether any metric has moved at least a step, or come or gone.
+fn moved(last: &Readings, now: &Readings) -> bool {
+    Metric::ALL
+        .into_iter()
+        .any(|metric| match (metric.value(last), metric.value(now)) {
+            (Some(a), Some(b)) => (b - a).abs() >= metric.descriptor().step,
+            (None, None) => false,
+            _ => true,
+        })
+}
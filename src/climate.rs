@@ -0,0 +1,136 @@
+//! The lowest and highest temperature and humidity over the last 24 hours, and the dew point, for
+//! the climate page.
+//!
+//! Each hour's lowest and highest are kept, so the window moves on an hour at a time. As with the
+//! report, hours are counted from boot rather than by the clock.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use micromath::F32Ext;
+use serde::Serialize;
+
+use crate::metric::Metric;
+use crate::sen55::Readings;
+
+/// How many hours the range covers.
+const HOURS: usize = 24;
+
+/// The lowest and highest a metric's been.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Range {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Range {
+    fn widen(range: &mut Option<Range>, value: f32) {
+        *range = Some(match *range {
+            Some(Range { min, max }) => Range {
+                min: min.min(value),
+                max: max.max(value),
+            },
+            None => Range {
+                min: value,
+                max: value,
+            },
+        });
+    }
+}
+
+#[derive(Clone)]
+struct History {
+    /// The hour since boot of the newest readings.
+    hour: u64,
+    /// Each hour's range, indexed by the hour since boot modulo `HOURS`.
+    temperature: [Option<Range>; HOURS],
+    humidity: [Option<Range>; HOURS],
+}
+
+static HISTORY: Mutex<ThreadModeRawMutex, RefCell<History>> = Mutex::new(RefCell::new(History {
+    hour: 0,
+    temperature: [None; HOURS],
+    humidity: [None; HOURS],
+}));
+
+/// Fold a new set of readings into the current hour's range.
+pub fn record(readings: &Readings) {
+    let hour = readings.taken_at.as_secs() / 3600;
+
+    HISTORY.lock(|h| {
+        let mut h = h.borrow_mut();
+
+        // Hours gone by since the last readings are from a day ago (or more), so they're cleared.
+        let passed = hour.saturating_sub(h.hour).min(HOURS as u64);
+        for i in 1..=passed {
+            let slot = ((h.hour + i) % HOURS as u64) as usize;
+            h.temperature[slot] = None;
+            h.humidity[slot] = None;
+        }
+        h.hour = h.hour.max(hour);
+
+        let slot = (h.hour % HOURS as u64) as usize;
+        if let Some(value) = Metric::Temperature.value(readings) {
+            Range::widen(&mut h.temperature[slot], value);
+        }
+        if let Some(value) = Metric::Humidity.value(readings) {
+            Range::widen(&mut h.humidity[slot], value);
+        }
+    });
+}
+
+/// Everything kept, to carry over a warm reboot (see `retained`).
+#[derive(Clone)]
+pub struct Snapshot(History);
+
+pub fn snapshot() -> Snapshot {
+    HISTORY.lock(|h| Snapshot(h.borrow().clone()))
+}
+
+/// Put back what was kept before a warm reboot. The hours are counted from boot, and the clock's
+/// started again since, so they're moved round to carry on from the current hour.
+pub fn restore(snapshot: Snapshot) {
+    let hour = Instant::now().as_secs() / 3600;
+    let mut restored = snapshot.0;
+
+    let shift =
+        (restored.hour % HOURS as u64 + HOURS as u64 - hour % HOURS as u64) as usize % HOURS;
+    restored.temperature.rotate_left(shift);
+    restored.humidity.rotate_left(shift);
+    restored.hour = hour;
+
+    HISTORY.lock(|h| *h.borrow_mut() = restored);
+}
+
+/// The temperature's range over the last 24 hours, in Celsius.
+pub fn temperature() -> Option<Range> {
+    HISTORY.lock(|h| combine(&h.borrow().temperature))
+}
+
+/// The humidity's range over the last 24 hours.
+pub fn humidity() -> Option<Range> {
+    HISTORY.lock(|h| combine(&h.borrow().humidity))
+}
+
+fn combine(hours: &[Option<Range>; HOURS]) -> Option<Range> {
+    hours.iter().flatten().fold(None, |range, hour| {
+        let mut range = range;
+        Range::widen(&mut range, hour.min);
+        Range::widen(&mut range, hour.max);
+        range
+    })
+}
+
+/// The dew point in Celsius, from the Magnus formula, or `None` for air with no humidity.
+pub fn dew_point(temperature: f32, humidity: f32) -> Option<f32> {
+    const B: f32 = 17.62;
+    const C: f32 = 243.12;
+
+    if humidity <= 0.0 {
+        return None;
+    }
+    let gamma = (humidity / 100.0).ln() + B * temperature / (C + temperature);
+    Some(C * gamma / (B - gamma))
+}
@@ -0,0 +1,66 @@
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+/// Reference pressure the SEN55's PM readings are calibrated against (sea level).
+const REFERENCE_PRESSURE_HPA: f32 = 1013.25;
+
+/// Anything outside this range is a broken sensor or a typo, not real weather.
+const MIN_PLAUSIBLE_HPA: f32 = 500.0;
+const MAX_PLAUSIBLE_HPA: f32 = 1100.0;
+
+/// Stop compensating if we haven't heard a new pressure in this long,
+/// rather than applying a correction from a reading that might be hours old.
+const PRESSURE_MAX_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// The most recent ambient pressure (hPa) and when it arrived.
+static AMBIENT_PRESSURE: Mutex<ThreadModeRawMutex, Cell<Option<(f32, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+/// The compensation applied to a set of readings, so it can be reported alongside them.
+#[derive(Debug, Clone, Copy)]
+pub struct PressureCompensation {
+    /// Ambient pressure used for the correction, in hPa.
+    pub pressure: f32,
+    /// What the raw PM readings were multiplied by.
+    pub pm_factor: f32,
+}
+
+/// Record a new ambient pressure reading in hPa, from an attached sensor or over MQTT.
+pub fn set_ambient_pressure(hpa: f32) {
+    if !(MIN_PLAUSIBLE_HPA..=MAX_PLAUSIBLE_HPA).contains(&hpa) {
+        warn!("Ignoring implausible ambient pressure {}hPa", hpa);
+        return;
+    }
+
+    info!("Ambient pressure now {}hPa", hpa);
+    AMBIENT_PRESSURE.lock(|p| p.set(Some((hpa, Instant::now()))));
+}
+
+/// Work out the PM correction for the current ambient pressure, if we have a recent one.
+///
+/// The SEN55 counts particles in a fixed volume of air, so in thinner air at altitude it
+/// under-reports relative to its sea-level calibration. Scaling by the pressure ratio normalises the
+/// mass concentration back to reference conditions.
+pub fn current() -> Option<PressureCompensation> {
+    let (pressure, received_at) = AMBIENT_PRESSURE.lock(|p| p.get())?;
+
+    if received_at.elapsed() > PRESSURE_MAX_AGE {
+        return None;
+    }
+
+    Some(PressureCompensation {
+        pressure,
+        pm_factor: REFERENCE_PRESSURE_HPA / pressure,
+    })
+}
+
+impl PressureCompensation {
+    /// Apply the correction to a raw PM reading.
+    pub fn apply(&self, pm: f32) -> f32 {
+        pm * self.pm_factor
+    }
+}
@@ -1,21 +1,152 @@
+use core::cell::Cell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Duration;
+use heapless::String;
+use protocol::{TopicBuilder, Topics};
+use static_cell::StaticCell;
+
+use crate::device_config;
+
+/// The Wi-Fi network and broker to use, and the identity to use with Home Assistant, until others
+/// are stored (see `device_config`).
 pub const WIFI_NETWORK: &str = env!("WF_SSID");
 pub const WIFI_PASSWORD: &str = env!("WF_PASS");
 
+/// How many failed attempts to join the Wi-Fi it takes to open the setup portal, see `portal`.
+/// Unset to keep trying forever. The portal's network is open unless `WIFI_PORTAL_PASSWORD` is set
+/// (to at least 8 characters).
+pub const WIFI_PORTAL_AFTER: Option<&str> = option_env!("WIFI_PORTAL_AFTER");
+pub const WIFI_PORTAL_PASSWORD: Option<&str> = option_env!("WIFI_PORTAL_PASSWORD");
+
+/// The two-letter country (e.g. `SE`) whose channels and power limits the Wi-Fi follows. Unset
+/// keeps the chip's worldwide default, which leaves out channels 12 and 13.
+pub const WIFI_COUNTRY: Option<&str> = option_env!("WIFI_COUNTRY");
+
+/// The Wi-Fi chip's power saving under the balanced profile: `none`, `power_save` (the default) or
+/// `aggressive`, see `power::WifiPower`.
+pub const WIFI_POWER: Option<&str> = option_env!("WIFI_POWER");
+
 pub const MQTT_CLIENT_ID: &str = env!("MQTT_CLIENT_ID");
-pub const MQTT_HOST: &str = env!("MQTT_HOST");
+/// The broker to publish to. Without one, the state is multicast to the LAN instead (see `lan`).
+pub const MQTT_HOST: Option<&str> = option_env!("MQTT_HOST");
+/// Optional credentials for brokers that want them.
+pub const MQTT_USERNAME: Option<&str> = option_env!("MQTT_USERNAME");
+pub const MQTT_PASSWORD: Option<&str> = option_env!("MQTT_PASSWORD");
+
+/// Optional topic publishing the ambient pressure in hPa (as a plain number), used to compensate
+/// the PM readings at altitude.
+pub const MQTT_TOPIC_PRESSURE: Option<&str> = option_env!("MQTT_PRESSURE_TOPIC");
+
+/// How often to read the SEN55, in milliseconds: from 1000 (the rate it measures at, and the
+/// default) to 15000. The averages cover the same time whatever it is, and the live graph has a bar
+/// per poll.
+pub const SENSOR_POLL_MS: Option<&str> = option_env!("SENSOR_POLL_MS");
+
+/// Optional syslog server to send events and errors to, with its UDP port (514 if unset) and the
+/// facility number to log as (16, `local0`, if unset).
+pub const SYSLOG_HOST: Option<&str> = option_env!("SYSLOG_HOST");
+pub const SYSLOG_PORT: Option<&str> = option_env!("SYSLOG_PORT");
+pub const SYSLOG_FACILITY: Option<&str> = option_env!("SYSLOG_FACILITY");
+
+/// Optional SNTP server (e.g. `pool.ntp.org`) to get the time of day from, so readings can be
+/// timestamped, see `sntp`.
+pub const NTP_SERVER: Option<&str> = option_env!("NTP_SERVER");
+
+/// Optional URL of a JSON manifest describing the latest firmware, e.g.
+/// `http://updates.lan/vindskrivare/manifest.json`, checked every few hours to update over the
+/// air, see `ota`.
+pub const OTA_URL: Option<&str> = option_env!("OTA_URL");
+
+/// The ed25519 public key updates from `OTA_URL` have to be signed with, as 64 hex digits. `cargo
+/// xtask ota-key` makes a key pair. Without it, nothing's updated.
+pub const OTA_PUBLIC_KEY: Option<&str> = option_env!("OTA_PUBLIC_KEY");
+
+/// Optional endpoint to POST the readings to as well, as `http://<host>[:<port>]/<path>`, e.g. a
+/// Home Assistant webhook or InfluxDB's write API. Pushed in `HTTP_PUSH_FORMAT` (`json`, the
+/// default, or `influx` for line protocol) every `HTTP_PUSH_INTERVAL` seconds (60 if unset), with
+/// `HTTP_PUSH_AUTH` as the `Authorization` header if it's set.
+pub const HTTP_PUSH_URL: Option<&str> = option_env!("HTTP_PUSH_URL");
+pub const HTTP_PUSH_FORMAT: Option<&str> = option_env!("HTTP_PUSH_FORMAT");
+pub const HTTP_PUSH_INTERVAL: Option<&str> = option_env!("HTTP_PUSH_INTERVAL");
+pub const HTTP_PUSH_AUTH: Option<&str> = option_env!("HTTP_PUSH_AUTH");
 
-// Easier to construct all this stuff at compile time than to do it at runtime
-// every time we need to send a message, which is very often.
+/// The hostname to register with DHCP, so the device shows up by name in the router's list of
+/// clients. Defaults to the device identifier. Either way it's tidied up by `dhcp_hostname`.
+pub const DHCP_HOSTNAME: Option<&str> = option_env!("DHCP_HOSTNAME");
 
-pub const MQTT_TOPIC_DICSOVERY: &str = concat!(
-    env!("MQTT_HASS_DISCOVERY_BASE"),
-    "/device/",
-    env!("HASS_DEVICE_IDENTIFIER"),
-    "/config"
-);
+/// `DHCP_HOSTNAME`, or the device identifier, made into a hostname routers will accept: lowercase
+/// letters, digits and hyphens, cut short at 32 characters.
+pub fn dhcp_hostname() -> String<32> {
+    let name = DHCP_HOSTNAME.unwrap_or(&device_config::get().identifier);
 
-pub const MQTT_TOPIC_STATE: &str =
-    concat!("/vindskrivare/", env!("HASS_DEVICE_IDENTIFIER"), "/state");
+    let mut hostname = String::new();
+    for c in name.chars() {
+        let c = match c {
+            'a'..='z' | '0'..='9' => c,
+            'A'..='Z' => c.to_ascii_lowercase(),
+            // Underscores, spaces and the like, but never two hyphens in a row.
+            _ if hostname.ends_with('-') => continue,
+            _ => '-',
+        };
+        if hostname.push(c).is_err() {
+            break;
+        }
+    }
+
+    // Hostnames can't start or end with a hyphen.
+    let trimmed = hostname.trim_matches('-');
+    match trimmed.is_empty() {
+        true => String::try_from("vindskrivare").unwrap_or_default(),
+        false => String::try_from(trimmed).unwrap_or_default(),
+    }
+}
+
+/// Set (to anything) to run a small HTTP server on port 80, see `http_server`.
+pub const HTTP_SERVER: bool = option_env!("HTTP_SERVER").is_some();
+
+/// Set (to anything) to measure the round trip to the gateway and broker, see `latency`.
+pub const LATENCY_MONITOR: bool = option_env!("LATENCY_MONITOR").is_some();
+
+/// Optional topic saying whether anyone's in the room (`on`/`off`), used to stop the SEN55's fan
+/// when nobody's been around for a while.
+pub const MQTT_TOPIC_PRESENCE: Option<&str> = option_env!("MQTT_PRESENCE_TOPIC");
+
+/// Optional namespace (e.g. `home-a/`) put in front of all the device's own topics, discovery
+/// included, so devices in several homes can share one broker.
+pub const MQTT_NAMESPACE: Option<&str> = option_env!("MQTT_NAMESPACE");
+
+/// Room for all the device's topics, laid out end to end.
+const MQTT_TOPICS_LEN: usize = 1800;
+
+static MQTT_TOPICS: Mutex<ThreadModeRawMutex, Cell<Option<Topics<'static>>>> =
+    Mutex::new(Cell::new(None));
+
+/// The device's MQTT topics. They're laid out the first time they're needed and kept after that,
+/// rather than formatted for every message, which is very often.
+pub fn mqtt_topics() -> Topics<'static> {
+    MQTT_TOPICS.lock(|topics| {
+        if let Some(built) = topics.get() {
+            return built;
+        }
+
+        static BUFFER: StaticCell<[u8; MQTT_TOPICS_LEN]> = StaticCell::new();
+        let built = TopicBuilder::new(
+            env!("MQTT_HASS_DISCOVERY_BASE"),
+            &device_config::get().identifier,
+        )
+        .namespace(MQTT_NAMESPACE)
+        .pressure(MQTT_TOPIC_PRESSURE)
+        .presence(MQTT_TOPIC_PRESENCE)
+        .build(BUFFER.init([0; MQTT_TOPICS_LEN]))
+        .expect("MQTT topics don't fit");
+
+        topics.set(Some(built));
+        built
+    })
+}
 
 pub const HASS_DEVICE_IDENTIFIER: &str = env!("HASS_DEVICE_IDENTIFIER");
 pub const HASS_DEVICE_NAME: &str = env!("HASS_DEVICE_NAME");
@@ -25,11 +156,264 @@ pub const HASS_DEVICE_SW: &str = env!("CARGO_PKG_VERSION");
 pub const HASS_DEVICE_HW: &str = "PicoW_SEN55_v1.0";
 pub const HASS_DEVICE_URL: &str = "https://github.com/mrbran4/Vindskrivare";
 
-pub const CMP_TEMPERATURE: &str = concat!(env!("HASS_DEVICE_IDENTIFIER"), "_t");
-pub const CMP_HUMIDITY: &str = concat!(env!("HASS_DEVICE_IDENTIFIER"), "_h");
-pub const CMP_PM1: &str = concat!(env!("HASS_DEVICE_IDENTIFIER"), "_pm1");
-pub const CMP_PM2_5: &str = concat!(env!("HASS_DEVICE_IDENTIFIER"), "_pm2_5");
-pub const CMP_PM4: &str = concat!(env!("HASS_DEVICE_IDENTIFIER"), "_pm4");
-pub const CMP_PM10: &str = concat!(env!("HASS_DEVICE_IDENTIFIER"), "_pm10");
-pub const CMP_VOC: &str = concat!(env!("HASS_DEVICE_IDENTIFIER"), "_voc");
-pub const CMP_NOX: &str = concat!(env!("HASS_DEVICE_IDENTIFIER"), "_nox");
+/// One of the device's components in Home Assistant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    Temperature,
+    Humidity,
+    Pm1,
+    Pm2_5,
+    Pm4,
+    Pm10,
+    Voc,
+    Nox,
+    SensorActivity,
+    Dropped,
+    PublishFailures,
+    Noise,
+    AirScore,
+    VocRelative,
+    GatewayRtt,
+    BrokerRtt,
+    DataSent,
+    DataReceived,
+    Rssi,
+    Uptime,
+    FreeRam,
+    IpAddress,
+    Broker,
+    AwayMode,
+    PowerProfile,
+    FanClean,
+    Button,
+    ShowTemperature,
+    ShowHumidity,
+    GuestMode,
+    ShowPm1,
+    ShowPm2_5,
+    ShowPm4,
+    ShowPm10,
+    ShowVoc,
+    ShowNox,
+}
+
+impl Component {
+    const ALL: [Component; 36] = [
+        Component::Temperature,
+        Component::Humidity,
+        Component::Pm1,
+        Component::Pm2_5,
+        Component::Pm4,
+        Component::Pm10,
+        Component::Voc,
+        Component::Nox,
+        Component::SensorActivity,
+        Component::Dropped,
+        Component::PublishFailures,
+        Component::Noise,
+        Component::AirScore,
+        Component::VocRelative,
+        Component::GatewayRtt,
+        Component::BrokerRtt,
+        Component::DataSent,
+        Component::DataReceived,
+        Component::Rssi,
+        Component::Uptime,
+        Component::FreeRam,
+        Component::IpAddress,
+        Component::Broker,
+        Component::AwayMode,
+        Component::PowerProfile,
+        Component::FanClean,
+        Component::Button,
+        Component::ShowTemperature,
+        Component::ShowHumidity,
+        Component::GuestMode,
+        Component::ShowPm1,
+        Component::ShowPm2_5,
+        Component::ShowPm4,
+        Component::ShowPm10,
+        Component::ShowVoc,
+        Component::ShowNox,
+    ];
+
+    /// What goes after the device identifier in the component's unique ID.
+    const fn suffix(&self) -> &'static str {
+        match self {
+            Component::Temperature => "t",
+            Component::Humidity => "h",
+            Component::Pm1 => "pm1",
+            Component::Pm2_5 => "pm2_5",
+            Component::Pm4 => "pm4",
+            Component::Pm10 => "pm10",
+            Component::Voc => "voc",
+            Component::Nox => "nox",
+            Component::SensorActivity => "sensor",
+            Component::Dropped => "dropped",
+            Component::PublishFailures => "publish_failures",
+            Component::Noise => "noise",
+            Component::AirScore => "air_score",
+            Component::VocRelative => "voc_relative",
+            Component::GatewayRtt => "gateway_rtt",
+            Component::BrokerRtt => "broker_rtt",
+            Component::DataSent => "data_sent",
+            Component::DataReceived => "data_received",
+            Component::Rssi => "rssi",
+            Component::Uptime => "uptime",
+            Component::FreeRam => "free_ram",
+            Component::IpAddress => "ip",
+            Component::Broker => "broker",
+            Component::AwayMode => "away",
+            Component::PowerProfile => "power_profile",
+            Component::FanClean => "fan_clean",
+            Component::Button => "button",
+            Component::ShowTemperature => "show_t",
+            Component::ShowHumidity => "show_h",
+            Component::GuestMode => "guest",
+            Component::ShowPm1 => "show_pm1",
+            Component::ShowPm2_5 => "show_pm2_5",
+            Component::ShowPm4 => "show_pm4",
+            Component::ShowPm10 => "show_pm10",
+            Component::ShowVoc => "show_voc",
+            Component::ShowNox => "show_nox",
+        }
+    }
+
+    /// The component's key and unique ID in the discovery payload, `<identifier>_<suffix>`. The
+    /// identifier's only known at runtime, so like the topics they're all laid out the first time
+    /// one's needed and kept after that.
+    pub fn id(&self) -> &'static str {
+        let ids = COMPONENT_IDS.lock(|ids| {
+            if let Some(built) = ids.get() {
+                return built;
+            }
+
+            static BUFFER: StaticCell<ComponentIds> = StaticCell::new();
+            let identifier = &device_config::get().identifier;
+            let built: &'static ComponentIds = BUFFER.init(core::array::from_fn(|i| {
+                let mut id = String::new();
+                _ = write!(id, "{}_{}", identifier, Component::ALL[i].suffix());
+                id
+            }));
+
+            ids.set(Some(built));
+            built
+        });
+
+        // `ALL` is in the order they're declared in.
+        &ids[*self as usize]
+    }
+}
+
+/// Every component's unique ID, in the order of `Component::ALL`.
+type ComponentIds = [String<64>; Component::ALL.len()];
+
+static COMPONENT_IDS: Mutex<ThreadModeRawMutex, Cell<Option<&'static ComponentIds>>> =
+    Mutex::new(Cell::new(None));
+
+/// Measure for only part of the time, as `<on>/<period>` in minutes, e.g. `5/15` to measure for
+/// five minutes in every fifteen. Unset (or invalid) to measure all the time.
+pub const DUTY_CYCLE: Option<&str> = option_env!("DUTY_CYCLE");
+
+/// Publish adaptively, as `<min>/<max>` in seconds: soon after the readings change, but at most
+/// every `min` and at least every `max`. Unset (or invalid) to publish every reading.
+pub const PUBLISH_CADENCE: Option<&str> = option_env!("PUBLISH_CADENCE");
+
+/// Rename or leave out state message fields, as comma-separated `<field>=<name>` pairs, e.g.
+/// `pm2_5=pm25,pm_compensation=`. An empty name leaves the field out. Unset to keep them all.
+pub const STATE_FIELDS: Option<&str> = option_env!("STATE_FIELDS");
+
+/// The entity the published automation turns on when the PM2.5 is high, e.g.
+/// `fan.bedroom_purifier`. Assumes `fan.air_purifier` if unset.
+pub const HASS_PURIFIER_ENTITY: &str = match option_env!("HASS_PURIFIER_ENTITY") {
+    Some(entity) => entity,
+    None => "fan.air_purifier",
+};
+
+/// Comma-separated pages to cycle through once running, e.g. `readings,large`. Defaults to just
+/// the readings page.
+pub const PAGES: Option<&str> = option_env!("PAGES");
+
+/// Draw the readings page in one of the built-in themes (`dark` or `light`) instead of using the
+/// bitmap backgrounds.
+pub const READINGS_THEME: Option<&str> = option_env!("READINGS_THEME");
+
+/// Layout the readings page starts in; `high_contrast` for the large-text layout, anything else
+/// for the standard one. The menu's Contrast item switches between them at runtime.
+pub const DISPLAY_MODE: Option<&str> = option_env!("DISPLAY_MODE");
+
+/// Set (to anything) if the panel's tearing-effect output is wired to GP20, so frames are drawn
+/// while it's between refreshes instead of shearing part way through a background change.
+pub const DISPLAY_TE: bool = option_env!("DISPLAY_TE").is_some();
+
+/// Comma-separated things for the vibration motor on GP9 to buzz for: `buttons` (presses),
+/// `health` (the air quality changing) and `rules` (buzz rules firing). Unset to leave it still.
+pub const HAPTICS: Option<&str> = option_env!("HAPTICS");
+
+/// Set (to anything) to keep the vibration motor from buzzing about the air quality while
+/// somebody's cooking.
+pub const COOKING_QUIET: bool = option_env!("COOKING_QUIET").is_some();
+
+/// Set (to anything) for kiosk mode: if the sensor or network is gone for a while, the pages make
+/// way for a screen saying so, with the last known values and how long ago they were good.
+pub const KIOSK: bool = option_env!("KIOSK").is_some();
+
+/// Set (to anything) to publish the gist of what's on screen (page, health colour and headline
+/// reading) for other displays to mirror, see `mirror`.
+pub const MIRROR: bool = option_env!("MIRROR").is_some();
+
+/// Set (to anything) if a PDM microphone is fitted, to measure the noise level.
+pub const NOISE_MIC: bool = option_env!("NOISE_MIC").is_some();
+
+/// The microphone's sensitivity in dBFS for a 94 dB SPL tone, from its datasheet. Assumes -26,
+/// which is typical, if unset.
+pub const NOISE_MIC_SENSITIVITY: Option<&str> = option_env!("NOISE_MIC_SENSITIVITY");
+
+/// How the device spends its time between power-on and the first reading.
+pub struct BootProfile {
+    /// How long the splash screen stays up before connecting.
+    pub splash: Duration,
+    /// How long to give the sensor to power up before talking to it.
+    pub sensor_power_up: Duration,
+    /// How long to let the sensor settle after starting measurement.
+    pub sensor_settle: Duration,
+}
+
+/// Takes its time so the splash screen can be seen, and gives the sensor plenty of time to warm up.
+const BOOT_PROFILE_NORMAL: BootProfile = BootProfile {
+    splash: Duration::from_secs(3),
+    sensor_power_up: Duration::from_secs(5),
+    sensor_settle: Duration::from_secs(5),
+};
+
+/// Skips the splash and trims the sensor delays down to what the datasheet actually asks for
+/// (plus a bit).
+const BOOT_PROFILE_FAST: BootProfile = BootProfile {
+    splash: Duration::from_ticks(0),
+    sensor_power_up: Duration::from_millis(500),
+    sensor_settle: Duration::from_secs(1),
+};
+
+/// Set `BOOT_PROFILE=fast` at build time for a quicker boot, anything else gets the normal one.
+pub const BOOT_PROFILE: BootProfile = match option_env!("BOOT_PROFILE") {
+    Some(profile) if str_eq(profile, "fast") => BOOT_PROFILE_FAST,
+    _ => BOOT_PROFILE_NORMAL,
+};
+
+/// String comparison usable in consts, since `==` on strs isn't (yet).
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+
+    true
+}
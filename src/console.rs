@@ -0,0 +1,280 @@
+//! A tiny line-based debug console over USB serial.
+//!
+//! Plug the Pico into a computer, open the serial port it shows up as, and type `dump` to get the
+//! device's entire current state as JSON. Handy for bug reports when there's no broker to look at.
+//!
+//! It's also where the device config is changed: `set <key> <value>` stores one of the keys in
+//! `device_config::KEYS` (e.g. `set wifi_network Attic`), and `reboot` restarts to use it.
+
+use defmt::{info, warn};
+use embassy_rp::peripherals::USB;
+use embassy_rp::usb::Driver;
+use embassy_time::Instant;
+use embassy_usb::class::cdc_acm::CdcAcmClass;
+use embassy_usb::driver::EndpointError;
+use heapless::{String, Vec};
+use serde::Serialize;
+
+use crate::alarm_log;
+use crate::batch::{self, BatchSnapshot};
+use crate::climate::{self, Range};
+use crate::device_config;
+use crate::hass::StateMessage;
+use crate::live;
+use crate::metric::Metric;
+use crate::profile::{self, CpuUsage, Job};
+use crate::report::{self, DailySummary};
+use crate::score;
+use crate::sen55;
+use crate::sensor_error::{SensorDiagnosticsSnapshot, SENSOR_DIAGNOSTICS};
+use crate::sockets::{self, SocketSnapshot};
+use crate::telemetry::{self, CrashReport};
+use crate::{config, retained, state};
+
+/// Largest USB full-speed bulk packet.
+const PACKET_SIZE: usize = 64;
+
+/// Longest command line we bother buffering, enough to `set` the longest config value.
+const MAX_LINE: usize = 96;
+
+/// Everything worth knowing about the device right now.
+#[derive(Serialize)]
+struct StateDump {
+    uptime_s: u64,
+    connection: &'static str,
+    sensor: &'static str,
+    health: Option<&'static str>,
+    readings: Option<StateMessage>,
+    averages: Vec<AverageDump, { Metric::ALL.len() }>,
+    history: HistoryDump,
+    published: u32,
+    dropped: u32,
+    sequence: u32,
+    publish_failures: u32,
+    broker_disconnect: Option<&'static str>,
+    sensor_errors: SensorDiagnosticsSnapshot,
+    last_crash: Option<CrashReport>,
+    cpu: Option<CpuUsage>,
+    sockets: SocketSnapshot,
+    display: BatchSnapshot,
+    #[cfg(feature = "heap")]
+    heap: crate::heap::HeapSnapshot,
+    config: ConfigDump,
+}
+
+/// One of the rolling averages the readings are, and how far back it reaches.
+#[derive(Serialize)]
+struct AverageDump {
+    metric: &'static str,
+    value: Option<f32>,
+    window_s: u64,
+}
+
+/// What's been kept of the readings over time.
+#[derive(Serialize)]
+struct HistoryDump {
+    /// Today's report so far.
+    today: DailySummary,
+    /// The last few days' air scores, oldest first.
+    scores: score::History,
+    temperature_24h: Option<Range>,
+    humidity_24h: Option<Range>,
+    /// The metric on the live graph, and its samples, oldest first.
+    live_metric: &'static str,
+    live: Vec<Option<f32>, { live::SAMPLES }>,
+}
+
+/// The config the device is running with, minus anything secret.
+#[derive(Serialize)]
+struct ConfigDump {
+    identifier: &'static str,
+    name: &'static str,
+    sw_version: &'static str,
+    hw_version: &'static str,
+    wifi_network: &'static str,
+    mqtt_host: Option<&'static str>,
+    mqtt_username: Option<&'static str>,
+    mqtt_client_id: &'static str,
+    state_topic: &'static str,
+    discovery_topic: &'static str,
+    pressure_topic: Option<&'static str>,
+}
+
+fn collect_state() -> StateDump {
+    let current = state::get();
+    let device = device_config::get();
+    let live = live::samples();
+
+    StateDump {
+        uptime_s: Instant::now().as_secs(),
+        connection: current.connection.name(),
+        sensor: current.sensor_activity.name(),
+        health: current.readings.map(|r| r.health().name()),
+        readings: current.readings.map(StateMessage::from),
+        averages: Metric::ALL
+            .into_iter()
+            .filter_map(|metric| {
+                Some(AverageDump {
+                    metric: metric.key(),
+                    value: current.readings.and_then(|r| metric.value(&r)),
+                    window_s: sen55::averaging_window(metric)?.as_secs(),
+                })
+            })
+            .collect(),
+        history: HistoryDump {
+            today: report::so_far(),
+            scores: score::history(),
+            temperature_24h: climate::temperature(),
+            humidity_24h: climate::humidity(),
+            live_metric: live.metric.key(),
+            live: live.values.into_iter().collect(),
+        },
+        published: current.published,
+        dropped: current.dropped,
+        sequence: current.sequence,
+        publish_failures: current.publish_failures,
+        broker_disconnect: current.broker_disconnect,
+        sensor_errors: SENSOR_DIAGNOSTICS.snapshot(),
+        last_crash: telemetry::crash_report(),
+        cpu: profile::usage(),
+        sockets: sockets::snapshot(),
+        display: batch::snapshot(),
+        #[cfg(feature = "heap")]
+        heap: crate::heap::snapshot(),
+        config: ConfigDump {
+            identifier: &device.identifier,
+            name: &device.name,
+            sw_version: config::HASS_DEVICE_SW,
+            hw_version: config::HASS_DEVICE_HW,
+            wifi_network: &device.wifi_network,
+            mqtt_host: device.mqtt_host(),
+            mqtt_username: device.mqtt_credentials().map(|(username, _)| username),
+            mqtt_client_id: &device.mqtt_client_id,
+            state_topic: config::mqtt_topics().state,
+            discovery_topic: config::mqtt_topics().discovery,
+            pressure_topic: config::MQTT_TOPIC_PRESSURE,
+        },
+    }
+}
+
+/// Reads commands from the USB serial port and answers them.
+#[embassy_executor::task]
+pub async fn worker(class: CdcAcmClass<'static, Driver<'static, USB>>) {
+    profile::track(Job::Usb, run(class)).await
+}
+
+async fn run(mut class: CdcAcmClass<'static, Driver<'static, USB>>) {
+    info!("started console worker");
+
+    loop {
+        class.wait_connection().await;
+        info!("Console connected");
+
+        match serve(&mut class).await {
+            Ok(()) => {}
+            Err(EndpointError::Disabled) => info!("Console disconnected"),
+            Err(EndpointError::BufferOverflow) => warn!("Console buffer overflow"),
+        }
+    }
+}
+
+/// Handle commands until the host goes away.
+async fn serve(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB>>,
+) -> Result<(), EndpointError> {
+    let mut packet = [0u8; PACKET_SIZE];
+    let mut line = String::<MAX_LINE>::new();
+
+    loop {
+        let n = class.read_packet(&mut packet).await?;
+
+        for &byte in &packet[..n] {
+            match byte {
+                b'\r' | b'\n' => {
+                    if !line.is_empty() {
+                        run_command(class, line.trim()).await?;
+                        line.clear();
+                    }
+                }
+                _ => {
+                    // Too long to be a command we know about, so just drop the excess.
+                    _ = line.push(byte as char);
+                }
+            }
+        }
+    }
+}
+
+async fn run_command(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB>>,
+    command: &str,
+) -> Result<(), EndpointError> {
+    match command {
+        "dump" => {
+            let mut buf = [0u8; 6144];
+            match serde_json_core::to_slice(&collect_state(), &mut buf) {
+                Ok(len) => write_all(class, &buf[..len]).await?,
+                Err(_) => write_all(class, b"error: state too large to serialize").await?,
+            }
+        }
+        "reboot" => {
+            write_all(class, b"rebooting\r\n").await?;
+            alarm_log::save().await;
+            retained::warm_reboot();
+        }
+        "help" => {
+            write_all(class, b"commands: dump, set <key> <value>, reboot, help").await?;
+        }
+        _ => match command.strip_prefix("set ") {
+            Some(setting) => set(class, setting).await?,
+            None => write_all(class, b"unknown command, try 'help'").await?,
+        },
+    }
+
+    write_all(class, b"\r\n").await
+}
+
+/// Store a new value for one of the device config's keys, used from the next boot.
+async fn set(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB>>,
+    setting: &str,
+) -> Result<(), EndpointError> {
+    let (key, value) = setting.split_once(' ').unwrap_or((setting, ""));
+    if !device_config::KEYS.contains(&key) {
+        write_all(class, b"error: unknown key, try one of:").await?;
+        for key in device_config::KEYS {
+            write_all(class, b" ").await?;
+            write_all(class, key.as_bytes()).await?;
+        }
+        return Ok(());
+    }
+
+    match device_config::update(key, value.trim()).await {
+        Ok(true) => write_all(class, b"saved, 'reboot' to use it").await,
+        Ok(false) => write_all(class, b"error: value too long").await,
+        Err(_) => write_all(class, b"error: couldn't save it").await,
+    }
+}
+
+/// Write a buffer out in packet-sized chunks.
+async fn write_all(
+    class: &mut CdcAcmClass<'static, Driver<'static, USB>>,
+    data: &[u8],
+) -> Result<(), EndpointError> {
+    for chunk in data.chunks(PACKET_SIZE) {
+        class.write_packet(chunk).await?;
+    }
+
+    // A full final packet needs a zero-length one after it, or the host keeps waiting for more.
+    if data.len() % PACKET_SIZE == 0 {
+        class.write_packet(&[]).await?;
+    }
+
+    Ok(())
+}
+
+/// Runs the USB device stack.
+#[embassy_executor::task]
+pub async fn usb_task(mut usb: embassy_usb::UsbDevice<'static, Driver<'static, USB>>) -> ! {
+    profile::track(Job::Usb, usb.run()).await
+}
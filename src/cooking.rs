@@ -0,0 +1,178 @@
+//! Spots somebody cooking from the shape of the readings: PM2.5 and the VOC index both jumping
+//! within a couple of minutes, then the PM decaying away over the next hour or so.
+//!
+//! Each episode is published on the event topic as it starts and ends, and logged to syslog. Set
+//! `COOKING_QUIET` to keep the vibration motor from buzzing about the air quality while it lasts;
+//! everything else (the state, alerts, the daily report) carries on as normal.
+
+use core::cell::RefCell;
+
+use defmt::info;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::sen55::Readings;
+use crate::syslog::{self, Severity};
+
+/// How often the readings are snapshotted to compare against.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many snapshots are kept, so how far back a rise is measured from (two minutes).
+const SNAPSHOTS: usize = 4;
+
+/// How far PM2.5 has to rise within that, in µg/m³.
+const PM_RISE: f32 = 20.0;
+
+/// How far the VOC index has to rise within that. Dust and pollen push the PM up on their own, but
+/// not the VOCs.
+const VOC_RISE: f32 = 50.0;
+
+/// The episode's over once PM2.5 has decayed to this fraction of the way from where it started to
+/// its peak.
+const DECAYED: f32 = 0.25;
+
+/// However slowly it decays, an episode's called over after this.
+const MAX_EPISODE: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// One end of an episode, as published on the event topic.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Event {
+    pub event: &'static str,
+    /// `start` or `end`.
+    pub state: &'static str,
+    /// PM2.5 when it started, in µg/m³.
+    pub baseline: f32,
+    /// The highest PM2.5 seen so far, in µg/m³.
+    pub peak: f32,
+    /// How long the episode lasted, in seconds. Zero at the start.
+    pub duration: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Snapshot {
+    pm2_5: f32,
+    voc: f32,
+}
+
+#[derive(Clone, Copy)]
+struct Episode {
+    started: Instant,
+    baseline: f32,
+    peak: f32,
+}
+
+struct Detector {
+    /// Oldest first.
+    snapshots: [Option<Snapshot>; SNAPSHOTS],
+    last_snapshot: Option<Instant>,
+    episode: Option<Episode>,
+    /// The latest event that hasn't been published yet. An end replaces a start that never made
+    /// it out, so a subscriber always learns how the last episode stands.
+    unpublished: Option<Event>,
+}
+
+static DETECTOR: Mutex<ThreadModeRawMutex, RefCell<Detector>> =
+    Mutex::new(RefCell::new(Detector {
+        snapshots: [None; SNAPSHOTS],
+        last_snapshot: None,
+        episode: None,
+        unpublished: None,
+    }));
+
+/// Look for the start or end of an episode in the latest readings.
+pub fn update(readings: &Readings) {
+    let (Some(pm2_5), Some(voc)) = (readings.pm2_5, readings.voc_index) else {
+        return;
+    };
+
+    let event = DETECTOR.lock(|d| {
+        let mut d = d.borrow_mut();
+        let now = Instant::now();
+
+        let event = match d.episode {
+            None => {
+                let oldest = d.snapshots.iter().flatten().next().copied();
+                oldest
+                    .filter(|o| pm2_5 - o.pm2_5 >= PM_RISE && voc - o.voc >= VOC_RISE)
+                    .map(|o| {
+                        let episode = Episode {
+                            started: now,
+                            baseline: o.pm2_5,
+                            peak: pm2_5,
+                        };
+                        d.episode = Some(episode);
+                        episode.event("start", now)
+                    })
+            }
+            Some(mut episode) => {
+                episode.peak = episode.peak.max(pm2_5);
+                d.episode = Some(episode);
+
+                let decayed = episode.baseline + (episode.peak - episode.baseline) * DECAYED;
+                (pm2_5 <= decayed || now - episode.started >= MAX_EPISODE).then(|| {
+                    d.episode = None;
+                    // Start afresh, so the tail of this one can't set off the next.
+                    d.snapshots = [None; SNAPSHOTS];
+                    episode.event("end", now)
+                })
+            }
+        };
+
+        if d.last_snapshot
+            .is_none_or(|at| now - at >= SNAPSHOT_INTERVAL)
+        {
+            d.snapshots.rotate_left(1);
+            d.snapshots[SNAPSHOTS - 1] = Some(Snapshot { pm2_5, voc });
+            d.last_snapshot = Some(now);
+        }
+
+        if event.is_some() {
+            d.unpublished = event;
+        }
+        event
+    });
+
+    if let Some(event) = event {
+        info!(
+            "Cooking {}: {} -> {} ug/m3",
+            event.state, event.baseline, event.peak
+        );
+        syslog::event(
+            Severity::Notice,
+            "air",
+            format_args!(
+                "cooking {} (PM2.5 {} to {})",
+                event.state, event.baseline, event.peak
+            ),
+        );
+    }
+}
+
+impl Episode {
+    fn event(&self, state: &'static str, now: Instant) -> Event {
+        Event {
+            event: "cooking",
+            state,
+            baseline: self.baseline,
+            peak: self.peak,
+            duration: (now - self.started).as_secs(),
+        }
+    }
+}
+
+/// Whether somebody seems to be cooking right now.
+pub fn active() -> bool {
+    DETECTOR.lock(|d| d.borrow().episode.is_some())
+}
+
+/// The latest event, if it hasn't been published yet.
+pub fn unpublished() -> Option<Event> {
+    DETECTOR.lock(|d| d.borrow().unpublished)
+}
+
+/// Mark the latest event as published.
+pub fn mark_published() {
+    DETECTOR.lock(|d| d.borrow_mut().unpublished = None);
+}
@@ -0,0 +1,247 @@
+//! The Wi-Fi network, broker and Home Assistant identity the device runs with, kept in flash so a
+//! device can be set up (or moved to another network) without rebuilding the firmware.
+//!
+//! The build-time `WF_SSID`, `WF_PASS`, `MQTT_HOST`, `MQTT_USERNAME`, `MQTT_PASSWORD`,
+//! `MQTT_CLIENT_ID`, `HASS_DEVICE_IDENTIFIER` and `HASS_DEVICE_NAME` are only the defaults, used
+//! until something's been stored. The config is read once at boot and doesn't change while the
+//! device is running: anything stored with `update` or `update_all` takes effect from the next
+//! boot.
+
+use core::cell::Cell;
+
+use defmt::{error, info, warn};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::String;
+use static_cell::StaticCell;
+
+use crate::config;
+use crate::storage::{self, Slot, StorageError};
+
+/// Bump this whenever the layout of the stored bytes changes, so an old config is ignored rather
+/// than misread.
+const CONFIG_VERSION: u8 = 2;
+
+/// The version, then each field as a length byte followed by its full capacity.
+const STORED_LEN: usize =
+    1 + (1 + 32) + (1 + 64) + (1 + 64) + (1 + 32) + (1 + 64) + (1 + 32) + (1 + 32) + (1 + 32);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub wifi_network: String<32>,
+    pub wifi_password: String<64>,
+    /// Empty for no broker, in which case the readings are shared with the LAN instead.
+    pub mqtt_host: String<64>,
+    /// Empty for a broker that doesn't want credentials.
+    pub mqtt_username: String<32>,
+    pub mqtt_password: String<64>,
+    pub mqtt_client_id: String<32>,
+    /// Identifies the device to Home Assistant, and goes in all its topics.
+    pub identifier: String<32>,
+    /// The name Home Assistant shows.
+    pub name: String<32>,
+}
+
+impl DeviceConfig {
+    /// The config from the build, cut short if any of it doesn't fit.
+    fn defaults() -> Self {
+        Self {
+            wifi_network: truncated(config::WIFI_NETWORK),
+            wifi_password: truncated(config::WIFI_PASSWORD),
+            mqtt_host: truncated(config::MQTT_HOST.unwrap_or("")),
+            mqtt_username: truncated(config::MQTT_USERNAME.unwrap_or("")),
+            mqtt_password: truncated(config::MQTT_PASSWORD.unwrap_or("")),
+            mqtt_client_id: truncated(config::MQTT_CLIENT_ID),
+            identifier: truncated(config::HASS_DEVICE_IDENTIFIER),
+            name: truncated(config::HASS_DEVICE_NAME),
+        }
+    }
+
+    pub fn mqtt_host(&self) -> Option<&str> {
+        Some(self.mqtt_host.as_str()).filter(|host| !host.is_empty())
+    }
+
+    /// The broker's username and password, if it wants them.
+    pub fn mqtt_credentials(&self) -> Option<(&str, &str)> {
+        Some((self.mqtt_username.as_str(), self.mqtt_password.as_str()))
+            .filter(|(username, _)| !username.is_empty())
+    }
+
+    /// Change the field called `key` (see `KEYS`) to `value`. Returns false if there's no such
+    /// field, or the value doesn't fit.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        fn replace<const N: usize>(field: &mut String<N>, value: &str) -> bool {
+            match String::try_from(value) {
+                Ok(value) => {
+                    *field = value;
+                    true
+                }
+                Err(()) => false,
+            }
+        }
+
+        match key {
+            "wifi_network" => replace(&mut self.wifi_network, value),
+            "wifi_password" => replace(&mut self.wifi_password, value),
+            "mqtt_host" => replace(&mut self.mqtt_host, value),
+            "mqtt_username" => replace(&mut self.mqtt_username, value),
+            "mqtt_password" => replace(&mut self.mqtt_password, value),
+            "mqtt_client_id" => replace(&mut self.mqtt_client_id, value),
+            "identifier" => replace(&mut self.identifier, value),
+            "name" => replace(&mut self.name, value),
+            _ => false,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; STORED_LEN] {
+        let mut bytes = [0u8; STORED_LEN];
+        bytes[0] = CONFIG_VERSION;
+
+        let mut at = 1;
+        for (field, capacity) in self.fields() {
+            bytes[at] = field.len() as u8;
+            bytes[at + 1..at + 1 + field.len()].copy_from_slice(field.as_bytes());
+            at += 1 + capacity;
+        }
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; STORED_LEN]) -> Option<Self> {
+        if bytes[0] != CONFIG_VERSION {
+            return None;
+        }
+
+        let mut config = Self::defaults();
+        let mut at = 1;
+        for key in KEYS {
+            let capacity = config.capacity(key);
+            let len = usize::from(bytes[at]).min(capacity);
+            let value = core::str::from_utf8(&bytes[at + 1..at + 1 + len]).ok()?;
+            if !config.set(key, value) {
+                return None;
+            }
+            at += 1 + capacity;
+        }
+
+        Some(config)
+    }
+
+    /// Each field with its capacity, in the order they're stored.
+    fn fields(&self) -> [(&str, usize); 8] {
+        [
+            (self.wifi_network.as_str(), 32),
+            (self.wifi_password.as_str(), 64),
+            (self.mqtt_host.as_str(), 64),
+            (self.mqtt_username.as_str(), 32),
+            (self.mqtt_password.as_str(), 64),
+            (self.mqtt_client_id.as_str(), 32),
+            (self.identifier.as_str(), 32),
+            (self.name.as_str(), 32),
+        ]
+    }
+
+    fn capacity(&self, key: &str) -> usize {
+        KEYS.iter()
+            .zip(self.fields())
+            .find(|(k, _)| **k == key)
+            .map_or(0, |(_, (_, capacity))| capacity)
+    }
+}
+
+/// The fields' keys, in the order they're stored.
+pub const KEYS: [&str; 8] = [
+    "wifi_network",
+    "wifi_password",
+    "mqtt_host",
+    "mqtt_username",
+    "mqtt_password",
+    "mqtt_client_id",
+    "identifier",
+    "name",
+];
+
+fn truncated<const N: usize>(value: &str) -> String<N> {
+    let mut out = String::new();
+    for c in value.chars() {
+        if out.push(c).is_err() {
+            warn!("Build-time config value is too long, cutting it short");
+            break;
+        }
+    }
+    out
+}
+
+static CONFIG: Mutex<ThreadModeRawMutex, Cell<Option<&'static DeviceConfig>>> =
+    Mutex::new(Cell::new(None));
+
+static STORE: StaticCell<DeviceConfig> = StaticCell::new();
+
+/// Read the config from flash, falling back to the build-time defaults if nothing's been stored.
+/// Called once at startup, after `storage::init` and before anything needs the config.
+pub async fn load() {
+    let config = match stored().await {
+        Ok(config) => {
+            info!("Using the stored device config");
+            config
+        }
+        Err(e) => {
+            info!("No stored device config ({}), using the build's", e);
+            DeviceConfig::defaults()
+        }
+    };
+
+    install(config);
+}
+
+/// The config the device is running with.
+pub fn get() -> &'static DeviceConfig {
+    match CONFIG.lock(|c| c.get()) {
+        Some(config) => config,
+        // Only before `load`, which shouldn't happen, but the build's config is better than none.
+        None => install(DeviceConfig::defaults()),
+    }
+}
+
+fn install(config: DeviceConfig) -> &'static DeviceConfig {
+    CONFIG.lock(|c| match c.get() {
+        Some(installed) => {
+            warn!("Device config already in use, not replacing it");
+            installed
+        }
+        None => {
+            let installed: &'static DeviceConfig = STORE.init(config);
+            c.set(Some(installed));
+            installed
+        }
+    })
+}
+
+async fn stored() -> Result<DeviceConfig, StorageError> {
+    let mut bytes = [0u8; STORED_LEN];
+    storage::load(Slot::DeviceConfig, &mut bytes).await?;
+    DeviceConfig::from_bytes(&bytes).ok_or(StorageError::Empty)
+}
+
+/// Store `value` for the key `key` (see `KEYS`), starting from whatever's stored already (or the
+/// build's config, if nothing is). It's used from the next boot. Returns `Ok(false)` without
+/// saving anything if there's no such key, or the value doesn't fit.
+pub async fn update(key: &str, value: &str) -> Result<bool, StorageError> {
+    update_all(&[(key, value)]).await
+}
+
+/// Store several keys' values at once, like `update`, saving only if every one of them is valid.
+pub async fn update_all(changes: &[(&str, &str)]) -> Result<bool, StorageError> {
+    let mut config = stored().await.unwrap_or_else(|_| DeviceConfig::defaults());
+    if !changes.iter().all(|(key, value)| config.set(key, value)) {
+        return Ok(false);
+    }
+
+    if let Err(e) = storage::save(Slot::DeviceConfig, &config.to_bytes()).await {
+        error!("Couldn't save the device config: {}", e);
+        return Err(e);
+    }
+
+    info!("Device config saved, it'll be used from the next boot");
+    Ok(true)
+}
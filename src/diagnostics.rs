@@ -0,0 +1,82 @@
+//! How the device itself is doing, as opposed to the air: the Wi-Fi signal and power saving, how
+//! long it's been up, how much RAM is left, the address it was given and which broker it's using.
+//! Published every minute to the diagnostics topic, and announced to Home Assistant as diagnostic
+//! sensors, so a device that's struggling (a weak signal, a stack creeping up) shows up without
+//! plugging anything in.
+
+use core::fmt::Write;
+
+use embassy_net::Stack;
+use embassy_time::{Duration, Instant};
+use heapless::String;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use crate::power::WifiPower;
+use crate::{latency, state, wifi};
+
+/// How often the diagnostics are published.
+pub const PUBLISH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What's published to the diagnostics topic.
+#[derive(Debug, Serialize)]
+pub struct DiagnosticsMessage {
+    /// The Wi-Fi signal strength, once it's been read.
+    pub rssi_dbm: Option<i32>,
+    pub uptime_s: u64,
+    pub free_ram_bytes: u32,
+    /// The device's IPv4 address, while it has one.
+    pub ip_address: Option<String<15>>,
+    /// The broker connected to, out of the list in the config.
+    pub broker: Option<&'static str>,
+    /// The Wi-Fi chip's power saving, see `power::WifiPower`.
+    pub wifi_power: Option<&'static str>,
+    /// What the power saving costs: the average round trip to the gateway under each mode, with
+    /// `LATENCY_MONITOR` on.
+    pub gateway_rtt_ms_by_wifi_power: RttByWifiPower,
+}
+
+/// Average round trips by power saving mode, serialized as an object keyed by the modes' keys.
+#[derive(Debug)]
+pub struct RttByWifiPower([Option<u32>; WifiPower::ALL.len()]);
+
+impl Serialize for RttByWifiPower {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut out = serializer.serialize_struct("RttByWifiPower", WifiPower::ALL.len())?;
+        for (power, rtt) in WifiPower::ALL.iter().zip(self.0) {
+            out.serialize_field(power.key(), &rtt)?;
+        }
+        out.end()
+    }
+}
+
+impl DiagnosticsMessage {
+    pub fn current(stack: Stack<'_>) -> Self {
+        Self {
+            rssi_dbm: wifi::rssi(),
+            uptime_s: Instant::now().as_secs(),
+            free_ram_bytes: free_ram(),
+            ip_address: stack.config_v4().and_then(|config| {
+                let mut address = String::new();
+                write!(address, "{}", config.address.address()).ok()?;
+                Some(address)
+            }),
+            broker: state::get().broker_host,
+            wifi_power: wifi::power().map(|power| power.key()),
+            gateway_rtt_ms_by_wifi_power: RttByWifiPower(latency::gateway_rtt_ms_by_wifi_power()),
+        }
+    }
+}
+
+/// The RAM between the end of the statics and the stack pointer, which is what the stack has left
+/// to grow into. The tasks all live in statics, so this is everything that isn't spoken for.
+fn free_ram() -> u32 {
+    extern "C" {
+        // The end of `.bss` and `.uninit`, from cortex-m-rt's linker script.
+        static __sheap: u8;
+    }
+
+    let stack_pointer = cortex_m::register::msp::read();
+    // Only the symbol's address matters, it's never read.
+    let statics_end = core::ptr::addr_of!(__sheap) as u32;
+    stack_pointer.saturating_sub(statics_end)
+}
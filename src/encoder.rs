@@ -0,0 +1,26 @@
+use defmt::info;
+use embassy_rp::peripherals::PIO1;
+use embassy_rp::pio_programs::rotary_encoder::{Direction, PioEncoder};
+
+use crate::buttons::ButtonEvent;
+use crate::UI_BUTTON_CHANNEL;
+
+/// Watches an optional rotary encoder (decoded by PIO, so no steps are missed while the CPU is
+/// busy drawing) and sends each detent to the UI, the same way as button presses.
+///
+/// With nothing wired up, the pull-ups hold both inputs high and it never sends anything.
+#[embassy_executor::task]
+pub async fn worker(mut encoder: PioEncoder<'static, PIO1, 0>) {
+    info!("started encoder worker");
+
+    loop {
+        let event = match encoder.read().await {
+            Direction::Clockwise => ButtonEvent::Clockwise,
+            Direction::CounterClockwise => ButtonEvent::CounterClockwise,
+        };
+
+        if UI_BUTTON_CHANNEL.try_send(event).is_err() {
+            info!("UI isn't keeping up with the encoder, dropping a step");
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Scaling for graphs, so a single outlier doesn't flatten the rest of the trace.
+//!
+//! A graph runs from the 5th to the 95th percentile of what's on it, rounded out to whole graph
+//! steps of its metric, rather than from zero to its peak. Anything over the top is clipped to it,
+//! and marked as such, and anything under the bottom is drawn as a stub.
+
+use heapless::Vec;
+use micromath::F32Ext;
+
+/// The percentiles a scale runs between.
+const LOW_PERCENTILE: f32 = 0.05;
+const HIGH_PERCENTILE: f32 = 0.95;
+
+/// A graph's values, sorted, to pick percentiles from.
+pub struct Sorted<const N: usize>(Vec<f32, N>);
+
+impl<const N: usize> Sorted<N> {
+    pub fn new(values: impl IntoIterator<Item = f32>) -> Self {
+        let mut values: Vec<f32, N> = values.into_iter().take(N).collect();
+        values.sort_unstable_by(f32::total_cmp);
+        Self(values)
+    }
+
+    /// The value `fraction` of the way up, by nearest rank, or `None` if there are no values.
+    pub fn percentile(&self, fraction: f32) -> Option<f32> {
+        let last = self.0.len().checked_sub(1)?;
+        let rank = (fraction * last as f32).round() as usize;
+        self.0.get(rank.min(last)).copied()
+    }
+}
+
+/// The range a graph's drawn over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale {
+    pub floor: f32,
+    pub ceiling: f32,
+}
+
+impl Scale {
+    /// Fit a scale to `values` in whole `step`s. It's always at least a step tall, so a flat trace
+    /// sits near the bottom rather than filling the graph.
+    pub fn fit<const N: usize>(values: &Sorted<N>, step: f32) -> Scale {
+        let low = values.percentile(LOW_PERCENTILE).unwrap_or(0.0);
+        let high = values.percentile(HIGH_PERCENTILE).unwrap_or(0.0);
+        Scale {
+            floor: (low / step).floor() * step,
+            ceiling: ((high / step).floor() + 1.0) * step,
+        }
+    }
+
+    /// Where `value` falls, from 0 at the floor to 1 at the ceiling, clipped to fit.
+    pub fn position(&self, value: f32) -> f32 {
+        ((value - self.floor) / (self.ceiling - self.floor)).clamp(0.0, 1.0)
+    }
+
+    /// Whether `value` is above the ceiling, and so clipped.
+    pub fn above(&self, value: f32) -> bool {
+        value > self.ceiling
+    }
+}
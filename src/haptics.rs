@@ -0,0 +1,102 @@
+//! An optional vibration motor, for a buzz under the finger when the button is pressed and a
+//! nudge when the air quality changes.
+//!
+//! The motor hangs off GP9 through a transistor (it draws far more than a pin can give). Set
+//! `HAPTICS` to pick what it buzzes for. It stays still in away mode, when nobody's meant to be
+//! around to feel it.
+
+use core::cell::Cell;
+
+use defmt::{info, Format};
+use embassy_rp::gpio::Output;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Timer;
+
+use crate::sen55::Health;
+use crate::{config, cooking, mode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Pattern {
+    /// One short buzz, for a button press.
+    Tap,
+    /// Three longer buzzes, for the air quality changing.
+    Alert,
+}
+
+impl Pattern {
+    /// How long the motor is on, then off, for each pulse, in milliseconds.
+    const fn pulses(&self) -> &'static [(u64, u64)] {
+        match self {
+            Pattern::Tap => &[(40, 0)],
+            Pattern::Alert => &[(200, 150), (200, 150), (200, 0)],
+        }
+    }
+}
+
+/// Whether `HAPTICS` (e.g. `buttons,health`) lists `what`.
+fn enabled_for(what: &str) -> bool {
+    config::HAPTICS.is_some_and(|list| list.split(',').any(|item| item.trim() == what))
+}
+
+/// The next pattern to play. A newer one replaces any that hasn't started yet.
+static BUZZ: Signal<ThreadModeRawMutex, Pattern> = Signal::new();
+
+/// The health of the last readings, to notice when it changes.
+static LAST_HEALTH: Mutex<ThreadModeRawMutex, Cell<Option<&'static str>>> =
+    Mutex::new(Cell::new(None));
+
+/// Whether `HAPTICS` asks for anything, so the motor's worker is only started if it's needed.
+pub fn enabled() -> bool {
+    enabled_for("buttons") || enabled_for("health") || enabled_for("rules")
+}
+
+/// A button was pressed.
+pub fn button() {
+    if enabled_for("buttons") {
+        play(Pattern::Tap);
+    }
+}
+
+/// Take note of the latest readings' health, buzzing if it's changed. The first readings after
+/// boot don't count as a change, and with `COOKING_QUIET` nor do any while somebody's cooking.
+pub fn health(health: &Health) {
+    let name = health.name();
+    let last = LAST_HEALTH.lock(|h| h.replace(Some(name)));
+    let quiet = config::COOKING_QUIET && cooking::active();
+
+    if enabled_for("health") && !quiet && last.is_some_and(|last| last != name) {
+        play(Pattern::Alert);
+    }
+}
+
+/// A rule with the buzz action fired.
+pub fn rule() {
+    if enabled_for("rules") {
+        play(Pattern::Alert);
+    }
+}
+
+fn play(pattern: Pattern) {
+    if mode::get().display_on() {
+        BUZZ.signal(pattern);
+    }
+}
+
+/// Plays patterns on the motor as they're asked for.
+#[embassy_executor::task]
+pub async fn worker(mut motor: Output<'static>) {
+    info!("started haptics worker");
+
+    loop {
+        let pattern = BUZZ.wait().await;
+
+        for (on, off) in pattern.pulses() {
+            motor.set_high();
+            Timer::after_millis(*on).await;
+            motor.set_low();
+            Timer::after_millis(*off).await;
+        }
+    }
+}
@@ -1,10 +1,21 @@
-use heapless::LinearMap;
-use serde::Serialize;
+use core::fmt::Write;
+
+use heapless::{LinearMap, String};
+use protocol::Remote;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
 use serde_json_core as _;
 
 use crate::{
-    config::{self, CMP_TEMPERATURE},
-    sen55,
+    buttons::ButtonEvent,
+    config::{self, Component},
+    device_config, latency,
+    metric::{Descriptor, Metric},
+    mode::Mode,
+    power::PowerProfile,
+    score, sen55,
+    sensirion::SensorInfo,
+    settings::Settings,
+    sntp, state, storage, voc_baseline,
 };
 
 #[derive(Debug, Serialize)]
@@ -18,8 +29,12 @@ pub struct DiscoveryMessage<'a> {
     #[serde(rename = "state_topic")]
     pub state_topic: &'a str,
 
+    /// Shared by every component, so they all go unavailable when the device drops off.
+    #[serde(rename = "avty_t")]
+    pub availability_topic: &'a str,
+
     #[serde(rename = "cmps")]
-    pub components: LinearMap<&'a str, DiscoveryComponent<'a>, 8>,
+    pub components: LinearMap<&'a str, DiscoveryComponent<'a>, 40>,
 }
 
 #[derive(Debug, Serialize)]
@@ -48,23 +63,334 @@ pub struct DiscoveryOrigin<'a> {
     pub url: &'a str,
 }
 
+/// One entity in the discovery payload. The keys use Home Assistant's abbreviations, as with this
+/// many components the full names wouldn't leave room in the MQTT buffers.
 #[derive(Debug, Serialize)]
 pub struct DiscoveryComponent<'a> {
     #[serde(rename = "p")]
     pub platform: &'a str,
-    #[serde(rename = "device_class")]
-    pub device_class: &'a str,
-    #[serde(rename = "unit_of_measurement")]
-    pub unit_of_measurement: &'a str,
-    #[serde(rename = "name")]
+    #[serde(rename = "dev_cla", skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<&'a str>,
+    #[serde(rename = "unit_of_meas", skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<&'a str>,
+    #[serde(rename = "sug_dsp_prc", skip_serializing_if = "Option::is_none")]
+    pub suggested_display_precision: Option<u8>,
+    // Left empty (along with the template and unique ID) when a component is being removed.
+    #[serde(rename = "name", skip_serializing_if = "str::is_empty")]
     pub name: &'a str,
-    #[serde(rename = "value_template")]
+    #[serde(rename = "val_tpl", skip_serializing_if = "str::is_empty")]
     pub value_template: &'a str,
-    #[serde(rename = "unique_id")]
+    #[serde(rename = "uniq_id", skip_serializing_if = "str::is_empty")]
     pub unique_id: &'a str,
+    #[serde(rename = "ent_cat", skip_serializing_if = "Option::is_none")]
+    pub entity_category: Option<&'a str>,
+
+    // Components that don't read from the device-wide state topic override it here.
+    #[serde(rename = "stat_t", skip_serializing_if = "Option::is_none")]
+    pub state_topic: Option<&'a str>,
+
+    // Only for components that can be controlled from Home Assistant.
+    #[serde(rename = "cmd_t", skip_serializing_if = "Option::is_none")]
+    pub command_topic: Option<&'a str>,
+    #[serde(rename = "pl_on", skip_serializing_if = "Option::is_none")]
+    pub payload_on: Option<&'a str>,
+    #[serde(rename = "pl_off", skip_serializing_if = "Option::is_none")]
+    pub payload_off: Option<&'a str>,
+    #[serde(rename = "stat_on", skip_serializing_if = "Option::is_none")]
+    pub state_on: Option<&'a str>,
+    #[serde(rename = "stat_off", skip_serializing_if = "Option::is_none")]
+    pub state_off: Option<&'a str>,
+
+    // Extra detail shown as attributes of the entity, read from the device-wide state topic.
+    #[serde(rename = "json_attr_t", skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<&'a str>,
+    #[serde(rename = "json_attr_tpl", skip_serializing_if = "Option::is_none")]
+    pub json_attributes_template: Option<&'a str>,
+
+    // Only for selects.
+    #[serde(rename = "ops", skip_serializing_if = "Option::is_none")]
+    pub options: Option<&'a [&'a str]>,
+
+    // Only for buttons.
+    #[serde(rename = "pl_prs", skip_serializing_if = "Option::is_none")]
+    pub payload_press: Option<&'a str>,
+
+    // Only for events.
+    #[serde(rename = "evt_typ", skip_serializing_if = "Option::is_none")]
+    pub event_types: Option<&'a [&'a str]>,
+}
+
+impl<'a> DiscoveryComponent<'a> {
+    /// A read-only sensor for one of the metrics, read from the device's state topic.
+    pub const fn metric(descriptor: &'a Descriptor, value_template: &'a str) -> Self {
+        Self {
+            platform: "sensor",
+            device_class: descriptor.device_class,
+            unit_of_measurement: Some(descriptor.unit),
+            suggested_display_precision: Some(descriptor.precision),
+            name: descriptor.name,
+            value_template,
+            unique_id: descriptor.component.id(),
+            entity_category: None,
+            state_topic: None,
+            command_topic: None,
+            payload_on: None,
+            payload_off: None,
+            state_on: None,
+            state_off: None,
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            options: None,
+            payload_press: None,
+            event_types: None,
+        }
+    }
+
+    /// A read-only sensor for something Home Assistant has no device class for, like a score.
+    pub const fn unclassed_sensor(
+        unit_of_measurement: &'a str,
+        name: &'a str,
+        value_template: &'a str,
+        unique_id: &'a str,
+    ) -> Self {
+        Self {
+            platform: "sensor",
+            device_class: None,
+            unit_of_measurement: Some(unit_of_measurement),
+            suggested_display_precision: None,
+            name,
+            value_template,
+            unique_id,
+            entity_category: None,
+            state_topic: None,
+            command_topic: None,
+            payload_on: None,
+            payload_off: None,
+            state_on: None,
+            state_off: None,
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            options: None,
+            payload_press: None,
+            event_types: None,
+        }
+    }
+
+    /// A read-only text sensor with extra detail about the device, read from the state topic.
+    pub const fn diagnostic(name: &'a str, value_template: &'a str, unique_id: &'a str) -> Self {
+        Self {
+            platform: "sensor",
+            device_class: None,
+            unit_of_measurement: None,
+            suggested_display_precision: None,
+            name,
+            value_template,
+            unique_id,
+            entity_category: Some("diagnostic"),
+            state_topic: None,
+            command_topic: None,
+            payload_on: None,
+            payload_off: None,
+            state_on: None,
+            state_off: None,
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            options: None,
+            payload_press: None,
+            event_types: None,
+        }
+    }
+
+    /// Tells Home Assistant to remove a component it was told about before.
+    pub const fn retired(platform: &'a str) -> Self {
+        Self {
+            platform,
+            device_class: None,
+            unit_of_measurement: None,
+            suggested_display_precision: None,
+            name: "",
+            value_template: "",
+            unique_id: "",
+            entity_category: None,
+            state_topic: None,
+            command_topic: None,
+            payload_on: None,
+            payload_off: None,
+            state_on: None,
+            state_off: None,
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            options: None,
+            payload_press: None,
+            event_types: None,
+        }
+    }
+
+    /// Show the JSON object `template` picks out of the state message as the entity's attributes.
+    pub fn with_attributes(mut self, template: &'a str) -> Self {
+        self.json_attributes_topic = Some(config::mqtt_topics().state);
+        self.json_attributes_template = Some(template);
+        self
+    }
+
+    /// A config switch turning a metric's tile on the display on or off.
+    ///
+    /// All the switches share one command topic, with the metric in the payload (e.g. `pm1_on`),
+    /// and read their state from the display state topic.
+    pub fn display_switch(
+        name: &'a str,
+        value_template: &'a str,
+        unique_id: &'a str,
+        payload_on: &'a str,
+        payload_off: &'a str,
+    ) -> Self {
+        Self {
+            platform: "switch",
+            device_class: None,
+            unit_of_measurement: None,
+            suggested_display_precision: None,
+            name,
+            value_template,
+            unique_id,
+            entity_category: Some("config"),
+            state_topic: Some(config::mqtt_topics().display_state),
+            command_topic: Some(config::mqtt_topics().display_set),
+            payload_on: Some(payload_on),
+            payload_off: Some(payload_off),
+            state_on: Some("ON"),
+            state_off: Some("OFF"),
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            options: None,
+            payload_press: None,
+            event_types: None,
+        }
+    }
+
+    /// A switch for away mode. The mode topics carry the mode's name as a plain string.
+    pub fn away_switch(name: &'a str, unique_id: &'a str) -> Self {
+        Self {
+            platform: "switch",
+            device_class: None,
+            unit_of_measurement: None,
+            suggested_display_precision: None,
+            name,
+            value_template: "{{ value }}",
+            unique_id,
+            entity_category: None,
+            state_topic: Some(config::mqtt_topics().mode_state),
+            command_topic: Some(config::mqtt_topics().mode_set),
+            payload_on: Some(Mode::Away.key()),
+            payload_off: Some(Mode::Home.key()),
+            state_on: Some(Mode::Away.key()),
+            state_off: Some(Mode::Home.key()),
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            options: None,
+            payload_press: None,
+            event_types: None,
+        }
+    }
+
+    /// A config select for the power profile. The profile topics carry the profile's name as a
+    /// plain string.
+    pub fn profile_select(name: &'a str, unique_id: &'a str) -> Self {
+        Self {
+            platform: "select",
+            device_class: None,
+            unit_of_measurement: None,
+            suggested_display_precision: None,
+            name,
+            value_template: "{{ value }}",
+            unique_id,
+            entity_category: Some("config"),
+            state_topic: Some(config::mqtt_topics().profile_state),
+            command_topic: Some(config::mqtt_topics().profile_set),
+            payload_on: None,
+            payload_off: None,
+            state_on: None,
+            state_off: None,
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            options: Some(&PROFILE_OPTIONS),
+            payload_press: None,
+            event_types: None,
+        }
+    }
+
+    /// A button sending one of the one-off commands to the command topic.
+    pub fn remote_button(name: &'a str, unique_id: &'a str, remote: Remote) -> Self {
+        Self {
+            platform: "button",
+            device_class: None,
+            unit_of_measurement: None,
+            suggested_display_precision: None,
+            name,
+            value_template: "",
+            unique_id,
+            entity_category: Some("config"),
+            state_topic: None,
+            command_topic: Some(config::mqtt_topics().command),
+            payload_on: None,
+            payload_off: None,
+            state_on: None,
+            state_off: None,
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            options: None,
+            payload_press: Some(remote.key()),
+            event_types: None,
+        }
+    }
+
+    /// An event for the device's button, so presses can trigger automations. The presses are
+    /// published to the button topic as they happen, see `ButtonMessage`.
+    pub fn button_event(name: &'a str, unique_id: &'a str) -> Self {
+        Self {
+            platform: "event",
+            device_class: Some("button"),
+            unit_of_measurement: None,
+            suggested_display_precision: None,
+            name,
+            value_template: "",
+            unique_id,
+            entity_category: None,
+            state_topic: Some(config::mqtt_topics().button),
+            command_topic: None,
+            payload_on: None,
+            payload_off: None,
+            state_on: None,
+            state_off: None,
+            json_attributes_topic: None,
+            json_attributes_template: None,
+            options: None,
+            payload_press: None,
+            event_types: Some(&ButtonEvent::EVENT_TYPES),
+        }
+    }
 }
 
+/// The power profiles' keys, as the options for the profile select.
+const PROFILE_OPTIONS: [&str; PowerProfile::ALL.len()] = {
+    let mut keys = [""; PowerProfile::ALL.len()];
+    let mut i = 0;
+    while i < keys.len() {
+        keys[i] = PowerProfile::ALL[i].key();
+        i += 1;
+    }
+    keys
+};
+
+/// A press of the button, published to the button topic in the shape Home Assistant's event
+/// entities expect.
 #[derive(Debug, Serialize)]
+pub struct ButtonMessage {
+    pub event_type: &'static str,
+}
+
+/// The readings and what else the device knows, published to the state topic. Serialized by hand
+/// so that `STATE_FIELDS` can rename or leave out fields.
+#[derive(Debug)]
 pub struct StateMessage {
     pub temperature: Option<f32>,
     pub humidity: Option<f32>,
@@ -74,6 +400,210 @@ pub struct StateMessage {
     pub pm10: Option<f32>,
     pub voc: Option<f32>,
     pub nox: Option<f32>,
+    pub pressure: Option<f32>,
+    pub pm_compensation: Option<f32>,
+    pub noise: Option<f32>,
+    /// How the VOC index compares with this home's usual, see `voc_baseline`. Filled in separately.
+    pub voc_relative: Option<f32>,
+    /// Today's air score so far. Not part of the readings, so it's filled in separately.
+    pub air_score: Option<u8>,
+    /// What the sensor is doing, see `state::SensorActivity`. Also filled in separately.
+    pub sensor: Option<&'static str>,
+    /// Which sensor is fitted and its versions, once they've been read. Also filled in separately.
+    pub sensor_info: Option<SensorInfo>,
+    /// The latest round trips to the gateway and broker, see `latency`. Also filled in separately.
+    pub gateway_rtt_ms: Option<u32>,
+    pub broker_rtt_ms: Option<u32>,
+    /// Device uptime when the latest measurement was taken.
+    pub taken_at_ms: u64,
+    /// When the latest measurement was taken as an ISO 8601 UTC time, once the clock's been synced
+    /// (see `sntp`). Also filled in separately.
+    pub timestamp: Option<String<24>>,
+    /// How old the readings were when the message was built, so delayed or buffered readings can
+    /// be told apart from fresh ones.
+    pub age_ms: Option<u64>,
+    /// Counts up with each message the publisher sends, and the publisher's own counters, so
+    /// consumers can tell when messages go missing. Filled in by the publisher.
+    pub seq: Option<u32>,
+    pub dropped: Option<u32>,
+    pub publish_failures: Option<u32>,
+}
+
+impl StateMessage {
+    /// The full message for a set of readings, including what the device knows besides them.
+    pub fn current(readings: sen55::Readings) -> Self {
+        let mut message = Self::from(readings);
+        message.air_score = score::today();
+        message.voc_relative = voc_baseline::relative(readings.voc_index);
+        let state = state::get();
+        message.sensor = Some(state.sensor_activity.name());
+        message.sensor_info = state.sensor_info;
+        message.gateway_rtt_ms = latency::gateway_rtt_ms();
+        message.broker_rtt_ms = latency::broker_rtt_ms();
+        message.age_ms = Some(readings.age().as_millis());
+        message.timestamp = sntp::iso8601(readings.taken_at);
+        message
+    }
+
+    /// Number the message and add the publisher's counters.
+    pub fn sequenced(mut self) -> Self {
+        let state = state::get();
+        self.seq = Some(state::next_sequence());
+        self.dropped = Some(state.dropped);
+        self.publish_failures = Some(state.publish_failures);
+        self
+    }
+}
+
+impl Serialize for StateMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut out = serializer.serialize_struct("StateMessage", 23)?;
+        state_field(&mut out, "temperature", &self.temperature)?;
+        state_field(&mut out, "humidity", &self.humidity)?;
+        state_field(&mut out, "pm1", &self.pm1)?;
+        state_field(&mut out, "pm2_5", &self.pm2_5)?;
+        state_field(&mut out, "pm4", &self.pm4)?;
+        state_field(&mut out, "pm10", &self.pm10)?;
+        state_field(&mut out, "voc", &self.voc)?;
+        state_field(&mut out, "nox", &self.nox)?;
+        state_field(&mut out, "pressure", &self.pressure)?;
+        state_field(&mut out, "pm_compensation", &self.pm_compensation)?;
+        state_field(&mut out, "noise", &self.noise)?;
+        state_field(&mut out, "voc_relative", &self.voc_relative)?;
+        state_field(&mut out, "air_score", &self.air_score)?;
+        state_field(&mut out, "sensor", &self.sensor)?;
+        state_field(&mut out, "sensor_info", &self.sensor_info)?;
+        state_field(&mut out, "gateway_rtt_ms", &self.gateway_rtt_ms)?;
+        state_field(&mut out, "broker_rtt_ms", &self.broker_rtt_ms)?;
+        state_field(&mut out, "taken_at_ms", &self.taken_at_ms)?;
+        state_field(&mut out, "timestamp", &self.timestamp)?;
+        state_field(&mut out, "age_ms", &self.age_ms)?;
+        state_field(&mut out, "seq", &self.seq)?;
+        state_field(&mut out, "dropped", &self.dropped)?;
+        state_field(&mut out, "publish_failures", &self.publish_failures)?;
+        out.end()
+    }
+}
+
+impl Serialize for SensorInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut firmware = String::<8>::new();
+        let mut hardware = String::<8>::new();
+        _ = write!(firmware, "{}.{}", self.firmware.0, self.firmware.1);
+        _ = write!(hardware, "{}.{}", self.hardware.0, self.hardware.1);
+
+        let mut out = serializer.serialize_struct("SensorInfo", 3)?;
+        out.serialize_field("product", self.product())?;
+        out.serialize_field("firmware", firmware.as_str())?;
+        out.serialize_field("hardware", hardware.as_str())?;
+        out.end()
+    }
+}
+
+/// Serialize one state message field under the name `STATE_FIELDS` gives it, if any.
+fn state_field<S: SerializeStruct, T: Serialize>(
+    out: &mut S,
+    field: &'static str,
+    value: &T,
+) -> Result<(), S::Error> {
+    match state_field_name(field) {
+        Some(name) => out.serialize_field(name, value),
+        None => out.skip_field(field),
+    }
+}
+
+/// The name a state message field is published under, or `None` if `STATE_FIELDS` leaves it out.
+pub fn state_field_name(field: &'static str) -> Option<&'static str> {
+    let Some(mapping) = config::STATE_FIELDS else {
+        return Some(field);
+    };
+
+    for pair in mapping.split(',') {
+        let Some((from, to)) = pair.split_once('=') else {
+            continue;
+        };
+        if from.trim() == field {
+            let to = to.trim();
+            return (!to.is_empty()).then_some(to);
+        }
+    }
+
+    Some(field)
+}
+
+/// Append a `checksum` field to a serialized state message, holding the CRC-32 of everything before
+/// the comma that starts it, so consumers can check the message arrived whole. Returns the new
+/// length, or `None` if it doesn't fit in `buf`.
+pub fn append_checksum(buf: &mut [u8], len: usize) -> Option<usize> {
+    let Some(name) = state_field_name("checksum") else {
+        return Some(len);
+    };
+
+    // Take off the closing brace, and put it back after the new field.
+    let body = len.checked_sub(1)?;
+    let mut field = String::<48>::new();
+    write!(
+        field,
+        ",\"{}\":\"{:08x}\"}}",
+        name,
+        storage::crc32(&buf[..body])
+    )
+    .ok()?;
+
+    buf.get_mut(body..body + field.len())?
+        .copy_from_slice(field.as_bytes());
+    Some(body + field.len())
+}
+
+/// The state message fields read by the sensors in the discovery payload.
+const TEMPLATED_FIELDS: [&str; 16] = [
+    "temperature",
+    "humidity",
+    "pm1",
+    "pm2_5",
+    "pm4",
+    "pm10",
+    "voc",
+    "nox",
+    "noise",
+    "voc_relative",
+    "air_score",
+    "sensor",
+    "dropped",
+    "publish_failures",
+    "gateway_rtt_ms",
+    "broker_rtt_ms",
+];
+
+/// Value templates for the sensors in the discovery payload, following `STATE_FIELDS`. A field
+/// that's left out has no template, and its sensor isn't announced.
+pub struct Templates {
+    values: [Option<String<64>>; TEMPLATED_FIELDS.len()],
+    /// Picks the sensor info out as the sensor status's attributes.
+    sensor_info: Option<String<64>>,
+}
+
+impl Templates {
+    pub fn from_config() -> Self {
+        Self {
+            values: TEMPLATED_FIELDS.map(|field| {
+                let name = state_field_name(field)?;
+                let mut template = String::new();
+                write!(template, "{{{{ value_json.{} }}}}", name).ok()?;
+                Some(template)
+            }),
+            sensor_info: state_field_name("sensor_info").and_then(|name| {
+                let mut template = String::new();
+                write!(template, "{{{{ value_json.{} | tojson }}}}", name).ok()?;
+                Some(template)
+            }),
+        }
+    }
+
+    fn get(&self, field: &str) -> Option<&str> {
+        let index = TEMPLATED_FIELDS.iter().position(|f| *f == field)?;
+        self.values[index].as_deref()
+    }
 }
 
 impl From<sen55::Readings> for StateMessage {
@@ -87,124 +617,341 @@ impl From<sen55::Readings> for StateMessage {
             pm10: readings.pm10_0,
             voc: readings.voc_index,
             nox: readings.nox_index,
+            pressure: readings.pressure,
+            pm_compensation: readings.pm_compensation,
+            noise: readings.noise,
+            voc_relative: None,
+            air_score: None,
+            sensor: None,
+            sensor_info: None,
+            gateway_rtt_ms: None,
+            broker_rtt_ms: None,
+            taken_at_ms: readings.taken_at.as_millis(),
+            timestamp: None,
+            age_ms: None,
+            seq: None,
+            dropped: None,
+            publish_failures: None,
         }
     }
 }
 
-pub fn get_discovery_payload() -> DiscoveryMessage<'static> {
+pub fn get_discovery_payload(templates: &Templates) -> DiscoveryMessage<'_> {
     let mut out = DiscoveryMessage {
         device: DiscoveryDevice {
-            identifier: config::HASS_DEVICE_IDENTIFIER,
-            name: config::HASS_DEVICE_NAME,
+            identifier: &device_config::get().identifier,
+            name: &device_config::get().name,
             manufacturer: config::HASS_DEVICE_MANUFACTURER,
             model: config::HASS_DEVICE_MODEL,
             sw_version: config::HASS_DEVICE_SW,
             hw_version: config::HASS_DEVICE_HW,
         },
         origin: DiscoveryOrigin {
-            name: config::HASS_DEVICE_NAME,
+            name: &device_config::get().name,
             sw_version: config::HASS_DEVICE_SW,
             url: config::HASS_DEVICE_URL,
         },
-        state_topic: config::MQTT_TOPIC_STATE,
+        state_topic: config::mqtt_topics().state,
+        availability_topic: config::mqtt_topics().availability,
         components: LinearMap::new(),
     };
 
-    _ = out.components.insert(
-        config::CMP_TEMPERATURE,
-        DiscoveryComponent {
-            platform: "sensor",
-            device_class: "temperature",
-            unit_of_measurement: "°C",
-            name: "Temperature",
-            value_template: "{{ value_json.temperature }}",
-            unique_id: CMP_TEMPERATURE,
-        },
-    );
+    for metric in Metric::ALL {
+        // Noise is only announced with a microphone fitted, as it'd never have a value otherwise.
+        if metric == Metric::Noise && !config::NOISE_MIC {
+            continue;
+        }
+        if let Some(template) = templates.get(metric.key()) {
+            let descriptor = metric.descriptor();
+            _ = out.components.insert(
+                descriptor.component.id(),
+                DiscoveryComponent::metric(descriptor, template),
+            );
+        }
+    }
 
-    _ = out.components.insert(
-        config::CMP_HUMIDITY,
-        DiscoveryComponent {
-            platform: "sensor",
-            device_class: "humidity",
-            unit_of_measurement: "%",
-            name: "Humidity",
-            value_template: "{{ value_json.humidity }}",
-            unique_id: config::CMP_HUMIDITY,
-        },
-    );
+    if let Some(template) = templates.get("voc_relative") {
+        _ = out.components.insert(
+            Component::VocRelative.id(),
+            DiscoveryComponent::unclassed_sensor(
+                "%",
+                "VOC (relative)",
+                template,
+                Component::VocRelative.id(),
+            ),
+        );
+    }
 
-    _ = out.components.insert(
-        config::CMP_PM1,
-        DiscoveryComponent {
-            platform: "sensor",
-            device_class: "pm1",
-            unit_of_measurement: "µg/m³",
-            name: "PM1.0",
-            value_template: "{{ value_json.pm1 }}",
-            unique_id: config::CMP_PM1,
-        },
-    );
+    if let Some(template) = templates.get("air_score") {
+        _ = out.components.insert(
+            Component::AirScore.id(),
+            DiscoveryComponent::unclassed_sensor(
+                "points",
+                "Air score",
+                template,
+                Component::AirScore.id(),
+            ),
+        );
+    }
 
-    _ = out.components.insert(
-        config::CMP_PM2_5,
-        DiscoveryComponent {
-            platform: "sensor",
-            device_class: "pm25",
-            unit_of_measurement: "µg/m³",
-            name: "PM2.5",
-            value_template: "{{ value_json.pm2_5 }}",
-            unique_id: config::CMP_PM2_5,
-        },
-    );
+    if let Some(template) = templates.get("sensor") {
+        let mut component = DiscoveryComponent::diagnostic(
+            "Sensor status",
+            template,
+            Component::SensorActivity.id(),
+        );
+        // The product name and versions ride along as attributes.
+        if let Some(attributes) = templates.sensor_info.as_deref() {
+            component = component.with_attributes(attributes);
+        }
+        _ = out
+            .components
+            .insert(Component::SensorActivity.id(), component);
+    }
+
+    if let Some(template) = templates.get("dropped") {
+        _ = out.components.insert(
+            Component::Dropped.id(),
+            DiscoveryComponent::diagnostic("Dropped readings", template, Component::Dropped.id()),
+        );
+    }
+
+    if let Some(template) = templates.get("publish_failures") {
+        _ = out.components.insert(
+            Component::PublishFailures.id(),
+            DiscoveryComponent::diagnostic(
+                "Publish failures",
+                template,
+                Component::PublishFailures.id(),
+            ),
+        );
+    }
+
+    if config::LATENCY_MONITOR {
+        let round_trips = [
+            (
+                "gateway_rtt_ms",
+                "Gateway round trip",
+                Component::GatewayRtt.id(),
+            ),
+            (
+                "broker_rtt_ms",
+                "Broker round trip",
+                Component::BrokerRtt.id(),
+            ),
+        ];
+        for (field, name, unique_id) in round_trips {
+            if let Some(template) = templates.get(field) {
+                let component = DiscoveryComponent {
+                    device_class: Some("duration"),
+                    unit_of_measurement: Some("ms"),
+                    ..DiscoveryComponent::diagnostic(name, template, unique_id)
+                };
+                _ = out.components.insert(unique_id, component);
+            }
+        }
+    }
+
+    // The daily traffic totals come from their own topic, as they're only sent once a day.
+    let data = [
+        (
+            "Data sent",
+            "{{ value_json.sent_bytes }}",
+            Component::DataSent.id(),
+        ),
+        (
+            "Data received",
+            "{{ value_json.received_bytes }}",
+            Component::DataReceived.id(),
+        ),
+    ];
+    for (name, template, unique_id) in data {
+        let component = DiscoveryComponent {
+            device_class: Some("data_size"),
+            unit_of_measurement: Some("B"),
+            state_topic: Some(config::mqtt_topics().traffic),
+            ..DiscoveryComponent::diagnostic(name, template, unique_id)
+        };
+        _ = out.components.insert(unique_id, component);
+    }
+
+    // The device's own health comes from the diagnostics topic, see `diagnostics`.
+    let device_health = [
+        (
+            "Wi-Fi signal",
+            Some("signal_strength"),
+            Some("dBm"),
+            "{{ value_json.rssi_dbm }}",
+            Component::Rssi.id(),
+        ),
+        (
+            "Uptime",
+            Some("duration"),
+            Some("s"),
+            "{{ value_json.uptime_s }}",
+            Component::Uptime.id(),
+        ),
+        (
+            "Free RAM",
+            Some("data_size"),
+            Some("B"),
+            "{{ value_json.free_ram_bytes }}",
+            Component::FreeRam.id(),
+        ),
+        (
+            "IP address",
+            None,
+            None,
+            "{{ value_json.ip_address }}",
+            Component::IpAddress.id(),
+        ),
+        (
+            "MQTT broker",
+            None,
+            None,
+            "{{ value_json.broker }}",
+            Component::Broker.id(),
+        ),
+    ];
+    for (name, device_class, unit_of_measurement, template, unique_id) in device_health {
+        let component = DiscoveryComponent {
+            device_class,
+            unit_of_measurement,
+            state_topic: Some(config::mqtt_topics().diagnostics),
+            ..DiscoveryComponent::diagnostic(name, template, unique_id)
+        };
+        _ = out.components.insert(unique_id, component);
+    }
 
     _ = out.components.insert(
-        config::CMP_PM4,
-        DiscoveryComponent {
-            platform: "sensor",
-            device_class: "pm25",
-            unit_of_measurement: "µg/m³",
-            name: "PM4.0",
-            value_template: "{{ value_json.pm4 }}",
-            unique_id: config::CMP_PM4,
-        },
+        Component::AwayMode.id(),
+        DiscoveryComponent::away_switch("Away mode", Component::AwayMode.id()),
     );
 
     _ = out.components.insert(
-        config::CMP_PM10,
-        DiscoveryComponent {
-            platform: "sensor",
-            device_class: "pm10",
-            unit_of_measurement: "µg/m³",
-            name: "PM10.0",
-            value_template: "{{ value_json.pm10 }}",
-            unique_id: config::CMP_PM10,
-        },
+        Component::PowerProfile.id(),
+        DiscoveryComponent::profile_select("Power profile", Component::PowerProfile.id()),
     );
 
     _ = out.components.insert(
-        config::CMP_VOC,
-        DiscoveryComponent {
-            platform: "sensor",
-            device_class: "volatile_organic_compounds",
-            unit_of_measurement: "µg/m³",
-            name: "tVOC",
-            value_template: "{{ value_json.voc }}",
-            unique_id: config::CMP_VOC,
-        },
+        Component::FanClean.id(),
+        DiscoveryComponent::remote_button("Clean fan", Component::FanClean.id(), Remote::FanClean),
     );
 
     _ = out.components.insert(
-        config::CMP_NOX,
-        DiscoveryComponent {
-            platform: "sensor",
-            device_class: "nitrous_oxide",
-            unit_of_measurement: "ppb",
-            name: "tNOx",
-            value_template: "{{ value_json.nox }}",
-            unique_id: config::CMP_NOX,
-        },
+        Component::Button.id(),
+        DiscoveryComponent::button_event("Button", Component::Button.id()),
     );
 
+    // One config switch per metric, to show or hide it on the display.
+    for (component, name, template, payload_on, payload_off) in DISPLAY_SWITCHES {
+        let key = component.id();
+        _ = out.components.insert(
+            key,
+            DiscoveryComponent::display_switch(name, template, key, payload_on, payload_off),
+        );
+    }
+
     out
 }
+
+/// Component key/unique ID, name, value template, and on/off payloads for each display switch.
+const DISPLAY_SWITCHES: [(Component, &str, &str, &str, &str); 9] = [
+    (
+        Component::ShowPm1,
+        "Show PM1.0",
+        "{{ value_json.pm1 }}",
+        "pm1_on",
+        "pm1_off",
+    ),
+    (
+        Component::ShowPm2_5,
+        "Show PM2.5",
+        "{{ value_json.pm2_5 }}",
+        "pm2_5_on",
+        "pm2_5_off",
+    ),
+    (
+        Component::ShowPm4,
+        "Show PM4.0",
+        "{{ value_json.pm4 }}",
+        "pm4_on",
+        "pm4_off",
+    ),
+    (
+        Component::ShowPm10,
+        "Show PM10.0",
+        "{{ value_json.pm10 }}",
+        "pm10_on",
+        "pm10_off",
+    ),
+    (
+        Component::ShowVoc,
+        "Show tVOC",
+        "{{ value_json.voc }}",
+        "voc_on",
+        "voc_off",
+    ),
+    (
+        Component::ShowNox,
+        "Show tNOx",
+        "{{ value_json.nox }}",
+        "nox_on",
+        "nox_off",
+    ),
+    (
+        Component::ShowTemperature,
+        "Show Temperature",
+        "{{ value_json.temperature }}",
+        "temperature_on",
+        "temperature_off",
+    ),
+    (
+        Component::ShowHumidity,
+        "Show Humidity",
+        "{{ value_json.humidity }}",
+        "humidity_on",
+        "humidity_off",
+    ),
+    (
+        Component::GuestMode,
+        "Guest mode",
+        "{{ value_json.guest }}",
+        "guest_on",
+        "guest_off",
+    ),
+];
+
+/// Which metrics are shown on the display, and whether guest mode is on, as published to the
+/// display state topic.
+#[derive(Debug, Serialize)]
+pub struct DisplayStateMessage {
+    pub pm1: &'static str,
+    pub pm2_5: &'static str,
+    pub pm4: &'static str,
+    pub pm10: &'static str,
+    pub voc: &'static str,
+    pub nox: &'static str,
+    pub temperature: &'static str,
+    pub humidity: &'static str,
+    pub guest: &'static str,
+}
+
+impl From<Settings> for DisplayStateMessage {
+    fn from(settings: Settings) -> Self {
+        let on_off = |on| if on { "ON" } else { "OFF" };
+        let visible = |metric| on_off(settings.visible_metrics.contains(metric));
+
+        Self {
+            pm1: visible(Metric::Pm1),
+            pm2_5: visible(Metric::Pm2_5),
+            pm4: visible(Metric::Pm4),
+            pm10: visible(Metric::Pm10),
+            voc: visible(Metric::Voc),
+            nox: visible(Metric::Nox),
+            temperature: visible(Metric::Temperature),
+            humidity: visible(Metric::Humidity),
+            guest: on_off(settings.guest_mode),
+        }
+    }
+}
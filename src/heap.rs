@@ -0,0 +1,143 @@
+//! An optional heap, for subsystems that are much easier to write with `alloc` (TLS, a web UI).
+//!
+//! Only built with the `heap` feature. The rest of the firmware sticks to static buffers, so the
+//! heap is kept small and every allocation is counted against whichever subsystem made it. The
+//! totals, along with the high-water mark, show up in the console's `dump`.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+
+use defmt::{info, Format};
+use embedded_alloc::LlffHeap;
+use portable_atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use serde::Serialize;
+
+/// The whole heap budget. Allocations beyond it fail rather than eating into the stack.
+pub const HEAP_SIZE: usize = 32 * 1024;
+
+/// Who an allocation is counted against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum Subsystem {
+    /// Anything allocated outside a `scope`.
+    Other = 0,
+}
+
+const SUBSYSTEM_COUNT: usize = 1;
+const SUBSYSTEMS: [Subsystem; SUBSYSTEM_COUNT] = [Subsystem::Other];
+
+impl Subsystem {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Subsystem::Other => "other",
+        }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Audited = Audited {
+    heap: LlffHeap::empty(),
+};
+
+static CURRENT: AtomicU8 = AtomicU8::new(Subsystem::Other as u8);
+static HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+static ALLOCATIONS: [AtomicU32; SUBSYSTEM_COUNT] = [const { AtomicU32::new(0) }; SUBSYSTEM_COUNT];
+static BYTES: [AtomicU32; SUBSYSTEM_COUNT] = [const { AtomicU32::new(0) }; SUBSYSTEM_COUNT];
+
+/// The heap, counting every allocation towards the current subsystem.
+struct Audited {
+    heap: LlffHeap,
+}
+
+unsafe impl GlobalAlloc for Audited {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // SAFETY: passed straight through.
+        let ptr = unsafe { self.heap.alloc(layout) };
+
+        if ptr.is_null() {
+            FAILURES.fetch_add(1, Ordering::Relaxed);
+            return ptr;
+        }
+
+        let subsystem = CURRENT.load(Ordering::Relaxed) as usize;
+        ALLOCATIONS[subsystem].fetch_add(1, Ordering::Relaxed);
+        BYTES[subsystem].fetch_add(layout.size() as u32, Ordering::Relaxed);
+        HIGH_WATER.fetch_max(self.heap.used(), Ordering::Relaxed);
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // SAFETY: passed straight through.
+        unsafe { self.heap.dealloc(ptr, layout) }
+    }
+}
+
+/// Hand the heap its memory. Must be called once at boot, before anything allocates.
+pub fn init() {
+    static mut MEMORY: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+
+    // SAFETY: called once, and nothing else touches MEMORY.
+    unsafe {
+        ALLOCATOR
+            .heap
+            .init(addr_of_mut!(MEMORY) as usize, HEAP_SIZE)
+    }
+    info!("Heap ready, {} bytes", HEAP_SIZE);
+}
+
+/// Count allocations against `subsystem` until the returned guard is dropped.
+///
+/// There's no telling tasks apart from inside the allocator, so don't hold the guard across an
+/// `.await`, or another task's allocations will be counted too.
+#[must_use]
+#[allow(unused)]
+pub fn scope(subsystem: Subsystem) -> Scope {
+    Scope {
+        previous: CURRENT.swap(subsystem as u8, Ordering::Relaxed),
+    }
+}
+
+pub struct Scope {
+    previous: u8,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.store(self.previous, Ordering::Relaxed);
+    }
+}
+
+/// How much of the heap is in use, and who's been using it.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HeapSnapshot {
+    pub size: usize,
+    pub used: usize,
+    pub high_water: usize,
+    pub failures: u32,
+    pub subsystems: [SubsystemUsage; SUBSYSTEM_COUNT],
+}
+
+/// Totals since boot, as frees can't be traced back to a subsystem.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SubsystemUsage {
+    pub name: &'static str,
+    pub allocations: u32,
+    pub bytes: u32,
+}
+
+pub fn snapshot() -> HeapSnapshot {
+    HeapSnapshot {
+        size: HEAP_SIZE,
+        used: ALLOCATOR.heap.used(),
+        high_water: HIGH_WATER.load(Ordering::Relaxed),
+        failures: FAILURES.load(Ordering::Relaxed),
+        subsystems: SUBSYSTEMS.map(|s| SubsystemUsage {
+            name: s.name(),
+            allocations: ALLOCATIONS[s as usize].load(Ordering::Relaxed),
+            bytes: BYTES[s as usize].load(Ordering::Relaxed),
+        }),
+    }
+}
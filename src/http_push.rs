@@ -0,0 +1,331 @@
+//! Pushes the readings to an HTTP endpoint (a webhook, or InfluxDB's write API) alongside MQTT or
+//! the LAN broadcast, rather than instead of them.
+//!
+//! It keeps its own pace and gets its own copy of the latest readings, so a slow or unreachable
+//! endpoint never holds up the broker, and a broker outage never stops the pushes.
+
+use core::fmt::Write as _;
+
+use embassy_net::dns::DnsQueryType;
+use embassy_net::tcp::{self, ConnectError, TcpSocket};
+use embassy_net::Stack;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+use log::{error, info, warn};
+use static_cell::StaticCell;
+
+use crate::device_config;
+use crate::hass::StateMessage;
+use crate::metric::Metric;
+use crate::sen55::Readings;
+use crate::sockets::{self, User};
+use crate::traffic::Counted;
+use crate::{config, HTTP_PUSH_READINGS};
+
+/// Used when `HTTP_PUSH_INTERVAL` isn't set.
+const DEFAULT_INTERVAL_S: u64 = 60;
+
+/// Failed pushes are retried less and less often, down to this.
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// Comfortably bigger than the state message, in either format.
+const BODY_SIZE: usize = 1024;
+
+/// Room for the request line and headers.
+const HEAD_SIZE: usize = 512;
+
+/// The longest response header line that's kept. Only the status line and `Content-Length`
+/// matter, so anything longer is cut short.
+const LINE_SIZE: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    /// The same JSON as the MQTT state message.
+    Json,
+    /// InfluxDB line protocol, one field per metric.
+    Influx,
+}
+
+/// Where to push to, picked out of `HTTP_PUSH_URL` (or a rule's webhook URL).
+pub struct Target<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+}
+
+impl<'a> Target<'a> {
+    /// Only plain `http://<host>[:<port>]/<path>` URLs, as there's no TLS.
+    pub fn parse(url: &'a str) -> Option<Target<'a>> {
+        let rest = url.strip_prefix("http://")?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (authority, 80),
+        };
+
+        (!host.is_empty()).then_some(Target { host, port, path })
+    }
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    Dns,
+    Connect(ConnectError),
+    Socket(tcp::Error),
+    /// The body or headers didn't fit.
+    TooBig,
+    /// None of the readings have a value yet.
+    NoReadings,
+    /// The endpoint didn't answer with an HTTP status line.
+    BadResponse,
+    /// The endpoint answered, but not with a 2xx.
+    Status(u16),
+}
+
+/// A response's status line and headers couldn't be read.
+#[derive(Debug)]
+pub enum HeadError {
+    Socket(tcp::Error),
+    /// It isn't an HTTP status line, or the connection closed before the end of the headers.
+    BadResponse,
+}
+
+impl From<HeadError> for PushError {
+    fn from(e: HeadError) -> Self {
+        match e {
+            HeadError::Socket(e) => PushError::Socket(e),
+            HeadError::BadResponse => PushError::BadResponse,
+        }
+    }
+}
+
+/// What matters of a response's status line and headers.
+pub struct ResponseHead {
+    /// E.g. 204, from `HTTP/1.1 204 No Content`.
+    pub status: u16,
+    pub content_length: Option<usize>,
+}
+
+impl ResponseHead {
+    /// Read the status line and headers, leaving the body in the socket. However long the head,
+    /// no more than a line of it is held at once.
+    pub async fn read(socket: &mut Counted<TcpSocket<'_>>) -> Result<Self, HeadError> {
+        let status = read_line(socket)
+            .await?
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or(HeadError::BadResponse)?;
+
+        let mut content_length = None;
+        loop {
+            let line = read_line(socket).await?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().ok();
+                }
+            }
+        }
+
+        Ok(Self {
+            status,
+            content_length,
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..=299).contains(&self.status)
+    }
+}
+
+/// Read one line of a response's head, without its line ending, cutting it short at `LINE_SIZE`.
+/// The head's small, and already in the socket's buffer, so it's read a byte at a time to leave
+/// the body where it is.
+async fn read_line(socket: &mut Counted<TcpSocket<'_>>) -> Result<String<LINE_SIZE>, HeadError> {
+    let mut line = String::new();
+    loop {
+        let mut byte = [0u8; 1];
+        match socket.read(&mut byte).await {
+            Ok(0) => return Err(HeadError::BadResponse),
+            Ok(_) if byte[0] == b'\n' => return Ok(line),
+            Ok(_) if byte[0] == b'\r' || !byte[0].is_ascii() => {}
+            Ok(_) => _ = line.push(byte[0] as char),
+            Err(e) => return Err(HeadError::Socket(e)),
+        }
+    }
+}
+
+/// Pushes the latest readings to `url` every `HTTP_PUSH_INTERVAL` seconds.
+#[embassy_executor::task]
+pub async fn worker(stack: Stack<'static>, url: &'static str) {
+    let Some(target) = Target::parse(url) else {
+        error!(
+            "Can't push to {}, expected http://<host>[:<port>]/<path>",
+            url
+        );
+        return;
+    };
+
+    let format = match config::HTTP_PUSH_FORMAT {
+        Some("influx") => Format::Influx,
+        _ => Format::Json,
+    };
+    let interval = Duration::from_secs(
+        config::HTTP_PUSH_INTERVAL
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(DEFAULT_INTERVAL_S),
+    );
+    info!(
+        "Pushing {:?} to {} every {}s",
+        format,
+        url,
+        interval.as_secs()
+    );
+
+    static RX_BUFFER: StaticCell<[u8; 512]> = StaticCell::new();
+    static TX_BUFFER: StaticCell<[u8; BODY_SIZE + HEAD_SIZE]> = StaticCell::new();
+    let rx_buffer = RX_BUFFER.init([0; 512]);
+    let tx_buffer = TX_BUFFER.init([0; BODY_SIZE + HEAD_SIZE]);
+
+    let mut wait = interval;
+    loop {
+        Timer::after(wait).await;
+        let readings = HTTP_PUSH_READINGS.wait().await;
+
+        wait = match push(stack, &target, format, readings, rx_buffer, tx_buffer).await {
+            Ok(()) => interval,
+            Err(e) => {
+                warn!("HTTP push failed: {:?}", e);
+                (wait * 2).min(MAX_BACKOFF)
+            }
+        };
+    }
+}
+
+async fn push(
+    stack: Stack<'static>,
+    target: &Target,
+    format: Format,
+    readings: Readings,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<(), PushError> {
+    let mut body = [0u8; BODY_SIZE];
+    let body_len = match format {
+        Format::Json => serde_json_core::to_slice(&StateMessage::current(readings), &mut body)
+            .map_err(|_| PushError::TooBig)?,
+        Format::Influx => {
+            let line = influx_line(&readings)?;
+            body[..line.len()].copy_from_slice(line.as_bytes());
+            line.len()
+        }
+    };
+
+    let content_type = match format {
+        Format::Json => "application/json",
+        Format::Influx => "text/plain; charset=utf-8",
+    };
+    post(
+        stack,
+        User::HttpPush,
+        target,
+        content_type,
+        config::HTTP_PUSH_AUTH,
+        &body[..body_len],
+        rx_buffer,
+        tx_buffer,
+    )
+    .await
+}
+
+/// POST `body` to `target` on behalf of `user`, and check it was accepted. Only the head of the
+/// response is read.
+#[allow(clippy::too_many_arguments)]
+pub async fn post(
+    stack: Stack<'static>,
+    user: User,
+    target: &Target<'_>,
+    content_type: &str,
+    auth: Option<&str>,
+    body: &[u8],
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<(), PushError> {
+    let mut head = String::<HEAD_SIZE>::new();
+    write!(
+        head,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+        target.path,
+        target.host,
+        content_type,
+        body.len()
+    )
+    .map_err(|_| PushError::TooBig)?;
+    if let Some(auth) = auth {
+        write!(head, "Authorization: {}\r\n", auth).map_err(|_| PushError::TooBig)?;
+    }
+    head.push_str("Connection: close\r\n\r\n")
+        .map_err(|_| PushError::TooBig)?;
+
+    let address = match stack.dns_query(target.host, DnsQueryType::A).await {
+        Ok(addresses) if !addresses.is_empty() => addresses[0],
+        Ok(_) | Err(_) => return Err(PushError::Dns),
+    };
+
+    let _claim = sockets::claim(user);
+    let mut socket = Counted::new(TcpSocket::new(stack, rx_buffer, tx_buffer), user);
+    socket.set_timeout(Some(Duration::from_secs(10)));
+    socket
+        .connect((address, target.port))
+        .await
+        .map_err(PushError::Connect)?;
+
+    socket
+        .write_all(head.as_bytes())
+        .await
+        .map_err(PushError::Socket)?;
+    socket.write_all(body).await.map_err(PushError::Socket)?;
+    socket.flush().await.map_err(PushError::Socket)?;
+
+    let head = ResponseHead::read(&mut socket).await;
+    socket.close();
+
+    match head? {
+        head if head.is_success() => Ok(()),
+        head => Err(PushError::Status(head.status)),
+    }
+}
+
+/// The readings as one line of InfluxDB line protocol, tagged with the device's identifier. There's
+/// no clock to timestamp it with, so that's left to the server.
+fn influx_line(readings: &Readings) -> Result<String<BODY_SIZE>, PushError> {
+    let mut line = String::new();
+    write!(
+        line,
+        "vindskrivare,device={}",
+        device_config::get().identifier
+    )
+    .map_err(|_| PushError::TooBig)?;
+
+    let mut separator = ' ';
+    for metric in Metric::ALL {
+        if let Some(value) = metric.value(readings) {
+            write!(line, "{}{}={}", separator, metric.key(), value)
+                .map_err(|_| PushError::TooBig)?;
+            separator = ',';
+        }
+    }
+
+    // A line needs at least one field.
+    match separator {
+        ',' => Ok(line),
+        _ => Err(PushError::NoReadings),
+    }
+}
@@ -0,0 +1,336 @@
+//! A small HTTP server on port 80, for talking to the device directly rather than through the
+//! broker. Up to `CONNECTIONS` clients are served at once, each by its own task, so a dashboard
+//! holding a connection open doesn't shut out a scraper or an API call. Connections are kept alive
+//! between requests until the client closes them or goes quiet for `KEEP_ALIVE_TIMEOUT`.
+//!
+//! Only what's listed in `route` is served:
+//!
+//! - `GET /live` upgrades to a WebSocket streaming the readings, see `websocket`
+//! - `GET /alarms` is the alarm log as JSON, newest first, see `alarm_log`
+//! - `GET /readings` is the latest averaged readings as JSON, the same as the MQTT state message
+//! - `GET /health` is how the device is doing as JSON, with the diagnostics
+//!
+//! Anything else gets a 404. Requests can't have bodies, and aren't pipelined.
+
+use core::cell::Cell;
+use core::fmt::Write as _;
+
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, WithTimeout};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::diagnostics::DiagnosticsMessage;
+use crate::hass::StateMessage;
+use crate::sockets::{self, User};
+use crate::traffic::Counted;
+use crate::{alarm_log, state, websocket};
+
+const PORT: u16 = 80;
+
+/// How many clients can be connected at once, each taking a socket.
+pub const CONNECTIONS: usize = 3;
+
+/// How long a new client gets to send its first request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a connection's kept open waiting for the next request.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How long sent data can go unacknowledged before the connection's given up on.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Room for the request line and headers. Anything bigger isn't for us.
+const REQUEST_SIZE: usize = 1024;
+
+/// Room for the readings or the health, as JSON.
+const BODY_SIZE: usize = 1024;
+
+/// Only one WebSocket client at a time, as they share the one mailbox of readings.
+static STREAMING: Mutex<ThreadModeRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// The parts of a request that matter here.
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    /// `Sec-WebSocket-Key`, if the client's asking to upgrade.
+    websocket_key: Option<&'a str>,
+    /// The client asked for the connection to be closed after this one.
+    close: bool,
+}
+
+impl<'a> Request<'a> {
+    fn parse(head: &'a str) -> Option<Request<'a>> {
+        let mut lines = head.split("\r\n");
+        let mut request_line = lines.next()?.split(' ');
+        let method = request_line.next()?;
+        let path = request_line.next()?;
+
+        let mut request = Request {
+            method,
+            path,
+            websocket_key: None,
+            close: false,
+        };
+        for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+            let (name, value) = (name.trim(), value.trim());
+            if name.eq_ignore_ascii_case("Sec-WebSocket-Key") {
+                request.websocket_key = Some(value);
+            } else if name.eq_ignore_ascii_case("Connection") {
+                request.close = value.eq_ignore_ascii_case("close");
+            }
+        }
+
+        Some(request)
+    }
+}
+
+/// What `GET /health` answers with.
+#[derive(Serialize)]
+struct Health {
+    /// `ok` while fresh readings are coming in, `stale` when the latest are too old to go by (as
+    /// they are while the sensor rests between duty cycles), and `starting` before the first.
+    status: &'static str,
+    connection: &'static str,
+    sensor: &'static str,
+    /// How old the latest readings are.
+    readings_age_ms: Option<u64>,
+    diagnostics: DiagnosticsMessage,
+}
+
+impl Health {
+    fn current(stack: Stack<'_>) -> Self {
+        let current = state::get();
+        let status = match current.readings {
+            None => "starting",
+            Some(readings) if readings.is_stale() => "stale",
+            Some(_) => "ok",
+        };
+
+        Self {
+            status,
+            connection: current.connection.name(),
+            sensor: current.sensor_activity.name(),
+            readings_age_ms: current.readings.map(|r| r.age().as_millis()),
+            diagnostics: DiagnosticsMessage::current(stack),
+        }
+    }
+}
+
+/// What's become of a connection after a request.
+enum Outcome {
+    /// Ready for another request.
+    KeepAlive,
+    /// Done with, one way or another.
+    Close,
+}
+
+/// Serves one connection after another. `CONNECTIONS` of these are spawned.
+#[embassy_executor::task(pool_size = CONNECTIONS)]
+pub async fn worker(stack: Stack<'static>, id: usize) {
+    info!("HTTP server {} listening on port {}", id, PORT);
+
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 2048];
+
+    // A socket's always open, either listening or connected.
+    let _claim = sockets::claim(User::HttpServer);
+
+    loop {
+        let mut socket = Counted::new(
+            TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer),
+            User::HttpServer,
+        );
+        socket.set_timeout(Some(SOCKET_TIMEOUT));
+        if let Err(e) = socket.accept(PORT).await {
+            warn!("HTTP accept failed: {:?}", e);
+            continue;
+        }
+
+        serve(&mut socket, stack).await;
+
+        socket.close();
+        _ = socket.flush().await;
+    }
+}
+
+/// Answer requests on a connection until it's done with.
+async fn serve(socket: &mut Counted<TcpSocket<'_>>, stack: Stack<'_>) {
+    let mut head = [0u8; REQUEST_SIZE];
+    let mut timeout = REQUEST_TIMEOUT;
+
+    loop {
+        let request = match read_head(socket, &mut head).with_timeout(timeout).await {
+            Ok(Some(len)) => core::str::from_utf8(&head[..len])
+                .ok()
+                .and_then(Request::parse),
+            Ok(None) => None,
+            // Nothing more from the client.
+            Err(_) => return,
+        };
+
+        let Some(request) = request else {
+            respond(socket, "400 Bad Request", true).await;
+            return;
+        };
+
+        match route(socket, &request, stack).await {
+            Outcome::KeepAlive => timeout = KEEP_ALIVE_TIMEOUT,
+            Outcome::Close => return,
+        }
+    }
+}
+
+async fn route(
+    socket: &mut Counted<TcpSocket<'_>>,
+    request: &Request<'_>,
+    stack: Stack<'_>,
+) -> Outcome {
+    let close = request.close;
+    match (request.method, request.path, request.websocket_key) {
+        ("GET", "/live", Some(key)) => {
+            if STREAMING.lock(|s| s.replace(true)) {
+                return respond(socket, "503 Service Unavailable", close).await;
+            }
+            websocket::serve(socket, key).await;
+            STREAMING.lock(|s| s.set(false));
+            Outcome::Close
+        }
+        ("GET", "/live", None) => respond(socket, "426 Upgrade Required", close).await,
+        ("GET", "/alarms", _) => send_alarm_log(socket, close).await,
+        ("GET", "/readings", _) => match state::get().readings {
+            Some(readings) => send_json(socket, &StateMessage::current(readings), close).await,
+            // Nothing to show until the sensor's been read.
+            None => respond(socket, "503 Service Unavailable", close).await,
+        },
+        ("GET", "/health", _) => send_json(socket, &Health::current(stack), close).await,
+        _ => respond(socket, "404 Not Found", close).await,
+    }
+}
+
+/// Read up to the blank line ending the headers, returning their length. `None` if the connection
+/// closed first or they don't fit.
+async fn read_head(socket: &mut Counted<TcpSocket<'_>>, buf: &mut [u8]) -> Option<usize> {
+    let mut len = 0;
+    loop {
+        if let Some(end) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            return Some(end);
+        }
+        if len == buf.len() {
+            return None;
+        }
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => len += n,
+        }
+    }
+}
+
+/// A response with no body, closing the connection after it if `close` is set.
+async fn respond(socket: &mut Counted<TcpSocket<'_>>, status: &str, close: bool) -> Outcome {
+    match send_head(socket, status, None, 0, close).await {
+        Ok(()) => outcome(close),
+        Err(()) => Outcome::Close,
+    }
+}
+
+/// The status line and headers of a response with a `content_length` byte body of
+/// `content_type`, which is up to the caller to send.
+pub async fn send_head(
+    socket: &mut Counted<TcpSocket<'_>>,
+    status: &str,
+    content_type: Option<&str>,
+    content_length: usize,
+    close: bool,
+) -> Result<(), ()> {
+    let connection = match close {
+        true => "close",
+        false => "keep-alive",
+    };
+    let mut length = String::<8>::new();
+    _ = write!(length, "{}", content_length);
+
+    let head = [
+        "HTTP/1.1 ",
+        status,
+        "\r\nContent-Type: ",
+        content_type.unwrap_or("text/plain"),
+        "\r\nContent-Length: ",
+        &length,
+        "\r\nConnection: ",
+        connection,
+        "\r\n\r\n",
+    ];
+    for part in head {
+        socket.write_all(part.as_bytes()).await.map_err(|_| ())?;
+    }
+    Ok(())
+}
+
+/// A `200 OK` with `value` as JSON.
+async fn send_json<T: Serialize>(
+    socket: &mut Counted<TcpSocket<'_>>,
+    value: &T,
+    close: bool,
+) -> Outcome {
+    let mut body = [0u8; BODY_SIZE];
+    let Ok(len) = serde_json_core::to_slice(value, &mut body) else {
+        warn!("HTTP response too big to send");
+        return respond(socket, "500 Internal Server Error", close).await;
+    };
+
+    let sent: Result<(), ()> = async {
+        send_head(socket, "200 OK", Some("application/json"), len, close).await?;
+        socket.write_all(&body[..len]).await.map_err(|_| ())
+    }
+    .await;
+
+    match sent {
+        Ok(()) => outcome(close),
+        Err(()) => Outcome::Close,
+    }
+}
+
+/// The alarm log as a JSON array, newest first. It's written an entry at a time, so there's no
+/// need for a buffer big enough for all of it.
+async fn send_alarm_log(socket: &mut Counted<TcpSocket<'_>>, close: bool) -> Outcome {
+    let entries = alarm_log::entries();
+    let mut buf = [0u8; 160];
+
+    // Brackets, commas, and the entries themselves.
+    let mut length = 2 + entries.len().saturating_sub(1);
+    for entry in &entries {
+        length += serde_json_core::to_slice(entry, &mut buf).unwrap_or(0);
+    }
+
+    let sent: Result<(), ()> = async {
+        send_head(socket, "200 OK", Some("application/json"), length, close).await?;
+        socket.write_all(b"[").await.map_err(|_| ())?;
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 {
+                socket.write_all(b",").await.map_err(|_| ())?;
+            }
+            let len = serde_json_core::to_slice(entry, &mut buf).unwrap_or(0);
+            socket.write_all(&buf[..len]).await.map_err(|_| ())?;
+        }
+        socket.write_all(b"]").await.map_err(|_| ())
+    }
+    .await;
+
+    match sent {
+        Ok(()) => outcome(close),
+        Err(()) => Outcome::Close,
+    }
+}
+
+fn outcome(close: bool) -> Outcome {
+    match close {
+        true => Outcome::Close,
+        false => Outcome::KeepAlive,
+    }
+}
@@ -0,0 +1,178 @@
+//! Small one-colour icons for status indicators and banners.
+//!
+//! Each icon is a 16x16 bitmap, one `u16` per row with the leftmost pixel in the top bit, so they
+//! can be drawn in whatever colour suits the background they're on.
+
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::{DrawTarget, Point};
+use embedded_graphics::Pixel;
+
+pub const ICON_SIZE: u32 = 16;
+
+type Bitmap = [u16; ICON_SIZE as usize];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Icon {
+    /// Wi-Fi signal, with 0 to 3 bars.
+    Wifi(u8),
+    Mqtt,
+    Warning,
+}
+
+impl Icon {
+    fn bitmap(&self) -> Bitmap {
+        match self {
+            Icon::Wifi(bars) => {
+                let mut out = WIFI_DOT;
+                for arc in WIFI_ARCS.iter().take(*bars as usize) {
+                    out = or(out, *arc);
+                }
+                out
+            }
+            Icon::Mqtt => MQTT,
+            Icon::Warning => WARNING,
+        }
+    }
+}
+
+/// Draw `icon` with its top left corner at `pos`. Unset pixels are left alone.
+pub fn draw<D>(target: &mut D, icon: Icon, pos: Point, color: Rgb565) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    let bitmap = icon.bitmap();
+
+    let pixels = bitmap.into_iter().enumerate().flat_map(move |(y, row)| {
+        (0..ICON_SIZE as i32)
+            .filter(move |x| row & (0x8000 >> x) != 0)
+            .map(move |x| Pixel(pos + Point::new(x, y as i32), color))
+    });
+
+    target.draw_iter(pixels)
+}
+
+const fn or(a: Bitmap, b: Bitmap) -> Bitmap {
+    let mut out = a;
+    let mut i = 0;
+    while i < out.len() {
+        out[i] |= b[i];
+        i += 1;
+    }
+    out
+}
+
+const WIFI_DOT: Bitmap = [
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000000000000,
+    0b0000000110000000,
+    0b0000001111000000,
+    0b0000000110000000,
+    0b0000000000000000,
+];
+
+/// Innermost arc first.
+const WIFI_ARCS: [Bitmap; 3] = [
+    [
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000001111000000,
+        0b0000011001100000,
+        0b0000100000010000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+    ],
+    [
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000011111100000,
+        0b0001110000111000,
+        0b0011000000001100,
+        0b0100000000000010,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+    ],
+    [
+        0b0000011111100000,
+        0b0011110000111100,
+        0b0111000000001110,
+        0b1100000000000011,
+        0b1000000000000001,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+        0b0000000000000000,
+    ],
+];
+
+/// Two arrows passing each other, for messages to and from the broker.
+const MQTT: Bitmap = [
+    0b0000000000000000,
+    0b0000000000010000,
+    0b0000000000011000,
+    0b0011111111111100,
+    0b0011111111111110,
+    0b0000000000011100,
+    0b0000000000011000,
+    0b0000000000010000,
+    0b0000100000000000,
+    0b0001100000000000,
+    0b0011100000000000,
+    0b0111111111111100,
+    0b0011111111111100,
+    0b0001100000000000,
+    0b0000100000000000,
+    0b0000000000000000,
+];
+
+const WARNING: Bitmap = [
+    0b0000000110000000,
+    0b0000000110000000,
+    0b0000001111000000,
+    0b0000001001000000,
+    0b0000011001100000,
+    0b0000010110100000,
+    0b0000110110110000,
+    0b0000100110010000,
+    0b0001100110011000,
+    0b0001000110001000,
+    0b0011000000001100,
+    0b0010000110000100,
+    0b0110000110000110,
+    0b0100000000000010,
+    0b1111111111111111,
+    0b1111111111111111,
+];
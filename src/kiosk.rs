@@ -0,0 +1,99 @@
+//! Kiosk mode, for a device on a wall that people glance at rather than look after.
+//!
+//! Normally, if the sensor stops answering the screen just keeps the last readings it had, and
+//! nothing says the network has gone. With `KIOSK` set the UI keeps an eye on both, and if either
+//! has been gone for a while it swaps the pages for a fallback screen that says what's wrong, shows
+//! the last known values and counts up how long ago they (or the network) were last good. It goes
+//! back to the pages as soon as things recover.
+
+use embassy_time::{Duration, Instant};
+
+use crate::sen55::Readings;
+use crate::state::{self, SensorActivity};
+
+/// How long the readings can go without updating, while the sensor's meant to be measuring, before
+/// it's counted as gone. Comfortably more than its warm-up after a rest.
+const SENSOR_GRACE: Duration = Duration::from_secs(2 * 60);
+
+/// How long the connection can be down before it's counted as gone, so a quick reconnect to the
+/// broker doesn't flash up the fallback screen.
+const NETWORK_GRACE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FaultKind {
+    /// No new readings from the sensor.
+    Sensor,
+    /// Not connected to the broker (or the LAN), so nothing's being published.
+    Network,
+}
+
+impl FaultKind {
+    /// Across the top of the fallback screen.
+    pub const fn banner(&self) -> &'static str {
+        match self {
+            FaultKind::Sensor => "NO SENSOR",
+            FaultKind::Network => "OFFLINE",
+        }
+    }
+
+    /// Under the values, saying what the timer counts from.
+    pub const fn description(&self) -> &'static str {
+        match self {
+            FaultKind::Sensor => "Sensor not responding",
+            FaultKind::Network => "Network down, not publishing",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    pub kind: FaultKind,
+    /// When things were last good: the last readings for the sensor, or when the connection
+    /// dropped for the network.
+    pub since: Instant,
+}
+
+/// Keeps track of when the sensor and network were last fine.
+pub struct Watch {
+    sensor_fine_at: Instant,
+    network_fine_at: Instant,
+}
+
+impl Watch {
+    pub fn new() -> Self {
+        Self {
+            sensor_fine_at: Instant::now(),
+            network_fine_at: Instant::now(),
+        }
+    }
+
+    /// Whatever's wrong, given the latest readings the UI has. A dead sensor takes priority, as
+    /// then the values on screen are old too.
+    pub fn check(&mut self, readings: Option<&Readings>) -> Option<Fault> {
+        let now = Instant::now();
+        let state = state::get();
+
+        // Readings stop while the sensor rests between measurements, which is fine.
+        let fresh = readings.is_some_and(|r| r.age() < SENSOR_GRACE);
+        if fresh || state.sensor_activity == SensorActivity::Idle {
+            self.sensor_fine_at = now;
+        }
+        if state.connection.connected() {
+            self.network_fine_at = now;
+        }
+
+        if now - self.sensor_fine_at >= SENSOR_GRACE {
+            Some(Fault {
+                kind: FaultKind::Sensor,
+                since: readings.map_or(self.sensor_fine_at, |r| r.taken_at),
+            })
+        } else if now - self.network_fine_at >= NETWORK_GRACE {
+            Some(Fault {
+                kind: FaultKind::Network,
+                since: self.network_fine_at,
+            })
+        } else {
+            None
+        }
+    }
+}
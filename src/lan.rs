@@ -0,0 +1,112 @@
+//! Broker-less mode: with no MQTT broker configured, the state is multicast to the LAN instead, so
+//! dashboards and other devices can pick it up without any setup.
+//!
+//! Each set of readings goes out as one UDP datagram to `LAN_MULTICAST_ADDR:LAN_PORT`, holding the
+//! same JSON as the MQTT state message, wrapped with the device's identifier and name.
+
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpAddress, IpEndpoint, Ipv4Address, Stack};
+use embassy_time::{Duration, WithTimeout};
+use log::{error, info};
+use serde::Serialize;
+use static_cell::StaticCell;
+
+use crate::device_config;
+use crate::hass::StateMessage;
+use crate::profile::{self, Job};
+use crate::sockets::{self, User};
+use crate::state::{self, ConnectionState};
+use crate::telemetry::{self, Task};
+use crate::traffic;
+use crate::{config, MQTT_READING_CHANNEL};
+
+/// Administratively scoped, so it stays on the local network.
+pub const LAN_MULTICAST_ADDR: Ipv4Address = Ipv4Address::new(239, 255, 55, 55);
+pub const LAN_PORT: u16 = 5555;
+
+/// Readings can stop for minutes while the sensor is duty cycling, so check in with the watchdog at
+/// least this often regardless.
+const IDLE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Comfortably bigger than the state message.
+const DATAGRAM_SIZE: usize = 1024;
+
+#[derive(Serialize)]
+struct LanMessage {
+    device: &'static str,
+    name: &'static str,
+    state: StateMessage,
+}
+
+/// Multicasts every set of readings to the LAN. Takes the place of the MQTT worker, so it reads
+/// the same channel and checks in with the watchdog as the MQTT task.
+#[embassy_executor::task]
+pub async fn worker(stack: Stack<'static>) {
+    profile::track(Job::Mqtt, run(stack)).await
+}
+
+async fn run(stack: Stack<'static>) {
+    info!("started lan worker");
+
+    static RX_META: StaticCell<[PacketMetadata; 1]> = StaticCell::new();
+    static RX_BUFFER: StaticCell<[u8; 64]> = StaticCell::new();
+    static TX_META: StaticCell<[PacketMetadata; 4]> = StaticCell::new();
+    static TX_BUFFER: StaticCell<[u8; DATAGRAM_SIZE * 2]> = StaticCell::new();
+
+    let _claim = sockets::claim(User::Lan);
+    let mut socket = UdpSocket::new(
+        stack,
+        RX_META.init([PacketMetadata::EMPTY; 1]),
+        RX_BUFFER.init([0; 64]),
+        TX_META.init([PacketMetadata::EMPTY; 4]),
+        TX_BUFFER.init([0; DATAGRAM_SIZE * 2]),
+    );
+
+    // Any local port will do, nothing is ever received.
+    if let Err(e) = socket.bind(0) {
+        error!("Couldn't bind LAN socket: {:?}", e);
+        return;
+    }
+
+    state::set_connection(ConnectionState::Online);
+
+    let destination = IpEndpoint::new(IpAddress::Ipv4(LAN_MULTICAST_ADDR), LAN_PORT);
+    let mut buf = [0u8; DATAGRAM_SIZE];
+
+    loop {
+        telemetry::heartbeat(Task::Mqtt);
+
+        let Ok(readings) = MQTT_READING_CHANNEL
+            .receive()
+            .with_timeout(IDLE_HEARTBEAT_INTERVAL)
+            .await
+        else {
+            continue;
+        };
+
+        let message = LanMessage {
+            device: &device_config::get().identifier,
+            name: &device_config::get().name,
+            state: StateMessage::current(readings).sequenced(),
+        };
+
+        let len = match serde_json_core::to_slice(&message, &mut buf) {
+            Ok(len) => len,
+            Err(e) => {
+                error!("Error serializing LAN message: {:?}", e);
+                continue;
+            }
+        };
+
+        match socket.send_to(&buf[..len], destination).await {
+            Ok(()) => {
+                state::count_published();
+                traffic::sent_datagram(User::Lan, len);
+            }
+            Err(e) => {
+                state::count_publish_failure();
+                error!("LAN send failed: {:?}", e);
+            }
+        }
+    }
+}
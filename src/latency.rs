@@ -0,0 +1,258 @@
+//! Measures the round trip to the gateway and the broker once a minute, to tell a poor Wi-Fi link
+//! from a struggling broker: if the gateway's slow too, it's the Wi-Fi.
+//!
+//! There are no ICMP sockets, so each probe times a TCP connection attempt instead. A refusal
+//! comes back just as quickly as an acceptance, so it works whether or not anything's listening
+//! (port 80 on the gateway, the MQTT port on the broker). Either way the connection's reset
+//! straight away.
+//!
+//! The round trips are published in the state message, and announced to Home Assistant as
+//! diagnostic sensors. The gateway's are also averaged under each Wi-Fi power saving mode, for the
+//! diagnostics. When one target's round trip or jitter goes over its threshold, an event is
+//! published, and another once it's back to normal.
+
+use core::cell::RefCell;
+
+use embassy_net::tcp::{ConnectError, TcpSocket};
+use embassy_net::{IpAddress, Stack};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::power::WifiPower;
+use crate::sockets::{self, User};
+use crate::syslog::{self, Severity};
+use crate::{state, wifi};
+
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Any longer and the target's counted as unreachable.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Anything on the gateway answers on one port or another.
+const GATEWAY_PORT: u16 = 80;
+
+/// A round trip over this is high.
+const RTT_HIGH_MS: u32 = 250;
+
+/// Jitter (the smoothed change from one round trip to the next) over this is high.
+const JITTER_HIGH_MS: f32 = 100.0;
+
+/// Both have to fall below this fraction of their thresholds to count as normal again, so a link
+/// hovering around a threshold doesn't raise an event every minute.
+const RECOVERED: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Target {
+    Gateway,
+    Broker,
+}
+
+impl Target {
+    const fn name(&self) -> &'static str {
+        match self {
+            Target::Gateway => "gateway",
+            Target::Broker => "broker",
+        }
+    }
+}
+
+/// A target's latency going high or back to normal, as published on the event topic.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Event {
+    pub event: &'static str,
+    /// `gateway` or `broker`.
+    pub target: &'static str,
+    /// `high` or `normal`.
+    pub state: &'static str,
+    /// The latest round trip, in milliseconds, or `None` if it didn't answer.
+    pub rtt_ms: Option<u32>,
+    pub jitter_ms: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Track {
+    rtt_ms: Option<u32>,
+    jitter_ms: f32,
+    high: bool,
+    unpublished: Option<Event>,
+}
+
+impl Track {
+    const NEW: Track = Track {
+        rtt_ms: None,
+        jitter_ms: 0.0,
+        high: false,
+        unpublished: None,
+    };
+
+    /// Take a new measurement into account, returning an event if it's changed whether the
+    /// latency's high.
+    fn record(&mut self, target: Target, rtt_ms: Option<u32>) -> Option<Event> {
+        // As RFC 3550 smooths jitter, over about 16 measurements.
+        if let (Some(last), Some(rtt)) = (self.rtt_ms, rtt_ms) {
+            let change = (rtt as f32 - last as f32).abs();
+            self.jitter_ms += (change - self.jitter_ms) / 16.0;
+        }
+        self.rtt_ms = rtt_ms;
+
+        let rtt = rtt_ms.unwrap_or(u32::MAX);
+        let high = match self.high {
+            false => rtt > RTT_HIGH_MS || self.jitter_ms > JITTER_HIGH_MS,
+            true => {
+                rtt as f32 >= RTT_HIGH_MS as f32 * RECOVERED
+                    || self.jitter_ms >= JITTER_HIGH_MS * RECOVERED
+            }
+        };
+        if high == self.high {
+            return None;
+        }
+        self.high = high;
+
+        let event = Event {
+            event: "latency",
+            target: target.name(),
+            state: if high { "high" } else { "normal" },
+            rtt_ms,
+            jitter_ms: self.jitter_ms as u32,
+        };
+        self.unpublished = Some(event);
+        Some(event)
+    }
+}
+
+struct Monitor {
+    gateway: Track,
+    broker: Track,
+    /// The gateway round trips' total and count under each of `WifiPower::ALL`, to see what the
+    /// chip's power saving costs.
+    by_wifi_power: [(u64, u32); WifiPower::ALL.len()],
+}
+
+impl Monitor {
+    fn track(&mut self, target: Target) -> &mut Track {
+        match target {
+            Target::Gateway => &mut self.gateway,
+            Target::Broker => &mut self.broker,
+        }
+    }
+}
+
+static MONITOR: Mutex<ThreadModeRawMutex, RefCell<Monitor>> = Mutex::new(RefCell::new(Monitor {
+    gateway: Track::NEW,
+    broker: Track::NEW,
+    by_wifi_power: [(0, 0); WifiPower::ALL.len()],
+}));
+
+/// The latest round trip to the gateway, in milliseconds.
+pub fn gateway_rtt_ms() -> Option<u32> {
+    MONITOR.lock(|m| m.borrow().gateway.rtt_ms)
+}
+
+/// The average round trip to the gateway while the Wi-Fi chip's been using each of
+/// `WifiPower::ALL`, in milliseconds, if it has been since boot.
+pub fn gateway_rtt_ms_by_wifi_power() -> [Option<u32>; WifiPower::ALL.len()] {
+    MONITOR.lock(|m| {
+        m.borrow()
+            .by_wifi_power
+            .map(|(total, count)| (count > 0).then(|| (total / u64::from(count)) as u32))
+    })
+}
+
+/// The latest round trip to the broker, in milliseconds.
+pub fn broker_rtt_ms() -> Option<u32> {
+    MONITOR.lock(|m| m.borrow().broker.rtt_ms)
+}
+
+/// The oldest event that hasn't been published yet, if any.
+pub fn unpublished() -> Option<Event> {
+    MONITOR.lock(|m| {
+        let m = m.borrow();
+        m.gateway.unpublished.or(m.broker.unpublished)
+    })
+}
+
+/// Mark `event` as published.
+pub fn mark_published(event: &Event) {
+    MONITOR.lock(|m| {
+        let mut m = m.borrow_mut();
+        for track in [&mut m.gateway, &mut m.broker] {
+            if track.unpublished.is_some_and(|e| e.target == event.target) {
+                track.unpublished = None;
+            }
+        }
+    });
+}
+
+/// Probes the gateway and the broker (once its address is known) every `PROBE_INTERVAL`.
+#[embassy_executor::task]
+pub async fn worker(stack: Stack<'static>, broker_port: u16) {
+    info!("started latency monitor");
+
+    loop {
+        Timer::after(PROBE_INTERVAL).await;
+
+        if let Some(gateway) = stack.config_v4().and_then(|c| c.gateway) {
+            let rtt = probe(stack, IpAddress::Ipv4(gateway), GATEWAY_PORT).await;
+            record(Target::Gateway, rtt);
+            if let (Some(rtt), Some(power)) = (rtt, wifi::power()) {
+                MONITOR.lock(|m| {
+                    let (total, count) = &mut m.borrow_mut().by_wifi_power[power as usize];
+                    *total += u64::from(rtt);
+                    *count += 1;
+                });
+            }
+        }
+
+        if let Some(broker) = state::get().broker_address {
+            let rtt = probe(stack, broker, broker_port).await;
+            record(Target::Broker, rtt);
+        }
+    }
+}
+
+fn record(target: Target, rtt_ms: Option<u32>) {
+    let Some(event) = MONITOR.lock(|m| m.borrow_mut().track(target).record(target, rtt_ms)) else {
+        return;
+    };
+
+    warn!(
+        "{} latency {}: {:?}ms, jitter {}ms",
+        event.target, event.state, event.rtt_ms, event.jitter_ms
+    );
+    syslog::event(
+        Severity::Warning,
+        "network",
+        format_args!(
+            "{} latency {} (rtt {:?}ms, jitter {}ms)",
+            event.target, event.state, event.rtt_ms, event.jitter_ms
+        ),
+    );
+}
+
+/// Time a connection attempt to `address`, in milliseconds, or `None` if nothing came back.
+async fn probe(stack: Stack<'static>, address: IpAddress, port: u16) -> Option<u32> {
+    let mut rx_buffer = [0u8; 16];
+    let mut tx_buffer = [0u8; 16];
+    let _claim = sockets::claim(User::Latency);
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(PROBE_TIMEOUT));
+
+    let start = Instant::now();
+    let result = socket
+        .connect((address, port))
+        .with_timeout(PROBE_TIMEOUT)
+        .await;
+    let rtt = start.elapsed();
+
+    // Nothing's ever sent, so don't bother closing politely.
+    socket.abort();
+    _ = socket.flush().with_timeout(PROBE_TIMEOUT).await;
+
+    match result {
+        Ok(Ok(())) | Ok(Err(ConnectError::ConnectionReset)) => Some(rtt.as_millis() as u32),
+        Ok(Err(_)) | Err(_) => None,
+    }
+}
@@ -0,0 +1,138 @@
+//! The last minute of raw samples of one metric (PM2.5 unless another's been picked from the menu),
+//! one per poll, for the live graph page. Unlike everything else on screen they aren't averaged, so
+//! short-lived sources (a match being struck, a pan on the hob) show up as they happen.
+//!
+//! The last `WINDOWS` minutes are kept, so the graph can be frozen and stepped back through to look
+//! at a spike that's already scrolled off.
+//!
+//! The minutes are at the default poll interval of a second. Samples are kept a poll interval
+//! apart (see `sen55::poll_interval`), so slower polling stretches the graph and its history out to
+//! match, rather than leaving gaps between the samples.
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+
+use crate::metric::Metric;
+use crate::sen55;
+
+/// How many polls' worth of samples are on the graph.
+pub const SAMPLES: usize = 60;
+
+/// How many graphs' worth of samples are kept.
+pub const WINDOWS: usize = 10;
+
+const HISTORY: usize = SAMPLES * WINDOWS;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Samples {
+    /// Which metric they're samples of.
+    pub metric: Metric,
+    /// Oldest first. Polls without a sample (e.g. while the fan's stopped) are `None`.
+    pub values: [Option<f32>; SAMPLES],
+    /// The poll interval of uptime the last value is for, counting from boot.
+    pub newest: u64,
+}
+
+impl Samples {
+    const fn new(metric: Metric) -> Self {
+        Self {
+            metric,
+            values: [None; SAMPLES],
+            newest: 0,
+        }
+    }
+
+    pub fn latest(&self) -> Option<f32> {
+        self.values[SAMPLES - 1]
+    }
+}
+
+#[derive(Clone)]
+struct History {
+    metric: Metric,
+    /// Oldest first, like `Samples::values`.
+    values: [Option<f32>; HISTORY],
+    /// The poll interval of uptime the last value is for, like `Samples::newest`.
+    newest: u64,
+}
+
+static LIVE: Mutex<ThreadModeRawMutex, RefCell<History>> = Mutex::new(RefCell::new(History {
+    metric: Metric::Pm2_5,
+    values: [None; HISTORY],
+    newest: 0,
+}));
+
+/// Record a raw sample of `metric`. Polls drift a little, so when two land in the same interval
+/// the later one wins. The samples of whatever was graphed before are dropped when it changes.
+pub fn record(metric: Metric, value: Option<f32>) {
+    let interval = Instant::now().as_millis() / sen55::poll_interval().as_millis();
+
+    LIVE.lock(|live| {
+        let mut live = live.borrow_mut();
+
+        if live.metric != metric {
+            live.metric = metric;
+            live.values = [None; HISTORY];
+        }
+
+        let shift = interval.saturating_sub(live.newest).min(HISTORY as u64) as usize;
+        live.values.rotate_left(shift);
+        live.values[HISTORY - shift..].fill(None);
+
+        live.values[HISTORY - 1] = value;
+        live.newest = interval;
+    });
+}
+
+/// Everything kept, to carry over a warm reboot (see `retained`).
+#[derive(Clone)]
+pub struct Snapshot(History);
+
+pub fn snapshot() -> Snapshot {
+    LIVE.lock(|live| Snapshot(live.borrow().clone()))
+}
+
+/// Put back what was kept before a warm reboot. The clock's started again since, so the newest
+/// sample is taken to be from now.
+pub fn restore(snapshot: Snapshot) {
+    let interval = Instant::now().as_millis() / sen55::poll_interval().as_millis();
+
+    LIVE.lock(|live| {
+        let mut live = live.borrow_mut();
+        *live = snapshot.0;
+        live.newest = interval;
+    });
+}
+
+/// A copy of the latest samples.
+pub fn samples() -> Samples {
+    LIVE.lock(|live| {
+        let live = live.borrow();
+        window(&live, live.newest)
+    })
+}
+
+/// A copy of the samples up to and including the one for `newest` (see `Samples::newest`). Any that
+/// are too old to have been kept are `None`.
+pub fn samples_to(newest: u64) -> Samples {
+    LIVE.lock(|live| window(&live.borrow(), newest))
+}
+
+fn window(live: &History, newest: u64) -> Samples {
+    let mut samples = Samples::new(live.metric);
+    samples.newest = newest;
+
+    for (i, value) in samples.values.iter_mut().enumerate() {
+        let interval = newest.checked_sub((SAMPLES - 1 - i) as u64);
+        let back = interval.and_then(|interval| live.newest.checked_sub(interval));
+        *value = match back {
+            Some(back) if back < HISTORY as u64 => live.values[HISTORY - 1 - back as usize],
+            _ => None,
+        };
+    }
+
+    samples
+}
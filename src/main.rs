@@ -2,64 +2,164 @@
 #![no_main]
 #![allow(async_fn_in_trait)]
 
+use core::cell::RefCell;
+use core::fmt::Write;
 use core::panic::PanicInfo;
 
-use cortex_m::delay::Delay;
-use cyw43::JoinOptions;
 use cyw43_pio::{PioSpi, DEFAULT_CLOCK_DIVIDER};
 
 use defmt::{error, flush, info, warn};
 use embassy_executor::Spawner;
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_time::{Duration, Timer, WithTimeout};
-use embedded_hal_1::delay::DelayNs;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
 use rand::RngCore;
 
-use embassy_net::{Config, StackResources};
+use embassy_net::{Config, DhcpConfig, StackResources};
 use embassy_rp::bind_interrupts;
-use embassy_rp::clocks::{clk_sys_freq, RoscRng};
-use embassy_rp::gpio::{Level, Output};
+use embassy_rp::clocks::RoscRng;
+use embassy_rp::flash::Flash;
+use embassy_rp::gpio::{Input, Level, Output, Pull};
 use embassy_rp::i2c::InterruptHandler as I2cInterruptHandler;
-use embassy_rp::peripherals::{DMA_CH0, I2C0, I2C1, PIO0, PIO1};
+use embassy_rp::peripherals::{DMA_CH0, I2C0, I2C1, PIO0, PIO1, USB};
 use embassy_rp::pio::{InterruptHandler, Pio};
+use embassy_rp::pio_programs::rotary_encoder::{PioEncoder, PioEncoderProgram};
+use embassy_rp::pwm::{self, Pwm};
+#[cfg(not(feature = "parallel-display"))]
 use embassy_rp::spi::{self, Spi};
+use embassy_rp::usb::{Driver as UsbDriver, InterruptHandler as UsbInterruptHandler};
+use embassy_rp::watchdog::Watchdog;
+use embassy_usb::class::cdc_acm::{self, CdcAcmClass};
+use state::ConnectionState;
+use telemetry::{ErrorCode, Task};
 use ui::{ConnectionStage, UiController};
 
 use defmt_rtt as _;
 
+use batch::Batched;
+use buttons::ButtonEvent;
+use icons::Icon;
+use profile::Job;
 use sen55::Readings;
-use st7789v2_driver::ST7789V2;
+use st7789::St7789;
 use static_cell::StaticCell;
 
+mod alarm_log;
+mod alarms;
+mod announced;
+mod asset;
+mod automation;
 mod avg;
+mod background;
+mod batch;
+mod buttons;
+mod cadence;
+mod climate;
+mod compensation;
 mod config;
+mod console;
+mod cooking;
+mod device_config;
+mod diagnostics;
+mod encoder;
+mod graph;
+mod haptics;
 mod hass;
+#[cfg(feature = "heap")]
+mod heap;
+mod http_push;
+mod http_server;
+mod icons;
+mod kiosk;
+mod lan;
+mod latency;
+mod live;
+mod menu;
+mod metric;
+mod mirror;
+mod mode;
 mod mqtt;
+// Not much of it's used when the parallel display has the microphone's pins.
+#[cfg_attr(feature = "parallel-display", allow(dead_code))]
+mod noise;
+mod ota;
+mod pages;
+#[cfg(feature = "parallel-display")]
+mod parallel;
+mod portal;
+mod power;
+mod presence;
+mod profile;
+mod qr;
+mod recovery;
+mod report;
+mod retained;
+mod rules;
+mod score;
 mod sen55;
+mod sensirion;
+mod sensor_error;
+mod settings;
+mod sntp;
+mod sockets;
+mod st7789;
+mod state;
+mod storage;
+mod syslog;
+mod telemetry;
+mod ticker;
+mod traffic;
 mod ui;
+mod voc_baseline;
+mod websocket;
+mod wifi;
+mod wifi_blobs;
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
     PIO1_IRQ_0 => InterruptHandler<PIO1>;
     I2C1_IRQ => I2cInterruptHandler<I2C1>;
     I2C0_IRQ => I2cInterruptHandler<I2C0>;
+    USBCTRL_IRQ => UsbInterruptHandler<USB>;
 });
 
+/// How long to leave the missing Wi-Fi firmware screen up before carrying on without the network.
+const OFFLINE_NOTICE: Duration = Duration::from_secs(10);
+
 static MQTT_RX_BUFFER: StaticCell<[u8; 4096]> = StaticCell::new();
 static MQTT_TX_BUFFER: StaticCell<[u8; 4096]> = StaticCell::new();
-static MQTT_WORKING_BUFFER: StaticCell<[u8; 8192]> = StaticCell::new();
+static MQTT_WORKING_BUFFER: StaticCell<[u8; 10240]> = StaticCell::new();
+
+pub type ReadingChannel = embassy_sync::channel::Channel<ThreadModeRawMutex, Readings, 10>;
 
 // Create channel for the sensor readings to be sent to the MQTT worker
-static MQTT_READING_CHANNEL: embassy_sync::channel::Channel<ThreadModeRawMutex, Readings, 10> =
-    embassy_sync::channel::Channel::new();
+static MQTT_READING_CHANNEL: ReadingChannel = embassy_sync::channel::Channel::new();
+
+// The UI only ever draws the latest readings, so it gets a mailbox rather than a queue.
+pub type ReadingMailbox = embassy_sync::signal::Signal<ThreadModeRawMutex, Readings>;
+static UI_READINGS: ReadingMailbox = embassy_sync::signal::Signal::new();
+
+// Likewise the HTTP push, which goes at its own pace.
+static HTTP_PUSH_READINGS: ReadingMailbox = embassy_sync::signal::Signal::new();
+
+// And a WebSocket client, which only ever wants the latest.
+static WEBSOCKET_READINGS: ReadingMailbox = embassy_sync::signal::Signal::new();
 
-// Create channel for the sensor readings to be sent to the UI
-static UI_READING_CHANNEL: embassy_sync::channel::Channel<ThreadModeRawMutex, Readings, 10> =
+// Create channel for button presses to be sent to the UI
+static UI_BUTTON_CHANNEL: embassy_sync::channel::Channel<ThreadModeRawMutex, ButtonEvent, 4> =
     embassy_sync::channel::Channel::new();
 
+// And to the MQTT worker, with when they happened, to pass on to Home Assistant
+static MQTT_BUTTON_CHANNEL: embassy_sync::channel::Channel<
+    ThreadModeRawMutex,
+    (ButtonEvent, Instant),
+    4,
+> = embassy_sync::channel::Channel::new();
+
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
     error!("{}", info);
+    telemetry::record_panic(info.location().map(|l| l.line()));
     flush();
 
     // Reset the board
@@ -70,12 +170,18 @@ fn panic_handler(info: &PanicInfo) -> ! {
 async fn main(spawner: Spawner) {
     // General setup
     let p = embassy_rp::init(Default::default());
-    let core = cortex_m::Peripherals::take().unwrap();
+    #[cfg(feature = "heap")]
+    heap::init();
 
     let mut rng = RoscRng;
 
-    let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
-    let clm = include_bytes!("../cyw43-firmware/43439A0_clm.bin");
+    // Find out why we reset before anything else touches the telemetry block.
+    let watchdog = Watchdog::new(p.WATCHDOG);
+    telemetry::init(&watchdog);
+    retained::restore_history();
+    spawner
+        .spawn(telemetry::watchdog_worker(watchdog))
+        .expect("couldn't spawn watchdog task");
 
     info!("Hello world!");
 
@@ -94,49 +200,136 @@ async fn main(spawner: Spawner) {
         embassy_rp::i2c::Config::default(),
     );
 
-    let mut display_spi_cfg = spi::Config::default();
-    display_spi_cfg.frequency = 64_000_000_u32; // 64 MHz
-    display_spi_cfg.phase = spi::Phase::CaptureOnSecondTransition;
-    display_spi_cfg.polarity = spi::Polarity::IdleHigh;
+    // The sensor worker shares the bus between the sen5x driver and its own raw commands.
+    static SENSOR_BUS: StaticCell<RefCell<sen55::SensorBus>> = StaticCell::new();
+    let sensor_bus = SENSOR_BUS.init(RefCell::new(i2c));
+
+    // The end of flash is reserved for settings and sensor state that need to survive a reboot.
+    storage::init(Flash::new_blocking(p.FLASH)).await;
+
+    // Optional button between GP15 and ground, used to switch display modes. Held at boot, it
+    // puts the device into recovery instead.
+    let button = Input::new(p.PIN_15, Pull::Up);
+    recovery::check(&button).await;
+
+    device_config::load().await;
+    settings::load().await;
+    score::load().await;
+    voc_baseline::load().await;
+    rules::load().await;
+    alarm_log::load().await;
+
+    // Start the sensor straight away so it's warming up (and buffering readings) while the display
+    // and network come up, rather than waiting until everything else is ready.
+    spawner
+        .spawn(sen55::worker(sensor_bus))
+        .expect("Couldn't spawn sen55 task");
+
+    // The rotary encoder, microphone and parallel display share the second PIO block.
+    #[allow(unused_variables)]
+    let Pio {
+        common: mut pio1,
+        sm0: encoder_sm,
+        sm1: mic_sm,
+        sm2: display_sm,
+        ..
+    } = Pio::new(p.PIO1, Irqs);
 
     // Display pins
-    let display_clk = p.PIN_18; // GP18 -> CLK
-    let display_mosi = p.PIN_19; // GP19 -> DIN
     let display_dc = Output::new(p.PIN_16, Level::Low); // GP16 -> DC
     let display_rst = Output::new(p.PIN_21, Level::Low); // GP21 -> RST
     let display_cs = Output::new(p.PIN_17, Level::High); // GP17 -> CS (assuming we only have one thing on the bus)
-    let mut display_bl = Output::new(p.PIN_22, Level::Low); // GP22 -> BL
+    let display_bl = Pwm::new_output_a(p.PWM_SLICE3, p.PIN_22, pwm::Config::default()); // GP22 -> BL (PWM 3A)
+
+    #[cfg(not(feature = "parallel-display"))]
+    let display_bus = {
+        let mut display_spi_cfg = spi::Config::default();
+        display_spi_cfg.frequency = 64_000_000_u32; // 64 MHz
+        display_spi_cfg.phase = spi::Phase::CaptureOnSecondTransition;
+        display_spi_cfg.polarity = spi::Polarity::IdleHigh;
+
+        let display_clk = p.PIN_18; // GP18 -> CLK
+        let display_mosi = p.PIN_19; // GP19 -> DIN
+        Spi::new_txonly(
+            p.SPI0,
+            display_clk,
+            display_mosi,
+            p.DMA_CH2,
+            display_spi_cfg,
+        )
+    };
+
+    // GP18 -> WR, GP0 to GP7 -> D0 to D7 (see `parallel`)
+    #[cfg(feature = "parallel-display")]
+    let display_bus = {
+        let data = [
+            pio1.make_pio_pin(p.PIN_0),
+            pio1.make_pio_pin(p.PIN_1),
+            pio1.make_pio_pin(p.PIN_2),
+            pio1.make_pio_pin(p.PIN_3),
+            pio1.make_pio_pin(p.PIN_4),
+            pio1.make_pio_pin(p.PIN_5),
+            pio1.make_pio_pin(p.PIN_6),
+            pio1.make_pio_pin(p.PIN_7),
+        ];
+        parallel::Parallel::new(&mut pio1, display_sm, p.PIN_18, data, p.DMA_CH2)
+    };
 
-    let display_spi = Spi::new_blocking_txonly(p.SPI0, display_clk, display_mosi, display_spi_cfg);
-
-    let screen_direction = st7789v2_driver::VERTICAL;
-    let lcd_width = 240_u32;
-    let lcd_height = 280_u32;
     // Initialize the display
-    let display: ui::Display = ST7789V2::new(
-        display_spi,
-        display_dc,
-        display_cs,
-        display_rst,
-        true, // 'is it rgb?' (yes)
-        screen_direction,
-        lcd_width,
-        lcd_height,
+    let mut panel = St7789::new(display_bus, display_dc, display_cs, display_rst);
+    if config::DISPLAY_TE {
+        panel = panel.with_tearing_effect(Input::new(p.PIN_20, Pull::None));
+    }
+    let display: ui::Display = Batched::new(panel);
+
+    // Debug console over USB serial, for dumping state without a broker.
+    let usb_driver = UsbDriver::new(p.USB, Irqs);
+    let mut usb_config = embassy_usb::Config::new(0xc0de, 0xcafe);
+    usb_config.manufacturer = Some(config::HASS_DEVICE_MANUFACTURER);
+    usb_config.product = Some(config::HASS_DEVICE_MODEL);
+    usb_config.serial_number = Some(&device_config::get().identifier);
+    usb_config.max_power = 100;
+    usb_config.max_packet_size_0 = 64;
+
+    static USB_CONFIG_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static USB_BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
+    static USB_CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+    static USB_CDC_STATE: StaticCell<cdc_acm::State> = StaticCell::new();
+    let mut usb_builder = embassy_usb::Builder::new(
+        usb_driver,
+        usb_config,
+        USB_CONFIG_DESCRIPTOR.init([0; 256]),
+        USB_BOS_DESCRIPTOR.init([0; 256]),
+        &mut [],
+        USB_CONTROL_BUF.init([0; 64]),
     );
-
-    // Set up the delay for the first core
-    let delay_wrapper = DelayWrapper::new(Delay::new(core.SYST, clk_sys_freq()));
+    let console_class = CdcAcmClass::new(
+        &mut usb_builder,
+        USB_CDC_STATE.init(cdc_acm::State::new()),
+        64,
+    );
+    spawner
+        .spawn(console::usb_task(usb_builder.build()))
+        .expect("couldn't spawn usb task");
+    spawner
+        .spawn(console::worker(console_class))
+        .expect("couldn't spawn console task");
 
     // Hand off display to the UI module
-    let mut display = ui::UiController::new(display, delay_wrapper);
+    let mut display = ui::UiController::new(display, display_bl);
 
     display.init().await;
 
-    display.render_startup();
+    let boot = &config::BOOT_PROFILE;
 
-    display_bl.set_high();
-
-    Timer::after_secs(3).await;
+    if boot.splash > Duration::from_ticks(0) {
+        display.render_startup();
+        display.set_backlight(true);
+        Timer::after(boot.splash).await;
+    } else {
+        display.render_connecting(ConnectionStage::Sen55);
+        display.set_backlight(true);
+    }
 
     // Grab pins for the CYW43 (wifi chip); set up SPI to it.
     // Wifi chip is integrated into the pico and we use PIO to drive SPI to it.
@@ -154,26 +347,97 @@ async fn main(spawner: Spawner) {
         p.DMA_CH0,
     );
 
+    // The Wi-Fi chip's firmware is kept out of the image, so updates don't have to carry it.
+    // Without it there's no network, but the readings are still worth showing.
+    match wifi_blobs::load() {
+        Ok(blobs) => {
+            // Generate random seed super securely
+            let seed = rng.next_u64();
+            start_network(spawner, blobs, pwr, spi, seed, &mut display).await;
+        }
+        Err(err) => run_offline(err, &mut display).await,
+    }
+
+    display.render_connecting(ConnectionStage::Ready);
+
+    spawner
+        .spawn(ui::worker(display))
+        .expect("Couldn't spawn ui task");
+
+    spawner
+        .spawn(buttons::worker(button))
+        .expect("Couldn't spawn button task");
+
+    if haptics::enabled() {
+        let motor = Output::new(p.PIN_9, Level::Low);
+        spawner
+            .spawn(haptics::worker(motor))
+            .expect("Couldn't spawn haptics task");
+    }
+
+    // Optional rotary encoder, with its A and B pins on GP10 and GP11 and common to ground. Its
+    // push switch, if it has one, goes in parallel with the button.
+    let encoder_program = PioEncoderProgram::new(&mut pio1);
+    let encoder = PioEncoder::new(&mut pio1, encoder_sm, p.PIN_10, p.PIN_11, &encoder_program);
+    spawner
+        .spawn(encoder::worker(encoder))
+        .expect("Couldn't spawn encoder task");
+
+    // Optional PDM microphone for the noise level, on the same PIO block as the encoder. Its pins
+    // are data lines for the parallel display.
+    #[cfg(feature = "parallel-display")]
+    if config::NOISE_MIC {
+        warn!("The noise microphone can't be used with the parallel display");
+    }
+    #[cfg(not(feature = "parallel-display"))]
+    if config::NOISE_MIC {
+        let mic = noise::Mic::new(&mut pio1, mic_sm, p.PIN_2, p.PIN_3, p.DMA_CH1);
+        spawner
+            .spawn(noise::worker(mic))
+            .expect("Couldn't spawn noise task");
+    }
+
+    // Once a minute.
+    let mut next_tick = Instant::now();
+    loop {
+        Timer::at(next_tick).await;
+        info!("Main loop");
+        telemetry::heartbeat(Task::Main);
+        sensor_error::SENSOR_DIAGNOSTICS.log();
+        profile::roll();
+        next_tick += Duration::from_secs(60);
+    }
+}
+
+/// Bring up the Wi-Fi chip and the network stack, wait for them to connect, and start everything
+/// that uses the network.
+async fn start_network(
+    spawner: Spawner,
+    blobs: wifi_blobs::Blobs,
+    pwr: Output<'static>,
+    spi: PioSpi<'static, PIO0, 0, DMA_CH0>,
+    seed: u64,
+    display: &mut UiController,
+) {
     // Start the CYW43 driver
     static STATE: StaticCell<cyw43::State> = StaticCell::new();
     let state = STATE.init(cyw43::State::new());
-    let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, fw).await;
+    let (net_device, mut control, runner) = cyw43::new(state, pwr, spi, blobs.firmware).await;
     spawner
         .spawn(cyw43_task(runner))
         .expect("couldn't spawn cyw43 worker task");
 
-    control.init(clm).await;
-    control
-        .set_power_management(cyw43::PowerManagementMode::PowerSave)
-        .await;
-
-    let config = Config::dhcpv4(Default::default());
+    control.init(blobs.clm).await;
+    wifi::set_country(&mut control).await;
+    wifi::set_power(&mut control, power::get().wifi_power()).await;
 
-    // Generate random seed super securely
-    let seed = rng.next_u64();
+    let mut dhcp = DhcpConfig::default();
+    dhcp.hostname = Some(config::dhcp_hostname());
+    let config = Config::dhcpv4(dhcp);
 
     // Init network stack
-    static RESOURCES: StaticCell<StackResources<5>> = StaticCell::new();
+    // Sized to the config, see `sockets`.
+    static RESOURCES: StaticCell<StackResources<{ sockets::BUDGET }>> = StaticCell::new();
     let (stack, runner) = embassy_net::new(
         net_device,
         config,
@@ -187,94 +451,196 @@ async fn main(spawner: Spawner) {
         .expect("couldn't spawn net task");
 
     // Wait for the network to be connected
-    wait_for_network(&mut control, &stack, &mut display).await;
+    wait_for_network(&mut control, stack, display).await;
 
-    let mqtt_rx_buffer = MQTT_RX_BUFFER.init([0u8; 4096]);
-    let mqtt_tx_buffer = MQTT_TX_BUFFER.init([0u8; 4096]);
-    let mqtt_working_buffer = MQTT_WORKING_BUFFER.init([0u8; 8192]);
+    // From here on the Wi-Fi chip's looked after in the background, in case the link drops.
     spawner
-        .spawn(mqtt::worker(
-            stack,
-            mqtt_rx_buffer,
-            mqtt_tx_buffer,
-            mqtt_working_buffer,
-        ))
-        .expect("Couldn't spawn mqtt task");
+        .spawn(wifi::worker(control, stack))
+        .expect("Couldn't spawn wifi task");
 
-    display.render_connecting(ConnectionStage::Mqtt);
+    if let Some(host) = config::SYSLOG_HOST {
+        spawner
+            .spawn(syslog::worker(stack, host))
+            .expect("Couldn't spawn syslog task");
+    }
 
+    if let Some(host) = config::NTP_SERVER {
+        spawner
+            .spawn(sntp::worker(stack, host))
+            .expect("Couldn't spawn sntp task");
+    }
+
+    // Always runs, to confirm an update that's just been swapped in even without `OTA_URL`.
     spawner
-        .spawn(sen55::worker(i2c))
-        .expect("Couldn't spawn sen55 task");
+        .spawn(ota::worker(stack))
+        .expect("Couldn't spawn ota task");
 
-    display.render_connecting(ConnectionStage::Ready);
+    if let Some(url) = config::HTTP_PUSH_URL {
+        spawner
+            .spawn(http_push::worker(stack, url))
+            .expect("Couldn't spawn http push task");
+    }
 
-    spawner
-        .spawn(ui::worker(display))
-        .expect("Couldn't spawn ui task");
+    if config::HTTP_SERVER {
+        for id in 0..http_server::CONNECTIONS {
+            spawner
+                .spawn(http_server::worker(stack, id))
+                .expect("Couldn't spawn http server task");
+        }
+    }
 
-    loop {
-        info!("Main loop");
+    if config::LATENCY_MONITOR {
+        spawner
+            .spawn(latency::worker(stack, mqtt::PORT))
+            .expect("Couldn't spawn latency task");
+    }
 
-        Timer::after(Duration::from_secs(60)).await;
+    spawner
+        .spawn(rules::webhook_worker(stack))
+        .expect("Couldn't spawn webhook task");
+
+    match device_config::get().mqtt_host() {
+        Some(host) => {
+            let mqtt_rx_buffer = MQTT_RX_BUFFER.init([0u8; 4096]);
+            let mqtt_tx_buffer = MQTT_TX_BUFFER.init([0u8; 4096]);
+            let mqtt_working_buffer = MQTT_WORKING_BUFFER.init([0u8; 10240]);
+            spawner
+                .spawn(mqtt::worker(
+                    host,
+                    stack,
+                    mqtt_rx_buffer,
+                    mqtt_tx_buffer,
+                    mqtt_working_buffer,
+                ))
+                .expect("Couldn't spawn mqtt task");
+
+            display.render_connecting(ConnectionStage::Mqtt);
+            wait_for_mqtt(display, host).await;
+        }
+        None => {
+            // No broker, so share the readings with the rest of the LAN instead.
+            info!("No MQTT broker configured, multicasting to the LAN");
+            spawner
+                .spawn(lan::worker(stack))
+                .expect("Couldn't spawn lan task");
+        }
     }
 }
 
+/// Without the Wi-Fi chip's firmware there's no network, so say how to put it there and carry on
+/// with just the sensor and the screen.
+async fn run_offline(err: wifi_blobs::BlobError, display: &mut UiController) {
+    error!(
+        "No usable Wi-Fi firmware in flash ({}), running offline",
+        err
+    );
+    state::set_connection(ConnectionState::NoWifiFirmware);
+
+    let problem = match err {
+        wifi_blobs::BlobError::Missing => "No Wi-Fi firmware in flash",
+        wifi_blobs::BlobError::Corrupt => "Wi-Fi firmware in flash is corrupt",
+    };
+    display.render_connecting(ConnectionStage::Wifi);
+    display.render_connecting_status(&[problem, "Write it with", "cargo xtask wifi-firmware"]);
+    Timer::after(OFFLINE_NOTICE).await;
+}
+
 /// Pokes the CYW43 driver to do hardware network stuff.
 #[embassy_executor::task]
 async fn cyw43_task(
     runner: cyw43::Runner<'static, Output<'static>, PioSpi<'static, PIO0, 0, DMA_CH0>>,
 ) -> ! {
-    runner.run().await
+    profile::track(Job::Wifi, runner.run()).await
 }
 
 /// Pokes embassy's network stack to do software network stuff.
 #[embassy_executor::task]
 async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'static>>) -> ! {
-    runner.run().await
+    profile::track(Job::Net, runner.run()).await
 }
 
 /// Wait (possibly forever) for the network to be connected.
 async fn wait_for_network(
     control: &mut cyw43::Control<'_>,
-    stack: &embassy_net::Stack<'_>,
+    stack: embassy_net::Stack<'static>,
     display: &mut UiController,
 ) {
     info!("Waiting for link up...");
     display.render_connecting(ConnectionStage::Wifi);
+    state::set_connection(ConnectionState::JoiningWifi);
+
+    // Guest mode leaves out anything identifying the network, with an icon to show what's going on.
+    let guest = settings::get().guest_mode;
+    if guest {
+        display.render_connecting_icon(Icon::Wifi(0));
+    }
+
+    let mut ssid_line = String::<48>::new();
+    if !guest {
+        _ = write!(ssid_line, "SSID: {}", device_config::get().wifi_network);
+    }
+
+    let mut attempt = 0;
+    let mut last_error = String::<48>::new();
+    let portal_after = portal::attempts();
 
     loop {
-        match control
-            .join(
-                config::WIFI_NETWORK,
-                JoinOptions::new(config::WIFI_PASSWORD.as_bytes()),
-            )
-            .with_timeout(Duration::from_secs(30))
-            .await
-        {
-            Ok(_) => break,
+        if portal_after.is_some_and(|after| attempt >= after) {
+            warn!("Couldn't join the Wi-Fi after {} attempts", attempt);
+            portal::run(control, stack, display).await;
+        }
+
+        attempt += 1;
+
+        let mut attempt_line = String::<48>::new();
+        _ = write!(attempt_line, "Attempt {}", attempt);
+        display.render_connecting_status(&[&ssid_line, &attempt_line, &last_error]);
+
+        match wifi::join(control).await {
+            Ok(()) => break,
             Err(err) => {
-                warn!("wifi join failed with status: {}", err);
+                warn!("wifi join failed: {}", err);
+                last_error.clear();
+                _ = write!(last_error, "Last error: {}", err);
             }
         }
     }
 
     display.render_connecting(ConnectionStage::Dhcp);
+    state::set_connection(ConnectionState::WaitingForDhcp);
+    if guest {
+        display.render_connecting_icon(Icon::Wifi(3));
+    }
 
     // Wait for DHCP, not necessary when using static IP
     info!("Waiting for DHCP...");
     let mut retries = 60;
     while !stack.is_config_up() {
+        let mut progress_line = String::<48>::new();
+        _ = write!(progress_line, "Waiting for DHCP ({}s left)", retries / 2);
+        display.render_connecting_status(&[&ssid_line, &progress_line]);
+
         Timer::after_millis(500).await;
         warn!("DHCP not up yet");
 
         retries -= 1;
 
         if retries == 0 {
+            telemetry::record_error(ErrorCode::DhcpTimeout);
             panic!("DHCP failed to come up within 30 seconds, giving up and resetting");
         }
     }
 
+    if let Some(v4) = stack.config_v4() {
+        let mut ip_line = String::<48>::new();
+        if guest {
+            _ = write!(ip_line, "Connected");
+        } else {
+            _ = write!(ip_line, "IP: {}", v4.address.address());
+        }
+        display.render_connecting_status(&[&ssid_line, &ip_line]);
+    }
+
     info!("Waiting for link up...");
     let mut retries = 120;
     while !stack.is_link_up() {
@@ -284,6 +650,7 @@ async fn wait_for_network(
         retries -= 1;
 
         if retries == 0 {
+            telemetry::record_error(ErrorCode::LinkTimeout);
             panic!("Link layer failed to come up within 30 seconds, giving up and resetting");
         }
     }
@@ -302,19 +669,42 @@ async fn wait_for_network(
     info!("Stack up!");
 }
 
-pub struct DelayWrapper {
-    delay: Delay,
-}
+/// Show the MQTT worker's progress until it's connected, or give up waiting and let it carry on
+/// in the background so the readings screen isn't held up by a broker that's down.
+async fn wait_for_mqtt(display: &mut UiController, host: &str) {
+    let guest = settings::get().guest_mode;
+    if guest {
+        display.render_connecting_icon(Icon::Mqtt);
+    }
 
-impl DelayWrapper {
-    pub fn new(delay: Delay) -> Self {
-        DelayWrapper { delay }
+    let mut host_line = String::<48>::new();
+    if !guest {
+        _ = write!(host_line, "Broker: {}", host);
     }
-}
 
-impl DelayNs for DelayWrapper {
-    fn delay_ns(&mut self, ns: u32) {
-        let us = (ns + 999) / 1000; // Convert nanoseconds to microseconds
-        self.delay.delay_us(us); // Use microsecond delay
+    for _ in 0..30 {
+        let current = state::get();
+        if current.connection.connected() {
+            return;
+        }
+
+        let mut address_line = String::<48>::new();
+        match current.broker_address {
+            Some(_) if guest => _ = write!(address_line, "Resolved"),
+            Some(address) => _ = write!(address_line, "Resolved: {}", address),
+            None => _ = write!(address_line, "Resolving..."),
+        }
+
+        let mut attempt_line = String::<48>::new();
+        _ = write!(attempt_line, "Attempt {}", current.mqtt_attempts);
+        if let Some(reason) = current.broker_disconnect {
+            _ = write!(attempt_line, " ({})", reason);
+        }
+
+        display.render_connecting_status(&[&host_line, &address_line, &attempt_line]);
+
+        Timer::after_millis(500).await;
     }
+
+    warn!("MQTT still not connected, carrying on without it");
 }
@@ -0,0 +1,321 @@
+//! A small on-device menu for the settings that can't wait for Home Assistant, driven with the one
+//! button: a short press moves to the next item, and a long press picks it.
+//!
+//! With a rotary encoder fitted, turning it moves through the items either way, and picking
+//! Brightness lets it be turned up and down until the next press.
+//!
+//! The menu sits on top of the page carousel. While it's open the UI draws it instead of the
+//! current page, and it closes itself after a while without a press.
+
+use core::fmt::Write;
+
+use defmt::{info, warn, Format};
+use embassy_time::{Duration, Instant};
+use embedded_graphics::prelude::{DrawTarget, Point, Primitive, RgbColor, Size};
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::{pixelcolor::Rgb565, Drawable};
+use heapless::String;
+use u8g2_fonts::types::{FontColor, HorizontalAlignment, VerticalPosition};
+use u8g2_fonts::FontRenderer;
+
+use crate::alarm_log;
+use crate::metric::Metric;
+use crate::pages::PageId;
+use crate::retained;
+use crate::settings::{self, TemperatureUnit, MIN_BRIGHTNESS};
+use crate::storage::{self, Slot};
+use crate::ui::{Display, DISPLAY_W};
+
+/// The menu closes if it's left alone this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Each pick of the brightness item steps up by this much, wrapping back round to the minimum.
+const BRIGHTNESS_STEP: u8 = 30;
+
+/// Each step of the encoder changes the brightness by this much.
+const BRIGHTNESS_TURN_STEP: u8 = 5;
+
+// Layout: one line per item, with the selected one highlighted.
+const FIRST_ITEM_Y: i32 = 8;
+const ITEM_SEP: i32 = 30;
+const ITEM_HEIGHT: u32 = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+enum Item {
+    Brightness,
+    Units,
+    Language,
+    Page,
+    Graph,
+    Contrast,
+    WifiReset,
+    FactoryReset,
+    Exit,
+}
+
+impl Item {
+    const ALL: [Item; 9] = [
+        Item::Brightness,
+        Item::Units,
+        Item::Language,
+        Item::Page,
+        Item::Graph,
+        Item::Contrast,
+        Item::WifiReset,
+        Item::FactoryReset,
+        Item::Exit,
+    ];
+
+    /// Whether the item throws something away, and so needs a second long press to go ahead.
+    const fn destructive(&self) -> bool {
+        matches!(self, Item::WifiReset | Item::FactoryReset)
+    }
+}
+
+/// What the UI needs to do after an item is picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Outcome {
+    /// Stay in the menu and redraw it.
+    Redraw,
+    /// A display setting changed, which the UI has to apply itself.
+    ToggleContrast,
+    Close,
+}
+
+pub struct Menu {
+    /// Index into `Item::ALL` of the selected item.
+    selected: usize,
+
+    /// Set after the first long press on a destructive item.
+    confirming: bool,
+
+    /// Set once the encoder has been turned, so picking Brightness adjusts it rather than
+    /// stepping through fixed levels.
+    has_encoder: bool,
+
+    /// Set while the encoder is adjusting the brightness instead of moving between items.
+    adjusting: bool,
+
+    /// When the menu was last pressed, to know when to give up on it.
+    last_input: Instant,
+}
+
+impl Menu {
+    pub fn new() -> Self {
+        info!("Menu opened");
+        Self {
+            selected: 0,
+            confirming: false,
+            has_encoder: false,
+            adjusting: false,
+            last_input: Instant::now(),
+        }
+    }
+
+    /// When the menu should close by itself.
+    pub fn closes_at(&self) -> Instant {
+        self.last_input + IDLE_TIMEOUT
+    }
+
+    /// Short press: move on to the next item, backing out of any confirmation. Finishes adjusting
+    /// the brightness instead, if that's under way.
+    pub fn next(&mut self) {
+        self.last_input = Instant::now();
+        self.confirming = false;
+
+        if self.adjusting {
+            self.adjusting = false;
+            return;
+        }
+        self.selected = (self.selected + 1) % Item::ALL.len();
+    }
+
+    /// A step of the encoder: adjust the brightness, or move between items.
+    pub async fn turn(&mut self, clockwise: bool) {
+        self.last_input = Instant::now();
+        self.has_encoder = true;
+
+        if self.adjusting {
+            settings::update(|s| {
+                s.brightness = match clockwise {
+                    true => s.brightness.saturating_add(BRIGHTNESS_TURN_STEP).min(100),
+                    false => s
+                        .brightness
+                        .saturating_sub(BRIGHTNESS_TURN_STEP)
+                        .max(MIN_BRIGHTNESS),
+                }
+            })
+            .await;
+            return;
+        }
+
+        self.confirming = false;
+        let len = Item::ALL.len();
+        self.selected = match clockwise {
+            true => (self.selected + 1) % len,
+            false => (self.selected + len - 1) % len,
+        };
+    }
+
+    /// Long press: act on the selected item.
+    pub async fn select(&mut self) -> Outcome {
+        self.last_input = Instant::now();
+
+        let item = Item::ALL[self.selected];
+        if item.destructive() && !self.confirming {
+            self.confirming = true;
+            return Outcome::Redraw;
+        }
+        self.confirming = false;
+
+        info!("Menu: {}", item);
+        match item {
+            Item::Brightness if self.has_encoder => {
+                self.adjusting = !self.adjusting;
+                Outcome::Redraw
+            }
+            Item::Brightness => {
+                settings::update(|s| {
+                    s.brightness = match s.brightness {
+                        100 => MIN_BRIGHTNESS,
+                        b => b.saturating_add(BRIGHTNESS_STEP).min(100),
+                    }
+                })
+                .await;
+                Outcome::Redraw
+            }
+            Item::Units => {
+                settings::update(|s| {
+                    s.temperature_unit = match s.temperature_unit {
+                        TemperatureUnit::Celsius => TemperatureUnit::Fahrenheit,
+                        TemperatureUnit::Fahrenheit => TemperatureUnit::Celsius,
+                    }
+                })
+                .await;
+                Outcome::Redraw
+            }
+            Item::Language => {
+                settings::update(|s| s.language = s.language.next()).await;
+                Outcome::Redraw
+            }
+            Item::Page => {
+                settings::update(|s| s.pinned_page = next_pinned_page(s.pinned_page)).await;
+                Outcome::Redraw
+            }
+            Item::Graph => {
+                settings::update(|s| s.graph_metric = next_graph_metric(s.graph_metric)).await;
+                Outcome::Redraw
+            }
+            Item::Contrast => Outcome::ToggleContrast,
+            Item::WifiReset => reset_wifi().await,
+            Item::FactoryReset => factory_reset().await,
+            Item::Exit => Outcome::Close,
+        }
+    }
+
+    /// Draw the whole menu, replacing whatever was on screen.
+    pub fn render(&self, display: &mut Display, high_contrast: bool) {
+        display.clear(Rgb565::BLACK).unwrap();
+
+        let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_helvB18_tf>();
+        let settings = settings::get();
+
+        for (i, item) in Item::ALL.iter().enumerate() {
+            let y = FIRST_ITEM_Y + i as i32 * ITEM_SEP;
+            let selected = i == self.selected;
+
+            let confirming = selected && self.confirming;
+            let mut text = String::<32>::new();
+            _ = match item {
+                Item::Brightness if selected && self.adjusting => {
+                    write!(text, "< {}% >", settings.brightness)
+                }
+                Item::Brightness => write!(text, "Brightness {}%", settings.brightness),
+                Item::Units => write!(text, "Units {}", settings.temperature_unit.symbol()),
+                Item::Language => write!(text, "Language {}", settings.language.name()),
+                Item::Page => write!(
+                    text,
+                    "Page {}",
+                    settings.pinned_page.map_or("auto", |page| page.key())
+                ),
+                Item::Graph => write!(text, "Graph {}", settings.graph_metric.descriptor().label),
+                Item::Contrast if high_contrast => write!(text, "Contrast high"),
+                Item::Contrast => write!(text, "Contrast normal"),
+                Item::WifiReset | Item::FactoryReset if confirming => {
+                    write!(text, "Hold to confirm")
+                }
+                Item::WifiReset => write!(text, "Reset Wi-Fi"),
+                Item::FactoryReset => write!(text, "Factory reset"),
+                Item::Exit => write!(text, "Exit"),
+            };
+
+            let color = if selected {
+                Rectangle::new(Point::new(0, y - 3), Size::new(DISPLAY_W, ITEM_HEIGHT))
+                    .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+                    .draw(display)
+                    .unwrap();
+                Rgb565::BLACK
+            } else {
+                Rgb565::WHITE
+            };
+
+            font.render_aligned(
+                text.as_str(),
+                Point::new(DISPLAY_W as i32 / 2, y),
+                VerticalPosition::Top,
+                HorizontalAlignment::Center,
+                FontColor::Transparent(color),
+                display,
+            )
+            .expect("couldn't render menu item");
+        }
+    }
+}
+
+/// Step through the carousel (`None`) and then each page in turn.
+fn next_pinned_page(current: Option<PageId>) -> Option<PageId> {
+    match current {
+        None => Some(PageId::ALL[0]),
+        Some(page) => {
+            let index = PageId::ALL.iter().position(|p| *p == page).unwrap_or(0);
+            PageId::ALL.get(index + 1).copied()
+        }
+    }
+}
+
+/// Each metric in turn, wrapping round.
+fn next_graph_metric(current: Metric) -> Metric {
+    let index = Metric::ALL.iter().position(|m| *m == current).unwrap_or(0);
+    Metric::ALL[(index + 1) % Metric::ALL.len()]
+}
+
+/// The Wi-Fi details are built into the firmware, so resetting Wi-Fi means starting the connection
+/// again from scratch. A warm reboot does that without losing the averages.
+async fn reset_wifi() -> ! {
+    warn!("Resetting Wi-Fi from the menu");
+    alarm_log::save().await;
+    retained::warm_reboot();
+}
+
+/// Wipe everything kept in flash and start again, as if the device were new.
+async fn factory_reset() -> ! {
+    warn!("Factory reset from the menu");
+
+    for slot in [
+        Slot::Settings,
+        Slot::VocState,
+        Slot::ScoreHistory,
+        Slot::Announced,
+        Slot::VocBaseline,
+        Slot::Rules,
+        Slot::AlarmLog,
+        Slot::DeviceConfig,
+    ] {
+        if let Err(e) = storage::clear(slot).await {
+            warn!("Couldn't wipe {}: {}", slot, e);
+        }
+    }
+
+    defmt::flush();
+    cortex_m::peripheral::SCB::sys_reset();
+}
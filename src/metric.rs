@@ -0,0 +1,277 @@
+use defmt::Format;
+
+use crate::config::{self, Component};
+use crate::sen55::{Health, Readings};
+use crate::settings;
+
+/// Each of the values the SEN55 reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Metric {
+    Pm1,
+    Pm2_5,
+    Pm4,
+    Pm10,
+    Voc,
+    Nox,
+    Temperature,
+    Humidity,
+    /// A-weighted sound level, from the optional microphone (see `noise`).
+    Noise,
+}
+
+/// Everything there is to know about a metric besides its value, in one place for Home Assistant,
+/// the screen, publishing and the health bands alike. Adding a metric means adding a row to
+/// `DESCRIPTORS` (and a field to `Readings`).
+pub struct Descriptor {
+    /// Short machine-friendly name, matching the keys in the state message.
+    pub key: &'static str,
+    /// The entity's name in Home Assistant.
+    pub name: &'static str,
+    /// Home Assistant's device class, if it has one that fits. The indices don't: Home Assistant's
+    /// VOC and NOx classes are concentrations, which the indices aren't.
+    pub device_class: Option<&'static str>,
+    pub unit: &'static str,
+    /// How many decimal places are worth showing. The sensor reports more than it can resolve.
+    pub precision: u8,
+    /// The label on the drawn readings page. The bitmaps have their own baked in.
+    pub label: &'static str,
+    /// The component's key and unique ID in the discovery payload.
+    pub component: Component,
+    /// Where the metric tips the air into poor and then dangerous, if it counts towards the
+    /// health at all.
+    pub thresholds: Option<Thresholds>,
+    /// How far the value has to move from its last published value to be worth publishing early.
+    /// Roughly the smallest change anyone would care about, and well above the sensor's noise.
+    pub step: f32,
+    /// Graphs of the metric scale up in steps of this, so the scale always says something.
+    pub graph_step: f32,
+}
+
+/// Above `warning` the air's poor, and above `danger` it's dangerous.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    pub warning: f32,
+    pub danger: f32,
+}
+
+/// The usual PM thresholds, in µg/m³.
+const PM_THRESHOLDS: Thresholds = Thresholds {
+    warning: 25.0,
+    danger: 100.0,
+};
+
+/// Indexed by `Metric as usize`.
+const DESCRIPTORS: [Descriptor; Metric::ALL.len()] = [
+    Descriptor {
+        key: "pm1",
+        name: "PM1.0",
+        device_class: Some("pm1"),
+        unit: "µg/m³",
+        precision: 1,
+        label: "PM1.0",
+        component: Component::Pm1,
+        thresholds: Some(PM_THRESHOLDS),
+        step: 3.0,
+        graph_step: 25.0,
+    },
+    Descriptor {
+        key: "pm2_5",
+        name: "PM2.5",
+        device_class: Some("pm25"),
+        unit: "µg/m³",
+        precision: 1,
+        label: "PM2.5",
+        component: Component::Pm2_5,
+        thresholds: Some(PM_THRESHOLDS),
+        step: 3.0,
+        graph_step: 25.0,
+    },
+    // Home Assistant has no class for PM4.
+    Descriptor {
+        key: "pm4",
+        name: "PM4.0",
+        device_class: None,
+        unit: "µg/m³",
+        precision: 1,
+        label: "PM4.0",
+        component: Component::Pm4,
+        thresholds: Some(PM_THRESHOLDS),
+        step: 3.0,
+        graph_step: 25.0,
+    },
+    Descriptor {
+        key: "pm10",
+        name: "PM10.0",
+        device_class: Some("pm10"),
+        unit: "µg/m³",
+        precision: 1,
+        label: "PM10",
+        component: Component::Pm10,
+        thresholds: Some(PM_THRESHOLDS),
+        step: 3.0,
+        graph_step: 25.0,
+    },
+    Descriptor {
+        key: "voc",
+        name: "VOC index",
+        device_class: None,
+        unit: "points",
+        precision: 0,
+        label: "tVOC",
+        component: Component::Voc,
+        thresholds: Some(Thresholds {
+            warning: 225.0,
+            danger: 400.0,
+        }),
+        step: 15.0,
+        graph_step: 100.0,
+    },
+    Descriptor {
+        key: "nox",
+        name: "NOx index",
+        device_class: None,
+        unit: "points",
+        precision: 0,
+        label: "tNOx",
+        component: Component::Nox,
+        thresholds: Some(Thresholds {
+            warning: 2.5,
+            danger: 5.0,
+        }),
+        step: 2.0,
+        graph_step: 5.0,
+    },
+    Descriptor {
+        key: "temperature",
+        name: "Temperature",
+        device_class: Some("temperature"),
+        unit: "°C",
+        precision: 1,
+        label: "Temp \u{b0}C",
+        component: Component::Temperature,
+        thresholds: None,
+        step: 0.3,
+        graph_step: 5.0,
+    },
+    Descriptor {
+        key: "humidity",
+        name: "Humidity",
+        device_class: Some("humidity"),
+        unit: "%",
+        precision: 1,
+        label: "Humidity %",
+        component: Component::Humidity,
+        thresholds: None,
+        step: 2.0,
+        graph_step: 10.0,
+    },
+    Descriptor {
+        key: "noise",
+        name: "Noise",
+        device_class: Some("sound_pressure"),
+        unit: "dBA",
+        precision: 0,
+        label: "Noise dBA",
+        component: Component::Noise,
+        thresholds: None,
+        step: 3.0,
+        graph_step: 10.0,
+    },
+];
+
+impl Metric {
+    pub const ALL: [Metric; 9] = [
+        Metric::Pm1,
+        Metric::Pm2_5,
+        Metric::Pm4,
+        Metric::Pm10,
+        Metric::Voc,
+        Metric::Nox,
+        Metric::Temperature,
+        Metric::Humidity,
+        Metric::Noise,
+    ];
+
+    /// Short machine-friendly name, matching the keys in the state message.
+    pub const fn key(&self) -> &'static str {
+        self.descriptor().key
+    }
+
+    pub const fn descriptor(&self) -> &'static Descriptor {
+        &DESCRIPTORS[*self as usize]
+    }
+
+    pub fn from_key(key: &str) -> Option<Metric> {
+        Metric::ALL.into_iter().find(|m| m.key() == key)
+    }
+
+    const fn bit(&self) -> u16 {
+        1 << (*self as u8)
+    }
+
+    /// Pick this metric's value out of a set of readings.
+    pub fn value(&self, readings: &Readings) -> Option<f32> {
+        match self {
+            Metric::Pm1 => readings.pm1_0,
+            Metric::Pm2_5 => readings.pm2_5,
+            Metric::Pm4 => readings.pm4_0,
+            Metric::Pm10 => readings.pm10_0,
+            Metric::Voc => readings.voc_index,
+            Metric::Nox => readings.nox_index,
+            Metric::Temperature => readings.temperature,
+            Metric::Humidity => readings.humidity,
+            Metric::Noise => readings.noise,
+        }
+    }
+
+    /// The health band a value of this metric is in, or `None` if it doesn't count towards the
+    /// health.
+    pub fn health(&self, value: f32) -> Option<Health> {
+        let thresholds = self.descriptor().thresholds?;
+        Some(if value > thresholds.danger {
+            Health::Dangerous
+        } else if value > thresholds.warning {
+            Health::Warning
+        } else {
+            Health::Ok
+        })
+    }
+
+    /// This metric's value as it's shown on screen, in the units picked in the settings.
+    pub fn shown_value(&self, readings: &Readings) -> Option<f32> {
+        let value = self.value(readings)?;
+        match self {
+            Metric::Temperature => Some(settings::get().temperature_unit.convert(value)),
+            _ => Some(value),
+        }
+    }
+}
+
+/// A set of metrics, one bit each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct MetricSet(u16);
+
+impl MetricSet {
+    pub const ALL: MetricSet = MetricSet(0x1FF);
+    pub const NONE: MetricSet = MetricSet(0);
+
+    pub const fn from_bits(bits: u16) -> Self {
+        MetricSet(bits)
+    }
+
+    pub const fn bits(&self) -> u16 {
+        self.0
+    }
+
+    pub const fn contains(&self, metric: Metric) -> bool {
+        self.0 & metric.bit() != 0
+    }
+
+    pub fn set(&mut self, metric: Metric, included: bool) {
+        if included {
+            self.0 |= metric.bit();
+        } else {
+            self.0 &= !metric.bit();
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! The gist of what's on screen, for other displays to mirror: which page is up, the colour of the
+//! air's health, and the headline reading.
+//!
+//! This is worked out from the readings and the page alone, with nothing to do with how the
+//! ST7789 draws it, so an LED matrix (an Awtrix clock, say) can show the same status in its own
+//! way. With `MIRROR` set it's published (retained) whenever it changes; `text` and `color` are
+//! named as Awtrix's custom apps expect, so the payload can be forwarded to one as it is.
+
+use core::cell::{Cell, RefCell};
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::String;
+use serde::Serialize;
+
+use crate::metric::Metric;
+use crate::sen55::{Health, Readings};
+use crate::settings;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RenderState {
+    /// The key of the page on screen, or `menu` or `fallback`.
+    pub page: &'static str,
+    /// `ok`, `warning` or `dangerous`.
+    pub health: &'static str,
+    /// The health as a colour, `#RRGGBB`.
+    pub color: &'static str,
+    /// The key of the headline metric.
+    pub metric: &'static str,
+    pub value: Option<f32>,
+    /// The headline as a short line, e.g. `PM2.5 12.4`.
+    pub text: String<16>,
+}
+
+/// What the UI last drew.
+static PAGE: Mutex<ThreadModeRawMutex, Cell<&'static str>> = Mutex::new(Cell::new("readings"));
+
+/// The render state last published, so it's only sent again when it changes.
+static PUBLISHED: Mutex<ThreadModeRawMutex, RefCell<Option<RenderState>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Note what's on screen. Called by the UI each time it draws.
+pub fn set_page(page: &'static str) {
+    PAGE.lock(|p| p.set(page));
+}
+
+/// The render state for these readings and whatever's on screen now.
+pub fn render(readings: &Readings) -> RenderState {
+    let page = PAGE.lock(|p| p.get());
+
+    // The noise page is the only one that isn't mostly about the air.
+    let metric = match page {
+        "noise" => Metric::Noise,
+        _ => Metric::Pm2_5,
+    };
+    let value = metric.value(readings);
+
+    let health = readings.health();
+    let color = match health {
+        Health::Ok => "#00C800",
+        Health::Warning => "#FFC800",
+        Health::Dangerous => "#FF0000",
+    };
+
+    let descriptor = metric.descriptor();
+    let mut text = String::new();
+    _ = write!(text, "{} ", descriptor.label);
+    let language = settings::get().language;
+    match value {
+        // Whole numbers once there's no room for the decimals.
+        Some(v) if v >= 100.0 => _ = language.write_number(&mut text, v, 0),
+        Some(v) => _ = language.write_number(&mut text, v, usize::from(descriptor.precision)),
+        None => _ = text.push_str("..."),
+    }
+
+    RenderState {
+        page,
+        health: health.name(),
+        color,
+        metric: metric.key(),
+        value,
+        text,
+    }
+}
+
+/// The render state for these readings, if it's changed since it was last published.
+pub fn unpublished(readings: &Readings) -> Option<RenderState> {
+    let state = render(readings);
+    PUBLISHED.lock(|p| (p.borrow().as_ref() != Some(&state)).then_some(state))
+}
+
+/// Mark `state` as published.
+pub fn mark_published(state: RenderState) {
+    PUBLISHED.lock(|p| *p.borrow_mut() = Some(state));
+}
@@ -0,0 +1,71 @@
+//! The device's operating mode, which other parts of the firmware check to decide how much to do.
+//!
+//! The mode is one of the runtime settings, so it's switched from Home Assistant and survives a
+//! reboot. Nothing acts on a change straight away: each subsystem asks what the current mode
+//! allows the next time it's about to do something.
+
+use embassy_time::Duration;
+
+use crate::settings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Mode {
+    /// Someone's around: everything runs as normal.
+    Home,
+    /// Nobody's around: the screen is off and readings are only published occasionally, unless
+    /// the air turns dangerous, which raises an alert straight away.
+    Away,
+}
+
+impl Mode {
+    /// The name used in MQTT payloads.
+    pub const fn key(&self) -> &'static str {
+        match self {
+            Mode::Home => "home",
+            Mode::Away => "away",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<Mode> {
+        match key {
+            "home" => Some(Mode::Home),
+            "away" => Some(Mode::Away),
+            _ => None,
+        }
+    }
+
+    /// The shortest time between published readings. Dangerous readings are always published.
+    pub const fn publish_interval(&self) -> Duration {
+        match self {
+            Mode::Home => Duration::from_ticks(0),
+            Mode::Away => Duration::from_secs(5 * 60),
+        }
+    }
+
+    /// Whether the screen should be on.
+    pub const fn display_on(&self) -> bool {
+        matches!(self, Mode::Home)
+    }
+
+    /// Whether dangerous air should raise an alert as well as being published as usual.
+    pub const fn alerts(&self) -> bool {
+        matches!(self, Mode::Away)
+    }
+
+    pub(crate) const fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) const fn from_byte(byte: u8) -> Option<Mode> {
+        match byte {
+            0 => Some(Mode::Home),
+            1 => Some(Mode::Away),
+            _ => None,
+        }
+    }
+}
+
+/// The current mode.
+pub fn get() -> Mode {
+    settings::get().mode
+}
@@ -1,17 +1,106 @@
+use core::cell::Cell;
+
+use embassy_futures::select::{select, select4, Either, Either4};
 use embassy_net::{dns::DnsQueryType, tcp::TcpSocket, Stack};
-use embassy_time::Timer;
-use log::{error, info};
+use embassy_sync::blocking_mutex::{raw::ThreadModeRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use heapless::{String, Vec};
+use log::{debug, error, info, warn};
+use protocol::{Access, AccessChange, Ack, Command, Ignored, Message, Remote, Transport};
 use rust_mqtt::{
     client::{client::MqttClient, client_config::ClientConfig},
     packet::v5::reason_codes::ReasonCode,
     utils::rng_generator::CountingRng,
 };
 
-use crate::{config, hass, MQTT_READING_CHANNEL};
+use crate::announced::Announced;
+use crate::buttons::ButtonEvent;
+use crate::cadence::Cadence;
+use crate::device_config;
+use crate::diagnostics::{self, DiagnosticsMessage};
+use crate::metric::Metric;
+use crate::mode::{self, Mode};
+use crate::power::{self, PowerProfile};
+use crate::profile::{self, Job};
+use crate::sen55::{self, Health};
+use crate::sockets::{self, User};
+use crate::syslog::{self, Severity};
+
+use crate::state::{self, ConnectionState};
+use crate::telemetry::{self, Task};
+use crate::traffic::{self, Counted};
+use crate::{
+    alarm_log, automation, compensation, config, cooking, hass, latency, mirror, presence, report,
+    retained, rules, settings, ui, MQTT_BUTTON_CHANNEL, MQTT_READING_CHANNEL,
+};
+
+/// The broker's port.
+pub const PORT: u16 = 1883;
+
+/// How long to go without hearing from the broker before pinging it. Publishes aren't
+/// acknowledged, so after the access point restarts they can go into a half-open socket for some
+/// time without anything failing; a ping that isn't answered shows it up. This also keeps the task
+/// checking in with the watchdog while readings stop, as they do for minutes at a time while the
+/// sensor is duty cycling.
+const IDLE_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the broker has to answer a ping before the connection's given up on.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the socket can sit idle before TCP checks the other end's still there.
+const TCP_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// How many times in a row the broker's address can fail to connect before it's looked up again,
+/// in case it's moved.
+const RESOLVE_AFTER_FAILURES: u32 = 3;
+
+/// How many times in a row a broker can fail to connect before moving on to the next one in the
+/// list. More than `RESOLVE_AFTER_FAILURES`, so a broker that's moved is looked up again first.
+const FAILOVER_AFTER_FAILURES: u32 = 6;
+
+/// Most brokers that can be listed, see `brokers`.
+const MAX_BROKERS: usize = 4;
+
+/// How long to stay with a fallback broker before trying the first one in the list again.
+const PRIMARY_RETRY_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Whether the broker's ACL is letting us publish, see `read_only`.
+static ACCESS: Mutex<ThreadModeRawMutex, Cell<Access>> = Mutex::new(Cell::new(Access::new()));
+
+/// How old a button press can be and still be passed on. Automations act on presses as they
+/// happen, so ones held up while the broker was away are dropped rather than acted on late.
+const STALE_PRESS: Duration = Duration::from_secs(5);
+
+/// The largest packet the broker's allowed to send us: a full set of rules, the largest thing that
+/// is, with room for the topic and properties.
+const MAX_PACKET: usize = rules::MAX_RULES_JSON + 200;
+
+/// How long to wait before reconnecting after an ordinary failure.
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// How long a restart from the command topic waits after clearing the command.
+const RESTART_GRACE: Duration = Duration::from_millis(500);
 
 /// Publishes updated readings to the MQTT broker, including the initial hass discovery message.
+/// `hosts` can list fallback brokers after the first, see `brokers`.
 #[embassy_executor::task]
 pub async fn worker(
+    hosts: &'static str,
+    stack: Stack<'static>,
+    rx_buffer: &'static mut [u8],
+    tx_buffer: &'static mut [u8],
+    work_buffer: &'static mut [u8],
+) {
+    profile::track(
+        Job::Mqtt,
+        run(hosts, stack, rx_buffer, tx_buffer, work_buffer),
+    )
+    .await
+}
+
+async fn run(
+    hosts: &'static str,
     stack: Stack<'static>,
     rx_buffer: &'static mut [u8],
     tx_buffer: &'static mut [u8],
@@ -19,30 +108,96 @@ pub async fn worker(
 ) {
     info!("started mqtt worker");
 
+    // Kept across reconnections, so a dropped connection doesn't reset the publishing pace or
+    // repeat an alert.
+    let mut cadence = Cadence::from_config();
+    let mut alerted = false;
+    let mut backoff = RECONNECT_DELAY;
+
+    // The brokers in the order they're preferred, and which one's being used.
+    let brokers = brokers(hosts);
+    let mut current = 0;
+
+    // The broker's address is only looked up again once it's stopped working, so a dropped
+    // connection doesn't cost a DNS query, but a broker that's moved is still followed.
+    let mut broker_address = None;
+    let mut failures = 0;
+    // Like `failures`, but only reset by connecting, or moving on to the next broker.
+    let mut unreachable = 0;
+
     loop {
-        Timer::after_millis(500).await;
+        wait_to_reconnect(backoff).await;
+        backoff = RECONNECT_DELAY;
+        telemetry::heartbeat(Task::Mqtt);
 
+        // The Wi-Fi's being rejoined (see `wifi`), so there's no broker to get to yet.
+        if !stack.is_config_up() {
+            continue;
+        }
+
+        // Give up on a broker that keeps failing, and move on to the next.
+        if unreachable >= FAILOVER_AFTER_FAILURES && brokers.len() > 1 {
+            let next = (current + 1) % brokers.len();
+            warn!(
+                "Broker {} unreachable, trying {}",
+                brokers[current], brokers[next]
+            );
+            syslog::event(
+                Severity::Warning,
+                "network",
+                format_args!(
+                    "failing over from {} to {}",
+                    brokers[current], brokers[next]
+                ),
+            );
+            current = next;
+            broker_address = None;
+            failures = 0;
+            unreachable = 0;
+        }
+        let host = brokers[current];
+
+        state::set_connection(ConnectionState::ConnectingMqtt);
+        state::count_mqtt_attempt();
+
+        let _claim = sockets::claim(User::Mqtt);
         let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
 
         socket.set_timeout(Some(embassy_time::Duration::from_secs(10)));
+        socket.set_keep_alive(Some(TCP_KEEP_ALIVE));
 
-        let address = match stack
-            .dns_query(config::MQTT_HOST, DnsQueryType::A)
-            .await
-            .map(|a| a[0])
-        {
-            Ok(address) => address,
-            Err(e) => {
-                error!("DNS lookup error: {e:?}");
-                continue;
+        if broker_address.is_none() || failures >= RESOLVE_AFTER_FAILURES {
+            match stack.dns_query(host, DnsQueryType::A).await.map(|a| a[0]) {
+                Ok(resolved) => {
+                    if let Some(cached) = broker_address.filter(|cached| *cached != resolved) {
+                        warn!("Broker moved from {} to {}", cached, resolved);
+                        syslog::event(
+                            Severity::Notice,
+                            "network",
+                            format_args!("broker moved from {} to {}", cached, resolved),
+                        );
+                    }
+                    broker_address = Some(resolved);
+                    state::set_broker_address(resolved);
+                    failures = 0;
+                }
+                // Carry on with the old address, if there is one, until the lookup works.
+                Err(e) => error!("DNS lookup error: {e:?}"),
             }
+        }
+        let Some(address) = broker_address else {
+            failures += 1;
+            unreachable += 1;
+            continue;
         };
 
-        let remote_endpoint = (address, 1883);
+        let remote_endpoint = (address, PORT);
         info!("connecting...");
         let connection = socket.connect(remote_endpoint).await;
         if let Err(e) = connection {
             error!("connect error: {:?}", e);
+            failures += 1;
+            unreachable += 1;
             continue;
         }
         info!("connected!");
@@ -52,104 +207,348 @@ pub async fn worker(
             CountingRng(20000),
         );
         config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
-        config.add_client_id(config::MQTT_CLIENT_ID);
-        config.max_packet_size = 100;
+        config.add_client_id(&device_config::get().mqtt_client_id);
+        // If we drop off without disconnecting (e.g. losing power), the broker marks us unavailable.
+        config.add_will(
+            config::mqtt_topics().availability,
+            protocol::OFFLINE,
+            Message::Availability.retain(),
+        );
+        if let Some((username, password)) = device_config::get().mqtt_credentials() {
+            config.add_username(username);
+            config.add_password(password);
+        }
+        // Big enough for a full set of rules, the largest thing that's sent to us.
+        config.max_packet_size = MAX_PACKET as u32;
         let mut recv_buffer = [0; 8192];
-        let mut write_buffer = [0; 8192];
+        // Room for the discovery payload, the largest thing we send.
+        let mut write_buffer = [0; 10240];
 
-        let mut client = MqttClient::<_, 5, _>::new(
-            socket,
+        let mut socket = Framed::new(Counted::new(socket, User::Mqtt));
+        let mut client = Broker(MqttClient::<_, 5, _>::new(
+            &mut socket,
             &mut write_buffer,
-            8192,
+            10240,
             &mut recv_buffer,
-            80,
+            MAX_PACKET,
             config,
-        );
+        ));
 
-        match client.connect_to_broker().await {
-            Ok(()) => {}
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    error!("MQTT Network Error");
-                    continue;
+        match client.0.connect_to_broker().await {
+            Ok(()) => {
+                failures = 0;
+                unreachable = 0;
+            }
+            Err(ReasonCode::NetworkError) => {
+                error!("MQTT Network Error");
+                failures += 1;
+                unreachable += 1;
+                continue;
+            }
+            Err(mqtt_error) => {
+                error!("Broker refused the connection: {:?}", mqtt_error);
+                backoff = broker_backoff(mqtt_error);
+                continue;
+            }
+        }
+
+        info!("Connected to MQTT Broker {}", host);
+        state::set_broker_host(host);
+        let connected_at = Instant::now();
+
+        // Saying we're online is the probe of whether the broker lets us publish. One that's
+        // already denied us isn't asked again until the retry's due, as it may well throw us out
+        // for asking.
+        let access = ACCESS.lock(Cell::get);
+        if access.read_only() && !access.probe_due(Instant::now().as_secs()) {
+            state::set_connection(ConnectionState::ReadOnly);
+        } else if let Err(mqtt_error) = probe(&mut client).await {
+            error!("Availability message failed: {:?}", mqtt_error);
+            continue;
+        }
+
+        // Always start by publishing a discovery message to Home Assistant.
+        if publish_discovery(&mut client, work_buffer).await.is_err() {
+            continue;
+        }
+
+        // If the last reset was a crash, tell someone about it.
+        if let Some(report) = telemetry::crash_report() {
+            match serde_json_core::to_slice(&report, work_buffer) {
+                Ok(len) => {
+                    match protocol::publish(
+                        &mut client,
+                        &config::mqtt_topics(),
+                        Message::Crash,
+                        &work_buffer[..len],
+                    )
+                    .await
+                    {
+                        Ok(()) => {
+                            info!("Sent crash report");
+                            telemetry::clear_crash_report();
+                        }
+                        Err(mqtt_error) => {
+                            error!("Crash report failed: {:?}", mqtt_error);
+                            continue;
+                        }
+                    }
                 }
-                _ => {
-                    error!("Other MQTT Error: {:?}", mqtt_error);
-                    continue;
+                Err(e) => {
+                    error!("Error serializing crash report: {:?}", e);
+                    telemetry::clear_crash_report();
                 }
-            },
+            }
         }
 
-        info!("Connected to MQTT Broker");
+        // Let Home Assistant know the current settings, then listen for changes to them, and for
+        // the pressure and presence if there's somewhere to get them from.
+        if let Err(mqtt_error) = publish_settings(&mut client, work_buffer).await {
+            error!("Settings publish failed: {:?}", mqtt_error);
+            continue;
+        }
 
-        // Always start by publishing a discovery message to Home Assistant.
-        let discovery_payload = hass::get_discovery_payload();
-        let serialized_len = match serde_json_core::to_slice(&discovery_payload, work_buffer) {
-            Ok(serialized_len) => serialized_len,
-            Err(e) => {
-                error!("Error serializing discovery payload: {:?}", e);
-                0
+        match protocol::subscribe_all(&mut client, &config::mqtt_topics()).await {
+            Ok(()) => info!("Subscribed to command topics"),
+            // Reconnecting won't change the broker's mind, so carry on without the commands.
+            Err(ReasonCode::NotAuthorized) => {
+                warn!("Broker won't let us subscribe, so nothing can be changed over MQTT");
+                syslog::event(
+                    Severity::Warning,
+                    "mqtt",
+                    format_args!("not authorized to subscribe"),
+                );
             }
-        };
+            Err(mqtt_error) => {
+                error!("Couldn't subscribe: {:?}", mqtt_error);
+                continue;
+            }
+        }
 
-        match client
-            .send_message(
-                config::MQTT_TOPIC_DICSOVERY,
-                &work_buffer[..serialized_len],
-                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
-                true,
+        // When the broker last showed it was listening, by answering a ping or sending a message.
+        let mut last_heard = Instant::now();
+
+        // The diagnostics go out straight away, then once a minute while connected.
+        let mut next_diagnostics = Instant::now();
+
+        loop {
+            // Wait for either new readings or a button press to publish, a message from the broker,
+            // or something due.
+            let readings = match select4(
+                select(
+                    MQTT_READING_CHANNEL.receive(),
+                    MQTT_BUTTON_CHANNEL.receive(),
+                ),
+                client.0.receive_message(),
+                Timer::at(last_heard + IDLE_PING_INTERVAL),
+                Timer::at(next_diagnostics),
             )
             .await
-        {
-            Ok(()) => {
-                info!("Sent discovery message");
-            }
-            Err(mqtt_error) => match mqtt_error {
-                ReasonCode::NetworkError => {
-                    error!("Discovery message failed: MQTT Network Error");
+            {
+                Either4::First(Either::First(readings)) => {
+                    telemetry::heartbeat(Task::Mqtt);
+                    readings
+                }
+                Either4::First(Either::Second((event, pressed_at))) => {
+                    if let Err(mqtt_error) =
+                        publish_button(&mut client, work_buffer, event, pressed_at).await
+                    {
+                        error!("Button press publish failed: {:?}", mqtt_error);
+                        break;
+                    }
+                    continue;
+                }
+                Either4::Second(Ok((topic, payload))) => {
+                    last_heard = Instant::now();
+                    // The message borrows the client's buffer, so copy it out before acting on it.
+                    let (Ok(topic), Ok(payload)) = (
+                        String::<96>::try_from(topic),
+                        Vec::<u8, { rules::MAX_RULES_JSON }>::from_slice(payload),
+                    ) else {
+                        warn!("Ignoring oversized message on {}", topic);
+                        continue;
+                    };
+
+                    match handle_message(&topic, &payload).await {
+                        Handled::Done => {}
+                        Handled::SettingsChanged => {
+                            if let Err(mqtt_error) =
+                                publish_settings(&mut client, work_buffer).await
+                            {
+                                error!("Settings publish failed: {:?}", mqtt_error);
+                                break;
+                            }
+                        }
+                        Handled::Remote(remote) => {
+                            if let Err(mqtt_error) =
+                                run_remote(&mut client, work_buffer, remote).await
+                            {
+                                error!("Command {} failed: {:?}", remote.key(), mqtt_error);
+                                break;
+                            }
+                        }
+                    }
+
+                    if alarm_log::take_dump_request() {
+                        let Some(len) = alarm_log::serialize(work_buffer) else {
+                            error!("Alarm log too big to publish");
+                            continue;
+                        };
+                        if let Err(mqtt_error) = protocol::publish(
+                            &mut client,
+                            &config::mqtt_topics(),
+                            Message::AlarmLog,
+                            &work_buffer[..len],
+                        )
+                        .await
+                        {
+                            error!("Alarm log publish failed: {:?}", mqtt_error);
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                // Having let us in, the broker's thrown us out for something we published.
+                Either4::Second(Err(ReasonCode::NotAuthorized)) => {
+                    record_access(Ack::NotAuthorized);
+                    break;
+                }
+                Either4::Second(Err(mqtt_error)) => {
+                    error!("Receive failed: {:?}", mqtt_error);
+                    backoff = broker_backoff(mqtt_error);
+                    break;
+                }
+                Either4::Third(()) => {
+                    telemetry::heartbeat(Task::Mqtt);
+
+                    // Every so often, go back to see whether the preferred broker's there again.
+                    if current != 0 && connected_at.elapsed() >= PRIMARY_RETRY_INTERVAL {
+                        info!("Leaving fallback broker {} to try {}", host, brokers[0]);
+                        leave(&mut client).await;
+                        current = 0;
+                        broker_address = None;
+                        break;
+                    }
+
+                    // A message arriving while waiting for the answer fails the probe, but the
+                    // reconnection that follows probes again before subscribing.
+                    if ACCESS.lock(Cell::get).probe_due(Instant::now().as_secs()) {
+                        if let Err(mqtt_error) = probe(&mut client).await {
+                            error!("Read-only probe failed: {:?}", mqtt_error);
+                            break;
+                        }
+                    }
+
+                    match client.0.send_ping().with_timeout(PING_TIMEOUT).await {
+                        Ok(Ok(())) => last_heard = Instant::now(),
+                        Ok(Err(mqtt_error)) => {
+                            error!("Ping failed: {:?}", mqtt_error);
+                            break;
+                        }
+                        Err(_) => {
+                            warn!("Broker stopped answering, reconnecting");
+                            syslog::event(
+                                Severity::Warning,
+                                "network",
+                                format_args!("broker stopped answering pings, reconnecting"),
+                            );
+                            break;
+                        }
+                    }
                     continue;
                 }
-                _ => {
-                    error!(
-                        "Discovery message failed due to other MQTT Error: {:?}",
-                        mqtt_error
-                    );
+                Either4::Fourth(()) => {
+                    next_diagnostics = Instant::now() + diagnostics::PUBLISH_INTERVAL;
+                    if let Err(mqtt_error) =
+                        publish_diagnostics(&mut client, work_buffer, stack).await
+                    {
+                        error!("Diagnostics publish failed: {:?}", mqtt_error);
+                        break;
+                    }
                     continue;
                 }
-            },
-        }
+            };
 
-        loop {
-            // Would be reading from the sensor channel here, for now just send a dummy message.
-            let readings = MQTT_READING_CHANNEL.receive().await;
+            // Readings that sat in the channel while the broker was away no longer describe the
+            // air, so they're dropped rather than published as the current state.
+            if readings.is_stale() {
+                debug!(
+                    "Not publishing readings from {}ms ago",
+                    readings.age().as_millis()
+                );
+                continue;
+            }
 
-            let state_payload_len =
-                match serde_json_core::to_slice(&hass::StateMessage::from(readings), work_buffer) {
-                    Ok(serialized_len) => serialized_len,
-                    Err(e) => {
-                        error!("Error serializing state payload: {:?}", e);
-                        continue;
+            // Other displays mirroring this one want to keep up with it, whatever the cadence.
+            if config::MIRROR {
+                if let Some(render) = mirror::unpublished(&readings) {
+                    match serde_json_core::to_slice(&render, work_buffer) {
+                        Ok(len) => {
+                            match protocol::publish(
+                                &mut client,
+                                &config::mqtt_topics(),
+                                Message::Render,
+                                &work_buffer[..len],
+                            )
+                            .await
+                            {
+                                Ok(()) => mirror::mark_published(render),
+                                Err(mqtt_error) => {
+                                    error!("Render state publish failed: {:?}", mqtt_error);
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) => error!("Error serializing render state: {:?}", e),
                     }
-                };
+                }
+            }
 
-            match client
-                .send_message(
-                    config::MQTT_TOPIC_STATE,
-                    &work_buffer[..state_payload_len],
-                    rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
-                    true,
-                )
-                .await
+            // Publishing is paced by the cadence, away mode and the power profile, but never sits
+            // on dangerous air.
+            let mode = mode::get();
+            let floor = mode.publish_interval().max(power::get().publish_interval());
+            let dangerous = matches!(readings.health(), Health::Dangerous);
+            if !dangerous && !cadence.due(&readings, floor) {
+                continue;
+            }
+
+            let state_message = hass::StateMessage::current(readings).sequenced();
+
+            let state_payload_len = match serde_json_core::to_slice(&state_message, work_buffer) {
+                Ok(serialized_len) => serialized_len,
+                Err(e) => {
+                    error!("Error serializing state payload: {:?}", e);
+                    continue;
+                }
+            };
+            let Some(state_payload_len) = hass::append_checksum(work_buffer, state_payload_len)
+            else {
+                error!("No room for the state payload's checksum");
+                continue;
+            };
+
+            match protocol::publish(
+                &mut client,
+                &config::mqtt_topics(),
+                Message::State,
+                &work_buffer[..state_payload_len],
+            )
+            .await
             {
                 Ok(()) => {
                     info!("State message sent");
+                    state::count_published();
+                    cadence.published(readings);
                 }
                 Err(mqtt_error) => match mqtt_error {
                     ReasonCode::NetworkError => {
+                        state::count_publish_failure();
                         error!("State publish failed: MQTT Network Error");
                         break;
                     }
                     _ => {
+                        state::count_publish_failure();
                         error!(
                             "State publish failed due to some other MQTT Error: {:?}",
                             mqtt_error
@@ -158,6 +557,799 @@ pub async fn worker(
                     }
                 },
             }
+
+            // With nobody around, dangerous air also raises an alert (once per episode) for
+            // automations to notify someone. It carries the same readings as the state message.
+            if !dangerous {
+                alerted = false;
+            } else if mode.alerts() && !alerted {
+                match protocol::publish(
+                    &mut client,
+                    &config::mqtt_topics(),
+                    Message::Alert,
+                    &work_buffer[..state_payload_len],
+                )
+                .await
+                {
+                    Ok(()) => {
+                        warn!("Sent dangerous air alert");
+                        syslog::event(
+                            Severity::Warning,
+                            "air",
+                            format_args!("dangerous air alert"),
+                        );
+                        alerted = true;
+                    }
+                    Err(mqtt_error) => {
+                        error!("Alert failed: {:?}", mqtt_error);
+                        break;
+                    }
+                }
+            }
+
+            // Once a day there's a summary report to send as well.
+            if let Some(summary) = report::ready() {
+                let len = match serde_json_core::to_slice(&summary, work_buffer) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("Error serializing daily report: {:?}", e);
+                        report::clear_ready();
+                        continue;
+                    }
+                };
+
+                match protocol::publish(
+                    &mut client,
+                    &config::mqtt_topics(),
+                    Message::Report,
+                    &work_buffer[..len],
+                )
+                .await
+                {
+                    Ok(()) => {
+                        info!("Sent daily report");
+                        report::clear_ready();
+                    }
+                    Err(mqtt_error) => {
+                        error!("Daily report failed: {:?}", mqtt_error);
+                        break;
+                    }
+                }
+            }
+
+            // And the day's traffic totals, once a day.
+            if let Some(totals) = traffic::ready() {
+                let len = match serde_json_core::to_slice(&totals, work_buffer) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("Error serializing traffic totals: {:?}", e);
+                        traffic::clear_ready();
+                        continue;
+                    }
+                };
+
+                match protocol::publish(
+                    &mut client,
+                    &config::mqtt_topics(),
+                    Message::Traffic,
+                    &work_buffer[..len],
+                )
+                .await
+                {
+                    Ok(()) => {
+                        info!("Sent daily traffic totals");
+                        traffic::clear_ready();
+                    }
+                    Err(mqtt_error) => {
+                        error!("Traffic totals publish failed: {:?}", mqtt_error);
+                        break;
+                    }
+                }
+            }
+
+            // The start or end of somebody cooking, if there's news.
+            if let Some(event) = cooking::unpublished() {
+                let len = match serde_json_core::to_slice(&event, work_buffer) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("Error serializing cooking event: {:?}", e);
+                        cooking::mark_published();
+                        continue;
+                    }
+                };
+
+                match protocol::publish(
+                    &mut client,
+                    &config::mqtt_topics(),
+                    Message::Event,
+                    &work_buffer[..len],
+                )
+                .await
+                {
+                    Ok(()) => cooking::mark_published(),
+                    Err(mqtt_error) => {
+                        error!("Cooking event publish failed: {:?}", mqtt_error);
+                        break;
+                    }
+                }
+            }
+
+            // A change in the latency to the gateway or broker, if there's news.
+            if let Some(event) = latency::unpublished() {
+                let len = match serde_json_core::to_slice(&event, work_buffer) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("Error serializing latency event: {:?}", e);
+                        latency::mark_published(&event);
+                        continue;
+                    }
+                };
+
+                match protocol::publish(
+                    &mut client,
+                    &config::mqtt_topics(),
+                    Message::Event,
+                    &work_buffer[..len],
+                )
+                .await
+                {
+                    Ok(()) => latency::mark_published(&event),
+                    Err(mqtt_error) => {
+                        error!("Latency event publish failed: {:?}", mqtt_error);
+                        break;
+                    }
+                }
+            }
+
+            // And a minute's CPU usage, whenever there's a new one.
+            if let Some(usage) = profile::unpublished() {
+                let len = match serde_json_core::to_slice(&usage, work_buffer) {
+                    Ok(len) => len,
+                    Err(e) => {
+                        error!("Error serializing CPU usage: {:?}", e);
+                        profile::mark_published();
+                        continue;
+                    }
+                };
+
+                match protocol::publish(
+                    &mut client,
+                    &config::mqtt_topics(),
+                    Message::Cpu,
+                    &work_buffer[..len],
+                )
+                .await
+                {
+                    Ok(()) => profile::mark_published(),
+                    Err(mqtt_error) => {
+                        error!("CPU usage publish failed: {:?}", mqtt_error);
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Whatever went wrong, the connection's done with. Reset it rather than leave it to time
+        // out, in case the broker's still holding its end open.
+        drop(client);
+        socket.abort();
+        _ = socket.flush().with_timeout(PING_TIMEOUT).await;
+    }
+}
+
+/// The brokers in `hosts`, a comma separated list in the order they're preferred. Always at least
+/// one, even if it's empty.
+fn brokers(hosts: &'static str) -> Vec<&'static str, MAX_BROKERS> {
+    let mut brokers = Vec::new();
+    for host in hosts
+        .split(',')
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+    {
+        if brokers.push(host).is_err() {
+            warn!("Only the first {} brokers are used", MAX_BROKERS);
+            break;
+        }
+    }
+    if brokers.is_empty() {
+        _ = brokers.push(hosts);
+    }
+    brokers
+}
+
+/// Say we're going, then disconnect, so the broker doesn't have to notice. A clean disconnect
+/// doesn't publish the will, hence saying so first.
+async fn leave<T: Read + Write>(client: &mut Broker<'_, T>) {
+    _ = protocol::publish(
+        client,
+        &config::mqtt_topics(),
+        Message::Availability,
+        protocol::OFFLINE,
+    )
+    .await;
+    _ = client.0.disconnect().with_timeout(PING_TIMEOUT).await;
+}
+
+/// Whether to hold off publishing, as the broker's denied a publish. Anything published meanwhile
+/// is dropped, as if it had been sent, so the rest of the worker carries on as usual: the screen,
+/// the commands and the pings all keep working.
+fn read_only() -> bool {
+    ACCESS.lock(Cell::get).read_only()
+}
+
+/// Publish that we're online, acknowledged, and go read-only or back again on the broker's answer.
+async fn probe<T: Read + Write>(client: &mut Broker<'_, T>) -> Result<(), ReasonCode> {
+    let mut access = ACCESS.lock(Cell::get);
+    let now = Instant::now().as_secs();
+    let change = protocol::probe(client, &config::mqtt_topics(), &mut access, now).await?;
+    ACCESS.lock(|shared| shared.set(access));
+    report_access(access, change);
+    Ok(())
+}
+
+/// Go read-only, or back again, on what the broker made of a publish.
+fn record_access(ack: Ack) {
+    let (access, change) = ACCESS.lock(|shared| {
+        let mut access = shared.get();
+        let change = access.record(ack, Instant::now().as_secs());
+        shared.set(access);
+        (access, change)
+    });
+    report_access(access, change);
+}
+
+/// Let everyone know if the broker's let us in or shut us out.
+fn report_access(access: Access, change: Option<AccessChange>) {
+    match change {
+        Some(AccessChange::ReadOnly) => {
+            warn!(
+                "Broker denied a publish, not publishing for {} minutes",
+                protocol::READ_ONLY_RETRY_SECS / 60
+            );
+            syslog::event(
+                Severity::Warning,
+                "mqtt",
+                format_args!("not authorized to publish, read-only"),
+            );
         }
+        Some(AccessChange::Restored) => info!("Broker's taking what we publish again"),
+        None => {}
+    }
+    state::set_connection(match access.read_only() {
+        true => ConnectionState::ReadOnly,
+        false => ConnectionState::Online,
+    });
+}
+
+/// How long to wait before reconnecting after the broker disconnected us or refused to let us
+/// connect, recording why so it shows up in the diagnostics.
+///
+/// Reconnecting straight away is the right thing after a network blip, but not when the broker
+/// has told us to go away: another client with our ID would just get kicked off in turn, and a
+/// broker that's over quota or restarting needs some time first.
+fn broker_backoff(code: ReasonCode) -> Duration {
+    let (reason, backoff) = match code {
+        ReasonCode::NetworkError => return RECONNECT_DELAY,
+        ReasonCode::SessionTakeOver => {
+            warn!(
+                "Another client connected as {}, is MQTT_CLIENT_ID shared?",
+                device_config::get().mqtt_client_id.as_str()
+            );
+            ("session_taken_over", Duration::from_secs(5 * 60))
+        }
+        ReasonCode::QuotaExceeded
+        | ReasonCode::MessageRateTooHigh
+        | ReasonCode::ConnectionRateExceeded => ("quota_exceeded", Duration::from_secs(60)),
+        ReasonCode::ServerShuttingDown
+        | ReasonCode::ServerUnavailable
+        | ReasonCode::ServerBusy
+        | ReasonCode::AdministrativeAction => ("server_unavailable", Duration::from_secs(15)),
+        ReasonCode::NotAuthorized | ReasonCode::BadUserNameOrPassword | ReasonCode::Banned => {
+            ("not_authorized", Duration::from_secs(5 * 60))
+        }
+        _ => ("other", Duration::from_secs(5)),
+    };
+
+    warn!(
+        "Broker disconnect ({}), retrying in {}s",
+        reason,
+        backoff.as_secs()
+    );
+    state::set_broker_disconnect(reason);
+    syslog::event(
+        Severity::Warning,
+        "mqtt",
+        format_args!("broker disconnect: {}", reason),
+    );
+
+    backoff
+}
+
+/// Wait out a reconnection backoff, checking in with the watchdog while we do.
+async fn wait_to_reconnect(backoff: Duration) {
+    let until = Instant::now() + backoff;
+
+    loop {
+        telemetry::heartbeat(Task::Mqtt);
+
+        let now = Instant::now();
+        if now >= until {
+            return;
+        }
+        Timer::at(until.min(now + IDLE_PING_INTERVAL)).await;
+    }
+}
+
+/// What's left for the worker to do once a message from the broker has been acted on.
+enum Handled {
+    Done,
+    /// The settings changed, so the new state needs publishing.
+    SettingsChanged,
+    /// A command from the command topic, which needs the broker connection to carry out.
+    Remote(Remote),
+}
+
+/// Act on a message received from the broker on one of our subscribed topics.
+async fn handle_message(topic: &str, payload: &[u8]) -> Handled {
+    let command = match config::mqtt_topics().parse(topic, payload) {
+        Ok(command) => command,
+        Err(Ignored::BadPayload) => {
+            warn!("Couldn't parse payload on {}", topic);
+            return Handled::Done;
+        }
+        Err(Ignored::UnknownTopic) => {
+            warn!("Ignoring message on unexpected topic {}", topic);
+            return Handled::Done;
+        }
+        // Most likely our own clearing of a retained command.
+        Err(Ignored::Empty) => return Handled::Done,
+    };
+
+    match command {
+        Command::Pressure(hpa) => {
+            compensation::set_ambient_pressure(hpa);
+            Handled::Done
+        }
+        Command::Presence(present) => {
+            presence::set(present);
+            Handled::Done
+        }
+        Command::Display { key: "guest", on } => {
+            info!("Guest mode {}", if on { "on" } else { "off" });
+            settings::update(|s| s.guest_mode = on).await;
+            Handled::SettingsChanged
+        }
+        Command::Display { key, on } => {
+            let Some(metric) = Metric::from_key(key) else {
+                warn!("Unknown display setting {}", key);
+                return Handled::Done;
+            };
+
+            info!(
+                "{} now {}",
+                metric.key(),
+                if on { "shown" } else { "hidden" }
+            );
+            settings::update(|s| s.visible_metrics.set(metric, on)).await;
+            Handled::SettingsChanged
+        }
+        Command::Mode(key) => {
+            let Some(mode) = Mode::from_key(key) else {
+                warn!("Unknown mode {}", key);
+                return Handled::Done;
+            };
+
+            info!("Mode now {}", mode.key());
+            syslog::event(
+                Severity::Notice,
+                "settings",
+                format_args!("mode {}", mode.key()),
+            );
+            settings::update(|s| s.mode = mode).await;
+            Handled::SettingsChanged
+        }
+        Command::Profile(key) => {
+            let Some(profile) = PowerProfile::from_key(key) else {
+                warn!("Unknown power profile {}", key);
+                return Handled::Done;
+            };
+
+            info!("Power profile now {}", profile.key());
+            syslog::event(
+                Severity::Notice,
+                "settings",
+                format_args!("power profile {}", profile.key()),
+            );
+            power::set(profile).await;
+            Handled::SettingsChanged
+        }
+        Command::Rules(json) => {
+            if let Err(e) = rules::set(json).await {
+                warn!("Rejected rules: {:?}", e);
+            }
+            // Either way, publish what's running so it's clear whether they took.
+            Handled::SettingsChanged
+        }
+        Command::DumpAlarmLog => {
+            alarm_log::request_dump();
+            Handled::Done
+        }
+        Command::Remote(remote) => Handled::Remote(remote),
+    }
+}
+
+/// Carry out a command from the command topic. It's cleared first, in case it was retained, so it
+/// isn't carried out again on every reconnect (or, for a restart, forever).
+async fn run_remote<T: Read + Write>(
+    client: &mut Broker<'_, T>,
+    work_buffer: &mut [u8],
+    remote: Remote,
+) -> Result<(), ReasonCode> {
+    info!("Remote command {}", remote.key());
+    syslog::event(
+        Severity::Notice,
+        "mqtt",
+        format_args!("command {}", remote.key()),
+    );
+    let Some(remote) = protocol::take_remote(client, &config::mqtt_topics(), remote).await? else {
+        warn!(
+            "Broker won't let us clear the {} command, ignoring it",
+            remote.key()
+        );
+        record_access(Ack::NotAuthorized);
+        return Ok(());
+    };
+
+    match remote {
+        Remote::Restart => {
+            // Give the clear a moment to get to the broker before the connection goes.
+            Timer::after(RESTART_GRACE).await;
+            alarm_log::save().await;
+            retained::warm_reboot();
+        }
+        Remote::FanClean => sen55::FAN_CLEAN.signal(()),
+        Remote::DisplayOff => ui::SCREEN_OFF.signal(()),
+        Remote::RepublishDiscovery => publish_discovery(client, work_buffer).await?,
+    }
+    Ok(())
+}
+
+/// Announce the device to Home Assistant, telling it to remove anything announced last time that's
+/// gone now, then publish the automation built from the same entities.
+async fn publish_discovery<T: Read + Write>(
+    client: &mut Broker<'_, T>,
+    work_buffer: &mut [u8],
+) -> Result<(), ReasonCode> {
+    let previous = Announced::load().await;
+    let templates = hass::Templates::from_config();
+    let mut discovery_payload = hass::get_discovery_payload(&templates);
+    let announced = Announced::of(&discovery_payload);
+    if let Some(previous) = &previous {
+        previous.retire_stale(&mut discovery_payload);
+    }
+
+    let serialized_len = match serde_json_core::to_slice(&discovery_payload, work_buffer) {
+        Ok(serialized_len) => serialized_len,
+        Err(e) => {
+            error!("Error serializing discovery payload: {:?}", e);
+            0
+        }
+    };
+
+    match protocol::publish(
+        client,
+        &config::mqtt_topics(),
+        Message::Discovery,
+        &work_buffer[..serialized_len],
+    )
+    .await
+    {
+        Ok(()) => {
+            info!("Sent discovery message");
+            match announced {
+                Some(announced) if previous.as_ref() != Some(&announced) => {
+                    announced.save().await;
+                }
+                Some(_) => {}
+                None => warn!("Too many components to keep track of"),
+            }
+        }
+        Err(mqtt_error) => {
+            match mqtt_error {
+                ReasonCode::NetworkError => error!("Discovery message failed: MQTT Network Error"),
+                _ => error!(
+                    "Discovery message failed due to other MQTT Error: {:?}",
+                    mqtt_error
+                ),
+            }
+            return Err(mqtt_error);
+        }
+    }
+
+    if let Some(yaml) = automation::build(&discovery_payload) {
+        match protocol::publish(
+            client,
+            &config::mqtt_topics(),
+            Message::Automation,
+            yaml.as_bytes(),
+        )
+        .await
+        {
+            Ok(()) => info!("Sent automation"),
+            Err(mqtt_error) => {
+                error!("Automation failed: {:?}", mqtt_error);
+                return Err(mqtt_error);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Publish how the device itself is doing, for the diagnostic sensors in Home Assistant.
+async fn publish_diagnostics<T: Read + Write>(
+    client: &mut Broker<'_, T>,
+    work_buffer: &mut [u8],
+    stack: Stack<'_>,
+) -> Result<(), ReasonCode> {
+    let message = DiagnosticsMessage::current(stack);
+    let len = match serde_json_core::to_slice(&message, work_buffer) {
+        Ok(len) => len,
+        Err(e) => {
+            error!("Error serializing diagnostics: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    protocol::publish(
+        client,
+        &config::mqtt_topics(),
+        Message::Diagnostics,
+        &work_buffer[..len],
+    )
+    .await
+}
+
+/// Pass a button press on to Home Assistant, as an event for automations.
+async fn publish_button<T: Read + Write>(
+    client: &mut Broker<'_, T>,
+    work_buffer: &mut [u8],
+    event: ButtonEvent,
+    pressed_at: Instant,
+) -> Result<(), ReasonCode> {
+    let Some(event_type) = event.event_type() else {
+        return Ok(());
+    };
+    if pressed_at.elapsed() > STALE_PRESS {
+        debug!(
+            "Not publishing a press from {}ms ago",
+            pressed_at.elapsed().as_millis()
+        );
+        return Ok(());
+    }
+
+    let message = hass::ButtonMessage { event_type };
+    let len = match serde_json_core::to_slice(&message, work_buffer) {
+        Ok(len) => len,
+        Err(e) => {
+            error!("Error serializing button press: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    protocol::publish(
+        client,
+        &config::mqtt_topics(),
+        Message::Button,
+        &work_buffer[..len],
+    )
+    .await
+}
+
+/// Publish the current settings, for the switches in Home Assistant.
+async fn publish_settings<T: Read + Write>(
+    client: &mut Broker<'_, T>,
+    work_buffer: &mut [u8],
+) -> Result<(), ReasonCode> {
+    publish_display_state(client, work_buffer).await?;
+
+    protocol::publish(
+        client,
+        &config::mqtt_topics(),
+        Message::ModeState,
+        mode::get().key().as_bytes(),
+    )
+    .await?;
+
+    protocol::publish(
+        client,
+        &config::mqtt_topics(),
+        Message::ProfileState,
+        power::get().key().as_bytes(),
+    )
+    .await?;
+
+    let Some(len) = rules::serialize(work_buffer) else {
+        error!("Error serializing rules");
+        return Ok(());
+    };
+    protocol::publish(
+        client,
+        &config::mqtt_topics(),
+        Message::Rules,
+        &work_buffer[..len],
+    )
+    .await
+}
+
+/// Publish which metrics are currently shown on the display, for the config switches.
+async fn publish_display_state<T: Read + Write>(
+    client: &mut Broker<'_, T>,
+    work_buffer: &mut [u8],
+) -> Result<(), ReasonCode> {
+    let message = hass::DisplayStateMessage::from(settings::get());
+    let len = match serde_json_core::to_slice(&message, work_buffer) {
+        Ok(len) => len,
+        Err(e) => {
+            error!("Error serializing display state: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    protocol::publish(
+        client,
+        &config::mqtt_topics(),
+        Message::DisplayState,
+        &work_buffer[..len],
+    )
+    .await
+}
+
+/// The MQTT client, as the transport for the protocol logic.
+struct Broker<'a, T: Read + Write>(MqttClient<'a, T, 5, CountingRng>);
+
+impl<T: Read + Write> Transport for Broker<'_, T> {
+    type Error = ReasonCode;
+
+    async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<(), ReasonCode> {
+        if read_only() {
+            return Ok(());
+        }
+
+        self.0
+            .send_message(
+                topic,
+                payload,
+                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS0,
+                retain,
+            )
+            .await
+    }
+
+    async fn publish_acknowledged(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        retain: bool,
+    ) -> Result<Ack, ReasonCode> {
+        let result = self
+            .0
+            .send_message(
+                topic,
+                payload,
+                rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1,
+                retain,
+            )
+            .await;
+
+        match result {
+            Ok(()) => Ok(Ack::Accepted),
+            Err(ReasonCode::NotAuthorized) => Ok(Ack::NotAuthorized),
+            Err(mqtt_error) => Err(mqtt_error),
+        }
+    }
+
+    async fn subscribe(&mut self, topic: &str) -> Result<(), ReasonCode> {
+        self.0.subscribe_to_topic(topic).await
+    }
+}
+
+/// Holds on to what the broker sends until there's a whole packet of it, so the client only ever
+/// reads complete packets. Waiting for a message is raced against everything else in `run`, and
+/// the client can't pick up a packet it was cancelled part way through reading; with this, the
+/// client's reads never wait once a packet's started, and the wait on the socket before that is
+/// safe to cancel, as nothing's taken out of the socket until it's returned.
+struct Framed<S> {
+    socket: S,
+    buf: [u8; MAX_PACKET],
+    /// How much of `buf` has been read from the socket.
+    len: usize,
+    /// How much of the front of `buf` is complete packets, ready to go to the client.
+    ready: usize,
+}
+
+impl<S> Framed<S> {
+    fn new(socket: S) -> Self {
+        Self {
+            socket,
+            buf: [0; MAX_PACKET],
+            len: 0,
+            ready: 0,
+        }
+    }
+}
+
+/// The length of the packet at the start of `buf`, if all of it's there: the type byte, the
+/// remaining length as a variable length integer, then that many bytes.
+fn packet_len(buf: &[u8]) -> Option<usize> {
+    let mut remaining = 0;
+    for (i, byte) in buf.iter().enumerate().skip(1).take(4) {
+        remaining |= (*byte as usize & 0x7F) << (7 * (i - 1));
+        if byte & 0x80 == 0 {
+            let len = 1 + i + remaining;
+            return (buf.len() >= len).then_some(len);
+        }
+    }
+    None
+}
+
+/// Reading from the socket failed, or the broker sent a packet bigger than it was allowed to.
+#[derive(Debug)]
+enum FramedError<E> {
+    Socket(E),
+    TooBig,
+}
+
+impl<E: embedded_io_async::Error> embedded_io_async::Error for FramedError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            FramedError::Socket(e) => e.kind(),
+            FramedError::TooBig => ErrorKind::OutOfMemory,
+        }
+    }
+}
+
+impl<S: ErrorType> ErrorType for Framed<S> {
+    type Error = FramedError<S::Error>;
+}
+
+impl<S: Read> Read for Framed<S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        while self.ready == 0 {
+            if let Some(len) = packet_len(&self.buf[..self.len]) {
+                self.ready = len;
+                break;
+            }
+            if self.len == self.buf.len() {
+                return Err(FramedError::TooBig);
+            }
+            match self.socket.read(&mut self.buf[self.len..]).await {
+                Ok(0) => return Ok(0),
+                Ok(n) => self.len += n,
+                Err(e) => return Err(FramedError::Socket(e)),
+            }
+        }
+
+        let n = buf.len().min(self.ready);
+        buf[..n].copy_from_slice(&self.buf[..n]);
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+        self.ready -= n;
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Framed<S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.socket.write(buf).await.map_err(FramedError::Socket)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.socket.flush().await.map_err(FramedError::Socket)
     }
 }
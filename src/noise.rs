@@ -0,0 +1,195 @@
+//! Sound level from an optional PDM microphone, as another metric alongside the SEN55's.
+//!
+//! The microphone's clock and data lines go on GP2 and GP3, with its L/R select pin to ground.
+//! A PIO state machine clocks it at 3.072 MHz and shifts in the one-bit samples, which are
+//! decimated by 64 down to 48 kHz by counting the ones in each pair of words. That's crude next
+//! to a proper CIC filter, but plenty for a level meter.
+//!
+//! The RP2040 has no FPU, so filtering every sample would take most of the CPU. Instead a short
+//! block is captured a few times a second, A-weighted, and folded into a slow moving average of
+//! its energy. The level is only as accurate as `NOISE_MIC_SENSITIVITY`, so treat it as a guide
+//! rather than a calibrated measurement.
+
+use core::cell::Cell;
+
+use defmt::{info, warn};
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::peripherals::{DMA_CH1, PIO1};
+use embassy_rp::pio::{
+    Common, Config, Direction, FifoJoin, PioPin, ShiftConfig, ShiftDirection, StateMachine,
+};
+use embassy_rp::{Peripheral, PeripheralRef};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use fixed::traits::ToFixed;
+use micromath::F32Ext;
+
+use crate::config;
+
+/// The microphone's clock. The state machine runs at twice this, as each bit takes two
+/// instructions.
+const MIC_CLOCK_HZ: u32 = 3_072_000;
+
+/// PDM bits per PCM sample, giving 48 kHz.
+const DECIMATION: u32 = 64;
+
+/// Words of PDM captured per block: 1024 samples, about 21 ms.
+const BLOCK_WORDS: usize = 2048;
+
+/// Samples at the start of each block left out of the level while the filter settles.
+const SETTLE_SAMPLES: usize = 256;
+
+/// How often a block is captured.
+const BLOCK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How much of each new block goes into the moving average. At four blocks a second this gives
+/// a time constant of a few seconds.
+const SMOOTHING: f32 = 0.1;
+
+/// A typical MEMS microphone's output for a 94 dB SPL tone, in dB relative to full scale.
+const DEFAULT_SENSITIVITY_DBFS: f32 = -26.0;
+
+/// A-weighting for 48 kHz, as a sixth order IIR filter.
+const A_WEIGHTING_B: [f32; 7] = [
+    0.234_301_8,
+    -0.468_603_6,
+    -0.234_301_8,
+    0.937_207_2,
+    -0.234_301_8,
+    -0.468_603_6,
+    0.234_301_8,
+];
+const A_WEIGHTING_A: [f32; 7] = [
+    1.0,
+    -4.113_043_4,
+    6.553_121_8,
+    -4.990_849_3,
+    1.785_737_3,
+    -0.246_190_6,
+    0.011_224_25,
+];
+
+/// The smoothed mean square of the A-weighted signal, relative to full scale.
+static ENERGY: Mutex<ThreadModeRawMutex, Cell<Option<f32>>> = Mutex::new(Cell::new(None));
+
+/// The current sound level in dBA, once the microphone has been listened to.
+pub fn level() -> Option<f32> {
+    let energy = ENERGY.lock(|e| e.get())?;
+    let dbfs = 10.0 * energy.max(1e-12).log10();
+    Some(dbfs + 94.0 - sensitivity())
+}
+
+/// `NOISE_MIC_SENSITIVITY`, or a typical microphone's if it's unset or can't be parsed.
+fn sensitivity() -> f32 {
+    let Some(text) = config::NOISE_MIC_SENSITIVITY else {
+        return DEFAULT_SENSITIVITY_DBFS;
+    };
+
+    text.trim().parse().unwrap_or_else(|_| {
+        warn!("Couldn't parse NOISE_MIC_SENSITIVITY, assuming a typical microphone");
+        DEFAULT_SENSITIVITY_DBFS
+    })
+}
+
+/// The microphone's state machine, and the DMA channel that empties it.
+pub struct Mic {
+    sm: StateMachine<'static, PIO1, 1>,
+    dma: PeripheralRef<'static, DMA_CH1>,
+}
+
+impl Mic {
+    pub fn new(
+        common: &mut Common<'static, PIO1>,
+        mut sm: StateMachine<'static, PIO1, 1>,
+        clk_pin: impl PioPin,
+        data_pin: impl PioPin,
+        dma: DMA_CH1,
+    ) -> Self {
+        // Clock low while sampling, high in between. With L/R select grounded, the microphone
+        // drives its data while the clock is high, so it's settled by the time it's read.
+        let program = pio_proc::pio_asm!(
+            ".side_set 1",
+            ".wrap_target",
+            "    in pins, 1  side 0",
+            "    nop         side 1",
+            ".wrap",
+        );
+        let program = common.load_program(&program.program);
+
+        let clk = common.make_pio_pin(clk_pin);
+        let data = common.make_pio_pin(data_pin);
+
+        let mut cfg = Config::default();
+        cfg.use_program(&program, &[&clk]);
+        cfg.set_in_pins(&[&data]);
+        cfg.clock_divider = (clk_sys_freq() as f32 / (MIC_CLOCK_HZ * 2) as f32).to_fixed();
+        cfg.shift_in = ShiftConfig {
+            auto_fill: true,
+            threshold: 32,
+            direction: ShiftDirection::Left,
+        };
+        cfg.fifo_join = FifoJoin::RxOnly;
+
+        sm.set_config(&cfg);
+        sm.set_pin_dirs(Direction::Out, &[&clk]);
+        sm.set_pin_dirs(Direction::In, &[&data]);
+        sm.set_enable(true);
+
+        Self {
+            sm,
+            dma: dma.into_ref(),
+        }
+    }
+}
+
+/// Listens to the microphone a block at a time and keeps the level up to date.
+#[embassy_executor::task]
+pub async fn worker(mut mic: Mic) {
+    info!("started noise worker");
+
+    let mut block = [0u32; BLOCK_WORDS];
+
+    loop {
+        // Whatever's been sitting in the FIFO is from before the wait, so start afresh.
+        mic.sm.clear_fifos();
+        mic.sm.rx().dma_pull(mic.dma.reborrow(), &mut block).await;
+
+        let block_energy = a_weighted_energy(&block);
+        ENERGY.lock(|e| {
+            let smoothed = match e.get() {
+                Some(energy) => energy + SMOOTHING * (block_energy - energy),
+                None => block_energy,
+            };
+            e.set(Some(smoothed));
+        });
+
+        Timer::after(BLOCK_INTERVAL).await;
+    }
+}
+
+/// The mean square of a block once it's been decimated and A-weighted, relative to full scale.
+fn a_weighted_energy(block: &[u32]) -> f32 {
+    // Transposed direct form II state.
+    let mut state = [0f32; 6];
+    let mut sum = 0f32;
+    let mut count = 0u32;
+
+    for (i, pair) in block.chunks_exact(2).enumerate() {
+        let ones = pair[0].count_ones() + pair[1].count_ones();
+        let x = (ones as f32 - (DECIMATION / 2) as f32) / (DECIMATION / 2) as f32;
+
+        let y = A_WEIGHTING_B[0] * x + state[0];
+        for k in 0..5 {
+            state[k] = A_WEIGHTING_B[k + 1] * x - A_WEIGHTING_A[k + 1] * y + state[k + 1];
+        }
+        state[5] = A_WEIGHTING_B[6] * x - A_WEIGHTING_A[6] * y;
+
+        if i >= SETTLE_SAMPLES {
+            sum += y * y;
+            count += 1;
+        }
+    }
+
+    sum / count.max(1) as f32
+}
@@ -0,0 +1,424 @@
+//! Over-the-air firmware updates, so a device on the wall doesn't have to come down to be flashed.
+//!
+//! Every few hours the manifest at `OTA_URL` is fetched, a small JSON object like
+//!
+//! `{"version": "0.2.0", "image": "vindskrivare.bin", "size": 412672, "crc32": 3735928559,
+//! "signature": "<128 hex digits>"}`
+//!
+//! and if its version is newer than the one running, the image (a URL of its own, or relative to
+//! the manifest's) is downloaded into the update partition a page at a time. Once it's all there
+//! it's read back and checked against the CRC-32, then its signature against `OTA_PUBLIC_KEY`,
+//! and only then marked for the bootloader to swap in at the next boot, which is straight away.
+//! `cargo xtask ota` builds the image and its manifest, and signs it.
+//!
+//! The new firmware confirms itself once it's been on the network for a minute. One that resets
+//! before then (crashes, or is reset by the watchdog) is swapped back out by the bootloader, so a
+//! bad update costs a couple of reboots rather than a trip up a ladder.
+
+use core::cell::RefCell;
+use core::fmt::Write as _;
+
+use embassy_boot_rp::{
+    AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, FirmwareUpdaterError, State,
+};
+use embassy_net::dns::DnsQueryType;
+use embassy_net::tcp::{self, ConnectError, TcpSocket};
+use embassy_net::Stack;
+use embassy_rp::flash::ERASE_SIZE;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::{Read, Write};
+use heapless::String;
+use log::{error, info, warn};
+use serde::Deserialize;
+use static_cell::StaticCell;
+
+use crate::alarm_log;
+use crate::config;
+use crate::http_push::{HeadError, ResponseHead, Target};
+use crate::retained;
+use crate::sockets::{self, Claim, User};
+use crate::storage::{self, StorageFlash};
+use crate::syslog::{self, Severity};
+use crate::traffic::Counted;
+
+/// How long a new firmware has to be on the network before it's kept.
+const CONFIRM_AFTER: Duration = Duration::from_secs(60);
+
+/// How often to check for an update.
+const CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// How long to wait before trying again after a failed check or download.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// How long the server can go quiet for, mid-download.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Comfortably bigger than the manifest.
+const MANIFEST_SIZE: usize = 512;
+
+/// Room for the image's URL, once it's been made absolute.
+const URL_SIZE: usize = 256;
+
+/// Where flash appears in the address space.
+const XIP_BASE: usize = 0x1000_0000;
+
+/// The running firmware's version, which the manifest's is compared against.
+const VERSION: &str = config::HASS_DEVICE_SW;
+
+#[derive(Debug)]
+pub enum OtaError {
+    Dns,
+    Connect(ConnectError),
+    Socket(tcp::Error),
+    /// The request or a response didn't fit.
+    TooBig,
+    /// The server didn't answer with an HTTP status line and a `Content-Length`.
+    BadResponse,
+    /// The server answered, but not with a 2xx.
+    Status(u16),
+    /// The connection closed before the whole body arrived.
+    Truncated,
+    /// The manifest isn't JSON, or is missing something, or its image URL isn't `http://`.
+    BadManifest,
+    /// The image is bigger than the firmware's partition.
+    ImageTooBig(u32),
+    /// The image in the update partition doesn't match the manifest's checksum.
+    Checksum,
+    /// The image's signature isn't from `OTA_PUBLIC_KEY`'s key.
+    Signature,
+    /// The update partition or the bootloader's state couldn't be read or written.
+    Flash(FirmwareUpdaterError),
+    /// The flash isn't ready yet.
+    Storage,
+}
+
+impl From<HeadError> for OtaError {
+    fn from(e: HeadError) -> Self {
+        match e {
+            HeadError::Socket(e) => OtaError::Socket(e),
+            HeadError::BadResponse => OtaError::BadResponse,
+        }
+    }
+}
+
+/// What the manifest says the latest firmware is.
+#[derive(Debug, Deserialize)]
+struct Manifest<'a> {
+    version: &'a str,
+    /// Where to download it from, as a full `http://` URL or relative to the manifest's.
+    image: &'a str,
+    size: u32,
+    crc32: u32,
+    /// The ed25519 signature of the SHA-512 of the image, in hex.
+    signature: &'a str,
+}
+
+/// One thing to ask of the bootloader's state and update partitions.
+enum Step<'a> {
+    /// Keep the firmware that's running, if it's just been swapped in.
+    Confirm,
+    /// Write a page of the update at this offset.
+    Write(usize, &'a [u8]),
+    /// Check the update of this length was signed by the key, and have the bootloader swap it in
+    /// at the next boot if it was.
+    Swap {
+        public_key: &'a [u8; 32],
+        signature: [u8; 64],
+        len: u32,
+    },
+}
+
+/// Confirms the running firmware, then checks `OTA_URL` for updates, if it's set.
+#[embassy_executor::task]
+pub async fn worker(stack: Stack<'static>) {
+    stack.wait_config_up().await;
+    Timer::after(CONFIRM_AFTER).await;
+    match apply(Step::Confirm).await {
+        Ok(()) => info!("Firmware {} confirmed", VERSION),
+        Err(e) => error!("Couldn't confirm the firmware: {:?}", e),
+    }
+
+    let Some(url) = config::OTA_URL else {
+        return;
+    };
+    if Target::parse(url).is_none() {
+        error!(
+            "Can't check {} for updates, expected http://<host>[:<port>]/<path>",
+            url
+        );
+        return;
+    }
+    let Some(public_key) = config::OTA_PUBLIC_KEY.and_then(from_hex::<32>) else {
+        error!(
+            "Can't check {} for updates without an OTA_PUBLIC_KEY to check them with",
+            url
+        );
+        return;
+    };
+    info!("Checking {} for updates", url);
+
+    static RX_BUFFER: StaticCell<[u8; 2048]> = StaticCell::new();
+    static TX_BUFFER: StaticCell<[u8; 512]> = StaticCell::new();
+    let rx_buffer = RX_BUFFER.init([0; 2048]);
+    let tx_buffer = TX_BUFFER.init([0; 512]);
+
+    loop {
+        let wait = match check(stack, url, &public_key, rx_buffer, tx_buffer).await {
+            Ok(()) => CHECK_INTERVAL,
+            Err(e) => {
+                warn!("Update check failed: {:?}", e);
+                RETRY_INTERVAL
+            }
+        };
+
+        Timer::after(wait).await;
+    }
+}
+
+/// Fetch the manifest, and update to the firmware it describes if it's newer than the one running.
+async fn check(
+    stack: Stack<'static>,
+    url: &str,
+    public_key: &[u8; 32],
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<(), OtaError> {
+    let mut body = [0u8; MANIFEST_SIZE];
+    let len = {
+        let target = Target::parse(url).ok_or(OtaError::BadManifest)?;
+        let mut response = get(stack, &target, rx_buffer, tx_buffer).await?;
+        if response.remaining > MANIFEST_SIZE {
+            return Err(OtaError::TooBig);
+        }
+        response.fill(&mut body).await?
+    };
+
+    let (manifest, _) =
+        serde_json_core::from_slice::<Manifest>(&body[..len]).map_err(|_| OtaError::BadManifest)?;
+    if !is_newer(manifest.version, VERSION).ok_or(OtaError::BadManifest)? {
+        return Ok(());
+    }
+    let signature = from_hex::<64>(manifest.signature).ok_or(OtaError::BadManifest)?;
+
+    info!(
+        "Updating from {} to {}, {} bytes",
+        VERSION, manifest.version, manifest.size
+    );
+    syslog::event(
+        Severity::Notice,
+        "ota",
+        format_args!("updating from {} to {}", VERSION, manifest.version),
+    );
+
+    let image = image_url(url, manifest.image).ok_or(OtaError::BadManifest)?;
+    let target = Target::parse(&image).ok_or(OtaError::BadManifest)?;
+    download(stack, &target, &manifest, rx_buffer, tx_buffer).await?;
+
+    apply(Step::Swap {
+        public_key,
+        signature,
+        len: manifest.size,
+    })
+    .await?;
+    info!("Update downloaded, restarting into {}", manifest.version);
+    // Give the log a moment to get out.
+    Timer::after(Duration::from_secs(1)).await;
+    alarm_log::save().await;
+    retained::warm_reboot();
+}
+
+/// Download the image into the update partition and check it arrived intact.
+async fn download(
+    stack: Stack<'static>,
+    target: &Target<'_>,
+    manifest: &Manifest<'_>,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<(), OtaError> {
+    // The update partition is a page bigger than the firmware's, for the bootloader's swap.
+    let room = update_partition().len() - ERASE_SIZE;
+    if manifest.size as usize > room {
+        return Err(OtaError::ImageTooBig(manifest.size));
+    }
+
+    let mut response = get(stack, target, rx_buffer, tx_buffer).await?;
+    if response.remaining != manifest.size as usize {
+        warn!(
+            "Image is {} bytes, but the manifest says {}",
+            response.remaining, manifest.size
+        );
+        return Err(OtaError::Truncated);
+    }
+
+    // Whole pages only, so the last is padded out as if it had been erased.
+    let mut offset = 0;
+    while response.remaining > 0 {
+        let mut page = [0xFFu8; ERASE_SIZE];
+        response.fill(&mut page).await?;
+        apply(Step::Write(offset, &page)).await?;
+        offset += ERASE_SIZE;
+    }
+
+    let written = &update_partition()[..manifest.size as usize];
+    match storage::crc32(written) == manifest.crc32 {
+        true => Ok(()),
+        false => Err(OtaError::Checksum),
+    }
+}
+
+/// Whether `version` is a later release than `running`, both as `<major>.<minor>.<patch>`. An
+/// older manifest, say one put back after a bad release, leaves devices as they are.
+fn is_newer(version: &str, running: &str) -> Option<bool> {
+    Some(parse_version(version)? > parse_version(running)?)
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
+    let mut parts = version.split('.').map(|part| part.parse().ok());
+    let parsed = (parts.next()??, parts.next()??, parts.next()??);
+    parts.next().is_none().then_some(parsed)
+}
+
+/// Hex digits, either case, as exactly `N` bytes.
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// `image` as a full URL: as it is if it's one already, otherwise relative to `manifest`'s.
+fn image_url(manifest: &str, image: &str) -> Option<String<URL_SIZE>> {
+    let mut url = String::new();
+    if !image.starts_with("http://") {
+        // Everything up to the manifest's name, which has to come after the host.
+        let end = manifest.rfind('/').filter(|&i| i >= "http://".len())?;
+        url.push_str(&manifest[..=end]).ok()?;
+    }
+    url.push_str(image).ok()?;
+    Some(url)
+}
+
+/// The update partition, as it's mapped through XIP.
+fn update_partition() -> &'static [u8] {
+    extern "C" {
+        // Offsets from the start of flash, from memory.x.
+        static __bootloader_dfu_start: u8;
+        static __bootloader_dfu_end: u8;
+    }
+
+    // Only the symbols' addresses matter, they're never read.
+    let start = core::ptr::addr_of!(__bootloader_dfu_start) as usize;
+    let end = core::ptr::addr_of!(__bootloader_dfu_end) as usize;
+    // SAFETY: the partition is mapped for as long as the program runs, and is only written
+    // through `apply`, never while this is being read.
+    unsafe { core::slice::from_raw_parts((XIP_BASE + start) as *const u8, end - start) }
+}
+
+/// Do `step`, borrowing the flash from `storage` for it.
+async fn apply(step: Step<'_>) -> Result<(), OtaError> {
+    storage::borrow(|flash| apply_blocking(flash, step))
+        .await
+        .map_err(|_| OtaError::Storage)?
+        .map_err(|e| match e {
+            FirmwareUpdaterError::Signature(_) => OtaError::Signature,
+            e => OtaError::Flash(e),
+        })
+}
+
+fn apply_blocking(flash: &mut StorageFlash, step: Step) -> Result<(), FirmwareUpdaterError> {
+    let flash = Mutex::<NoopRawMutex, _>::new(RefCell::new(flash));
+    let config = FirmwareUpdaterConfig::from_linkerfile_blocking(&flash, &flash);
+    let mut aligned = AlignedBuffer([0; 1]);
+    let mut updater = BlockingFirmwareUpdater::new(config, &mut aligned.0);
+
+    match step {
+        Step::Confirm => match updater.get_state()? {
+            State::Swap => updater.mark_booted(),
+            _ => Ok(()),
+        },
+        Step::Write(offset, page) => updater.write_firmware(offset, page),
+        Step::Swap {
+            public_key,
+            signature,
+            len,
+        } => updater.verify_and_mark_updated(public_key, &signature, len),
+    }
+}
+
+/// A response being read, once the status line and headers are out of the way.
+struct Response<'a> {
+    socket: Counted<TcpSocket<'a>>,
+    /// How much of the body is still to come.
+    remaining: usize,
+    _claim: Claim,
+}
+
+impl Response<'_> {
+    /// Fill `buf` from the body, or as much of it as there is left. Returns how much was read.
+    async fn fill(&mut self, buf: &mut [u8]) -> Result<usize, OtaError> {
+        let want = buf.len().min(self.remaining);
+        let mut len = 0;
+        while len < want {
+            match self.socket.read(&mut buf[len..want]).await {
+                Ok(0) => return Err(OtaError::Truncated),
+                Ok(n) => len += n,
+                Err(e) => return Err(OtaError::Socket(e)),
+            }
+        }
+
+        self.remaining -= len;
+        Ok(len)
+    }
+}
+
+/// GET `target`, and read as far as the body. Only responses with a `Content-Length` are taken,
+/// which is what any server sends for a file.
+async fn get<'a>(
+    stack: Stack<'static>,
+    target: &Target<'_>,
+    rx_buffer: &'a mut [u8],
+    tx_buffer: &'a mut [u8],
+) -> Result<Response<'a>, OtaError> {
+    let mut head = String::<URL_SIZE>::new();
+    write!(
+        head,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        target.path, target.host
+    )
+    .map_err(|_| OtaError::TooBig)?;
+
+    let address = match stack.dns_query(target.host, DnsQueryType::A).await {
+        Ok(addresses) if !addresses.is_empty() => addresses[0],
+        Ok(_) | Err(_) => return Err(OtaError::Dns),
+    };
+
+    let claim = sockets::claim(User::Ota);
+    let mut socket = Counted::new(TcpSocket::new(stack, rx_buffer, tx_buffer), User::Ota);
+    socket.set_timeout(Some(TIMEOUT));
+    socket
+        .connect((address, target.port))
+        .await
+        .map_err(OtaError::Connect)?;
+    socket
+        .write_all(head.as_bytes())
+        .await
+        .map_err(OtaError::Socket)?;
+    socket.flush().await.map_err(OtaError::Socket)?;
+
+    let head = ResponseHead::read(&mut socket).await?;
+    if !head.is_success() {
+        return Err(OtaError::Status(head.status));
+    }
+
+    Ok(Response {
+        socket,
+        remaining: head.content_length.ok_or(OtaError::BadResponse)?,
+        _claim: claim,
+    })
+}
@@ -0,0 +1,1422 @@
+//! The pages the UI can show once the device is up and running.
+//!
+//! Each page draws itself from the latest readings. The UI worker keeps them in a carousel and
+//! decides which one is on screen; see `ui::worker`.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::mem::discriminant;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use embedded_graphics::framebuffer::{buffer_size, Framebuffer};
+use embedded_graphics::image::{ImageDrawable, ImageDrawableExt};
+use embedded_graphics::pixelcolor::raw::{LittleEndian, RawU16};
+use embedded_graphics::prelude::{DrawTarget, Point, Primitive, RgbColor, Size};
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::{image::Image, pixelcolor::Rgb565, Drawable};
+use heapless::{String, Vec};
+use u8g2_fonts::types::HorizontalAlignment;
+use u8g2_fonts::FontRenderer;
+
+use crate::alarm_log;
+use crate::alarms::{self, MAX_ALARMS};
+use crate::asset::{compressed_asset, CompressedImage};
+use crate::background::{DrawnBackground, ReadingsBackground, Theme};
+use crate::graph::{Scale, Sorted};
+use crate::icons::{self, Icon, ICON_SIZE};
+use crate::kiosk::Fault;
+use crate::metric::{Metric, MetricSet};
+use crate::score::{self, History, HISTORY_DAYS};
+use crate::sen55::{Health, Readings};
+use crate::ui::{Display, DISPLAY_H, DISPLAY_W};
+use crate::{climate, config, live, settings, state, ticker};
+
+// Each reading is drawn on a plate cut out of the background, slightly bigger than the text.
+const TILE_W: usize = 71;
+const TILE_H: usize = 26;
+
+type TileBuffer = Framebuffer<
+    Rgb565,
+    RawU16,
+    LittleEndian,
+    TILE_W,
+    TILE_H,
+    { buffer_size::<Rgb565>(TILE_W, TILE_H) },
+>;
+
+type LargeValueBuffer = Framebuffer<
+    Rgb565,
+    RawU16,
+    LittleEndian,
+    { DISPLAY_W as usize },
+    HC_VALUE_HEIGHT,
+    { buffer_size::<Rgb565>(DISPLAY_W as usize, HC_VALUE_HEIGHT) },
+>;
+
+/// Where the large values are drawn off screen. At nearly 30K it's far too big for the stack, and
+/// only the UI worker draws, so the one is shared.
+static LARGE_VALUE_STRIP: Mutex<ThreadModeRawMutex, RefCell<LargeValueBuffer>> =
+    Mutex::new(RefCell::new(LargeValueBuffer::new()));
+
+// High contrast layout: two big values stacked, with a health banner underneath.
+const HC_PM25_LABEL_Y: i32 = 8;
+const HC_PM25_VALUE_Y: i32 = 38;
+const HC_TEMP_LABEL_Y: i32 = 118;
+const HC_TEMP_VALUE_Y: i32 = 148;
+const HC_VALUE_HEIGHT: usize = 60;
+const HC_BANNER_Y: i32 = 230;
+
+// Air score layout: today's score big, yesterday's underneath, then a bar per day for the last week.
+const SCORE_LABEL_Y: i32 = 8;
+const SCORE_VALUE_Y: i32 = 38;
+const SCORE_YESTERDAY_Y: i32 = 110;
+const SCORE_CHART_TOP: i32 = 150;
+const SCORE_CHART_HEIGHT: u32 = 100;
+const SCORE_BAR_W: u32 = 24;
+const SCORE_BAR_GAP: u32 = 8;
+
+// Noise layout: the level big, with its unit underneath.
+const NOISE_LABEL_Y: i32 = 8;
+const NOISE_VALUE_Y: i32 = 38;
+const NOISE_UNIT_Y: i32 = 110;
+
+// Layout for the live graph page: a bar per second, newest on the right, with its scale above.
+const LIVE_LABEL_Y: i32 = 8;
+const LIVE_VALUE_Y: i32 = 38;
+const LIVE_SCALE_Y: i32 = 98;
+const LIVE_GRAPH_TOP: i32 = 116;
+const LIVE_GRAPH_H: u32 = 150;
+const LIVE_BAR_W: u32 = DISPLAY_W / live::SAMPLES as u32;
+/// Bars clipped at the top of the graph get a white cap this tall.
+const LIVE_CLIP_MARK_H: u32 = 4;
+
+// Climate layout: temperature and humidity big, then the dew point and the last 24 hours' ranges.
+const CLIMATE_TEMP_LABEL_Y: i32 = 8;
+const CLIMATE_TEMP_VALUE_Y: i32 = 38;
+const CLIMATE_HMTY_LABEL_Y: i32 = 100;
+const CLIMATE_HMTY_VALUE_Y: i32 = 130;
+const CLIMATE_DETAILS_Y: i32 = 196;
+const CLIMATE_DETAIL_SEP: i32 = 26;
+const CLIMATE_DETAILS: usize = 3;
+
+// Alarms layout: a title, then a line per alarm.
+const ALARMS_LABEL_Y: i32 = 8;
+const ALARMS_FIRST_Y: i32 = 48;
+const ALARMS_LINE_SEP: i32 = 32;
+
+// Kiosk fallback layout: a banner saying what's wrong, the last PM2.5 and temperature, and how long
+// ago they were good along the bottom.
+const FALLBACK_BANNER_H: u32 = 40;
+const FALLBACK_PM25_LABEL_Y: i32 = 48;
+const FALLBACK_PM25_VALUE_Y: i32 = 76;
+const FALLBACK_TEMP_LABEL_Y: i32 = 140;
+const FALLBACK_TEMP_VALUE_Y: i32 = 168;
+const FALLBACK_STATUS_Y: i32 = 240;
+
+// Diagnostics layout: a line of text per fact, top to bottom.
+const DIAG_FIRST_Y: i32 = 8;
+const DIAG_LINE_SEP: i32 = 26;
+const DIAG_LINES: usize = 10;
+
+/// How many of the latest alarms the diagnostics page lists.
+const DIAG_ALARMS: usize = 2;
+
+// The ticker runs along the bottom of the readings page, under the last row of tiles.
+const FOOTER_Y: i32 = 258;
+const FOOTER_H: usize = 18;
+
+type FooterBuffer = Framebuffer<
+    Rgb565,
+    RawU16,
+    LittleEndian,
+    { DISPLAY_W as usize },
+    FOOTER_H,
+    { buffer_size::<Rgb565>(DISPLAY_W as usize, FOOTER_H) },
+>;
+
+/// Values are drawn in this instead once the readings are stale.
+const STALE_COLOR: Rgb565 = Rgb565::new(12, 24, 12);
+
+const READING_SEP: i32 = 66;
+const FIRST_READING_Y: i32 = 28;
+
+const PM1_POS: Point = reading_pos(60, 0);
+const PM25_POS: Point = reading_pos(60, 1);
+const PM4_POS: Point = reading_pos(60, 2);
+const PM10_POS: Point = reading_pos(60, 3);
+
+const TVOC_POS: Point = reading_pos(168, 0);
+const TNOX_POS: Point = reading_pos(168, 1);
+const TEMP_POS: Point = reading_pos(168, 2);
+const HMTY_POS: Point = reading_pos(168, 3);
+
+/// Where each metric's value goes on the standard readings page. The labels are part of the
+/// background images, so the tiles can be hidden but not moved.
+pub const TILES: [(Metric, Point); 8] = [
+    (Metric::Pm1, PM1_POS),
+    (Metric::Voc, TVOC_POS),
+    (Metric::Pm10, PM10_POS),
+    (Metric::Humidity, HMTY_POS),
+    (Metric::Pm2_5, PM25_POS),
+    (Metric::Nox, TNOX_POS),
+    (Metric::Pm4, PM4_POS),
+    (Metric::Temperature, TEMP_POS),
+];
+
+const fn reading_pos(x: i32, index: u32) -> Point {
+    Point::new(x, FIRST_READING_Y + (READING_SEP * index as i32))
+}
+
+const RAW_BG_READINGS_OK: CompressedImage =
+    CompressedImage::new(compressed_asset!("readings-default"), DISPLAY_W, DISPLAY_H);
+
+const RAW_BG_READINGS_UNHAPPY: CompressedImage =
+    CompressedImage::new(compressed_asset!("readings-unhappy"), DISPLAY_W, DISPLAY_H);
+
+const RAW_BG_READINGS_DANGEROUS: CompressedImage = CompressedImage::new(
+    compressed_asset!("readings-dangerous"),
+    DISPLAY_W,
+    DISPLAY_H,
+);
+
+/// The most pages a carousel can hold.
+pub const MAX_PAGES: usize = 8;
+
+/// Something the UI can show full screen once the device is running.
+pub trait Page {
+    /// Draw the page. `full` is set when something else was on screen before, so the page can't
+    /// rely on anything it drew last time still being there.
+    fn render(&mut self, display: &mut Display, readings: &Readings, full: bool);
+
+    /// How long the page stays up before the carousel moves on to the next one.
+    fn dwell_time(&self) -> Duration;
+
+    /// Whether a new set of readings is worth redrawing for. Only asked while the page is on
+    /// screen, and not before a full redraw.
+    fn wants_refresh(&mut self, readings: &Readings) -> bool;
+
+    /// Freeze the page's graph where it is, so it can be stepped back through, or let it carry on.
+    /// Returns whether it's now frozen, which it can't be if there's no graph.
+    fn set_frozen(&mut self, _frozen: bool) -> bool {
+        false
+    }
+
+    /// Step a frozen graph back through its history, or forward again.
+    fn step_frozen(&mut self, _back: bool) {}
+}
+
+/// Identifies each kind of page, so the carousel's order can come from config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PageId {
+    /// All eight readings on the illustrated backgrounds.
+    Readings,
+    /// PM2.5 and temperature in very large white-on-black text.
+    Large,
+    /// Today's air score, with the last week's as a bar chart.
+    Score,
+    /// The sensor's product name and versions, and how the connection is doing.
+    Diagnostics,
+    /// The sound level, if there's a microphone.
+    Noise,
+    /// The last minute of raw PM2.5, second by second.
+    Live,
+    /// Every metric over its threshold, worst first.
+    Alarms,
+    /// Temperature and humidity in large text, with the dew point and the last 24 hours' range.
+    Climate,
+}
+
+impl PageId {
+    pub fn from_key(key: &str) -> Option<PageId> {
+        PageId::ALL.into_iter().find(|page| page.key() == key)
+    }
+
+    /// Every page, in the order the menu offers them.
+    pub const ALL: [PageId; 8] = [
+        PageId::Readings,
+        PageId::Large,
+        PageId::Score,
+        PageId::Diagnostics,
+        PageId::Noise,
+        PageId::Live,
+        PageId::Alarms,
+        PageId::Climate,
+    ];
+
+    pub const fn key(&self) -> &'static str {
+        match self {
+            PageId::Readings => "readings",
+            PageId::Large => "large",
+            PageId::Score => "score",
+            PageId::Diagnostics => "diagnostics",
+            PageId::Noise => "noise",
+            PageId::Live => "live",
+            PageId::Alarms => "alarms",
+            PageId::Climate => "climate",
+        }
+    }
+
+    /// Stored in the settings, with 0 meaning no page.
+    pub(crate) const fn to_byte(self) -> u8 {
+        self as u8 + 1
+    }
+
+    pub(crate) fn from_byte(byte: u8) -> Option<PageId> {
+        PageId::ALL.get(usize::from(byte).checked_sub(1)?).copied()
+    }
+
+    /// Parse a comma-separated list of page keys (e.g. `readings,large`), skipping any that
+    /// aren't recognised. Falls back to just the readings page if nothing usable is left.
+    pub fn parse_list(list: Option<&str>) -> Vec<PageId, MAX_PAGES> {
+        let mut out = Vec::new();
+
+        for key in list.unwrap_or("").split(',') {
+            match PageId::from_key(key.trim()) {
+                Some(id) => {
+                    if out.push(id).is_err() {
+                        defmt::warn!("Too many pages, ignoring the rest");
+                        break;
+                    }
+                }
+                None if key.trim().is_empty() => {}
+                None => defmt::warn!("Unknown page {}", key),
+            }
+        }
+
+        if out.is_empty() {
+            _ = out.push(PageId::Readings);
+        }
+
+        out
+    }
+}
+
+/// One of every page, so the carousel can refer to them by id.
+pub struct Pages {
+    readings: ReadingsPage,
+    large: LargePage,
+    score: AirScorePage,
+    diagnostics: DiagnosticsPage,
+    noise: NoisePage,
+    live: LivePage,
+    alarms: AlarmsPage,
+    climate: ClimatePage,
+}
+
+impl Pages {
+    pub fn new() -> Self {
+        Self {
+            readings: ReadingsPage::new(Theme::from_config()),
+            large: LargePage::new(),
+            score: AirScorePage::new(),
+            diagnostics: DiagnosticsPage::new(),
+            noise: NoisePage::new(),
+            live: LivePage::new(),
+            alarms: AlarmsPage::new(),
+            climate: ClimatePage::new(),
+        }
+    }
+
+    pub fn get(&mut self, id: PageId) -> &mut dyn Page {
+        match id {
+            PageId::Readings => &mut self.readings,
+            PageId::Large => &mut self.large,
+            PageId::Score => &mut self.score,
+            PageId::Diagnostics => &mut self.diagnostics,
+            PageId::Noise => &mut self.noise,
+            PageId::Live => &mut self.live,
+            PageId::Alarms => &mut self.alarms,
+            PageId::Climate => &mut self.climate,
+        }
+    }
+}
+
+/// The standard page: every reading, on a background that changes with the air quality.
+pub struct ReadingsPage {
+    // The previous health of the readings, used to determine if the background needs to be redrawn
+    last_health: Option<Health>,
+
+    /// The metrics shown last time, so hidden tiles can be cleared.
+    last_visible: MetricSet,
+
+    // We only show every 5th reading to reduce flicker.
+    // This counter is used to keep track.
+    reading_skip: u8,
+
+    /// Colours to draw the background in, or `None` to use the bitmaps.
+    theme: Option<Theme>,
+}
+
+impl ReadingsPage {
+    const fn new(theme: Option<Theme>) -> Self {
+        Self {
+            last_health: None,
+            last_visible: MetricSet::ALL,
+            reading_skip: 0,
+            theme,
+        }
+    }
+}
+
+impl Page for ReadingsPage {
+    fn render(&mut self, display: &mut Display, readings: &Readings, full: bool) {
+        if full {
+            self.last_health = None;
+        }
+
+        // Work out the health of the readings
+        let new_health = readings.health();
+        let bg = match self.theme {
+            Some(theme) => ReadingsBackground::Drawn(DrawnBackground::new(theme, new_health)),
+            None => ReadingsBackground::Bitmap(match new_health {
+                Health::Ok => &RAW_BG_READINGS_OK,
+                Health::Warning => &RAW_BG_READINGS_UNHAPPY,
+                Health::Dangerous => &RAW_BG_READINGS_DANGEROUS,
+            }),
+        };
+
+        // Last health is different (or unset), redraw the background
+        let img = Image::new(&bg, Point::zero());
+
+        if self.last_health.is_none() {
+            // First time rendering, draw background directly to display
+            img.draw(display).unwrap();
+        }
+
+        // Tiles that have just been hidden still have their old value on screen.
+        let visible = settings::get().visible_metrics;
+        if visible != self.last_visible {
+            img.draw(display).unwrap();
+            self.last_visible = visible;
+        }
+
+        if let Some(last_health) = &self.last_health {
+            if discriminant(&new_health) != discriminant(last_health) {
+                // Health hasn changed, draw background directly to display
+                img.draw(display).unwrap();
+            }
+        }
+
+        // Draw the readings, leaving hidden ones as a blank plate
+        let color = match readings.is_stale() {
+            true => STALE_COLOR,
+            false => bg.value_color(),
+        };
+        // Top to bottom, so the background's only inflated once (see `asset`).
+        let mut tiles = TILES;
+        tiles.sort_unstable_by_key(|(_, pos)| (pos.y, pos.x));
+        for (metric, pos) in tiles {
+            if visible.contains(metric) {
+                draw_reading(display, &bg, pos, metric, readings, color);
+            }
+        }
+
+        draw_footer(display, &bg, ticker::current().as_deref().unwrap_or(""));
+
+        self.last_health = Some(new_health);
+    }
+
+    fn dwell_time(&self) -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn wants_refresh(&mut self, _readings: &Readings) -> bool {
+        // Skip some readings to reduce flicker
+        if self.reading_skip < 5 {
+            self.reading_skip += 1;
+            false
+        } else {
+            self.reading_skip = 0;
+            true
+        }
+    }
+}
+
+/// Just PM2.5 and temperature, in very large white-on-black text, for anyone who finds the
+/// readings page hard to read.
+pub struct LargePage {
+    last_health: Option<Health>,
+
+    /// The values on screen, so they're only redrawn (and flicker) when they actually change.
+    last_text: (String<8>, String<8>),
+}
+
+impl LargePage {
+    const fn new() -> Self {
+        Self {
+            last_health: None,
+            last_text: (String::new(), String::new()),
+        }
+    }
+
+    fn text_for(readings: &Readings) -> (String<8>, String<8>) {
+        let mut pm2_5 = String::new();
+        let mut temperature = String::new();
+        format_metric(&mut pm2_5, Metric::Pm2_5, readings);
+        format_metric(&mut temperature, Metric::Temperature, readings);
+        (pm2_5, temperature)
+    }
+}
+
+impl Page for LargePage {
+    fn render(&mut self, display: &mut Display, readings: &Readings, full: bool) {
+        let new_health = readings.health();
+
+        let health_changed = match &self.last_health {
+            Some(last_health) => discriminant(&new_health) != discriminant(last_health),
+            None => true,
+        };
+
+        if full || health_changed {
+            display.clear(Rgb565::BLACK).unwrap();
+
+            draw_large_label(display, HC_PM25_LABEL_Y, "PM2.5");
+            let mut label = String::<16>::new();
+            let unit = settings::get().temperature_unit;
+            _ = write!(label, "Temp {}", unit.symbol());
+            draw_large_label(display, HC_TEMP_LABEL_Y, &label);
+            draw_health_banner(display, &new_health);
+        }
+
+        let color = value_color(readings);
+        let text = Self::text_for(readings);
+        draw_large_text(display, HC_PM25_VALUE_Y, &text.0, color);
+        draw_large_text(display, HC_TEMP_VALUE_Y, &text.1, color);
+
+        self.last_health = Some(new_health);
+        self.last_text = text;
+    }
+
+    fn dwell_time(&self) -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn wants_refresh(&mut self, readings: &Readings) -> bool {
+        Self::text_for(readings) != self.last_text
+            || self.last_health.as_ref().map(discriminant) != Some(discriminant(&readings.health()))
+    }
+}
+
+/// A daily score out of 100 for how clean the air has been, to make a game of keeping it good.
+pub struct AirScorePage {
+    /// What's on screen, so the page is only redrawn when the score or history changes.
+    last_today: Option<Option<u8>>,
+    last_history: History,
+}
+
+impl AirScorePage {
+    const fn new() -> Self {
+        Self {
+            last_today: None,
+            last_history: [None; HISTORY_DAYS],
+        }
+    }
+}
+
+impl Page for AirScorePage {
+    fn render(&mut self, display: &mut Display, _readings: &Readings, full: bool) {
+        let today = score::today();
+        let history = score::history();
+
+        // Yesterday's score and the chart only change once a day.
+        if full || history != self.last_history {
+            display.clear(Rgb565::BLACK).unwrap();
+            draw_large_label(display, SCORE_LABEL_Y, "Air score");
+
+            let mut yesterday = String::<16>::new();
+            match history[HISTORY_DAYS - 1] {
+                Some(score) => write!(yesterday, "Yesterday {}", score).unwrap(),
+                None => yesterday.push_str("Yesterday -").unwrap(),
+            }
+            draw_large_label(display, SCORE_YESTERDAY_Y, &yesterday);
+            draw_score_chart(display, &history);
+        }
+
+        let mut text = String::<8>::new();
+        match today {
+            Some(score) => write!(text, "{}", score).unwrap(),
+            None => text.push_str("...").unwrap(),
+        }
+        draw_large_text(display, SCORE_VALUE_Y, &text, Rgb565::WHITE);
+
+        self.last_today = Some(today);
+        self.last_history = history;
+    }
+
+    fn dwell_time(&self) -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn wants_refresh(&mut self, _readings: &Readings) -> bool {
+        self.last_today != Some(score::today()) || self.last_history != score::history()
+    }
+}
+
+/// The sound level in large text, as it's not one of the readings page's tiles.
+pub struct NoisePage {
+    /// The value on screen, so it's only redrawn when it changes.
+    last_text: Option<String<8>>,
+}
+
+impl NoisePage {
+    const fn new() -> Self {
+        Self { last_text: None }
+    }
+
+    fn text_for(readings: &Readings) -> String<8> {
+        let mut text = String::new();
+        format_metric(&mut text, Metric::Noise, readings);
+        text
+    }
+}
+
+impl Page for NoisePage {
+    fn render(&mut self, display: &mut Display, readings: &Readings, full: bool) {
+        if full {
+            display.clear(Rgb565::BLACK).unwrap();
+            draw_large_label(display, NOISE_LABEL_Y, "Noise");
+            draw_large_label(display, NOISE_UNIT_Y, "dBA");
+        }
+
+        let text = Self::text_for(readings);
+        draw_large_text(display, NOISE_VALUE_Y, &text, value_color(readings));
+        self.last_text = Some(text);
+    }
+
+    fn dwell_time(&self) -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn wants_refresh(&mut self, readings: &Readings) -> bool {
+        self.last_text.as_ref() != Some(&Self::text_for(readings))
+    }
+}
+
+/// What the device knows about itself: which sensor is fitted, how the connection is doing, the
+/// hostname it registered with DHCP, and the latest alarms.
+pub struct DiagnosticsPage {
+    /// The lines on screen, so the page is only redrawn when one of them changes.
+    last_lines: Option<[String<24>; DIAG_LINES]>,
+}
+
+impl DiagnosticsPage {
+    const fn new() -> Self {
+        Self { last_lines: None }
+    }
+
+    fn lines() -> [String<24>; DIAG_LINES] {
+        let state = state::get();
+        let mut lines: [String<24>; DIAG_LINES] = Default::default();
+
+        _ = lines[0].push_str("Sensor");
+        match state.sensor_info {
+            Some(info) => {
+                _ = lines[1].push_str(info.product());
+                _ = write!(
+                    lines[2],
+                    "FW {}.{}  HW {}.{}",
+                    info.firmware.0, info.firmware.1, info.hardware.0, info.hardware.1
+                );
+            }
+            None => {
+                _ = lines[1].push_str("...");
+            }
+        }
+
+        _ = lines[3].push_str("Network");
+        _ = lines[4].push_str(state.connection.name());
+        _ = write!(lines[5], "Sent {}", state.published);
+        // The hostname's a network detail too, so it's kept off the screen in guest mode.
+        if !settings::get().guest_mode {
+            let hostname = config::dhcp_hostname();
+            _ = lines[6].push_str(&hostname[..hostname.len().min(24)]);
+        }
+
+        // The latest alarms from the log, e.g. `PM2.5 142 for 12m`.
+        _ = lines[7].push_str("Alarms");
+        let entries = alarm_log::entries();
+        if entries.is_empty() {
+            _ = lines[8].push_str("None");
+        }
+        for (line, entry) in lines[8..].iter_mut().zip(entries.iter().take(DIAG_ALARMS)) {
+            let mut peak = String::<8>::new();
+            format_reading(&mut peak, entry.metric, &Some(entry.peak));
+            _ = write!(
+                line,
+                "{} {} for {}",
+                entry.metric.descriptor().label,
+                peak,
+                ticker::Elapsed(Duration::from_secs(u64::from(entry.duration_s)))
+            );
+        }
+
+        lines
+    }
+}
+
+impl Page for DiagnosticsPage {
+    fn render(&mut self, display: &mut Display, _readings: &Readings, _full: bool) {
+        let lines = Self::lines();
+
+        // Lines are different widths, so clear rather than draw over the old ones.
+        display.clear(Rgb565::BLACK).unwrap();
+        for (i, line) in lines.iter().enumerate() {
+            draw_large_label(display, DIAG_FIRST_Y + i as i32 * DIAG_LINE_SEP, line);
+        }
+
+        self.last_lines = Some(lines);
+    }
+
+    fn dwell_time(&self) -> Duration {
+        Duration::from_secs(10)
+    }
+
+    fn wants_refresh(&mut self, _readings: &Readings) -> bool {
+        self.last_lines.as_ref() != Some(&Self::lines())
+    }
+}
+
+/// The last minute of raw PM2.5 as a bar graph, for tracking down what's causing a spike. Only the
+/// value and the graph are redrawn as new samples come in, once a second.
+///
+/// The graph can be frozen, and then stepped back a minute at a time through what `live` keeps.
+pub struct LivePage {
+    /// The second of the newest sample on screen, so it's only redrawn when there's a new one.
+    drawn: Option<u64>,
+
+    /// While the graph's frozen: the second it was frozen at, and how many graphs' worth back from
+    /// there is on screen.
+    frozen: Option<(u64, usize)>,
+}
+
+impl LivePage {
+    const fn new() -> Self {
+        Self {
+            drawn: None,
+            frozen: None,
+        }
+    }
+}
+
+impl Page for LivePage {
+    fn render(&mut self, display: &mut Display, _readings: &Readings, full: bool) {
+        let samples = match self.frozen {
+            Some((at, back)) => live::samples_to(at.saturating_sub((back * live::SAMPLES) as u64)),
+            None => live::samples(),
+        };
+
+        if full {
+            display.clear(Rgb565::BLACK).unwrap();
+            let mut label = String::<24>::new();
+            _ = match self.frozen {
+                None => write!(label, "Live {}", samples.metric.descriptor().label),
+                Some((_, 0)) => label.write_str("Paused"),
+                Some((_, back)) => write!(label, "Paused -{} min", back),
+            };
+            draw_large_label(display, LIVE_LABEL_Y, &label);
+        }
+
+        draw_large_value(
+            display,
+            LIVE_VALUE_Y,
+            samples.metric,
+            &samples.latest(),
+            Rgb565::WHITE,
+        );
+        draw_live_graph(display, &samples);
+
+        self.drawn = Some(samples.newest);
+    }
+
+    fn dwell_time(&self) -> Duration {
+        Duration::from_secs(30)
+    }
+
+    fn wants_refresh(&mut self, _readings: &Readings) -> bool {
+        self.frozen.is_none() && self.drawn != Some(live::samples().newest)
+    }
+
+    fn set_frozen(&mut self, frozen: bool) -> bool {
+        self.frozen = frozen.then(|| (live::samples().newest, 0));
+        frozen
+    }
+
+    fn step_frozen(&mut self, back: bool) {
+        // Going back from the oldest minute that's kept wraps round to where it was frozen.
+        if let Some((_, windows)) = &mut self.frozen {
+            *windows = match back {
+                true => (*windows + 1) % live::WINDOWS,
+                false => windows.saturating_sub(1),
+            };
+        }
+    }
+}
+
+/// Each metric over its threshold, dangerous ones first, with its value, the threshold it's over,
+/// and how long it's been over it.
+pub struct AlarmsPage {
+    /// The lines on screen, so the page is only redrawn when one of them changes.
+    last_lines: Option<Vec<(String<24>, Rgb565), MAX_ALARMS>>,
+}
+
+impl AlarmsPage {
+    const fn new() -> Self {
+        Self { last_lines: None }
+    }
+
+    /// A line per alarm, e.g. `PM2.5 142 >100 12m`, in its health's colour.
+    fn lines() -> Vec<(String<24>, Rgb565), MAX_ALARMS> {
+        alarms::active()
+            .iter()
+            .map(|alarm| {
+                let mut value = String::<8>::new();
+                let mut threshold = String::<8>::new();
+                format_reading(&mut value, alarm.metric, &Some(alarm.value));
+                format_reading(&mut threshold, alarm.metric, &Some(alarm.threshold));
+
+                let mut line = String::new();
+                _ = write!(
+                    line,
+                    "{} {} >{} {}",
+                    alarm.metric.descriptor().label,
+                    value,
+                    threshold,
+                    ticker::Elapsed(alarm.since.elapsed())
+                );
+                let color = match alarm.health {
+                    Health::Dangerous => Rgb565::RED,
+                    _ => Rgb565::YELLOW,
+                };
+                (line, color)
+            })
+            .collect()
+    }
+}
+
+impl Page for AlarmsPage {
+    fn render(&mut self, display: &mut Display, _readings: &Readings, _full: bool) {
+        let lines = Self::lines();
+
+        // Lines come and go, so clear rather than draw over the old ones.
+        display.clear(Rgb565::BLACK).unwrap();
+        draw_large_label(display, ALARMS_LABEL_Y, "Alarms");
+        if lines.is_empty() {
+            draw_medium_text(display, ALARMS_FIRST_Y, "None", Rgb565::WHITE);
+        }
+        for (i, (line, color)) in lines.iter().enumerate() {
+            draw_medium_text(
+                display,
+                ALARMS_FIRST_Y + i as i32 * ALARMS_LINE_SEP,
+                line,
+                *color,
+            );
+        }
+
+        self.last_lines = Some(lines);
+    }
+
+    fn dwell_time(&self) -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn wants_refresh(&mut self, _readings: &Readings) -> bool {
+        self.last_lines.as_ref() != Some(&Self::lines())
+    }
+}
+
+/// Temperature and humidity in large text, with the dew point and the lowest and highest of each over
+/// the last 24 hours, for anyone using the device mainly as a room thermometer.
+pub struct ClimatePage {
+    /// What's on screen: the temperature unit in the label, the two values, and the lines under
+    /// them, so only what's changed is redrawn.
+    last_drawn: Option<(
+        &'static str,
+        (String<8>, String<8>),
+        [String<24>; CLIMATE_DETAILS],
+    )>,
+}
+
+impl ClimatePage {
+    const fn new() -> Self {
+        Self { last_drawn: None }
+    }
+
+    fn text_for(
+        readings: &Readings,
+    ) -> (
+        &'static str,
+        (String<8>, String<8>),
+        [String<24>; CLIMATE_DETAILS],
+    ) {
+        let settings = settings::get();
+        let (unit, space) = (settings.temperature_unit, settings.language.unit_space());
+        let shown = |celsius: f32| {
+            let mut text = String::<8>::new();
+            format_reading(&mut text, Metric::Temperature, &Some(unit.convert(celsius)));
+            text
+        };
+
+        let mut temperature = String::new();
+        let mut humidity = String::new();
+        format_metric(&mut temperature, Metric::Temperature, readings);
+        format_metric(&mut humidity, Metric::Humidity, readings);
+
+        // e.g. `Dew point 9.8°C`, `24h 19.2 to 23.4°C` and `24h 35.0 to 52.1%`, or `9,8 °C` and so
+        // on in Swedish.
+        let mut details: [String<24>; CLIMATE_DETAILS] = Default::default();
+        let dew_point = match (readings.temperature, readings.humidity) {
+            (Some(t), Some(h)) => climate::dew_point(t, h),
+            _ => None,
+        };
+        _ = match dew_point {
+            Some(dew_point) => write!(
+                details[0],
+                "Dew point {}{}{}",
+                shown(dew_point),
+                space,
+                unit.symbol()
+            ),
+            None => details[0].write_str("Dew point ..."),
+        };
+        if let Some(range) = climate::temperature() {
+            _ = write!(
+                details[1],
+                "24h {} to {}{}{}",
+                shown(range.min),
+                shown(range.max),
+                space,
+                unit.symbol()
+            );
+        }
+        if let Some(range) = climate::humidity() {
+            let mut min = String::<8>::new();
+            let mut max = String::<8>::new();
+            format_reading(&mut min, Metric::Humidity, &Some(range.min));
+            format_reading(&mut max, Metric::Humidity, &Some(range.max));
+            _ = write!(details[2], "24h {} to {}{}%", min, max, space);
+        }
+
+        (unit.symbol(), (temperature, humidity), details)
+    }
+}
+
+impl Page for ClimatePage {
+    fn render(&mut self, display: &mut Display, readings: &Readings, full: bool) {
+        let (symbol, (temperature, humidity), details) = Self::text_for(readings);
+        let last = self
+            .last_drawn
+            .take()
+            .filter(|last| !full && last.0 == symbol);
+
+        if last.is_none() {
+            display.clear(Rgb565::BLACK).unwrap();
+            let mut label = String::<16>::new();
+            _ = write!(label, "Temp {}", symbol);
+            draw_large_label(display, CLIMATE_TEMP_LABEL_Y, &label);
+            draw_large_label(display, CLIMATE_HMTY_LABEL_Y, "Humidity %");
+        }
+
+        let color = value_color(readings);
+        draw_large_text(display, CLIMATE_TEMP_VALUE_Y, &temperature, color);
+        draw_large_text(display, CLIMATE_HMTY_VALUE_Y, &humidity, color);
+
+        // The lines are different widths, so clear them rather than draw over the old ones.
+        if last.as_ref().map(|last| &last.2) != Some(&details) {
+            Rectangle::new(
+                Point::new(0, CLIMATE_DETAILS_Y),
+                Size::new(DISPLAY_W, DISPLAY_H - CLIMATE_DETAILS_Y as u32),
+            )
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+            .draw(display)
+            .unwrap();
+            for (i, line) in details.iter().enumerate() {
+                draw_medium_text(
+                    display,
+                    CLIMATE_DETAILS_Y + i as i32 * CLIMATE_DETAIL_SEP,
+                    line,
+                    Rgb565::WHITE,
+                );
+            }
+        }
+
+        self.last_drawn = Some((symbol, (temperature, humidity), details));
+    }
+
+    fn dwell_time(&self) -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn wants_refresh(&mut self, readings: &Readings) -> bool {
+        self.last_drawn.as_ref() != Some(&Self::text_for(readings))
+    }
+}
+
+/// Shown in kiosk mode instead of the pages while the sensor or network is gone: what's wrong in a
+/// red banner, the last known PM2.5 and temperature, and a timer counting up from when they were
+/// last good. Not part of the carousel, see `kiosk`.
+pub struct FallbackPage {
+    /// What's on screen, so it's only redrawn when something changes (the timer, once a second).
+    last_drawn: Option<(Fault, (String<8>, String<8>), String<24>)>,
+}
+
+impl FallbackPage {
+    pub const fn new() -> Self {
+        Self { last_drawn: None }
+    }
+
+    fn timer_for(fault: &Fault) -> String<24> {
+        let elapsed = Instant::now().saturating_duration_since(fault.since);
+        let secs = elapsed.as_secs();
+
+        let mut text = String::new();
+        if secs < 60 * 60 {
+            _ = write!(text, "{}m {:02}s ago", secs / 60, secs % 60);
+        } else {
+            _ = write!(text, "{} ago", ticker::Elapsed(elapsed));
+        }
+        text
+    }
+
+    fn values_for(readings: Option<&Readings>) -> (String<8>, String<8>) {
+        match readings {
+            Some(readings) => LargePage::text_for(readings),
+            None => (
+                String::try_from("...").unwrap(),
+                String::try_from("...").unwrap(),
+            ),
+        }
+    }
+
+    pub fn render(&mut self, display: &mut Display, readings: Option<&Readings>, fault: Fault) {
+        let values = Self::values_for(readings);
+        let timer = Self::timer_for(&fault);
+
+        let full = self.last_drawn.as_ref().map(|(f, ..)| f.kind) != Some(fault.kind);
+        if full {
+            display.clear(Rgb565::BLACK).unwrap();
+
+            Rectangle::new(Point::zero(), Size::new(DISPLAY_W, FALLBACK_BANNER_H))
+                .into_styled(PrimitiveStyle::with_fill(Rgb565::RED))
+                .draw(display)
+                .unwrap();
+            let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_helvB18_tf>();
+            font.render_aligned(
+                fault.kind.banner(),
+                Point::new(DISPLAY_W as i32 / 2, 8),
+                u8g2_fonts::types::VerticalPosition::Top,
+                HorizontalAlignment::Center,
+                u8g2_fonts::types::FontColor::Transparent(Rgb565::WHITE),
+                display,
+            )
+            .expect("couldn't render banner");
+
+            draw_large_label(display, FALLBACK_PM25_LABEL_Y, "Last PM2.5");
+            let mut label = String::<16>::new();
+            _ = write!(
+                label,
+                "Last temp {}",
+                settings::get().temperature_unit.symbol()
+            );
+            draw_large_label(display, FALLBACK_TEMP_LABEL_Y, &label);
+        }
+
+        draw_large_text(display, FALLBACK_PM25_VALUE_Y, &values.0, Rgb565::WHITE);
+        draw_large_text(display, FALLBACK_TEMP_VALUE_Y, &values.1, Rgb565::WHITE);
+        draw_status_line(display, FALLBACK_STATUS_Y, fault.kind.description());
+        draw_status_line(display, FALLBACK_STATUS_Y + FOOTER_H as i32, &timer);
+
+        self.last_drawn = Some((fault, values, timer));
+    }
+
+    /// Forget what's on screen, so the next render starts afresh.
+    pub fn invalidate(&mut self) {
+        self.last_drawn = None;
+    }
+
+    pub fn wants_refresh(&self, readings: Option<&Readings>, fault: Fault) -> bool {
+        self.last_drawn.as_ref()
+            != Some(&(fault, Self::values_for(readings), Self::timer_for(&fault)))
+    }
+}
+
+/// A bar per second, coloured by the health band it's in, on a scale that leaves out outliers (see
+/// `graph`), which is spelled out above. Each column is drawn top to bottom in one go, background
+/// and all, so there's no need to clear the graph first.
+fn draw_live_graph<D>(display: &mut D, samples: &live::Samples)
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let metric = samples.metric;
+    let sorted = Sorted::<{ live::SAMPLES }>::new(samples.values.iter().flatten().copied());
+    let scale = Scale::fit(&sorted, metric.descriptor().graph_step);
+    let bottom = LIVE_GRAPH_TOP + LIVE_GRAPH_H as i32;
+
+    // e.g. `Scale 0 to 50, 2 clipped`, since the bottom isn't always zero.
+    let mut floor = String::<8>::new();
+    let mut ceiling = String::<8>::new();
+    format_reading(&mut floor, metric, &Some(scale.floor));
+    format_reading(&mut ceiling, metric, &Some(scale.ceiling));
+    let mut line = String::<32>::new();
+    _ = write!(line, "Scale {} to {}", floor, ceiling);
+    let clipped = samples
+        .values
+        .iter()
+        .flatten()
+        .filter(|value| scale.above(**value));
+    match clipped.count() {
+        0 => {}
+        count => _ = write!(line, ", {} clipped", count),
+    }
+    draw_status_line(display, LIVE_SCALE_Y, &line);
+
+    for (i, value) in samples.values.iter().enumerate() {
+        let x = (i as u32 * LIVE_BAR_W) as i32;
+
+        let (height, color) = match value {
+            Some(value) => {
+                let color = match metric.health(*value) {
+                    Some(Health::Dangerous) => Rgb565::RED,
+                    Some(Health::Warning) => Rgb565::YELLOW,
+                    _ => Rgb565::GREEN,
+                };
+                let height = (scale.position(*value) * LIVE_GRAPH_H as f32) as u32;
+                (height.clamp(1, LIVE_GRAPH_H), color)
+            }
+            None => (0, Rgb565::BLACK),
+        };
+
+        Rectangle::new(
+            Point::new(x, LIVE_GRAPH_TOP),
+            Size::new(LIVE_BAR_W, LIVE_GRAPH_H - height),
+        )
+        .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+        .draw(display)
+        .unwrap();
+
+        // Leave a pixel between bars so they read as separate seconds.
+        Rectangle::new(
+            Point::new(x, bottom - height as i32),
+            Size::new(LIVE_BAR_W - 1, height),
+        )
+        .into_styled(PrimitiveStyle::with_fill(color))
+        .draw(display)
+        .unwrap();
+
+        if value.is_some_and(|value| scale.above(value)) {
+            Rectangle::new(
+                Point::new(x, LIVE_GRAPH_TOP),
+                Size::new(LIVE_BAR_W - 1, LIVE_CLIP_MARK_H),
+            )
+            .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+            .draw(display)
+            .unwrap();
+        }
+    }
+}
+
+/// A bar per day, oldest on the left, coloured by how good the score was. Days with no score get
+/// a stub so the gap is obvious.
+fn draw_score_chart<D>(display: &mut D, history: &History)
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let chart_w = HISTORY_DAYS as u32 * (SCORE_BAR_W + SCORE_BAR_GAP) - SCORE_BAR_GAP;
+    let left = (DISPLAY_W - chart_w) as i32 / 2;
+    let bottom = SCORE_CHART_TOP + SCORE_CHART_HEIGHT as i32;
+
+    for (i, day) in history.iter().enumerate() {
+        let x = left + (i as u32 * (SCORE_BAR_W + SCORE_BAR_GAP)) as i32;
+
+        let (height, color) = match day {
+            Some(score) => {
+                let color = match score {
+                    80.. => Rgb565::GREEN,
+                    50..=79 => Rgb565::YELLOW,
+                    _ => Rgb565::RED,
+                };
+                // Always at least a sliver, so a zero still shows up as a score.
+                let height = (*score as u32 * SCORE_CHART_HEIGHT / 100).max(2);
+                (height, color)
+            }
+            None => (2, Rgb565::new(8, 16, 8)),
+        };
+
+        Rectangle::new(
+            Point::new(x, bottom - height as i32),
+            Size::new(SCORE_BAR_W, height),
+        )
+        .into_styled(PrimitiveStyle::with_fill(color))
+        .draw(display)
+        .unwrap();
+    }
+}
+
+fn draw_reading<D>(
+    display: &mut D,
+    bg: &ReadingsBackground,
+    pos: Point,
+    metric: Metric,
+    readings: &Readings,
+    color: Rgb565,
+) where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_logisoso24_tn>();
+
+    let mut buf = String::<8>::new();
+    format_metric(&mut buf, metric, readings);
+    let content = buf.as_str();
+
+    // Build the tile off screen and send it in one go, so the plate and the new value never show
+    // up half drawn.
+    let mut tile = TileBuffer::new();
+
+    // Render background plate
+    Image::new(
+        &bg.sub_image(&Rectangle {
+            top_left: pos,
+            size: Size {
+                width: TILE_W as u32,
+                height: TILE_H as u32,
+            },
+        }),
+        Point::zero(),
+    )
+    .draw(&mut tile)
+    .unwrap();
+
+    font.render_aligned(
+        content,
+        Point::zero(),
+        u8g2_fonts::types::VerticalPosition::Top,
+        HorizontalAlignment::Left,
+        u8g2_fonts::types::FontColor::Transparent(color),
+        &mut tile,
+    )
+    .expect("couldn't render time");
+
+    Image::new(&tile.as_image(), pos).draw(display).unwrap();
+}
+
+/// One line of the ticker, centred along the bottom of the readings page on a strip cut from the
+/// background. An empty line just clears the strip.
+fn draw_footer<D>(display: &mut D, bg: &ReadingsBackground, text: &str)
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_6x13_tf>();
+    let pos = Point::new(0, FOOTER_Y);
+
+    let mut strip = FooterBuffer::new();
+    Image::new(
+        &bg.sub_image(&Rectangle::new(pos, Size::new(DISPLAY_W, FOOTER_H as u32))),
+        Point::zero(),
+    )
+    .draw(&mut strip)
+    .unwrap();
+
+    if !text.is_empty() {
+        font.render_aligned(
+            text,
+            Point::new(DISPLAY_W as i32 / 2, 2),
+            u8g2_fonts::types::VerticalPosition::Top,
+            HorizontalAlignment::Center,
+            u8g2_fonts::types::FontColor::Transparent(bg.value_color()),
+            &mut strip,
+        )
+        .expect("couldn't render footer");
+    }
+
+    Image::new(&strip.as_image(), pos).draw(display).unwrap();
+}
+
+/// A line of small white text, centred on black, replacing whatever was on that line before.
+fn draw_status_line<D>(display: &mut D, y: i32, text: &str)
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_6x13_tf>();
+
+    let mut strip = FooterBuffer::new();
+    strip.clear(Rgb565::BLACK).unwrap();
+    font.render_aligned(
+        text,
+        Point::new(DISPLAY_W as i32 / 2, 2),
+        u8g2_fonts::types::VerticalPosition::Top,
+        HorizontalAlignment::Center,
+        u8g2_fonts::types::FontColor::Transparent(Rgb565::WHITE),
+        &mut strip,
+    )
+    .expect("couldn't render status line");
+
+    Image::new(&strip.as_image(), Point::new(0, y))
+        .draw(display)
+        .unwrap();
+}
+
+/// White, or grey if the readings are stale, so nobody trusts numbers that have stopped updating.
+fn value_color(readings: &Readings) -> Rgb565 {
+    match readings.is_stale() {
+        true => STALE_COLOR,
+        false => Rgb565::WHITE,
+    }
+}
+
+/// Format a reading with fewer decimal places the bigger it is, so it's always about the same width,
+/// but never more than the metric's precision, with the language's decimal separator.
+/// Format a metric from the readings as it's shown on screen: `--` if the sensor says it doesn't
+/// have a value, rather than the `...` of one that's still being averaged.
+fn format_metric(buf: &mut String<8>, metric: Metric, readings: &Readings) {
+    let value = metric.shown_value(readings);
+    if value.is_none() && readings.unavailable.contains(metric) {
+        buf.push_str("--").unwrap();
+    } else {
+        format_reading(buf, metric, &value);
+    }
+}
+
+fn format_reading(buf: &mut String<8>, metric: Metric, value: &Option<f32>) {
+    let Some(v) = value else {
+        buf.push_str("...").unwrap();
+        return;
+    };
+
+    let decimals = match v {
+        v if *v >= 100.0 => 0,
+        v if *v >= 10.0 => 1,
+        _ => 2,
+    };
+    let decimals = decimals.min(usize::from(metric.descriptor().precision));
+    settings::get()
+        .language
+        .write_number(buf, *v, decimals)
+        .unwrap();
+}
+
+fn draw_large_label<D>(display: &mut D, y: i32, label: &str)
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_helvB18_tf>();
+
+    font.render_aligned(
+        label,
+        Point::new(DISPLAY_W as i32 / 2, y),
+        u8g2_fonts::types::VerticalPosition::Top,
+        HorizontalAlignment::Center,
+        u8g2_fonts::types::FontColor::Transparent(Rgb565::WHITE),
+        display,
+    )
+    .expect("couldn't render label");
+}
+
+/// A line of medium text, centred, a little smaller than a label so longer lines fit across the screen.
+fn draw_medium_text<D>(display: &mut D, y: i32, text: &str, color: Rgb565)
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_helvB14_tf>();
+
+    font.render_aligned(
+        text,
+        Point::new(DISPLAY_W as i32 / 2, y),
+        u8g2_fonts::types::VerticalPosition::Top,
+        HorizontalAlignment::Center,
+        u8g2_fonts::types::FontColor::Transparent(color),
+        display,
+    )
+    .expect("couldn't render text");
+}
+
+fn draw_large_value<D>(display: &mut D, y: i32, metric: Metric, value: &Option<f32>, color: Rgb565)
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let mut buf = String::<8>::new();
+    format_reading(&mut buf, metric, value);
+    draw_large_text(display, y, &buf, color);
+}
+
+fn draw_large_text<D>(display: &mut D, y: i32, text: &str, color: Rgb565)
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_logisoso50_tn>();
+
+    // Like the tiles, draw off screen first so the old value is replaced in a single write rather
+    // than blanked and redrawn.
+    LARGE_VALUE_STRIP.lock(|strip| {
+        let mut strip = strip.borrow_mut();
+        strip.clear(Rgb565::BLACK).unwrap();
+
+        font.render_aligned(
+            text,
+            Point::new(DISPLAY_W as i32 / 2, 0),
+            u8g2_fonts::types::VerticalPosition::Top,
+            HorizontalAlignment::Center,
+            u8g2_fonts::types::FontColor::Transparent(color),
+            &mut *strip,
+        )
+        .expect("couldn't render value");
+
+        Image::new(&strip.as_image(), Point::new(0, y))
+            .draw(display)
+            .unwrap();
+    });
+}
+
+/// A strip along the bottom spelling out the health, since colour alone isn't enough.
+fn draw_health_banner<D>(display: &mut D, health: &Health)
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let (text, fg, bg) = match health {
+        Health::Ok => ("Air OK", Rgb565::WHITE, Rgb565::BLACK),
+        Health::Warning => ("Air poor", Rgb565::BLACK, Rgb565::YELLOW),
+        Health::Dangerous => ("AIR BAD", Rgb565::BLACK, Rgb565::WHITE),
+    };
+
+    Rectangle::new(
+        Point::new(0, HC_BANNER_Y),
+        Size::new(DISPLAY_W, DISPLAY_H - HC_BANNER_Y as u32),
+    )
+    .into_styled(PrimitiveStyle::with_fill(bg))
+    .draw(display)
+    .unwrap();
+
+    let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_helvB18_tf>();
+    font.render_aligned(
+        text,
+        Point::new(DISPLAY_W as i32 / 2, HC_BANNER_Y + 8),
+        u8g2_fonts::types::VerticalPosition::Top,
+        HorizontalAlignment::Center,
+        u8g2_fonts::types::FontColor::Transparent(fg),
+        display,
+    )
+    .expect("couldn't render health");
+
+    // Flag anything but good air with a warning sign at each end, so it reads at a glance.
+    if !matches!(health, Health::Ok) {
+        let y = HC_BANNER_Y + 12;
+        icons::draw(display, Icon::Warning, Point::new(16, y), fg).unwrap();
+        icons::draw(
+            display,
+            Icon::Warning,
+            Point::new(DISPLAY_W as i32 - 16 - ICON_SIZE as i32, y),
+            fg,
+        )
+        .unwrap();
+    }
+}
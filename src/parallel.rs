@@ -0,0 +1,103 @@
+//! An 8080-style parallel bus for panels that have one, driven by PIO, for the
+//! `parallel-display` feature.
+//!
+//! A byte goes out a write cycle at a time on eight data lines (GP0 to GP7, D0 to D7) and is
+//! latched by the rising edge of WR (GP18), about twice as fast as the SPI bus manages. DC, CS,
+//! RST, TE and the backlight stay on the pins they use over SPI, and RD is tied high as nothing's
+//! ever read back. The data lines take GP2 and GP3 from the noise microphone, so it can't be used
+//! alongside.
+
+use core::convert::Infallible;
+
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::peripherals::{DMA_CH2, PIO1};
+use embassy_rp::pio::{
+    Common, Config, Direction, Pin, PioPin, ShiftConfig, ShiftDirection, StateMachine,
+};
+use embassy_rp::{Peripheral, PeripheralRef};
+use fixed::traits::ToFixed;
+
+use crate::st7789::Bus;
+
+/// Write cycles a second. The ST7789 wants at least 66 ns for each, and the state machine takes
+/// two instructions.
+const WRITE_CYCLE_HZ: u32 = 15_000_000;
+
+/// The parallel bus's state machine, and the DMA channel that feeds it.
+pub struct Parallel {
+    sm: StateMachine<'static, PIO1, 2>,
+    dma: PeripheralRef<'static, DMA_CH2>,
+}
+
+impl Parallel {
+    /// `data` must be eight consecutive pins, D0 first.
+    pub fn new(
+        common: &mut Common<'static, PIO1>,
+        mut sm: StateMachine<'static, PIO1, 2>,
+        wr_pin: impl PioPin,
+        data: [Pin<'static, PIO1>; 8],
+        dma: DMA_CH2,
+    ) -> Self {
+        // WR rises (latching the last byte) while waiting for the next, and falls as it's put on
+        // the data lines, so it idles high between writes.
+        let program = pio_proc::pio_asm!(
+            ".side_set 1",
+            ".wrap_target",
+            "    pull         side 1",
+            "    out pins, 8  side 0",
+            ".wrap",
+        );
+        let program = common.load_program(&program.program);
+
+        let wr = common.make_pio_pin(wr_pin);
+        let data = data.each_ref();
+
+        let mut cfg = Config::default();
+        cfg.use_program(&program, &[&wr]);
+        cfg.set_out_pins(&data);
+        cfg.clock_divider = (clk_sys_freq() as f32 / (WRITE_CYCLE_HZ * 2) as f32).to_fixed();
+        cfg.shift_out = ShiftConfig {
+            auto_fill: false,
+            threshold: 32,
+            direction: ShiftDirection::Right,
+        };
+
+        sm.set_config(&cfg);
+        sm.set_pin_dirs(Direction::Out, &[&wr]);
+        sm.set_pin_dirs(Direction::Out, &data);
+        sm.set_enable(true);
+
+        Self {
+            sm,
+            dma: dma.into_ref(),
+        }
+    }
+
+    /// Wait for the last byte to be latched, which is when the state machine's back to waiting for
+    /// another with nothing left in the FIFO. The stall flag sticks, so it's cleared first.
+    fn flush(&mut self) {
+        while !self.sm.tx().empty() {}
+        self.sm.tx().stalled();
+        while !self.sm.tx().stalled() {}
+    }
+}
+
+impl Bus for Parallel {
+    type Error = Infallible;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Infallible> {
+        for &byte in bytes {
+            while !self.sm.tx().try_push(byte as u32) {}
+        }
+        self.flush();
+        Ok(())
+    }
+
+    async fn write_async(&mut self, bytes: &[u8]) -> Result<(), Infallible> {
+        // Bytes written to the FIFO are repeated across the word, so the low eight bits that go
+        // out are the byte either way.
+        self.sm.tx().dma_push(self.dma.reborrow(), bytes).await;
+        self.flush();
+        Ok(())
+    }
+}
@@ -0,0 +1,570 @@
+//! Wi-Fi setup for when the configured network can't be joined, so a device can be flashed once
+//! and set up wherever it ends up.
+//!
+//! With `WIFI_PORTAL_AFTER` set, once that many attempts to join have failed the CYW43 opens a
+//! network of its own, `Vindskrivare-<identifier>`, and serves a form for the Wi-Fi network,
+//! password and broker at `http://192.168.4.1/`. Clients are handed an address by a tiny DHCP
+//! server and have every name looked up answered with the device's own address, so most phones
+//! and laptops pop the form up as a captive portal as soon as they join. Saving it stores the new
+//! config (see `device_config`) and restarts the device to use it. The screen shows the network
+//! as a `WIFI:` QR code, so a phone can join it by scanning rather than typing.
+
+use core::fmt::Write as _;
+
+use embassy_futures::select::select3;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{ConfigV4, IpAddress, IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4};
+use embassy_time::{Duration, Timer, WithTimeout};
+use embedded_io_async::{Read, Write};
+use heapless::{String, Vec};
+use log::{info, warn};
+
+use crate::http_server;
+use crate::sockets::{self, User};
+use crate::state::{self, ConnectionState};
+use crate::traffic::{self, Counted};
+use crate::ui::UiController;
+use crate::{alarm_log, config, device_config, retained};
+
+/// Whether the portal's been set up at all.
+pub const ENABLED: bool = config::WIFI_PORTAL_AFTER.is_some();
+
+/// The form's TCP socket, and the DHCP and DNS servers' UDP ones.
+pub const SOCKETS: usize = 3;
+
+/// Used when `WIFI_PORTAL_AFTER` isn't a number.
+const DEFAULT_ATTEMPTS: u32 = 5;
+
+/// The device's address on its own network, and the one every name resolves to.
+const ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 4, 1);
+
+const CHANNEL: u8 = 6;
+
+/// Clients get `192.168.4.<FIRST_LEASE>` onwards, one per MAC address.
+const FIRST_LEASE: u8 = 10;
+const LEASES: usize = 8;
+
+/// How long a lease is good for, in seconds. Nobody's on the network for long.
+const LEASE_TIME: u32 = 3600;
+
+/// Room for the request line, headers and the form's body.
+const REQUEST_SIZE: usize = 1024;
+
+/// How long a client gets to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many failed attempts to join the network it takes to open the portal, or `None` if it's not
+/// set up.
+pub fn attempts() -> Option<u32> {
+    let attempts = config::WIFI_PORTAL_AFTER?;
+    match attempts.trim().parse() {
+        Ok(attempts) => Some(attempts),
+        Err(_) => {
+            warn!(
+                "WIFI_PORTAL_AFTER isn't a number, opening the portal after {} attempts",
+                DEFAULT_ATTEMPTS
+            );
+            Some(DEFAULT_ATTEMPTS)
+        }
+    }
+}
+
+/// Open the access point and serve the form until it's filled in, then restart to use it.
+pub async fn run(
+    control: &mut cyw43::Control<'_>,
+    stack: Stack<'static>,
+    display: &mut UiController,
+) -> ! {
+    let mut ssid = String::<32>::new();
+    for c in "Vindskrivare-"
+        .chars()
+        .chain(device_config::get().identifier.chars())
+    {
+        if ssid.push(c).is_err() {
+            break;
+        }
+    }
+
+    info!("Opening the Wi-Fi setup portal as {}", ssid.as_str());
+    state::set_connection(ConnectionState::Provisioning);
+    match config::WIFI_PORTAL_PASSWORD {
+        Some(password) => control.start_ap_wpa2(&ssid, password, CHANNEL).await,
+        None => control.start_ap_open(&ssid, CHANNEL).await,
+    }
+
+    stack.set_config_v4(ConfigV4::Static(StaticConfigV4 {
+        address: Ipv4Cidr::new(ADDRESS, 24),
+        gateway: None,
+        dns_servers: Vec::new(),
+    }));
+
+    let mut join_line = String::<48>::new();
+    _ = write!(join_line, "Join {}", ssid);
+    let status = ["Couldn't join Wi-Fi", &join_line, "then open 192.168.4.1"];
+    if display.render_qr(&wifi_qr(&ssid), &status).is_err() {
+        display.render_connecting_status(&status);
+    }
+
+    select3(serve_dhcp(stack), serve_dns(stack), serve_form(stack)).await;
+
+    info!("Wi-Fi setup saved, restarting");
+    Timer::after_secs(1).await;
+    alarm_log::save().await;
+    retained::warm_reboot();
+}
+
+/// The portal's network as a `WIFI:` QR code's text, which phones offer to join when scanned.
+fn wifi_qr(ssid: &str) -> String<128> {
+    fn push_escaped(text: &mut String<128>, value: &str) {
+        for c in value.chars() {
+            if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+                _ = text.push('\\');
+            }
+            _ = text.push(c);
+        }
+    }
+
+    let mut text = String::new();
+    _ = text.push_str("WIFI:S:");
+    push_escaped(&mut text, ssid);
+    match config::WIFI_PORTAL_PASSWORD {
+        Some(password) => {
+            _ = text.push_str(";T:WPA;P:");
+            push_escaped(&mut text, password);
+        }
+        None => _ = text.push_str(";T:nopass"),
+    }
+    _ = text.push_str(";;");
+
+    text
+}
+
+/// Hands out addresses, one per client, with the device as their router and DNS server.
+async fn serve_dhcp(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+
+    let _claim = sockets::claim(User::Portal);
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(67) {
+        warn!("Couldn't bind the portal's DHCP socket: {:?}", e);
+        return core::future::pending().await;
+    }
+
+    let mut leases = Vec::<[u8; 6], LEASES>::new();
+    let mut request = [0u8; 576];
+    let mut reply = [0u8; 300];
+    let clients = IpEndpoint::new(IpAddress::Ipv4(Ipv4Address::BROADCAST), 68);
+
+    loop {
+        let Ok((n, _)) = socket.recv_from(&mut request).await else {
+            continue;
+        };
+        traffic::received(User::Portal, n);
+
+        let Some(len) = dhcp_reply(&request[..n], &mut leases, &mut reply) else {
+            continue;
+        };
+        if socket.send_to(&reply[..len], clients).await.is_ok() {
+            traffic::sent_datagram(User::Portal, len);
+        }
+    }
+}
+
+/// The offer or acknowledgement for a DHCP discover or request, written to `reply`, returning its
+/// length. `None` for anything else.
+fn dhcp_reply(
+    request: &[u8],
+    leases: &mut Vec<[u8; 6], LEASES>,
+    reply: &mut [u8; 300],
+) -> Option<usize> {
+    const MAGIC: [u8; 4] = [99, 130, 83, 99];
+    const DISCOVER: u8 = 1;
+    const OFFER: u8 = 2;
+    const REQUEST: u8 = 3;
+    const ACK: u8 = 5;
+
+    // A boot request, on Ethernet, with the magic cookie where the options start.
+    if request.len() < 240 || request[0] != 1 || request[1] != 1 || request[2] != 6 {
+        return None;
+    }
+    if request[236..240] != MAGIC {
+        return None;
+    }
+
+    let mut kind = None;
+    let mut options = &request[240..];
+    while let [code, rest @ ..] = options {
+        match code {
+            0 => options = rest,
+            255 => break,
+            _ => {
+                let [len, rest @ ..] = rest else { break };
+                let value = rest.get(..usize::from(*len))?;
+                if *code == 53 {
+                    kind = value.first().copied();
+                }
+                options = &rest[usize::from(*len)..];
+            }
+        }
+    }
+    let answer = match kind? {
+        DISCOVER => OFFER,
+        REQUEST => ACK,
+        _ => return None,
+    };
+
+    let mac: [u8; 6] = request[28..34].try_into().ok()?;
+    let index = match leases.iter().position(|lease| *lease == mac) {
+        Some(index) => index,
+        None if leases.push(mac).is_ok() => leases.len() - 1,
+        // Full, so share one out. It's only for long enough to fill in a form.
+        None => usize::from(mac[5]) % LEASES,
+    };
+    let address = Ipv4Address::new(192, 168, 4, FIRST_LEASE + index as u8);
+
+    reply.fill(0);
+    reply[0] = 2;
+    reply[1..3].copy_from_slice(&request[1..3]);
+    // Transaction ID, flags and hardware address, as the client sent them.
+    reply[4..8].copy_from_slice(&request[4..8]);
+    reply[10..12].copy_from_slice(&request[10..12]);
+    reply[16..20].copy_from_slice(&address.octets());
+    reply[20..24].copy_from_slice(&ADDRESS.octets());
+    reply[28..44].copy_from_slice(&request[28..44]);
+    reply[236..240].copy_from_slice(&MAGIC);
+
+    let server = ADDRESS.octets();
+    let lease = LEASE_TIME.to_be_bytes();
+    let options: [&[u8]; 7] = [
+        &[53, 1, answer],
+        &[54, 4, server[0], server[1], server[2], server[3]],
+        &[51, 4, lease[0], lease[1], lease[2], lease[3]],
+        &[1, 4, 255, 255, 255, 0],
+        &[3, 4, server[0], server[1], server[2], server[3]],
+        &[6, 4, server[0], server[1], server[2], server[3]],
+        &[255],
+    ];
+    let mut len = 240;
+    for option in options {
+        reply[len..len + option.len()].copy_from_slice(option);
+        len += option.len();
+    }
+
+    Some(len)
+}
+
+/// Answers every lookup for an address with the device's own, so whatever a client tries to load
+/// ends up at the form.
+async fn serve_dns(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0u8; 1024];
+
+    let _claim = sockets::claim(User::Portal);
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(53) {
+        warn!("Couldn't bind the portal's DNS socket: {:?}", e);
+        return core::future::pending().await;
+    }
+
+    let mut packet = [0u8; 512];
+    loop {
+        let Ok((n, meta)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        traffic::received(User::Portal, n);
+
+        let Some(len) = dns_reply(&mut packet, n) else {
+            continue;
+        };
+        if socket.send_to(&packet[..len], meta).await.is_ok() {
+            traffic::sent_datagram(User::Portal, len);
+        }
+    }
+}
+
+/// Turn the query in the first `len` bytes of `packet` into its answer, returning the answer's
+/// length. `None` if it isn't a query for a single name.
+fn dns_reply(packet: &mut [u8; 512], len: usize) -> Option<usize> {
+    const A: [u8; 2] = [0, 1];
+
+    // A standard query, with one question.
+    if len < 12 || packet[2] & 0xF8 != 0 || packet[4..6] != [0, 1] {
+        return None;
+    }
+
+    // The question's name is a run of labels, ending with an empty one, then its type and class.
+    let mut end = 12;
+    while *packet.get(end)? != 0 {
+        end += 1 + usize::from(packet[end]);
+    }
+    let question_end = end + 5;
+    if question_end > len {
+        return None;
+    }
+    let is_a = packet[end + 1..end + 3] == A;
+
+    // A response, recursion available, with one answer for an address and none otherwise. Anything
+    // after the question (like an EDNS record) is left off.
+    packet[2] |= 0x80;
+    packet[3] = 0x80;
+    packet[6..12].copy_from_slice(&[0, is_a as u8, 0, 0, 0, 0]);
+    if !is_a {
+        return Some(question_end);
+    }
+
+    let address = ADDRESS.octets();
+    #[rustfmt::skip]
+    let answer = [
+        0xC0, 12,       // The question's name, pointed back to
+        0, 1,           // A
+        0, 1,           // IN
+        0, 0, 0, 60,    // TTL, a minute
+        0, 4,           // The address's length, then the address
+        address[0], address[1], address[2], address[3],
+    ];
+    packet
+        .get_mut(question_end..question_end + answer.len())?
+        .copy_from_slice(&answer);
+
+    Some(question_end + answer.len())
+}
+
+/// Serves the form to anything that asks for a page, until it's been filled in.
+async fn serve_form(stack: Stack<'static>) {
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 2048];
+
+    let _claim = sockets::claim(User::Portal);
+    loop {
+        let mut socket = Counted::new(
+            TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer),
+            User::Portal,
+        );
+        socket.set_timeout(Some(REQUEST_TIMEOUT));
+        if let Err(e) = socket.accept(80).await {
+            warn!("Portal accept failed: {:?}", e);
+            continue;
+        }
+
+        let mut request = [0u8; REQUEST_SIZE];
+        let saved = match read_request(&mut socket, &mut request)
+            .with_timeout(REQUEST_TIMEOUT)
+            .await
+        {
+            Ok(Some((end, total))) if request.starts_with(b"POST /save ") => {
+                let body = core::str::from_utf8(&request[end + 4..total]).unwrap_or_default();
+                save(&mut socket, body).await
+            }
+            Ok(Some(_)) => {
+                send_form(&mut socket).await;
+                false
+            }
+            Ok(None) | Err(_) => false,
+        };
+
+        socket.close();
+        _ = socket.flush().await;
+        if saved {
+            return;
+        }
+    }
+}
+
+/// Read a request into `buf`, returning where its head ends and its body does. `None` if the
+/// connection closed first or it doesn't fit.
+async fn read_request(
+    socket: &mut Counted<TcpSocket<'_>>,
+    buf: &mut [u8; REQUEST_SIZE],
+) -> Option<(usize, usize)> {
+    let mut len = 0;
+    loop {
+        if let Some(end) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            let total = end + 4 + content_length(&buf[..end]);
+            if total > buf.len() {
+                return None;
+            }
+            if len >= total {
+                return Some((end, total));
+            }
+        }
+        if len == buf.len() {
+            return None;
+        }
+        match socket.read(&mut buf[len..]).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => len += n,
+        }
+    }
+}
+
+/// The `Content-Length` in a request's head, or 0 without one.
+fn content_length(head: &[u8]) -> usize {
+    core::str::from_utf8(head)
+        .unwrap_or_default()
+        .split("\r\n")
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// The form, filled in with what the device has now (bar the passwords, which are kept unless
+/// something new is typed in).
+async fn send_form(socket: &mut Counted<TcpSocket<'_>>) {
+    let config = device_config::get();
+
+    let mut page = String::<2048>::new();
+    _ = page.push_str(
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+         <title>Vindskrivare setup</title></head><body><h1>Vindskrivare setup</h1>\
+         <form method=\"post\" action=\"/save\">\
+         <p><label>Wi-Fi network<br><input name=\"wifi_network\" value=\"",
+    );
+    escape(&mut page, &config.wifi_network);
+    _ = page.push_str(
+        "\"></label></p><p><label>Wi-Fi password (leave blank to keep)<br>\
+         <input name=\"wifi_password\" type=\"password\"></label></p>\
+         <p><label>MQTT broker (blank for none)<br><input name=\"mqtt_host\" value=\"",
+    );
+    escape(&mut page, &config.mqtt_host);
+    _ = page.push_str(
+        "\"></label></p><p><label>MQTT username (blank for none)<br>\
+         <input name=\"mqtt_username\" value=\"",
+    );
+    escape(&mut page, &config.mqtt_username);
+    _ = page.push_str(
+        "\"></label></p><p><label>MQTT password (leave blank to keep)<br>\
+         <input name=\"mqtt_password\" type=\"password\"></label></p>\
+         <p><button>Save and restart</button></p></form></body></html>",
+    );
+
+    send_page(socket, "200 OK", &page).await;
+}
+
+/// Store what was filled in, returning whether it was.
+async fn save(socket: &mut Counted<TcpSocket<'_>>, body: &str) -> bool {
+    const KEYS: [&str; 5] = [
+        "wifi_network",
+        "wifi_password",
+        "mqtt_host",
+        "mqtt_username",
+        "mqtt_password",
+    ];
+    let mut fields: [Option<String<64>>; 5] = Default::default();
+
+    for (name, value) in body.split('&').filter_map(|pair| pair.split_once('=')) {
+        if let Some(i) = KEYS.iter().position(|key| *key == name) {
+            fields[i] = decode(value);
+        }
+    }
+
+    let Some(fields) = fields
+        .iter()
+        .map(Option::as_ref)
+        .collect::<Option<Vec<_, 5>>>()
+    else {
+        send_page(
+            socket,
+            "400 Bad Request",
+            "Something's missing, go back and try again.",
+        )
+        .await;
+        return false;
+    };
+    if fields[0].is_empty() {
+        send_page(
+            socket,
+            "400 Bad Request",
+            "The Wi-Fi network can't be blank.",
+        )
+        .await;
+        return false;
+    }
+
+    let changes = KEYS
+        .iter()
+        .zip(fields)
+        .filter(|(key, value)| !(key.ends_with("_password") && value.is_empty()))
+        .map(|(key, value)| (*key, value.as_str()))
+        .collect::<Vec<_, 5>>();
+
+    match device_config::update_all(&changes).await {
+        Ok(true) => {}
+        Ok(false) => {
+            send_page(
+                socket,
+                "400 Bad Request",
+                "That's too long, go back and try again.",
+            )
+            .await;
+            return false;
+        }
+        Err(_) => {
+            send_page(socket, "500 Internal Server Error", "Couldn't save that.").await;
+            return false;
+        }
+    }
+
+    send_page(socket, "200 OK", "Saved, restarting to join the network.").await;
+    true
+}
+
+async fn send_page(socket: &mut Counted<TcpSocket<'_>>, status: &str, page: &str) {
+    if http_server::send_head(socket, status, Some("text/html"), page.len(), true)
+        .await
+        .is_ok()
+    {
+        _ = socket.write_all(page.as_bytes()).await;
+    }
+}
+
+/// Undo a form's URL encoding, or `None` if it's not valid or doesn't fit.
+fn decode<const N: usize>(value: &str) -> Option<String<N>> {
+    let mut bytes = Vec::<u8, N>::new();
+    let mut rest = value.as_bytes();
+    while let [byte, tail @ ..] = rest {
+        let (decoded, tail) = match byte {
+            b'+' => (b' ', tail),
+            b'%' => {
+                let hex = core::str::from_utf8(tail.get(..2)?).ok()?;
+                (u8::from_str_radix(hex, 16).ok()?, &tail[2..])
+            }
+            _ => (*byte, tail),
+        };
+        bytes.push(decoded).ok()?;
+        rest = tail;
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Write `value` to `page` with anything that'd break out of an attribute escaped.
+fn escape<const N: usize>(page: &mut String<N>, value: &str) {
+    for c in value.chars() {
+        _ = match c {
+            '&' => page.push_str("&amp;"),
+            '<' => page.push_str("&lt;"),
+            '>' => page.push_str("&gt;"),
+            '"' => page.push_str("&quot;"),
+            _ => page.push(c),
+        };
+    }
+}
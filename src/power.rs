@@ -0,0 +1,155 @@
+//! Power profiles, trading responsiveness for power. Each one bundles how often the screen is
+//! redrawn, how often readings are published, the Wi-Fi chip's power saving and how long the
+//! readings are averaged over.
+//!
+//! Like the mode, the profile is one of the runtime settings, picked from Home Assistant. The
+//! screen, publishing and averaging pick it up the next time they ask; the Wi-Fi worker applies the
+//! Wi-Fi power saving as soon as it changes.
+//!
+//! Some access points don't get on with the chip's power saving, and answer it late, so the
+//! balanced profile's power saving can be changed with `WIFI_POWER`.
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::Duration;
+
+use crate::{config, settings};
+
+/// How much the Wi-Fi chip sleeps between beacons, from not at all to as much as it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum WifiPower {
+    None,
+    PowerSave,
+    Aggressive,
+    SuperSave,
+}
+
+impl WifiPower {
+    pub const ALL: [WifiPower; 4] = [
+        WifiPower::None,
+        WifiPower::PowerSave,
+        WifiPower::Aggressive,
+        WifiPower::SuperSave,
+    ];
+
+    /// The name used in `WIFI_POWER` and the diagnostics.
+    pub const fn key(&self) -> &'static str {
+        match self {
+            WifiPower::None => "none",
+            WifiPower::PowerSave => "power_save",
+            WifiPower::Aggressive => "aggressive",
+            WifiPower::SuperSave => "super_save",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<WifiPower> {
+        WifiPower::ALL.into_iter().find(|p| p.key() == key)
+    }
+
+    pub const fn mode(&self) -> cyw43::PowerManagementMode {
+        match self {
+            WifiPower::None => cyw43::PowerManagementMode::None,
+            WifiPower::PowerSave => cyw43::PowerManagementMode::PowerSave,
+            WifiPower::Aggressive => cyw43::PowerManagementMode::Aggressive,
+            WifiPower::SuperSave => cyw43::PowerManagementMode::SuperSave,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PowerProfile {
+    /// Redraw and publish as soon as anything changes, with Wi-Fi power saving off and shorter
+    /// averages, so changes show up quickly.
+    Responsive,
+    /// The normal behaviour, and the default.
+    Balanced,
+    /// Redraw and publish less often, with the Wi-Fi chip sleeping as much as it can.
+    LowPower,
+}
+
+impl PowerProfile {
+    pub const ALL: [PowerProfile; 3] = [
+        PowerProfile::Responsive,
+        PowerProfile::Balanced,
+        PowerProfile::LowPower,
+    ];
+
+    /// The name used in MQTT payloads.
+    pub const fn key(&self) -> &'static str {
+        match self {
+            PowerProfile::Responsive => "responsive",
+            PowerProfile::Balanced => "balanced",
+            PowerProfile::LowPower => "low_power",
+        }
+    }
+
+    pub fn from_key(key: &str) -> Option<PowerProfile> {
+        PowerProfile::ALL.into_iter().find(|p| p.key() == key)
+    }
+
+    /// The shortest time between frames drawn on the screen.
+    pub const fn frame_interval(&self) -> Duration {
+        match self {
+            PowerProfile::Responsive => Duration::from_millis(100),
+            PowerProfile::Balanced => Duration::from_millis(250),
+            PowerProfile::LowPower => Duration::from_secs(2),
+        }
+    }
+
+    /// The shortest time between published readings, on top of what the mode and the cadence
+    /// ask for. Dangerous readings are always published.
+    pub const fn publish_interval(&self) -> Duration {
+        match self {
+            PowerProfile::Responsive | PowerProfile::Balanced => Duration::from_ticks(0),
+            PowerProfile::LowPower => Duration::from_secs(60),
+        }
+    }
+
+    /// The Wi-Fi chip's power saving. The balanced profile's is `WIFI_POWER`'s, if that's set.
+    pub fn wifi_power(&self) -> WifiPower {
+        match self {
+            PowerProfile::Responsive => WifiPower::None,
+            PowerProfile::Balanced => config::WIFI_POWER
+                .and_then(|key| WifiPower::from_key(key.trim()))
+                .unwrap_or(WifiPower::PowerSave),
+            PowerProfile::LowPower => WifiPower::SuperSave,
+        }
+    }
+
+    /// What share of each rolling average's window to average over, as a divisor.
+    pub const fn window_divisor(&self) -> usize {
+        match self {
+            PowerProfile::Responsive => 3,
+            PowerProfile::Balanced | PowerProfile::LowPower => 1,
+        }
+    }
+
+    pub(crate) const fn to_byte(self) -> u8 {
+        self as u8
+    }
+
+    pub(crate) const fn from_byte(byte: u8) -> Option<PowerProfile> {
+        match byte {
+            0 => Some(PowerProfile::Responsive),
+            1 => Some(PowerProfile::Balanced),
+            2 => Some(PowerProfile::LowPower),
+            _ => None,
+        }
+    }
+}
+
+/// Signalled with the new profile whenever it changes.
+pub static CHANGED: Signal<ThreadModeRawMutex, PowerProfile> = Signal::new();
+
+/// The current profile.
+pub fn get() -> PowerProfile {
+    settings::get().power_profile
+}
+
+/// Switch to `profile`, saving it with the other settings.
+pub async fn set(profile: PowerProfile) {
+    if get() != profile {
+        settings::update(|s| s.power_profile = profile).await;
+        CHANGED.signal(profile);
+    }
+}
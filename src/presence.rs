@@ -0,0 +1,35 @@
+use core::cell::Cell;
+
+use defmt::info;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+/// How long the room has to be empty before the SEN55's fan is stopped to save wear.
+const FAN_SAVER_AFTER: Duration = Duration::from_secs(4 * 60 * 60);
+
+/// Whether anyone was last seen in the room, and when that last changed. `None` until the first
+/// presence message arrives.
+static PRESENCE: Mutex<ThreadModeRawMutex, Cell<Option<(bool, Instant)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Record whether anyone's in the room, e.g. from a Home Assistant occupancy sensor.
+pub fn set(present: bool) {
+    PRESENCE.lock(|p| match p.get() {
+        // Repeats of the same state don't restart the clock.
+        Some((was_present, _)) if was_present == present => {}
+        _ => {
+            info!("Presence now {}", present);
+            p.set(Some((present, Instant::now())));
+        }
+    });
+}
+
+/// Whether the room has been empty long enough to stop the fan. Never true if no presence has
+/// been heard of, so the sensor runs as normal without a presence topic.
+pub fn fan_saver_wanted() -> bool {
+    match PRESENCE.lock(|p| p.get()) {
+        Some((false, since)) => since.elapsed() > FAN_SAVER_AFTER,
+        _ => false,
+    }
+}
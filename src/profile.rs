@@ -0,0 +1,134 @@
+//! A rough CPU profiler: how much of the time each of the busier tasks spends running.
+//!
+//! Each tracked task's body is wrapped with `track`, which times every poll of it. The executor
+//! only ever runs one task at a time and a task's code only runs inside its poll, so the total
+//! is that task's CPU time, give or take the interrupts that happened to land in it. Whatever
+//! isn't counted is either idle or one of the small tasks that isn't tracked.
+//!
+//! The main loop rolls the totals into percentages once a minute. They're published on the
+//! `cpu` topic and included in the console's `dump`.
+
+use core::cell::Cell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use portable_atomic::{AtomicU32, Ordering};
+use serde::Serialize;
+
+/// The tasks whose CPU time is tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Job {
+    Sen55,
+    /// The MQTT worker, or the LAN worker standing in for it.
+    Mqtt,
+    Ui,
+    /// embassy-net's stack.
+    Net,
+    /// The CYW43 driver.
+    Wifi,
+    /// The USB device stack and debug console.
+    Usb,
+}
+
+const JOB_COUNT: usize = 6;
+
+/// Microseconds spent in each job since boot. Wraps after about 71 minutes, which is fine as only
+/// the differences are used.
+static BUSY_US: [AtomicU32; JOB_COUNT] = [const { AtomicU32::new(0) }; JOB_COUNT];
+
+/// The totals at the end of the last window, and when that was.
+static LAST_ROLL: Mutex<ThreadModeRawMutex, Cell<([u32; JOB_COUNT], Instant)>> =
+    Mutex::new(Cell::new(([0; JOB_COUNT], Instant::from_ticks(0))));
+
+/// The most recent window's usage, and whether it's still waiting to be published.
+static USAGE: Mutex<ThreadModeRawMutex, Cell<(Option<CpuUsage>, bool)>> =
+    Mutex::new(Cell::new((None, false)));
+
+/// Percentage of the CPU each job used over the last window.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CpuUsage {
+    pub window_s: u32,
+    pub sen55: f32,
+    pub mqtt: f32,
+    pub ui: f32,
+    pub net: f32,
+    pub wifi: f32,
+    pub usb: f32,
+}
+
+/// Count the time spent polling `future` towards `job`.
+pub fn track<F: Future>(job: Job, future: F) -> Tracked<F> {
+    Tracked { job, future }
+}
+
+pub struct Tracked<F> {
+    job: Job,
+    future: F,
+}
+
+impl<F: Future> Future for Tracked<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // SAFETY: the future is never moved out of self, so it stays pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        let start = Instant::now();
+        let result = future.poll(cx);
+        BUSY_US[this.job as usize].fetch_add(start.elapsed().as_micros() as u32, Ordering::Relaxed);
+
+        result
+    }
+}
+
+/// Close the current window and work out the percentages for it.
+pub fn roll() {
+    let now = Instant::now();
+    let totals: [u32; JOB_COUNT] = core::array::from_fn(|i| BUSY_US[i].load(Ordering::Relaxed));
+    let (previous, since) = LAST_ROLL.lock(|l| l.replace((totals, now)));
+
+    let window_us = (now - since).as_micros() as f32;
+    if window_us <= 0.0 {
+        return;
+    }
+
+    let percent = |job: Job| {
+        let busy = totals[job as usize].wrapping_sub(previous[job as usize]);
+        busy as f32 * 100.0 / window_us
+    };
+
+    let usage = CpuUsage {
+        window_s: (now - since).as_secs() as u32,
+        sen55: percent(Job::Sen55),
+        mqtt: percent(Job::Mqtt),
+        ui: percent(Job::Ui),
+        net: percent(Job::Net),
+        wifi: percent(Job::Wifi),
+        usb: percent(Job::Usb),
+    };
+
+    USAGE.lock(|u| u.set((Some(usage), true)));
+}
+
+/// The last window's usage, if a window has finished yet.
+pub fn usage() -> Option<CpuUsage> {
+    USAGE.lock(|u| u.get().0)
+}
+
+/// The last window's usage, if it hasn't been published yet.
+pub fn unpublished() -> Option<CpuUsage> {
+    USAGE.lock(|u| match u.get() {
+        (usage, true) => usage,
+        _ => None,
+    })
+}
+
+/// Mark the last window as published.
+pub fn mark_published() {
+    USAGE.lock(|u| u.set((u.get().0, false)));
+}
@@ -0,0 +1,80 @@
+use defmt::{warn, Format};
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::{DrawTarget, Point, Primitive, RgbColor, Size};
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::Drawable;
+use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
+
+/// Biggest code we'll generate. Version 6 is 41x41 modules, which still scans comfortably at
+/// 3px per module on the 240px-wide screen and holds ~130 bytes, plenty for a URL or Wi-Fi login.
+const MAX_VERSION: Version = Version::new(6);
+const BUFFER_LEN: usize = MAX_VERSION.buffer_len();
+
+/// Blank modules around the code. The spec says 4, but 2 scans fine and saves space.
+const QUIET_ZONE: i32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum QrError {
+    /// Too much data for the largest code we're willing to draw.
+    TooLong,
+    /// The code wouldn't fit in the space given at even 1px per module.
+    TooSmall,
+}
+
+/// Encode `text` as a QR code and draw it, black on white, centred on `centre` and no bigger than
+/// `max_size` pixels square.
+pub fn draw<D>(target: &mut D, text: &str, centre: Point, max_size: u32) -> Result<(), QrError>
+where
+    D: DrawTarget<Color = Rgb565>,
+    <D as DrawTarget>::Error: core::fmt::Debug,
+{
+    let mut temp = [0u8; BUFFER_LEN];
+    let mut out = [0u8; BUFFER_LEN];
+
+    let code = QrCode::encode_text(
+        text,
+        &mut temp,
+        &mut out,
+        QrCodeEcc::Low,
+        Version::MIN,
+        MAX_VERSION,
+        None,
+        true,
+    )
+    .map_err(|_| {
+        warn!("{} bytes is too long for a QR code", text.len());
+        QrError::TooLong
+    })?;
+
+    let modules = code.size() + (QUIET_ZONE * 2);
+    let scale = max_size as i32 / modules;
+    if scale < 1 {
+        return Err(QrError::TooSmall);
+    }
+
+    let side = (modules * scale) as u32;
+    let top_left = centre - Point::new(side as i32 / 2, side as i32 / 2);
+
+    // White background doubles as the quiet zone.
+    Rectangle::new(top_left, Size::new(side, side))
+        .into_styled(PrimitiveStyle::with_fill(Rgb565::WHITE))
+        .draw(target)
+        .unwrap();
+
+    let black = PrimitiveStyle::with_fill(Rgb565::BLACK);
+    for y in 0..code.size() {
+        for x in 0..code.size() {
+            if !code.get_module(x, y) {
+                continue;
+            }
+
+            let pos = top_left + Point::new((x + QUIET_ZONE) * scale, (y + QUIET_ZONE) * scale);
+            Rectangle::new(pos, Size::new(scale as u32, scale as u32))
+                .into_styled(black)
+                .draw(target)
+                .unwrap();
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! Recovery mode, for rescuing a device that can't get far enough through boot to be fixed any
+//! other way.
+//!
+//! Holding the button while the device powers up wipes the stored settings and restarts into the
+//! RP2040's USB bootloader, which shows up as a `RPI-RP2` drive (and as a picoboot device for
+//! `picotool`) to copy new firmware onto. None of the application runs first, so a bad setting or
+//! a build that panics on the way up can't get in the way.
+
+use defmt::{info, warn};
+use embassy_rp::gpio::Input;
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::storage::{self, Slot};
+
+/// How long the button has to be held at boot to go into recovery, so a press that happens to
+/// overlap with a power blip doesn't wipe anything.
+const HOLD_TIME: Duration = Duration::from_secs(3);
+
+/// Go into recovery if the button is being held down. Returns straight away otherwise.
+///
+/// Must be called after `storage::init`, and before anything that might fail because of what's
+/// stored.
+pub async fn check(button: &Input<'_>) {
+    let start = Instant::now();
+
+    while button.is_low() {
+        if start.elapsed() >= HOLD_TIME {
+            enter().await;
+        }
+        Timer::after_millis(50).await;
+    }
+
+    if start.elapsed() > Duration::from_millis(50) {
+        info!("Button let go before recovery, booting as normal");
+    }
+}
+
+async fn enter() -> ! {
+    warn!("Entering recovery mode");
+
+    if let Err(e) = storage::clear(Slot::Settings).await {
+        warn!("Couldn't wipe settings: {}", e);
+    }
+
+    // Let the log get out before the USB bootloader takes over.
+    defmt::flush();
+    embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+
+    // The bootloader never returns.
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
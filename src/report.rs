@@ -0,0 +1,230 @@
+//! Daily summary of the readings, published to the report topic once a day.
+//!
+//! There's no wall clock yet, so "daily" means every 24 hours since boot rather than at a set
+//! time of day.
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use serde::Serialize;
+
+use crate::metric::Metric;
+use crate::score;
+use crate::sen55::{self, Health, Readings};
+use crate::ticker::{Elapsed, Line};
+
+/// How long each report covers.
+pub const REPORT_PERIOD: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Running min/max/mean of one metric.
+#[derive(Debug, Clone, Copy)]
+struct MetricStats {
+    min: f32,
+    max: f32,
+    sum: f32,
+    count: u32,
+}
+
+impl MetricStats {
+    const EMPTY: MetricStats = MetricStats {
+        min: f32::MAX,
+        max: f32::MIN,
+        sum: 0.0,
+        count: 0,
+    };
+
+    fn push(&mut self, value: f32) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn summary(&self) -> Option<MetricSummary> {
+        (self.count > 0).then(|| MetricSummary {
+            min: self.min,
+            max: self.max,
+            avg: self.sum / self.count as f32,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct MetricSummary {
+    pub min: f32,
+    pub max: f32,
+    pub avg: f32,
+}
+
+/// Hours spent in each health band.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct HealthHours {
+    pub ok: f32,
+    pub warning: f32,
+    pub dangerous: f32,
+}
+
+/// The report as published.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DailySummary {
+    /// How many hours of readings the report covers. Less than 24 if the sensor wasn't producing
+    /// readings the whole time.
+    pub hours: f32,
+    pub pm1: Option<MetricSummary>,
+    pub pm2_5: Option<MetricSummary>,
+    pub pm4: Option<MetricSummary>,
+    pub pm10: Option<MetricSummary>,
+    pub voc: Option<MetricSummary>,
+    pub nox: Option<MetricSummary>,
+    pub temperature: Option<MetricSummary>,
+    pub humidity: Option<MetricSummary>,
+    pub noise: Option<MetricSummary>,
+    pub health_hours: HealthHours,
+    /// How many times PM2.5 rose above the spike threshold.
+    pub spikes: u32,
+    /// The day's air score out of 100, see `score::from_hours`.
+    pub score: Option<u8>,
+}
+
+struct Accumulator {
+    started: Instant,
+    last_sample: Option<Instant>,
+    stats: [MetricStats; Metric::ALL.len()],
+    health_ms: [u64; 3],
+    above_spike_threshold: bool,
+    spikes: u32,
+    /// When PM2.5 last rose above the spike threshold, carried over from report to report.
+    last_spike: Option<Instant>,
+    /// The last finished report, until it's been published.
+    ready: Option<DailySummary>,
+}
+
+impl Accumulator {
+    const fn new(started: Instant) -> Self {
+        Self {
+            started,
+            last_sample: None,
+            stats: [MetricStats::EMPTY; Metric::ALL.len()],
+            health_ms: [0; 3],
+            above_spike_threshold: false,
+            spikes: 0,
+            last_spike: None,
+            ready: None,
+        }
+    }
+
+    fn summary(&self) -> DailySummary {
+        let stats = |metric: Metric| self.stats[metric as usize].summary();
+        let hours = |ms: u64| ms as f32 / 3_600_000.0;
+        let health_hours = HealthHours {
+            ok: hours(self.health_ms[0]),
+            warning: hours(self.health_ms[1]),
+            dangerous: hours(self.health_ms[2]),
+        };
+
+        DailySummary {
+            hours: hours(self.health_ms.iter().sum()),
+            pm1: stats(Metric::Pm1),
+            pm2_5: stats(Metric::Pm2_5),
+            pm4: stats(Metric::Pm4),
+            pm10: stats(Metric::Pm10),
+            voc: stats(Metric::Voc),
+            nox: stats(Metric::Nox),
+            temperature: stats(Metric::Temperature),
+            humidity: stats(Metric::Humidity),
+            noise: stats(Metric::Noise),
+            health_hours,
+            spikes: self.spikes,
+            score: score::from_hours(&health_hours),
+        }
+    }
+}
+
+static REPORT: Mutex<ThreadModeRawMutex, RefCell<Accumulator>> =
+    Mutex::new(RefCell::new(Accumulator::new(Instant::MIN)));
+
+/// Fold a new set of readings into today's report, finishing it off (and adding the day to the
+/// score history) if the period is up.
+pub async fn record(readings: &Readings) {
+    let now = readings.taken_at;
+
+    let finished = REPORT.lock(|r| {
+        let mut r = r.borrow_mut();
+
+        // Time since the last sample is credited to the health band we're in now. Gaps long
+        // enough for the readings to have gone stale (e.g. the sensor restarting) aren't credited
+        // to anything.
+        if let Some(last) = r.last_sample {
+            let gap = now - last;
+            if gap < sen55::stale_after() {
+                let band = match readings.health() {
+                    Health::Ok => 0,
+                    Health::Warning => 1,
+                    Health::Dangerous => 2,
+                };
+                r.health_ms[band] += gap.as_millis();
+            }
+        }
+        r.last_sample = Some(now);
+
+        for metric in Metric::ALL {
+            if let Some(value) = metric.value(readings) {
+                r.stats[metric as usize].push(value);
+            }
+        }
+
+        if let Some(pm2_5) = readings.pm2_5 {
+            // Rising into poor air or worse counts as a spike.
+            let above = Metric::Pm2_5
+                .health(pm2_5)
+                .is_some_and(|health| !matches!(health, Health::Ok));
+            if above && !r.above_spike_threshold {
+                r.spikes += 1;
+                r.last_spike = Some(now);
+            }
+            r.above_spike_threshold = above;
+        }
+
+        if now - r.started >= REPORT_PERIOD {
+            let summary = r.summary();
+            let mut fresh = Accumulator::new(now);
+            fresh.last_sample = r.last_sample;
+            fresh.above_spike_threshold = r.above_spike_threshold;
+            fresh.last_spike = r.last_spike;
+            fresh.ready = Some(summary);
+            *r = fresh;
+            Some(summary)
+        } else {
+            None
+        }
+    });
+
+    if let Some(summary) = finished {
+        score::push(summary.score).await;
+    }
+}
+
+/// The last finished report, if it hasn't been published yet.
+pub fn ready() -> Option<DailySummary> {
+    REPORT.lock(|r| r.borrow().ready)
+}
+
+/// Call once the ready report has been published.
+pub fn clear_ready() {
+    REPORT.lock(|r| r.borrow_mut().ready = None);
+}
+
+/// The report so far for the current period.
+pub fn so_far() -> DailySummary {
+    REPORT.lock(|r| r.borrow().summary())
+}
+
+/// Ticker line: how long ago PM2.5 last spiked, if it has since boot.
+pub fn last_spike_line(line: &mut Line) {
+    if let Some(at) = REPORT.lock(|r| r.borrow().last_spike) {
+        _ = write!(line, "PM spike {} ago", Elapsed(at.elapsed()));
+    }
+}
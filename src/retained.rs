@@ -0,0 +1,152 @@
+//! A block of RAM that isn't zeroed at boot, so state can be carried across a soft reset.
+//!
+//! The contents are only trusted after a deliberate warm reboot by the same firmware; after a
+//! power cycle, a crash or an update the block is full of garbage (or stale data) and is ignored.
+//!
+//! It carries the rolling averages, and the history behind the live graph and the climate page.
+
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+
+use defmt::info;
+
+use crate::sen55::Averages;
+use crate::{climate, config, live};
+
+/// Written just before a warm reboot, and cleared as soon as the block has been read back.
+const WARM_MARKER: u32 = 0x5741_524D; // "WARM"
+const COLD_MARKER: u32 = 0;
+
+/// Differs between firmware versions, and blocks of different sizes, so an update doesn't take
+/// what the old firmware left behind as its own: its block may well be laid out differently.
+const LAYOUT: u32 = layout();
+
+const fn layout() -> u32 {
+    // FNV-1a of the version, starting from the size.
+    let version = config::HASS_DEVICE_SW.as_bytes();
+    let mut hash = 0x811C_9DC5 ^ core::mem::size_of::<RetainedBlock>() as u32;
+    let mut i = 0;
+    while i < version.len() {
+        hash = (hash ^ version[i] as u32).wrapping_mul(0x0100_0193);
+        i += 1;
+    }
+    hash
+}
+
+struct RetainedBlock {
+    /// WARM_MARKER if the rest of the block was left for us by a warm reboot.
+    marker: u32,
+    /// Bitwise inverse of the marker, so random RAM after power-up can't pass for a valid block.
+    marker_check: u32,
+    /// LAYOUT of the firmware that wrote the block.
+    layout: u32,
+    /// Whether `averages` has been written since boot.
+    has_averages: bool,
+    averages: MaybeUninit<Averages>,
+    /// Whether `live` and `climate` were written by the warm reboot.
+    has_history: bool,
+    /// What the live graph and climate page were showing.
+    live: MaybeUninit<live::Snapshot>,
+    climate: MaybeUninit<climate::Snapshot>,
+}
+
+// Nothing in .retained is touched at startup, or by the bootloader, see memory.x.
+#[link_section = ".retained.state"]
+static mut RETAINED: MaybeUninit<RetainedBlock> = MaybeUninit::uninit();
+
+fn block() -> *mut RetainedBlock {
+    // SAFETY: only used to build raw pointers, never references, and all access is from thread mode.
+    unsafe { addr_of_mut!(RETAINED).cast() }
+}
+
+/// Whether the block was left behind by a warm reboot. Must be checked before reading anything else.
+fn is_warm() -> bool {
+    // SAFETY: u32 has no invalid bit patterns, so reading these from uninitialised RAM is fine.
+    unsafe {
+        let marker = addr_of_mut!((*block()).marker).read_volatile();
+        let check = addr_of_mut!((*block()).marker_check).read_volatile();
+        let layout = addr_of_mut!((*block()).layout).read_volatile();
+
+        marker == WARM_MARKER && check == !WARM_MARKER && layout == LAYOUT
+    }
+}
+
+/// Take the averages left behind by a warm reboot, if any.
+///
+/// Only returns something once per boot; the block is marked cold again straight away so a crash
+/// later on doesn't resurrect stale values.
+pub fn take_averages() -> Option<Averages> {
+    critical_section::with(|_| {
+        let warm = is_warm();
+
+        // SAFETY: we only read past the markers if they say we wrote the block ourselves, in which
+        // case every field was initialised before the reboot.
+        unsafe {
+            let averages = if warm && (*block()).has_averages {
+                Some((*block()).averages.assume_init_read())
+            } else {
+                None
+            };
+
+            addr_of_mut!((*block()).marker).write_volatile(COLD_MARKER);
+            addr_of_mut!((*block()).marker_check).write_volatile(!COLD_MARKER);
+            addr_of_mut!((*block()).has_averages).write(false);
+
+            averages
+        }
+    })
+}
+
+/// Put back the live graph's samples and the climate page's ranges from before a warm reboot, if
+/// there was one. Must be called before `take_averages`, which marks the block cold.
+pub fn restore_history() {
+    critical_section::with(|_| {
+        // SAFETY: as in `take_averages`, the fields are only read if we wrote them.
+        unsafe {
+            if is_warm() && (*block()).has_history {
+                live::restore((*block()).live.assume_init_read());
+                climate::restore((*block()).climate.assume_init_read());
+                info!("Restored the graph history from before warm reboot");
+            }
+
+            addr_of_mut!((*block()).has_history).write(false);
+        }
+    })
+}
+
+/// Keep a copy of the latest averages in retained RAM, ready for a warm reboot.
+pub fn stash_averages(averages: &Averages) {
+    critical_section::with(|_| {
+        // SAFETY: writing through raw pointers never reads the uninitialised contents.
+        unsafe {
+            addr_of_mut!((*block()).averages).write(MaybeUninit::new(averages.clone()));
+            addr_of_mut!((*block()).has_averages).write(true);
+        }
+    })
+}
+
+/// Reset the board, keeping whatever has been stashed in retained RAM for the next boot.
+///
+/// Use this instead of a plain reset for deliberate restarts (e.g. after an update or config
+/// change) so the averages and graphs carry on where they left off.
+pub fn warm_reboot() -> ! {
+    info!("Warm reboot requested");
+
+    let live = live::snapshot();
+    let climate = climate::snapshot();
+
+    critical_section::with(|_| {
+        // SAFETY: as above, raw pointer writes only.
+        unsafe {
+            addr_of_mut!((*block()).live).write(MaybeUninit::new(live));
+            addr_of_mut!((*block()).climate).write(MaybeUninit::new(climate));
+            addr_of_mut!((*block()).has_history).write(true);
+            addr_of_mut!((*block()).layout).write_volatile(LAYOUT);
+            addr_of_mut!((*block()).marker).write_volatile(WARM_MARKER);
+            addr_of_mut!((*block()).marker_check).write_volatile(!WARM_MARKER);
+        }
+    });
+
+    defmt::flush();
+    cortex_m::peripheral::SCB::sys_reset();
+}
@@ -0,0 +1,349 @@
+//! Simple rules, set over MQTT, for reacting to the readings without a round trip through Home
+//! Assistant: when a metric has been above (or below) a threshold for long enough, buzz, light the
+//! Pico's LED, call a webhook or put up a banner.
+//!
+//! The rules arrive as a JSON array on the rules topic and replace whatever was there before, e.g.
+//! `[{"metric":"pm2_5","above":35,"for":300,"action":"led"}]`. They're kept in flash, and the ones
+//! running are published back (retained) so it's easy to see what the device made of them.
+//!
+//! A rule fires once when its condition has held for `for` seconds, and is released as soon as it
+//! stops holding. The LED stays lit, and the banner stays up, while the rule is fired; a buzz or a
+//! webhook only happens as it fires.
+
+use core::cell::RefCell;
+
+use defmt::{error, info, warn, Format};
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant};
+use heapless::{String, Vec};
+use serde::{Deserialize, Serialize};
+use static_cell::StaticCell;
+
+use crate::metric::Metric;
+use crate::sen55::Readings;
+use crate::sockets::User;
+use crate::storage::{self, Slot};
+use crate::ticker::Line;
+use crate::{haptics, http_push};
+
+/// There's only so much to keep an eye on.
+pub const MAX_RULES: usize = 8;
+
+/// The most JSON the rules can take up, whether arriving over MQTT or kept in flash.
+pub const MAX_RULES_JSON: usize = 1024;
+
+/// The length (2) and then the JSON, padded out to a fixed size.
+const STORED_LEN: usize = 2 + MAX_RULES_JSON;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Buzz the vibration motor, if there is one.
+    Buzz,
+    /// Light the LED on the Pico W.
+    Led,
+    /// POST to the URL in the rule's `text`.
+    Webhook,
+    /// Show the rule's `text` along the bottom of the readings page.
+    Banner,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// The key of the metric to watch, as in the state message.
+    pub metric: String<16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub above: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub below: Option<f32>,
+    /// How long the condition has to hold before the rule fires, in seconds.
+    #[serde(rename = "for", default)]
+    pub hold: u32,
+    pub action: Action,
+    /// The banner to show, or the URL to call.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub text: String<64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RuleError {
+    /// Not a JSON array of rules, or too many of them.
+    Parse,
+    /// A rule names a metric that doesn't exist.
+    UnknownMetric,
+    /// A rule has neither `above` nor `below`.
+    NoThreshold,
+    /// A webhook rule's `text` isn't an `http://` URL.
+    BadUrl,
+    /// The rules are too long to save, more than `MAX_RULES_JSON` once serialized.
+    TooLong,
+}
+
+impl Rule {
+    fn check(&self) -> Result<Metric, RuleError> {
+        let metric = Metric::from_key(&self.metric).ok_or(RuleError::UnknownMetric)?;
+        if self.above.is_none() && self.below.is_none() {
+            return Err(RuleError::NoThreshold);
+        }
+        if self.action == Action::Webhook && http_push::Target::parse(&self.text).is_none() {
+            return Err(RuleError::BadUrl);
+        }
+        Ok(metric)
+    }
+
+    fn holds(&self, value: f32) -> bool {
+        self.above.is_none_or(|above| value > above) && self.below.is_none_or(|below| value < below)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Status {
+    /// When the condition started holding, if it does.
+    since: Option<Instant>,
+    fired: bool,
+}
+
+struct Engine {
+    rules: Vec<Rule, MAX_RULES>,
+    status: [Status; MAX_RULES],
+}
+
+const IDLE: Status = Status {
+    since: None,
+    fired: false,
+};
+
+static ENGINE: Mutex<ThreadModeRawMutex, RefCell<Engine>> = Mutex::new(RefCell::new(Engine {
+    rules: Vec::new(),
+    status: [IDLE; MAX_RULES],
+}));
+
+/// Whether the LED should be lit, for the main loop (which owns the Wi-Fi chip it hangs off).
+pub static LED: Signal<ThreadModeRawMutex, bool> = Signal::new();
+
+/// A webhook rule that's just fired, waiting for the webhook worker.
+struct Firing {
+    url: String<64>,
+    body: Webhook,
+}
+
+/// What's POSTed to a webhook rule's URL.
+#[derive(Serialize)]
+struct Webhook {
+    /// Which rule fired, counting from zero.
+    rule: u8,
+    metric: &'static str,
+    value: f32,
+}
+
+static WEBHOOKS: Channel<ThreadModeRawMutex, Firing, 4> = Channel::new();
+
+/// Check every rule against the latest readings, acting on any that fire.
+pub fn evaluate(readings: &Readings) {
+    let now = Instant::now();
+    let mut buzz = false;
+
+    let led = ENGINE.lock(|e| {
+        let mut e = e.borrow_mut();
+        let Engine { rules, status } = &mut *e;
+        let was_lit = lit(rules, status);
+
+        for (i, (rule, status)) in rules.iter().zip(status.iter_mut()).enumerate() {
+            let Some(metric) = Metric::from_key(&rule.metric) else {
+                continue;
+            };
+            let value = metric.value(readings);
+
+            if !value.is_some_and(|v| rule.holds(v)) {
+                if status.fired {
+                    info!("Rule {} released", i);
+                }
+                *status = IDLE;
+                continue;
+            }
+
+            let since = *status.since.get_or_insert(now);
+            if status.fired || now - since < Duration::from_secs(rule.hold as u64) {
+                continue;
+            }
+
+            status.fired = true;
+            info!("Rule {} fired: {} {}", i, metric.key(), rule.action);
+            match rule.action {
+                Action::Buzz => buzz = true,
+                Action::Webhook => {
+                    let firing = Firing {
+                        url: rule.text.clone(),
+                        body: Webhook {
+                            rule: i as u8,
+                            metric: metric.key(),
+                            value: value.unwrap_or_default(),
+                        },
+                    };
+                    if WEBHOOKS.try_send(firing).is_err() {
+                        warn!("Too many webhooks waiting, dropping rule {}'s", i);
+                    }
+                }
+                Action::Led | Action::Banner => {}
+            }
+        }
+
+        let lit = lit(rules, status);
+        (lit != was_lit).then_some(lit)
+    });
+
+    if buzz {
+        haptics::rule();
+    }
+    if let Some(on) = led {
+        LED.signal(on);
+    }
+}
+
+fn lit(rules: &[Rule], status: &[Status]) -> bool {
+    rules
+        .iter()
+        .zip(status)
+        .any(|(rule, status)| rule.action == Action::Led && status.fired)
+}
+
+/// A fired banner rule's text, for the ticker. It takes over from the usual lines until the rule's
+/// released.
+pub fn banner(line: &mut Line) {
+    ENGINE.lock(|e| {
+        let e = e.borrow();
+        let fired = e
+            .rules
+            .iter()
+            .zip(e.status)
+            .find(|(rule, status)| rule.action == Action::Banner && status.fired);
+
+        if let Some((rule, _)) = fired {
+            // Anything too long for the screen is cut short.
+            for c in rule.text.chars() {
+                if line.push(c).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Replace the rules with the ones in `json`, and save them to flash. On error the old rules carry
+/// on.
+pub async fn set(json: &str) -> Result<(), RuleError> {
+    let rules = parse(json.as_bytes())?;
+    // They're saved as they serialize, which can be longer than they came in (e.g. with defaults
+    // filled in), so check that's going to fit before they replace the ones running.
+    if serde_json_core::to_slice(&rules, &mut [0u8; MAX_RULES_JSON]).is_err() {
+        return Err(RuleError::TooLong);
+    }
+    info!("Now running {} rules", rules.len());
+
+    let was_lit = ENGINE.lock(|e| {
+        let mut e = e.borrow_mut();
+        let was_lit = lit(&e.rules, &e.status);
+        e.rules = rules;
+        e.status = [IDLE; MAX_RULES];
+        was_lit
+    });
+    if was_lit {
+        LED.signal(false);
+    }
+
+    save().await;
+    Ok(())
+}
+
+fn parse(json: &[u8]) -> Result<Vec<Rule, MAX_RULES>, RuleError> {
+    let (rules, _) =
+        serde_json_core::from_slice::<Vec<Rule, MAX_RULES>>(json).map_err(|_| RuleError::Parse)?;
+    for rule in &rules {
+        rule.check()?;
+    }
+    Ok(rules)
+}
+
+/// The rules that are running, as JSON, into `out`.
+pub fn serialize(out: &mut [u8]) -> Option<usize> {
+    ENGINE.lock(|e| serde_json_core::to_slice(&e.borrow().rules, out).ok())
+}
+
+/// Load the rules from flash. Storage must be initialised first.
+pub async fn load() {
+    let mut bytes = [0u8; STORED_LEN];
+
+    if let Err(e) = storage::load(Slot::Rules, &mut bytes).await {
+        info!("No rules ({})", e);
+        return;
+    }
+
+    let len = (u16::from_le_bytes([bytes[0], bytes[1]]) as usize).min(MAX_RULES_JSON);
+    match parse(&bytes[2..2 + len]) {
+        Ok(rules) => {
+            info!("Loaded {} rules", rules.len());
+            ENGINE.lock(|e| e.borrow_mut().rules = rules);
+        }
+        Err(e) => warn!("Ignoring stored rules: {}", e),
+    }
+}
+
+async fn save() {
+    let mut bytes = [0u8; STORED_LEN];
+    let Some(len) = serialize(&mut bytes[2..]) else {
+        error!("Couldn't serialize the rules to save them");
+        return;
+    };
+    bytes[..2].copy_from_slice(&(len as u16).to_le_bytes());
+
+    match storage::save(Slot::Rules, &bytes).await {
+        Ok(()) => info!("Saved rules"),
+        Err(e) => error!("Couldn't save rules: {}", e),
+    }
+}
+
+/// Calls webhook rules' URLs as they fire. Failures are logged and dropped: by the time a retry
+/// went through, the news would be old.
+#[embassy_executor::task]
+pub async fn webhook_worker(stack: Stack<'static>) {
+    static RX_BUFFER: StaticCell<[u8; 256]> = StaticCell::new();
+    static TX_BUFFER: StaticCell<[u8; 1024]> = StaticCell::new();
+    let rx_buffer = RX_BUFFER.init([0; 256]);
+    let tx_buffer = TX_BUFFER.init([0; 1024]);
+
+    loop {
+        let firing = WEBHOOKS.receive().await;
+        let Some(target) = http_push::Target::parse(&firing.url) else {
+            continue;
+        };
+
+        let mut body = [0u8; 128];
+        let Ok(len) = serde_json_core::to_slice(&firing.body, &mut body) else {
+            continue;
+        };
+
+        let result = http_push::post(
+            stack,
+            User::Webhook,
+            &target,
+            "application/json",
+            None,
+            &body[..len],
+            rx_buffer,
+            tx_buffer,
+        )
+        .await;
+        match result {
+            Ok(()) => info!("Called rule {}'s webhook", firing.body.rule),
+            Err(e) => warn!(
+                "Rule {}'s webhook failed: {}",
+                firing.body.rule,
+                defmt::Debug2Format(&e)
+            ),
+        }
+    }
+}
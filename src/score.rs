@@ -0,0 +1,85 @@
+//! A daily "air score" out of 100, from how long the air spent in each health band, plus the last
+//! week's scores kept in flash.
+
+use core::cell::Cell;
+
+use defmt::{error, info};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+
+use crate::report::{self, HealthHours};
+use crate::storage::{self, Slot};
+
+/// How many past days are kept.
+pub const HISTORY_DAYS: usize = 7;
+
+/// Bump this whenever the layout of the stored bytes changes.
+const HISTORY_VERSION: u8 = 1;
+
+/// Marks a day with no score, e.g. because the device was off.
+const NO_SCORE: u8 = 0xFF;
+
+/// Past scores, oldest first, so the last one is yesterday's.
+pub type History = [Option<u8>; HISTORY_DAYS];
+
+static HISTORY: Mutex<ThreadModeRawMutex, Cell<History>> =
+    Mutex::new(Cell::new([None; HISTORY_DAYS]));
+
+/// Score some time spent in each band: full marks for good air, half for poor air and nothing for
+/// bad air. `None` if no time was spent in any of them.
+pub fn from_hours(hours: &HealthHours) -> Option<u8> {
+    let total = hours.ok + hours.warning + hours.dangerous;
+    if total <= 0.0 {
+        return None;
+    }
+
+    let score = 100.0 * (hours.ok + 0.5 * hours.warning) / total;
+    Some(score.clamp(0.0, 100.0) as u8)
+}
+
+/// The score so far today.
+pub fn today() -> Option<u8> {
+    from_hours(&report::so_far().health_hours)
+}
+
+/// The scores of the last few days.
+pub fn history() -> History {
+    HISTORY.lock(|h| h.get())
+}
+
+/// Load the history from flash. Storage must be initialised first.
+pub async fn load() {
+    let mut bytes = [0u8; HISTORY_DAYS + 1];
+
+    match storage::load(Slot::ScoreHistory, &mut bytes).await {
+        Ok(()) if bytes[0] == HISTORY_VERSION => {
+            let mut history = [None; HISTORY_DAYS];
+            for (day, byte) in history.iter_mut().zip(&bytes[1..]) {
+                *day = (*byte != NO_SCORE).then_some(*byte);
+            }
+            HISTORY.lock(|h| h.set(history));
+        }
+        Ok(()) => info!("Ignoring score history from an old version"),
+        Err(e) => info!("No score history ({})", e),
+    }
+}
+
+/// Add a finished day's score to the history and save it.
+pub async fn push(score: Option<u8>) {
+    let history = HISTORY.lock(|h| {
+        let mut history = h.get();
+        history.rotate_left(1);
+        history[HISTORY_DAYS - 1] = score;
+        h.set(history);
+        history
+    });
+
+    let mut bytes = [HISTORY_VERSION; HISTORY_DAYS + 1];
+    for (byte, day) in bytes[1..].iter_mut().zip(history) {
+        *byte = day.unwrap_or(NO_SCORE);
+    }
+
+    if let Err(e) = storage::save(Slot::ScoreHistory, &bytes).await {
+        error!("Couldn't save score history: {}", e);
+    }
+}
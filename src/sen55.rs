@@ -1,12 +1,155 @@
-use defmt::{error, info, warn};
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use defmt::{debug, error, info, warn};
 use embassy_rp::i2c::{Blocking, I2c};
 use embassy_rp::peripherals::I2C1;
-use embassy_time::{Delay, Timer};
-use sen5x_rs::Error;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::TrySendError;
+use embassy_sync::signal::Signal;
+use embassy_time::{Delay, Duration, Instant, Timer};
+use embedded_hal_bus::i2c::RefCellDevice;
 
+use crate::alarm_log;
+use crate::alarms;
 use crate::avg::Hysterysiser;
-use crate::{MQTT_READING_CHANNEL, UI_READING_CHANNEL};
+use crate::climate;
+use crate::compensation::{self, PressureCompensation};
+use crate::config;
+use crate::cooking;
+use crate::live;
+use crate::metric::{Metric, MetricSet};
+use crate::noise;
+use crate::power::{self, PowerProfile};
+use crate::presence;
+use crate::profile::{self, Job};
+use crate::report;
+use crate::retained;
+use crate::rules;
+use crate::sensirion::{self, DeviceStatus, Fetch, Measurement, VOC_STATE_LEN};
+use crate::sensor_error::{RetryPolicy, SensorError, SENSOR_DIAGNOSTICS};
+use crate::settings;
+use crate::state::{self, SensorActivity};
+use crate::storage::{self, Slot};
+use crate::syslog::{self, Severity};
+use crate::telemetry::{self, ErrorCode, Task};
+use crate::ticker::{Elapsed, Line};
+use crate::voc_baseline;
+use crate::{
+    ReadingChannel, HTTP_PUSH_READINGS, MQTT_READING_CHANNEL, UI_READINGS, WEBSOCKET_READINGS,
+};
+
+pub type SensorBus = I2c<'static, I2C1, Blocking>;
+
+/// The sen5x-rs driver, plus a second handle on the same bus for the commands it doesn't support.
+struct Sensor {
+    driver: sen5x_rs::Sen5x<RefCellDevice<'static, SensorBus>, Delay>,
+    raw: RefCellDevice<'static, SensorBus>,
+
+    /// Set while measuring without PM, so the fan isn't running.
+    pm_paused: bool,
+
+    /// When measurement last started, or `None` while the sensor is idle between duty cycles.
+    measuring_since: Option<Instant>,
+
+    /// When the sensor last went idle.
+    idle_since: Instant,
+
+    /// Readings before this are thrown away while the sensor warms up.
+    warm_at: Instant,
+
+    /// The VOC algorithm's state from when measurement last stopped. The algorithm resets every
+    /// time measurement starts, so this is written back to carry on where it left off.
+    voc_state: Option<[u8; VOC_STATE_LEN]>,
+
+    /// The device status from the latest measurement, to log when it changes.
+    status: DeviceStatus,
+}
+
+/// Measure for `on` out of every `period`, to spare the fan and laser where the air doesn't change
+/// much.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+struct DutyCycle {
+    on: Duration,
+    period: Duration,
+}
+
+impl DutyCycle {
+    /// Parse `DUTY_CYCLE`, e.g. `5/15` to measure for 5 minutes in every 15.
+    fn from_config() -> Option<DutyCycle> {
+        let (on, period) = config::DUTY_CYCLE?.split_once('/')?;
+        let (Ok(on), Ok(period)) = (on.trim().parse::<u64>(), period.trim().parse::<u64>()) else {
+            warn!("Couldn't parse DUTY_CYCLE, measuring all the time");
+            return None;
+        };
+
+        if on == 0 || on >= period {
+            warn!("DUTY_CYCLE needs 0 < on < period, measuring all the time");
+            return None;
+        }
+
+        Some(DutyCycle {
+            on: Duration::from_secs(on * 60),
+            period: Duration::from_secs(period * 60),
+        })
+    }
+}
+
+/// How long readings are ignored for after measurement restarts, while the fan spins up and the
+/// PM readings settle. The datasheet asks for at least 30 seconds.
+const WARM_UP: Duration = Duration::from_secs(30);
+
+/// How often the sensor is polled when everything is going well, unless `SENSOR_POLL_MS` says
+/// otherwise. The SEN55 measures once a second, so polling any faster would only read the same
+/// measurement twice.
+const DEFAULT_POLL_MS: u64 = 1000;
+
+/// The slowest `SENSOR_POLL_MS` that's allowed, which still leaves a couple of readings in the
+/// shortest averaging window.
+const MAX_POLL_MS: u64 = 15_000;
 
+/// The SEN55 cleans its fan by itself this often (its default), counting from a reset.
+const FAN_CLEAN_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How many polls' worth of time a reading can stand in for in the averages, so a few failed reads
+/// are covered by the one after them but a longer gap isn't papered over.
+const MAX_GAP_POLLS: u32 = 3;
+
+/// How many seconds (and readings, at the default poll interval) each rolling average covers. PM
+/// can change rapidly so it's averaged over less time; VOC and NOx are slower, and temperature and
+/// humidity slower still.
+const PM_WINDOW: usize = 30;
+const GAS_WINDOW: usize = 60;
+const CLIMATE_WINDOW: usize = 90;
+
+/// How many failures in a row we tolerate before reinitialising the sensor.
+const MAX_CONSECUTIVE_FAILURES: u32 = 10;
+
+/// How often the VOC algorithm state is written to flash.
+///
+/// The algorithm needs a while to learn before its state is worth keeping, and flash has limited
+/// erase cycles, so this is deliberately infrequent. The first save happens one interval after boot.
+const VOC_STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Signalled to have the fan cleaned on the next poll that's measuring PM.
+pub static FAN_CLEAN: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// How often the sensor is polled when everything is going well, see `SENSOR_POLL_MS`.
+pub fn poll_interval() -> Duration {
+    let ms = config::SENSOR_POLL_MS
+        .and_then(|ms| ms.trim().parse().ok())
+        .unwrap_or(DEFAULT_POLL_MS);
+    Duration::from_millis(ms.clamp(DEFAULT_POLL_MS, MAX_POLL_MS))
+}
+
+/// Readings with no newer measurement for longer than this are stale: they're shown greyed out and
+/// aren't published as the current state. That's 10 seconds, or three polls if they're further
+/// apart than that.
+pub fn stale_after() -> Duration {
+    (poll_interval() * 3).max(Duration::from_secs(10))
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct Readings {
     pub pm1_0: Option<f32>,
     pub pm2_5: Option<f32>,
@@ -16,16 +159,52 @@ pub struct Readings {
     pub nox_index: Option<f32>,
     pub temperature: Option<f32>,
     pub humidity: Option<f32>,
+
+    /// Ambient pressure (hPa) the PM readings were compensated for, if any.
+    pub pressure: Option<f32>,
+    /// Factor the raw PM readings were multiplied by to compensate for ambient pressure.
+    pub pm_compensation: Option<f32>,
+
+    /// A-weighted sound level (dBA) from the microphone, if there is one.
+    pub noise: Option<f32>,
+
+    /// Metrics the sensor had no value for in the latest measurement, e.g. PM while the fan's
+    /// stopped or NOx on a SEN54. Their averages may still hold older values.
+    pub unavailable: MetricSet,
+
+    /// When the latest measurement in these readings was taken.
+    pub taken_at: Instant,
 }
 
 /// A vague health indicator for the overall readings.
+#[derive(Debug, Clone, Copy)]
 pub enum Health {
     Ok,
     Warning,
     Dangerous,
 }
 
+impl Health {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Health::Ok => "ok",
+            Health::Warning => "warning",
+            Health::Dangerous => "dangerous",
+        }
+    }
+}
+
 impl Readings {
+    /// How long ago the latest measurement was taken.
+    pub fn age(&self) -> Duration {
+        self.taken_at.elapsed()
+    }
+
+    /// Whether the sensor's gone quiet since these were taken, see `stale_after`.
+    pub fn is_stale(&self) -> bool {
+        self.age() > stale_after()
+    }
+
     pub fn has_all(&self) -> bool {
         self.pm1_0.is_some()
             && self.pm2_5.is_some()
@@ -43,31 +222,146 @@ impl Readings {
             return Health::Ok;
         }
 
-        // If any of the readings are above the threshold, we're in the danger zone.
-        // unwrapping is safe because we've already checked that all the readings are Some.
-        if self.pm1_0.unwrap() > 100.0
-            || self.pm2_5.unwrap() > 100.0
-            || self.pm4_0.unwrap() > 100.0
-            || self.pm10_0.unwrap() > 100.0
-            || self.voc_index.unwrap() > 400.0
-            || self.nox_index.unwrap() > 5.0
-        {
-            return Health::Dangerous;
+        // The worst of the metrics with thresholds, see `metric::Descriptor`.
+        let mut health = Health::Ok;
+        for metric in Metric::ALL {
+            match metric.value(self).and_then(|value| metric.health(value)) {
+                Some(Health::Dangerous) => return Health::Dangerous,
+                Some(Health::Warning) => health = Health::Warning,
+                _ => {}
+            }
+        }
+
+        health
+    }
+}
+
+/// Rolling averages of the last few readings of each metric, to smooth out noise.
+///
+/// Kept together in one plain-data struct so they can be carried across a warm reboot.
+#[derive(Clone)]
+pub struct Averages {
+    pm1: Hysterysiser<PM_WINDOW>,
+    pm2_5: Hysterysiser<PM_WINDOW>,
+    pm4: Hysterysiser<PM_WINDOW>,
+    pm10: Hysterysiser<PM_WINDOW>,
+    voc: Hysterysiser<GAS_WINDOW>,
+    nox: Hysterysiser<GAS_WINDOW>,
+    temp: Hysterysiser<CLIMATE_WINDOW>,
+    humidity: Hysterysiser<CLIMATE_WINDOW>,
+
+    /// Metrics missing from the latest measurement.
+    unavailable: MetricSet,
+}
+
+impl Averages {
+    pub fn new() -> Self {
+        Self {
+            pm1: Hysterysiser::new(),
+            pm2_5: Hysterysiser::new(),
+            pm4: Hysterysiser::new(),
+            pm10: Hysterysiser::new(),
+            voc: Hysterysiser::new(),
+            nox: Hysterysiser::new(),
+            temp: Hysterysiser::new(),
+            humidity: Hysterysiser::new(),
+            unavailable: MetricSet::NONE,
         }
+    }
+
+    /// Forget the PM averages, so they read as unknown until there are enough new readings.
+    fn clear_pm(&mut self) {
+        self.pm1 = Hysterysiser::new();
+        self.pm2_5 = Hysterysiser::new();
+        self.pm4 = Hysterysiser::new();
+        self.pm10 = Hysterysiser::new();
+    }
 
-        // Same thing but with warning thresholds.
-        if self.pm1_0.unwrap() > 25.0
-            || self.pm2_5.unwrap() > 25.0
-            || self.pm4_0.unwrap() > 25.0
-            || self.pm10_0.unwrap() > 25.0
-            || self.voc_index.unwrap() > 225.0
-            || self.nox_index.unwrap() > 2.5
-        {
-            return Health::Warning;
+    /// The current averages as a set of readings, noting any pressure compensation applied and
+    /// when the latest measurement was taken.
+    fn readings(&self, compensation: Option<PressureCompensation>, taken_at: Instant) -> Readings {
+        let profile = power::get();
+        Readings {
+            pm1_0: windowed(&self.pm1, profile),
+            pm2_5: windowed(&self.pm2_5, profile),
+            pm4_0: windowed(&self.pm4, profile),
+            pm10_0: windowed(&self.pm10, profile),
+            voc_index: windowed(&self.voc, profile),
+            nox_index: windowed(&self.nox, profile),
+            temperature: windowed(&self.temp, profile),
+            humidity: windowed(&self.humidity, profile),
+            pressure: compensation.map(|c| c.pressure),
+            pm_compensation: compensation.map(|c| c.pm_factor),
+            noise: noise::level(),
+            unavailable: self.unavailable,
+            taken_at,
         }
+    }
+}
+
+/// The average over as much of its window as the power profile wants. Each window is `L` seconds,
+/// which is room for `L` readings at the default poll interval.
+fn windowed<const L: usize>(values: &Hysterysiser<L>, profile: PowerProfile) -> Option<f32> {
+    values.average(window(L, profile), poll_interval() * MAX_GAP_POLLS)
+}
+
+fn window(seconds: usize, profile: PowerProfile) -> Duration {
+    Duration::from_secs(seconds as u64) / profile.window_divisor() as u32
+}
+
+/// How far back the metric's rolling average reaches with the current power profile, or `None`
+/// if it isn't averaged.
+pub fn averaging_window(metric: Metric) -> Option<Duration> {
+    let seconds = match metric {
+        Metric::Pm1 | Metric::Pm2_5 | Metric::Pm4 | Metric::Pm10 => PM_WINDOW,
+        Metric::Voc | Metric::Nox => GAS_WINDOW,
+        Metric::Temperature | Metric::Humidity => CLIMATE_WINDOW,
+        Metric::Noise => return None,
+    };
+    Some(window(seconds, power::get()))
+}
+
+/// Log when the sensor's status flags change. Anything they say can't be trusted has already been
+/// left out of the measurement.
+fn note_status(sensor: &mut Sensor, status: DeviceStatus) {
+    if status == sensor.status {
+        return;
+    }
+    sensor.status = status;
+
+    if status.has_error() {
+        warn!("Sensor reports a fault, status {=u32:#010x}", status.0);
+        syslog::event(
+            Severity::Warning,
+            "sensor",
+            format_args!("fault, status {:#010x}", status.0),
+        );
+    } else if status.fan_speed_warning() {
+        warn!(
+            "Sensor fan speed out of range, status {=u32:#010x}",
+            status.0
+        );
+    } else {
+        info!("Sensor status now {=u32:#010x}", status.0);
+    }
+}
 
-        Health::Ok
+/// The metrics the sensor didn't have a value for.
+fn unavailable(measurement: &Measurement) -> MetricSet {
+    let mut set = MetricSet::NONE;
+    for (metric, value) in [
+        (Metric::Pm1, measurement.pm1_0),
+        (Metric::Pm2_5, measurement.pm2_5),
+        (Metric::Pm4, measurement.pm4_0),
+        (Metric::Pm10, measurement.pm10_0),
+        (Metric::Voc, measurement.voc_index),
+        (Metric::Nox, measurement.nox_index),
+        (Metric::Temperature, measurement.temperature),
+        (Metric::Humidity, measurement.humidity),
+    ] {
+        set.set(metric, value.is_none());
     }
+    set
 }
 
 /// Polls the SEN55 sensor and sends the readings to the shared channel.
@@ -75,176 +369,461 @@ impl Readings {
 /// If the sensor fails to read too many times in a row, it will attempt to reinit the sensor, and
 /// if that fails the board will be put into reset.
 ///
-/// The sensor updates every 1s, is polled every 750ms, is hysterised over 30, 60, and 90 readings.
+/// The sensor updates every 1s and is polled every second (or every `SENSOR_POLL_MS`). Readings
+/// are hysterised over 30, 60, and 90 seconds (a third of that with the responsive power profile),
+/// each weighted by the time since the one before, so missed reads don't stretch the averages over
+/// a longer time (see `avg`).
 #[embassy_executor::task]
-pub async fn worker(i2c: I2c<'static, I2C1, Blocking>) {
+pub async fn worker(bus: &'static RefCell<SensorBus>) {
+    profile::track(Job::Sen55, run(bus)).await
+}
+
+async fn run(bus: &'static RefCell<SensorBus>) {
     info!("started sen55 worker");
 
-    info!("Give sensor 5s to power up");
-    Timer::after_secs(5).await;
+    info!(
+        "Give sensor {}ms to power up",
+        config::BOOT_PROFILE.sensor_power_up.as_millis()
+    );
+    Timer::after(config::BOOT_PROFILE.sensor_power_up).await;
+
+    let mut sensor = Sensor {
+        driver: sen5x_rs::Sen5x::new(RefCellDevice::new(bus), Delay),
+        raw: RefCellDevice::new(bus),
+        pm_paused: false,
+        measuring_since: None,
+        idle_since: Instant::now(),
+        warm_at: Instant::now(),
+        voc_state: None,
+        status: DeviceStatus(0),
+    };
+
+    let duty_cycle = DutyCycle::from_config();
+    if let Some(duty_cycle) = duty_cycle {
+        info!("Duty cycling: {}", duty_cycle);
+    }
 
-    let mut sensor = sen5x_rs::Sen5x::new(i2c, Delay);
     if init_and_start_readings(&mut sensor).await.is_err() {
         error!("couldn't init sensor, board will reset");
+        telemetry::record_error(ErrorCode::SensorInit);
         panic!("couldn't init sensor");
     }
 
-    // Track the rolling averages of the last few readings to smooth out noise.
-    // pm1.0, pm2.5, pm4.0, pm10.0 can change rapidly so we average over fewer readings.
-    let mut avg_pm1 = Hysterysiser::<30>::new();
-    let mut avg_pm2_5 = Hysterysiser::<30>::new();
-    let mut avg_pm4 = Hysterysiser::<30>::new();
-    let mut avg_pm10 = Hysterysiser::<30>::new();
+    // Carry the averages over from before a warm reboot, so they don't start empty again.
+    let mut averages = match retained::take_averages() {
+        Some(averages) => {
+            info!("Restored rolling averages from before warm reboot");
+            averages
+        }
+        None => Averages::new(),
+    };
 
-    // tVOC and tNOx are slower to change so we average over more readings.
-    let mut avg_voc = Hysterysiser::<60>::new();
-    let mut avg_nox = Hysterysiser::<60>::new();
+    let mut recent_read_failures = 0;
 
-    // Temperature and humidity are also slow to change.
-    let mut avg_temp = Hysterysiser::<90>::new();
-    let mut avg_humidity = Hysterysiser::<90>::new();
+    // When the latest measurement in the averages was taken.
+    let mut last_taken = Instant::now();
 
-    let mut recent_read_failures = 0;
+    // When the averages were last added to, for weighting each reading by the time since. After a
+    // warm reboot the first reading carries on from the restored averages as if none were missed.
+    let mut last_pushed: Option<Instant> = None;
+
+    // How long to wait before the next poll, adjusted by the retry policy after errors.
+    let poll_interval = poll_interval();
+    if config::SENSOR_POLL_MS.is_some_and(|ms| ms.trim().parse::<u64>().is_err()) {
+        warn!(
+            "SENSOR_POLL_MS isn't a number, polling every {}ms",
+            DEFAULT_POLL_MS
+        );
+    }
+    info!("Polling every {}ms", poll_interval.as_millis());
+    let mut next_poll = poll_interval;
+
+    let mut last_voc_state_save = Instant::now();
 
     loop {
-        Timer::after_millis(1000).await;
+        Timer::after(next_poll).await;
+        next_poll = poll_interval;
+        telemetry::heartbeat(Task::Sen55);
 
         // If we've had too many read failures in a row, try to reinit the sensor.
-        if recent_read_failures > 10 {
+        if recent_read_failures > MAX_CONSECUTIVE_FAILURES {
             warn!("Too many consecutive failures; reinitialising sensor");
-
-            if init_and_start_readings(&mut sensor).await.is_err() {
-                error!("couldn't init sensor, board will reset");
-                panic!("couldn't init sensor");
-            }
+            reinit_or_reset(&mut sensor).await;
 
             // Reset the failure counter so we don't immediately reinit again.
             recent_read_failures = 0;
         };
 
-        match sensor.data_ready_status() {
-            Ok(false) => {
-                // Data not ready yet, try again later.
+        // Rest between duty cycles, starting and stopping measurement as each phase comes round.
+        if let Some(duty_cycle) = duty_cycle {
+            match duty_cycle_step(&mut sensor, duty_cycle).await {
+                Ok(DutyStep::Measure) => {}
+                Ok(DutyStep::Rest) => continue,
+                Ok(DutyStep::Stopped) => {
+                    // Nothing else gets published while idle, so make sure the broker hears we've
+                    // stopped.
+                    let readings = averages.readings(compensation::current(), last_taken);
+                    _ = push_latest(&MQTT_READING_CHANNEL, readings);
+                    continue;
+                }
+                Err(err) => {
+                    err.record("Couldn't switch duty cycle phase");
+                    recent_read_failures += 1;
+                    next_poll = handle_failure(&mut sensor, err, recent_read_failures).await;
+                    continue;
+                }
+            }
+        }
+
+        // Stop the fan while nobody's around, and start it again when they're back.
+        let want_pm_paused = presence::fan_saver_wanted();
+        if want_pm_paused != sensor.pm_paused {
+            if let Err(err) = restart_measurement(&mut sensor, want_pm_paused).await {
+                err.record("Couldn't switch measurement mode");
                 recent_read_failures += 1;
+                next_poll = handle_failure(&mut sensor, err, recent_read_failures).await;
                 continue;
             }
-            Err(err) => {
-                // Error reading data ready status, incremenent the failure counter.
-                match err {
-                    Error::Crc => warn!("Couldn't read sen5x readiness: CRC mismatch"),
-                    Error::I2c(_) => error!("Couldn't read sen5x readiness: i2c mismatch"),
-                    Error::Internal => error!("Couldn't read sen5x readiness: sensirion internal"),
-                    Error::SelfTest => error!("Couldn't read sen5x readiness: self-test failure"),
-                    Error::NotAllowed => error!("Couldn't read sen5x readiness: not allowed"),
+
+            // Without the fan the PM readings are meaningless, so don't let stale ones linger.
+            if want_pm_paused {
+                averages.clear_pm();
+            }
+        }
+
+        // The fan can only be cleaned while it's running, so a request waits until it is.
+        if !sensor.pm_paused && FAN_CLEAN.try_take().is_some() {
+            match sensirion::start_fan_cleaning(&mut sensor.raw).await {
+                Ok(()) => {
+                    info!("Cleaning the fan");
+                    syslog::event(Severity::Info, "sensor", format_args!("fan cleaning"));
                 }
+                Err(err) => err.record("Couldn't start fan cleaning"),
+            }
+        }
+
+        let measurement = match sensirion::fetch(&mut sensor.raw).await {
+            Ok(Fetch::NotReady) => {
+                // Data not ready yet, try again later.
                 recent_read_failures += 1;
                 continue;
             }
-            _ => {
+            Ok(Fetch::Ready {
+                measurement,
+                status,
+            }) => {
                 // Data is ready, reset the failure counter.
                 recent_read_failures = 0;
+                last_taken = Instant::now();
+                note_status(&mut sensor, status);
+                measurement
             }
-        }
-
-        let measurement = match sensor.measurement() {
-            Ok(measurement) => measurement,
             Err(err) => {
-                match err {
-                    Error::Crc => error!("Couldn't read sensor: CRC mismatch"),
-                    Error::I2c(_) => error!("Couldn't read sensor: i2c mismatch"),
-                    Error::Internal => error!("Couldn't read sensor: sensirion internal"),
-                    Error::SelfTest => error!("Couldn't read sensor: self-test failure"),
-                    Error::NotAllowed => error!("Couldn't read sensor: not allowed"),
-                }
-
+                err.record("Couldn't read sensor");
                 recent_read_failures += 1;
+                next_poll = handle_failure(&mut sensor, err, recent_read_failures).await;
                 continue;
             }
         };
 
-        // Push the new readings into the rolling averages.
-        avg_pm1.push(measurement.pm1_0 * 10_f32);
-        avg_pm2_5.push(measurement.pm2_5 * 10_f32);
-        avg_pm4.push(measurement.pm4_0 * 10_f32);
-        avg_pm10.push(measurement.pm10_0 * 10_f32);
-        avg_voc.push(measurement.voc_index);
-        avg_nox.push(measurement.nox_index);
-        avg_temp.push(measurement.temperature);
-        avg_humidity.push(measurement.humidity);
+        // The first readings after starting are unreliable, so drop them.
+        if Instant::now() < sensor.warm_at {
+            continue;
+        }
+        state::set_sensor_activity(SensorActivity::Measuring);
 
-        // Publish the rolling averages.
-        MQTT_READING_CHANNEL
-            .send(Readings {
-                pm1_0: avg_pm1.average(),
-                pm2_5: avg_pm2_5.average(),
-                pm4_0: avg_pm4.average(),
-                pm10_0: avg_pm10.average(),
-                voc_index: avg_voc.average(),
-                nox_index: avg_nox.average(),
-                temperature: avg_temp.average(),
-                humidity: avg_humidity.average(),
-            })
-            .await;
-
-        if UI_READING_CHANNEL
-            .try_send(Readings {
-                pm1_0: avg_pm1.average(),
-                pm2_5: avg_pm2_5.average(),
-                pm4_0: avg_pm4.average(),
-                pm10_0: avg_pm10.average(),
-                voc_index: avg_voc.average(),
-                nox_index: avg_nox.average(),
-                temperature: avg_temp.average(),
-                humidity: avg_humidity.average(),
-            })
-            .is_err()
-        {
-            warn!("UI's readings channel is full, it might be struggling to keep up");
+        if last_voc_state_save.elapsed() > VOC_STATE_SAVE_INTERVAL {
+            save_voc_state(&mut sensor).await;
+            voc_baseline::save().await;
+            last_voc_state_save = Instant::now();
+        }
+
+        // Correct the PM readings for ambient pressure, if we know it.
+        let compensation = compensation::current();
+        let compensate = |pm: Option<f32>| match &compensation {
+            Some(c) => pm.map(|pm| c.apply(pm)),
+            None => pm,
         };
+
+        // There's no PM while the fan's stopped.
+        let pm = |pm: Option<f32>| (!sensor.pm_paused).then(|| compensate(pm)).flatten();
+
+        // The live graph gets the raw value, before any averaging.
+        let graphed = settings::get().graph_metric;
+        live::record(
+            graphed,
+            match graphed {
+                Metric::Pm1 => pm(measurement.pm1_0),
+                Metric::Pm2_5 => pm(measurement.pm2_5),
+                Metric::Pm4 => pm(measurement.pm4_0),
+                Metric::Pm10 => pm(measurement.pm10_0),
+                Metric::Voc => measurement.voc_index,
+                Metric::Nox => measurement.nox_index,
+                Metric::Temperature => measurement.temperature,
+                Metric::Humidity => measurement.humidity,
+                Metric::Noise => noise::level(),
+            },
+        );
+
+        // Push the new readings into the rolling averages. The gap goes in while PM's missing too,
+        // or the next PM reading would be stretched over it.
+        let gap = last_pushed.map_or(poll_interval, |at| at.elapsed());
+        last_pushed = Some(Instant::now());
+        averages.pm1.push(pm(measurement.pm1_0), gap);
+        averages.pm2_5.push(pm(measurement.pm2_5), gap);
+        averages.pm4.push(pm(measurement.pm4_0), gap);
+        averages.pm10.push(pm(measurement.pm10_0), gap);
+        averages.voc.push(measurement.voc_index, gap);
+        averages.nox.push(measurement.nox_index, gap);
+        averages.temp.push(measurement.temperature, gap);
+        averages.humidity.push(measurement.humidity, gap);
+        averages.unavailable = unavailable(&measurement);
+
+        // Keep a copy somewhere that survives a warm reboot.
+        retained::stash_averages(&averages);
+
+        // Publish the rolling averages.
+        let readings = averages.readings(compensation, last_taken);
+        state::set_readings(readings);
+        report::record(&readings).await;
+        climate::record(&readings);
+        cooking::update(&readings);
+        voc_baseline::learn(readings.voc_index);
+        rules::evaluate(&readings);
+        alarms::update(&readings);
+        alarm_log::save_if_due().await;
+
+        // Never wait on the consumers: the MQTT worker won't be draining its channel until the
+        // network is up, and the sensor should keep sampling (and buffering the latest readings)
+        // in the meantime. The UI and the HTTP push only want the latest, so anything they
+        // haven't used yet is simply replaced.
+        if push_latest(&MQTT_READING_CHANNEL, readings) {
+            debug!("MQTT isn't keeping up, dropped the oldest buffered readings");
+        }
+        UI_READINGS.signal(readings);
+        HTTP_PUSH_READINGS.signal(readings);
+        WEBSOCKET_READINGS.signal(readings);
+    }
+}
+
+/// Queue readings without blocking. If the channel is full the oldest queued readings make way, so
+/// the consumer always gets the most recent ones. Returns whether anything was dropped.
+fn push_latest(channel: &ReadingChannel, readings: Readings) -> bool {
+    match channel.try_send(readings) {
+        Ok(()) => false,
+        Err(TrySendError::Full(readings)) => {
+            _ = channel.try_receive();
+            _ = channel.try_send(readings);
+            state::count_dropped();
+            true
+        }
     }
 }
 
-async fn init_and_start_readings(
-    sensor: &mut sen5x_rs::Sen5x<I2c<'static, I2C1, Blocking>, Delay>,
-) -> Result<(), ()> {
-    if let Err(e) = sensor.reinit() {
-        match e {
-            Error::Crc => warn!("Couldn't init sensor: CRC mismatch"),
-            Error::I2c(_) => error!("Couldn't init sensor: i2c mismatch"),
-            Error::Internal => error!("Couldn't init sensor: sensirion internal"),
-            Error::SelfTest => error!("Couldn't init sensor: self-test failure"),
-            Error::NotAllowed => error!("Couldn't init sensor: not allowed"),
+/// Apply the retry policy for an error, returning how long to wait before polling again.
+///
+/// If the policy calls for it the sensor is reinitialised here (or the board reset if that fails).
+async fn handle_failure(
+    sensor: &mut Sensor,
+    err: SensorError,
+    consecutive_failures: u32,
+) -> Duration {
+    match err.retry_policy(consecutive_failures) {
+        RetryPolicy::RetryNow => Duration::from_ticks(0),
+        RetryPolicy::Backoff(backoff) => {
+            warn!("Backing off sensor polling for {}ms", backoff.as_millis());
+            backoff
+        }
+        RetryPolicy::Reinit => {
+            warn!("Sensor needs reinitialising after {}", err);
+            reinit_or_reset(sensor).await;
+            poll_interval()
         }
-        return Err(());
+    }
+}
+
+/// Reinitialise the sensor, resetting the board if even that doesn't work.
+async fn reinit_or_reset(sensor: &mut Sensor) {
+    SENSOR_DIAGNOSTICS.count_reinit();
+
+    if init_and_start_readings(sensor).await.is_err() {
+        error!("couldn't init sensor, board will reset");
+        telemetry::record_error(ErrorCode::SensorInit);
+        panic!("couldn't init sensor");
+    }
+}
+
+async fn init_and_start_readings(sensor: &mut Sensor) -> Result<(), SensorError> {
+    if let Err(e) = sensor.driver.reinit() {
+        let e = SensorError::from(e);
+        e.record("Couldn't init sensor");
+        return Err(e);
     };
+    state::set_sensor_started(Instant::now());
 
-    match sensor.serial_number() {
+    match sensor.driver.serial_number() {
         Ok(serial) => info!("Sensor serial: {}", serial),
         Err(e) => {
-            match e {
-                Error::Crc => warn!("Couldn't read sen5x serial: CRC mismatch"),
-                Error::I2c(_) => error!("Couldn't read sen5x serial: i2c mismatch"),
-                Error::Internal => error!("Couldn't read sen5x serial: sensirion internal"),
-                Error::SelfTest => error!("Couldn't read sen5x serial: self-test failure"),
-                Error::NotAllowed => error!("Couldn't read sen5x serial: not allowed"),
-            }
-            return Err(());
+            let e = SensorError::from(e);
+            e.record("Couldn't read sen5x serial");
+            return Err(e);
         }
     }
 
-    if let Err(e) = sensor.start_measurement() {
-        match e {
-            Error::Crc => warn!("Couldn't start readings: CRC mismatch"),
-            Error::I2c(_) => error!("Couldn't start readings: i2c mismatch"),
-            Error::Internal => error!("Couldn't start readings: sensirion internal"),
-            Error::SelfTest => error!("Couldn't start readings: self-test failure"),
-            Error::NotAllowed => error!("Couldn't start readings: not allowed"),
+    // Nice to have for diagnostics, but no reason not to measure.
+    match sensirion::read_info(&mut sensor.raw).await {
+        Ok(info) => {
+            info!(
+                "Sensor is a {}, firmware {}.{}, hardware {}.{}",
+                info.product(),
+                info.firmware.0,
+                info.firmware.1,
+                info.hardware.0,
+                info.hardware.1
+            );
+            state::set_sensor_info(info);
         }
-        return Err(());
+        Err(e) => e.record("Couldn't read sen5x product and version"),
+    }
+
+    // The VOC state can only be written in idle mode, so restore it before starting measurement.
+    restore_voc_state(sensor).await;
+
+    if let Err(e) = sensor.driver.start_measurement() {
+        let e = SensorError::from(e);
+        e.record("Couldn't start readings");
+        return Err(e);
     }
+    sensor.pm_paused = false;
+    sensor.measuring_since = Some(Instant::now());
+    sensor.warm_at = Instant::now();
+    state::set_sensor_activity(SensorActivity::Measuring);
 
     info!("Waiting for sensor to settle");
-    Timer::after_secs(5).await;
+    Timer::after(config::BOOT_PROFILE.sensor_settle).await;
+
+    Ok(())
+}
+
+/// Stop measuring and restart, either with PM or without it (and so without the fan).
+///
+/// Both measurement modes can only be started from idle, so the sensor is stopped first if it's
+/// running.
+async fn restart_measurement(sensor: &mut Sensor, without_pm: bool) -> Result<(), SensorError> {
+    if sensor.measuring_since.is_some() {
+        stop_measurement(sensor).await?;
+    }
 
+    // Pick the VOC algorithm up where it left off.
+    if let Some(voc_state) = sensor.voc_state.take() {
+        sensirion::write_voc_state(&mut sensor.raw, &voc_state).await?;
+    }
+
+    syslog::event(
+        Severity::Info,
+        "sensor",
+        format_args!("measuring{}", if without_pm { " without PM" } else { "" }),
+    );
+
+    if without_pm {
+        info!("Nobody around, measuring without the fan");
+        sensirion::start_measurement_without_pm(&mut sensor.raw).await?;
+    } else {
+        info!("Starting full measurement");
+        sensor
+            .driver
+            .start_measurement()
+            .map_err(SensorError::from)?;
+    }
+
+    sensor.pm_paused = without_pm;
+    sensor.measuring_since = Some(Instant::now());
+    sensor.warm_at = Instant::now() + WARM_UP;
+    state::set_sensor_activity(SensorActivity::WarmingUp);
     Ok(())
 }
+
+/// Stop measuring, keeping hold of the VOC algorithm's state for when it starts again.
+async fn stop_measurement(sensor: &mut Sensor) -> Result<(), SensorError> {
+    sensor.voc_state = Some(sensirion::read_voc_state(&mut sensor.raw).await?);
+    sensirion::stop_measurement(&mut sensor.raw).await?;
+    sensor.measuring_since = None;
+    sensor.idle_since = Instant::now();
+    Ok(())
+}
+
+/// What to do with this poll when duty cycling.
+enum DutyStep {
+    /// Read the sensor as usual.
+    Measure,
+    /// Nothing to do, the sensor is idle.
+    Rest,
+    /// Measuring has just stopped for the rest of the period.
+    Stopped,
+}
+
+/// Start or stop measurement if it's time for the next phase of the duty cycle.
+async fn duty_cycle_step(
+    sensor: &mut Sensor,
+    duty_cycle: DutyCycle,
+) -> Result<DutyStep, SensorError> {
+    match sensor.measuring_since {
+        Some(since) if since.elapsed() >= duty_cycle.on => {
+            info!("Duty cycle done, going idle");
+            syslog::event(Severity::Info, "sensor", format_args!("idle"));
+            stop_measurement(sensor).await?;
+            state::set_sensor_activity(SensorActivity::Idle);
+            Ok(DutyStep::Stopped)
+        }
+        Some(_) => Ok(DutyStep::Measure),
+        None if sensor.idle_since.elapsed() >= duty_cycle.period - duty_cycle.on => {
+            restart_measurement(sensor, presence::fan_saver_wanted()).await?;
+            Ok(DutyStep::Rest)
+        }
+        None => Ok(DutyStep::Rest),
+    }
+}
+
+/// Write the VOC algorithm's learned state to flash so it survives a reboot.
+async fn save_voc_state(sensor: &mut Sensor) {
+    let state = match sensirion::read_voc_state(&mut sensor.raw).await {
+        Ok(state) => state,
+        Err(e) => {
+            e.record("Couldn't read VOC algorithm state");
+            return;
+        }
+    };
+
+    match storage::save(Slot::VocState, &state).await {
+        Ok(()) => info!("Saved VOC algorithm state"),
+        Err(e) => error!("Couldn't save VOC algorithm state: {}", e),
+    }
+}
+
+/// Restore the VOC algorithm's state from flash, if there is one, skipping the hours of re-learning.
+///
+/// Failing to restore isn't fatal, the algorithm just starts learning from scratch.
+async fn restore_voc_state(sensor: &mut Sensor) {
+    let mut state = [0u8; VOC_STATE_LEN];
+    if let Err(e) = storage::load(Slot::VocState, &mut state).await {
+        info!("No VOC algorithm state to restore ({})", e);
+        return;
+    }
+
+    match sensirion::write_voc_state(&mut sensor.raw, &state).await {
+        Ok(()) => info!("Restored VOC algorithm state"),
+        Err(e) => e.record("Couldn't restore VOC algorithm state"),
+    }
+}
+
+/// Ticker line: roughly when the fan will next clean itself.
+pub fn fan_clean_line(line: &mut Line) {
+    let Some(started) = state::get().sensor_started else {
+        return;
+    };
+
+    // Counted from when the sensor was last reset, in whole intervals.
+    let elapsed = started.elapsed().as_secs() % FAN_CLEAN_INTERVAL.as_secs();
+    let due = FAN_CLEAN_INTERVAL - Duration::from_secs(elapsed);
+    _ = write!(line, "Fan clean in {}", Elapsed(due));
+}
@@ -0,0 +1,316 @@
+//! Raw SEN5x commands that the sen5x-rs driver doesn't expose, or that it doesn't read the way we
+//! need.
+
+use defmt::Format;
+use embassy_time::Timer;
+use embedded_hal_1::i2c::I2c;
+use sensirion_i2c::{crc8, i2c as sensirion};
+
+use crate::sensor_error::SensorError;
+
+/// The SEN5x is always at this address.
+const SEN5X_ADDR: u8 = 0x69;
+
+/// Read/write the VOC algorithm state (4 words).
+const CMD_VOC_ALGORITHM_STATE: u16 = 0x6181;
+
+/// Start measuring temperature, humidity, VOC and NOx only, with the fan and laser off.
+const CMD_START_MEASUREMENT_RHT_GAS: u16 = 0x0037;
+
+/// Go back to idle mode from either measurement mode.
+const CMD_STOP_MEASUREMENT: u16 = 0x0104;
+
+/// Run the fan at full speed for 10 seconds to blow the dust out. Only works while measuring PM.
+const CMD_START_FAN_CLEANING: u16 = 0x5607;
+
+/// Whether a new measurement is ready (1 word, the flag in the low byte).
+const CMD_READ_DATA_READY: u16 = 0x0202;
+
+/// Read the latest measured values (8 words).
+const CMD_READ_MEASURED_VALUES: u16 = 0x03C4;
+
+/// Read the device status register (2 words). Error flags stay set until cleared or a reset.
+const CMD_READ_DEVICE_STATUS: u16 = 0xD206;
+
+/// Read the product name, as a null-terminated string of up to 32 characters.
+const CMD_PRODUCT_NAME: u16 = 0xD014;
+
+/// Read the firmware, hardware and protocol versions.
+const CMD_VERSION: u16 = 0xD100;
+
+/// Longest product name the sensor can report.
+const PRODUCT_NAME_LEN: usize = 32;
+
+/// Size of the VOC algorithm state blob, without CRCs.
+pub const VOC_STATE_LEN: usize = 8;
+
+/// What the sensor reports for an unsigned value it doesn't have.
+const UNKNOWN_UNSIGNED: u16 = 0xFFFF;
+
+/// What the sensor reports for a signed value it doesn't have.
+const UNKNOWN_SIGNED: i16 = 0x7FFF;
+
+impl<I: I2c> From<sensirion::Error<I>> for SensorError {
+    fn from(err: sensirion::Error<I>) -> Self {
+        match err {
+            sensirion::Error::Crc => SensorError::Crc,
+            _ => SensorError::I2c,
+        }
+    }
+}
+
+/// Which sensor is fitted, and what it's running.
+#[derive(Debug, Clone, Copy)]
+pub struct SensorInfo {
+    product: [u8; PRODUCT_NAME_LEN],
+    /// Major and minor firmware version.
+    pub firmware: (u8, u8),
+    /// Major and minor hardware version.
+    pub hardware: (u8, u8),
+}
+
+impl SensorInfo {
+    /// The product name, e.g. `SEN55`.
+    pub fn product(&self) -> &str {
+        let len = self
+            .product
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(PRODUCT_NAME_LEN);
+        core::str::from_utf8(&self.product[..len]).unwrap_or("?")
+    }
+}
+
+/// One set of measured values, in the units they're published in. A value's `None` if the sensor
+/// says it doesn't have one: PM while the fan's stopped, NOx for its first few seconds, or
+/// anything the fitted model doesn't measure (e.g. NOx on a SEN54).
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub pm1_0: Option<f32>,
+    pub pm2_5: Option<f32>,
+    pub pm4_0: Option<f32>,
+    pub pm10_0: Option<f32>,
+    pub humidity: Option<f32>,
+    pub temperature: Option<f32>,
+    pub voc_index: Option<f32>,
+    pub nox_index: Option<f32>,
+}
+
+/// The device status register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct DeviceStatus(pub u32);
+
+impl DeviceStatus {
+    const FAN_SPEED_WARNING: u32 = 1 << 21;
+    const FAN_CLEANING: u32 = 1 << 19;
+    const GAS_ERROR: u32 = 1 << 7;
+    const RHT_ERROR: u32 = 1 << 6;
+    const LASER_ERROR: u32 = 1 << 5;
+    const FAN_ERROR: u32 = 1 << 4;
+
+    /// Whether the PM values can't be trusted: the fan's being cleaned, or it or the laser has
+    /// failed.
+    pub const fn pm_unreliable(&self) -> bool {
+        self.0 & (Self::FAN_CLEANING | Self::LASER_ERROR | Self::FAN_ERROR) != 0
+    }
+
+    /// Whether the sensor has flagged a fault, as opposed to just a warning or fan cleaning.
+    pub const fn has_error(&self) -> bool {
+        self.0 & (Self::GAS_ERROR | Self::RHT_ERROR | Self::LASER_ERROR | Self::FAN_ERROR) != 0
+    }
+
+    /// Whether the fan's running too fast or slow, which usually sorts itself out.
+    pub const fn fan_speed_warning(&self) -> bool {
+        self.0 & Self::FAN_SPEED_WARNING != 0
+    }
+}
+
+/// What polling the sensor found.
+pub enum Fetch {
+    /// No new measurement since the last one.
+    NotReady,
+    /// A new measurement, with anything the status says can't be trusted left out.
+    Ready {
+        measurement: Measurement,
+        status: DeviceStatus,
+    },
+}
+
+/// Poll the sensor: check it has a new measurement and if so read it and the device status.
+///
+/// The SEN5x wants a separate write and read for each command, so this can't be a single
+/// transaction, but it skips the values and status when there's nothing new and never blocks on
+/// the execution times the way the driver does.
+pub async fn fetch<I: I2c>(i2c: &mut I) -> Result<Fetch, SensorError> {
+    let mut ready = [0u8; 3];
+    read_words(i2c, CMD_READ_DATA_READY, &mut ready).await?;
+    if ready[1] == 0 {
+        return Ok(Fetch::NotReady);
+    }
+
+    let mut measurement = read_measured_values(i2c).await?;
+
+    let mut words = [0u8; 2 * 3];
+    read_words(i2c, CMD_READ_DEVICE_STATUS, &mut words).await?;
+    let mut status = [0u8; 4];
+    strip_crcs(&words, &mut status);
+    let status = DeviceStatus(u32::from_be_bytes(status));
+
+    if status.pm_unreliable() {
+        measurement.pm1_0 = None;
+        measurement.pm2_5 = None;
+        measurement.pm4_0 = None;
+        measurement.pm10_0 = None;
+    }
+    if status.0 & DeviceStatus::GAS_ERROR != 0 {
+        measurement.voc_index = None;
+        measurement.nox_index = None;
+    }
+    if status.0 & DeviceStatus::RHT_ERROR != 0 {
+        measurement.humidity = None;
+        measurement.temperature = None;
+    }
+
+    Ok(Fetch::Ready {
+        measurement,
+        status,
+    })
+}
+
+/// Read the latest measured values. The driver would pass the sensor's placeholders for missing
+/// values on as real (and very wrong) readings, so they're picked out here instead.
+async fn read_measured_values<I: I2c>(i2c: &mut I) -> Result<Measurement, SensorError> {
+    let mut words = [0u8; 8 * 3];
+    read_words(i2c, CMD_READ_MEASURED_VALUES, &mut words).await?;
+
+    let mut values = [0u8; 8 * 2];
+    strip_crcs(&words, &mut values);
+    let word = |i: usize| [values[i * 2], values[i * 2 + 1]];
+
+    let unsigned = |i: usize, scale: f32| {
+        let value = u16::from_be_bytes(word(i));
+        (value != UNKNOWN_UNSIGNED).then(|| value as f32 / scale)
+    };
+    let signed = |i: usize, scale: f32| {
+        let value = i16::from_be_bytes(word(i));
+        (value != UNKNOWN_SIGNED).then(|| value as f32 / scale)
+    };
+
+    Ok(Measurement {
+        pm1_0: unsigned(0, 10.0),
+        pm2_5: unsigned(1, 10.0),
+        pm4_0: unsigned(2, 10.0),
+        pm10_0: unsigned(3, 10.0),
+        humidity: signed(4, 100.0),
+        temperature: signed(5, 200.0),
+        voc_index: signed(6, 10.0),
+        nox_index: signed(7, 10.0),
+    })
+}
+
+/// Read the product name and versions. Only works in idle mode.
+pub async fn read_info<I: I2c>(i2c: &mut I) -> Result<SensorInfo, SensorError> {
+    let mut words = [0u8; PRODUCT_NAME_LEN / 2 * 3];
+    read_words(i2c, CMD_PRODUCT_NAME, &mut words).await?;
+    let mut product = [0u8; PRODUCT_NAME_LEN];
+    strip_crcs(&words, &mut product);
+
+    // Firmware major, minor and debug, hardware major and minor, protocol major and minor, then a
+    // padding byte.
+    let mut words = [0u8; 12];
+    read_words(i2c, CMD_VERSION, &mut words).await?;
+    let mut version = [0u8; 8];
+    strip_crcs(&words, &mut version);
+
+    Ok(SensorInfo {
+        product,
+        firmware: (version[0], version[1]),
+        hardware: (version[3], version[4]),
+    })
+}
+
+/// Read the VOC algorithm's internal state. Works in both idle and measurement mode.
+pub async fn read_voc_state<I: I2c>(i2c: &mut I) -> Result<[u8; VOC_STATE_LEN], SensorError> {
+    let mut words = [0u8; VOC_STATE_LEN / 2 * 3];
+    read_words(i2c, CMD_VOC_ALGORITHM_STATE, &mut words).await?;
+
+    let mut state = [0u8; VOC_STATE_LEN];
+    strip_crcs(&words, &mut state);
+
+    Ok(state)
+}
+
+/// Restore a previously read VOC algorithm state.
+///
+/// The sensor only accepts this in idle mode, so it has to happen before measurement starts.
+pub async fn write_voc_state<I: I2c>(
+    i2c: &mut I,
+    state: &[u8; VOC_STATE_LEN],
+) -> Result<(), SensorError> {
+    write_words(i2c, CMD_VOC_ALGORITHM_STATE, state).await
+}
+
+/// Stop measuring and go back to idle mode.
+pub async fn stop_measurement<I: I2c>(i2c: &mut I) -> Result<(), SensorError> {
+    sensirion::write_command_u16(i2c, SEN5X_ADDR, CMD_STOP_MEASUREMENT)
+        .map_err(|_| SensorError::I2c)?;
+    Timer::after_millis(200).await;
+    Ok(())
+}
+
+/// Start measuring everything but PM, so the fan doesn't run. Only works in idle mode.
+pub async fn start_measurement_without_pm<I: I2c>(i2c: &mut I) -> Result<(), SensorError> {
+    sensirion::write_command_u16(i2c, SEN5X_ADDR, CMD_START_MEASUREMENT_RHT_GAS)
+        .map_err(|_| SensorError::I2c)?;
+    Timer::after_millis(50).await;
+    Ok(())
+}
+
+/// Clean the fan now, rather than waiting for the weekly clean. PM reads as missing (see
+/// [`DeviceStatus::pm_unreliable`]) for the 10 seconds it takes.
+pub async fn start_fan_cleaning<I: I2c>(i2c: &mut I) -> Result<(), SensorError> {
+    sensirion::write_command_u16(i2c, SEN5X_ADDR, CMD_START_FAN_CLEANING)
+        .map_err(|_| SensorError::I2c)?;
+    Timer::after_millis(20).await;
+    Ok(())
+}
+
+/// Send a command and read back the CRC-protected words it returns.
+async fn read_words<I: I2c>(i2c: &mut I, command: u16, out: &mut [u8]) -> Result<(), SensorError> {
+    sensirion::write_command_u16(i2c, SEN5X_ADDR, command).map_err(|_| SensorError::I2c)?;
+
+    // Every read command we use has an execution time of 20ms.
+    Timer::after_millis(20).await;
+
+    sensirion::read_words_with_crc(i2c, SEN5X_ADDR, out)?;
+
+    Ok(())
+}
+
+/// Send a command followed by `data`, split into words with a CRC after each.
+async fn write_words<I: I2c>(i2c: &mut I, command: u16, data: &[u8]) -> Result<(), SensorError> {
+    // Largest payload we send is the VOC state, 4 words + 4 CRCs, plus the command.
+    let mut buf = [0u8; 2 + VOC_STATE_LEN / 2 * 3];
+    let len = 2 + data.len() / 2 * 3;
+
+    buf[0..2].copy_from_slice(&command.to_be_bytes());
+    for (word, chunk) in data.chunks(2).zip(buf[2..len].chunks_mut(3)) {
+        chunk[0] = word[0];
+        chunk[1] = word[1];
+        chunk[2] = crc8::calculate(word);
+    }
+
+    i2c.write(SEN5X_ADDR, &buf[..len])
+        .map_err(|_| SensorError::I2c)?;
+
+    Timer::after_millis(20).await;
+
+    Ok(())
+}
+
+/// Drop the CRC byte after every word, the driver has already checked them.
+fn strip_crcs(words: &[u8], out: &mut [u8]) {
+    for (chunk, word) in words.chunks(3).zip(out.chunks_mut(2)) {
+        word.copy_from_slice(&chunk[0..2]);
+    }
+}
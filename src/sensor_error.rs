@@ -0,0 +1,180 @@
+use defmt::{error, info, warn, Format};
+use embassy_time::Duration;
+use portable_atomic::{AtomicU32, Ordering};
+use serde::Serialize;
+
+use crate::syslog::{self, Severity};
+
+/// Every way a conversation with the SEN55 can go wrong, without the i2c driver's error type
+/// dragging its generics around with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SensorError {
+    /// The data came back but the checksum didn't match, usually a one-off glitch on the bus.
+    Crc,
+    /// The i2c transaction itself failed (NACK, arbitration loss, etc).
+    I2c,
+    /// The sensor reported an internal error.
+    Internal,
+    /// The sensor's self test failed.
+    SelfTest,
+    /// The sensor refused the command in its current state, e.g. not measuring yet.
+    NotAllowed,
+}
+
+/// What the caller should do after a given error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RetryPolicy {
+    /// Try the same command again straight away.
+    RetryNow,
+    /// Give the bus a moment before trying again.
+    Backoff(Duration),
+    /// The sensor is in a state we can't recover from without reinitialising it.
+    Reinit,
+}
+
+/// Base delay for errors that want a backoff; doubled for each consecutive failure.
+const BACKOFF_BASE: Duration = Duration::from_millis(250);
+
+/// Cap the backoff so we don't stop polling for too long.
+const BACKOFF_MAX: Duration = Duration::from_secs(8);
+
+impl<E> From<sen5x_rs::Error<E>> for SensorError {
+    fn from(err: sen5x_rs::Error<E>) -> Self {
+        match err {
+            sen5x_rs::Error::Crc => SensorError::Crc,
+            sen5x_rs::Error::I2c(_) => SensorError::I2c,
+            sen5x_rs::Error::Internal => SensorError::Internal,
+            sen5x_rs::Error::SelfTest => SensorError::SelfTest,
+            sen5x_rs::Error::NotAllowed => SensorError::NotAllowed,
+        }
+    }
+}
+
+impl SensorError {
+    /// Decide how to recover from this error, given how many failures have happened in a row.
+    pub fn retry_policy(&self, consecutive_failures: u32) -> RetryPolicy {
+        match self {
+            SensorError::Crc => RetryPolicy::RetryNow,
+            SensorError::I2c | SensorError::Internal => {
+                let shift = consecutive_failures.min(5);
+                let backoff = BACKOFF_BASE * (1 << shift);
+
+                RetryPolicy::Backoff(if backoff > BACKOFF_MAX {
+                    BACKOFF_MAX
+                } else {
+                    backoff
+                })
+            }
+            SensorError::SelfTest | SensorError::NotAllowed => RetryPolicy::Reinit,
+        }
+    }
+
+    /// Log the error with a short description of what we were doing at the time.
+    ///
+    /// CRC errors are expected occasionally so they're only a warning, everything else is an error.
+    pub fn log(&self, context: &str) {
+        match self {
+            SensorError::Crc => warn!("{}: CRC mismatch", context),
+            SensorError::I2c => error!("{}: i2c error", context),
+            SensorError::Internal => error!("{}: sensirion internal", context),
+            SensorError::SelfTest => error!("{}: self-test failure", context),
+            SensorError::NotAllowed => error!("{}: not allowed", context),
+        }
+    }
+
+    /// The same description as `log` uses, for sending elsewhere.
+    pub const fn description(&self) -> &'static str {
+        match self {
+            SensorError::Crc => "CRC mismatch",
+            SensorError::I2c => "i2c error",
+            SensorError::Internal => "sensirion internal",
+            SensorError::SelfTest => "self-test failure",
+            SensorError::NotAllowed => "not allowed",
+        }
+    }
+
+    /// Log the error and count it towards the diagnostics.
+    pub fn record(&self, context: &str) {
+        self.log(context);
+
+        let severity = match self {
+            SensorError::Crc => Severity::Warning,
+            _ => Severity::Error,
+        };
+        syslog::event(
+            severity,
+            "sensor",
+            format_args!("{}: {}", context, self.description()),
+        );
+        SENSOR_DIAGNOSTICS.count(*self);
+    }
+}
+
+/// Running totals of sensor errors since boot.
+pub struct SensorDiagnostics {
+    crc: AtomicU32,
+    i2c: AtomicU32,
+    internal: AtomicU32,
+    self_test: AtomicU32,
+    not_allowed: AtomicU32,
+    reinits: AtomicU32,
+}
+
+/// A point-in-time copy of the diagnostics counters.
+#[derive(Debug, Clone, Copy, Default, Format, Serialize)]
+pub struct SensorDiagnosticsSnapshot {
+    pub crc: u32,
+    pub i2c: u32,
+    pub internal: u32,
+    pub self_test: u32,
+    pub not_allowed: u32,
+    pub reinits: u32,
+}
+
+pub static SENSOR_DIAGNOSTICS: SensorDiagnostics = SensorDiagnostics::new();
+
+impl SensorDiagnostics {
+    const fn new() -> Self {
+        Self {
+            crc: AtomicU32::new(0),
+            i2c: AtomicU32::new(0),
+            internal: AtomicU32::new(0),
+            self_test: AtomicU32::new(0),
+            not_allowed: AtomicU32::new(0),
+            reinits: AtomicU32::new(0),
+        }
+    }
+
+    fn count(&self, err: SensorError) {
+        let counter = match err {
+            SensorError::Crc => &self.crc,
+            SensorError::I2c => &self.i2c,
+            SensorError::Internal => &self.internal,
+            SensorError::SelfTest => &self.self_test,
+            SensorError::NotAllowed => &self.not_allowed,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that the sensor had to be reinitialised.
+    pub fn count_reinit(&self) {
+        self.reinits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> SensorDiagnosticsSnapshot {
+        SensorDiagnosticsSnapshot {
+            crc: self.crc.load(Ordering::Relaxed),
+            i2c: self.i2c.load(Ordering::Relaxed),
+            internal: self.internal.load(Ordering::Relaxed),
+            self_test: self.self_test.load(Ordering::Relaxed),
+            not_allowed: self.not_allowed.load(Ordering::Relaxed),
+            reinits: self.reinits.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Dump the counters to the log.
+    pub fn log(&self) {
+        info!("Sensor diagnostics: {}", self.snapshot());
+    }
+}
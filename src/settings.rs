@@ -0,0 +1,232 @@
+//! Settings that can be changed at runtime (e.g. from Home Assistant) and are kept in flash so they
+//! survive a reboot.
+
+use core::cell::Cell;
+use core::fmt::{self, Write};
+
+use defmt::{error, info};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use heapless::String;
+
+use crate::metric::{Metric, MetricSet};
+use crate::mode::Mode;
+use crate::pages::PageId;
+use crate::power::PowerProfile;
+use crate::storage::{self, Slot};
+
+/// Bump this whenever the layout of the stored bytes changes, so old settings are ignored rather
+/// than misread.
+const SETTINGS_VERSION: u8 = 8;
+
+const SETTINGS_LEN: usize = 11;
+
+/// Which unit temperatures are shown in on the screen. Published readings are always in Celsius.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+}
+
+impl TemperatureUnit {
+    /// Convert a temperature in Celsius to this unit.
+    pub fn convert(&self, celsius: f32) -> f32 {
+        match self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+        }
+    }
+
+    pub const fn symbol(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "\u{b0}C",
+            TemperatureUnit::Fahrenheit => "\u{b0}F",
+        }
+    }
+}
+
+/// Whose conventions numbers are shown in on the screen: the decimal separator, and whether a unit
+/// is spaced from its number. Published readings are always JSON numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Language {
+    /// `21.5°C`
+    English,
+    /// `21,5 °C`
+    Swedish,
+    /// `21,5 °C`
+    German,
+}
+
+impl Language {
+    pub const ALL: [Language; 3] = [Language::English, Language::Swedish, Language::German];
+
+    /// The language's own name for itself.
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Language::English => "English",
+            Language::Swedish => "Svenska",
+            Language::German => "Deutsch",
+        }
+    }
+
+    pub const fn decimal_separator(&self) -> char {
+        match self {
+            Language::English => '.',
+            Language::Swedish | Language::German => ',',
+        }
+    }
+
+    /// What goes between a number and its unit, e.g. `°C` or `%`.
+    pub const fn unit_space(&self) -> &'static str {
+        match self {
+            Language::English => "",
+            Language::Swedish | Language::German => " ",
+        }
+    }
+
+    /// Write `value` with `decimals` decimal places and this language's decimal separator.
+    pub fn write_number(&self, out: &mut impl Write, value: f32, decimals: usize) -> fmt::Result {
+        let mut text = String::<16>::new();
+        write!(text, "{:.*}", decimals, value)?;
+        for c in text.chars() {
+            out.write_char(match c {
+                '.' => self.decimal_separator(),
+                c => c,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The next language along, for the menu.
+    pub fn next(&self) -> Language {
+        Language::ALL[(*self as usize + 1) % Language::ALL.len()]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Settings {
+    /// Metrics shown on the readings page. Hidden ones leave their tile blank.
+    pub visible_metrics: MetricSet,
+
+    /// The metric on the live graph page.
+    pub graph_metric: Metric,
+
+    /// Whether anyone's around, see `mode`.
+    pub mode: Mode,
+
+    /// Keep network details (SSID, addresses) off the screen, for devices in shared spaces.
+    pub guest_mode: bool,
+
+    /// Backlight brightness, as a percentage.
+    pub brightness: u8,
+
+    pub temperature_unit: TemperatureUnit,
+
+    /// How numbers are written on screen.
+    pub language: Language,
+
+    /// A page to stay on instead of cycling through the carousel.
+    pub pinned_page: Option<PageId>,
+
+    /// How much to trade responsiveness for power, see `power`.
+    pub power_profile: PowerProfile,
+}
+
+impl Settings {
+    const DEFAULT: Settings = Settings {
+        visible_metrics: MetricSet::ALL,
+        graph_metric: Metric::Pm2_5,
+        mode: Mode::Home,
+        guest_mode: false,
+        brightness: 100,
+        temperature_unit: TemperatureUnit::Celsius,
+        language: Language::English,
+        pinned_page: None,
+        power_profile: PowerProfile::Balanced,
+    };
+
+    fn to_bytes(self) -> [u8; SETTINGS_LEN] {
+        let [metrics_low, metrics_high] = self.visible_metrics.bits().to_le_bytes();
+        [
+            SETTINGS_VERSION,
+            metrics_low,
+            metrics_high,
+            self.mode.to_byte(),
+            self.guest_mode as u8,
+            self.brightness,
+            self.temperature_unit as u8,
+            self.pinned_page.map_or(0, |page| page.to_byte()),
+            self.power_profile.to_byte(),
+            self.language as u8,
+            self.graph_metric as u8,
+        ]
+    }
+
+    fn from_bytes(bytes: &[u8; SETTINGS_LEN]) -> Option<Settings> {
+        if bytes[0] != SETTINGS_VERSION {
+            return None;
+        }
+
+        Some(Settings {
+            visible_metrics: MetricSet::from_bits(u16::from_le_bytes([bytes[1], bytes[2]])),
+            mode: Mode::from_byte(bytes[3])?,
+            guest_mode: bytes[4] != 0,
+            brightness: bytes[5].clamp(MIN_BRIGHTNESS, 100),
+            temperature_unit: match bytes[6] {
+                0 => TemperatureUnit::Celsius,
+                _ => TemperatureUnit::Fahrenheit,
+            },
+            pinned_page: PageId::from_byte(bytes[7]),
+            power_profile: PowerProfile::from_byte(bytes[8])?,
+            language: *Language::ALL.get(usize::from(bytes[9]))?,
+            graph_metric: *Metric::ALL.get(usize::from(bytes[10]))?,
+        })
+    }
+}
+
+/// The backlight never goes dimmer than this, so the screen can't be turned off by accident.
+pub const MIN_BRIGHTNESS: u8 = 10;
+
+static SETTINGS: Mutex<ThreadModeRawMutex, Cell<Settings>> =
+    Mutex::new(Cell::new(Settings::DEFAULT));
+
+/// Load the settings from flash, falling back to the defaults if there aren't any (or they're from
+/// an incompatible version). Storage must be initialised first.
+pub async fn load() {
+    let mut bytes = [0u8; SETTINGS_LEN];
+
+    let settings = match storage::load(Slot::Settings, &mut bytes).await {
+        Ok(()) => Settings::from_bytes(&bytes).unwrap_or(Settings::DEFAULT),
+        Err(e) => {
+            info!("No stored settings ({}), using defaults", e);
+            Settings::DEFAULT
+        }
+    };
+
+    info!("Settings: {}", settings);
+    SETTINGS.lock(|s| s.set(settings));
+}
+
+/// A copy of the current settings.
+pub fn get() -> Settings {
+    SETTINGS.lock(|s| s.get())
+}
+
+/// Change the settings and write them to flash. Nothing is written if they didn't actually change.
+pub async fn update(change: impl FnOnce(&mut Settings)) -> Settings {
+    let old = get();
+    let mut new = old;
+    change(&mut new);
+
+    if new == old {
+        return new;
+    }
+
+    SETTINGS.lock(|s| s.set(new));
+
+    if let Err(e) = storage::save(Slot::Settings, &new.to_bytes()).await {
+        error!("Couldn't save settings: {}", e);
+    }
+
+    new
+}
@@ -0,0 +1,196 @@
+//! Keeps track of the time of day with SNTP (RFC 4330), so readings can be timestamped with when
+//! they were actually taken rather than when they happened to arrive.
+//!
+//! Only the offset between the uptime clock and the wall clock is kept, so any `Instant` can be
+//! turned into a wall clock time once the first sync has worked, including ones from before it.
+//! The server's asked again every hour to keep up with the uptime clock's drift, and sooner after a
+//! failure. Only runs with `NTP_SERVER` set.
+
+use core::cell::Cell;
+use core::fmt::Write;
+
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer, WithTimeout};
+use heapless::String;
+use log::{info, warn};
+
+use crate::sockets::{self, User};
+use crate::traffic;
+
+const PORT: u16 = 123;
+
+/// How often the clock's synced once it's working.
+const SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long to wait before trying again after a failed sync.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the server has to answer.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PACKET_LEN: usize = 48;
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970).
+const UNIX_EPOCH: u64 = 2_208_988_800;
+
+/// The wall clock time at uptime zero, in microseconds since the Unix epoch, once it's known.
+static BOOTED_AT_US: Mutex<ThreadModeRawMutex, Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+/// When `at` was, in milliseconds since the Unix epoch, or `None` before the clock's been synced.
+pub fn unix_ms(at: Instant) -> Option<u64> {
+    let booted_at = BOOTED_AT_US.lock(|booted_at| booted_at.get())?;
+    Some((booted_at + at.as_micros()) / 1000)
+}
+
+/// When `at` was as an ISO 8601 UTC time to the millisecond, e.g. `2025-03-14T09:26:53.589Z`, or
+/// `None` before the clock's been synced.
+pub fn iso8601(at: Instant) -> Option<String<24>> {
+    let ms = unix_ms(at)?;
+    let secs = ms / 1000;
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let of_day = secs % 86_400;
+
+    let mut out = String::new();
+    write!(
+        out,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60,
+        ms % 1000
+    )
+    .ok()?;
+    Some(out)
+}
+
+/// The date `days` after 1970-01-01, from Howard Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Keeps the clock synced with `host` for as long as the device is up.
+#[embassy_executor::task]
+pub async fn worker(stack: Stack<'static>, host: &'static str) {
+    info!("started sntp worker");
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; PACKET_LEN];
+
+    let _claim = sockets::claim(User::Sntp);
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    if let Err(e) = socket.bind(0) {
+        warn!("Couldn't bind SNTP socket: {:?}", e);
+        return;
+    }
+
+    loop {
+        // Looked up each time, as pools hand out a different server every so often.
+        let wait = match stack.dns_query(host, DnsQueryType::A).await {
+            Ok(addresses) if !addresses.is_empty() => {
+                match sync(&mut socket, IpEndpoint::new(addresses[0], PORT)).await {
+                    Ok(()) => SYNC_INTERVAL,
+                    Err(reason) => {
+                        warn!("SNTP sync with {} failed: {}", host, reason);
+                        RETRY_INTERVAL
+                    }
+                }
+            }
+            Ok(_) | Err(_) => {
+                warn!("Couldn't resolve SNTP server {}", host);
+                RETRY_INTERVAL
+            }
+        };
+
+        Timer::after(wait).await;
+    }
+}
+
+/// Ask the server the time once, and take it as the clock's if the answer makes sense.
+async fn sync(socket: &mut UdpSocket<'_>, server: IpEndpoint) -> Result<(), &'static str> {
+    // Version 4, client mode. The transmit time is only there for the server to echo back, so the
+    // answer can be told apart from a stray or late one.
+    let mut request = [0; PACKET_LEN];
+    request[0] = 0b00_100_011;
+    let sent = Instant::now();
+    let cookie = sent.as_ticks().to_be_bytes();
+    request[40..48].copy_from_slice(&cookie);
+
+    socket
+        .send_to(&request, server)
+        .await
+        .map_err(|_| "couldn't send")?;
+    traffic::sent_datagram(User::Sntp, PACKET_LEN);
+
+    let mut answer = [0; PACKET_LEN];
+    let received = loop {
+        let (len, from) = socket
+            .recv_from(&mut answer)
+            .with_timeout(ANSWER_TIMEOUT)
+            .await
+            .map_err(|_| "no answer")?
+            .map_err(|_| "couldn't receive")?;
+        traffic::received(User::Sntp, len);
+
+        if from.endpoint == server && len == PACKET_LEN && answer[24..32] == cookie {
+            break Instant::now();
+        }
+    };
+
+    let leap = answer[0] >> 6;
+    let mode = answer[0] & 0b111;
+    let stratum = answer[1];
+    if mode != 4 || leap == 3 || stratum == 0 || stratum > 15 {
+        return Err("server isn't synced");
+    }
+
+    let seconds = u64::from(u32::from_be_bytes([
+        answer[40], answer[41], answer[42], answer[43],
+    ]));
+    let fraction = u64::from(u32::from_be_bytes([
+        answer[44], answer[45], answer[46], answer[47],
+    ]));
+    // The seconds wrap in 2036. Anything in the first half of the range is taken to be after that,
+    // as it'd otherwise be before 1968.
+    let seconds = match seconds < 1 << 31 {
+        true => seconds + (1 << 32),
+        false => seconds,
+    };
+    let unix_seconds = seconds - UNIX_EPOCH;
+    let transmitted_us = unix_seconds * 1_000_000 + ((fraction * 1_000_000) >> 32);
+
+    // The answer spent about half the round trip getting back.
+    let now_us = transmitted_us + (received - sent).as_micros() / 2;
+    let booted_at = now_us.saturating_sub(received.as_micros());
+
+    let first = BOOTED_AT_US
+        .lock(|cell| cell.replace(Some(booted_at)))
+        .is_none();
+    if first {
+        info!("Clock synced, booted at {}ms since 1970", booted_at / 1000);
+    }
+    Ok(())
+}
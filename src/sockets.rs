@@ -0,0 +1,177 @@
+//! The network stack has a fixed number of socket slots, set when it's created, and running out
+//! means a socket silently failing to open. So every user of a socket is listed here with how many
+//! it can hold at once under the current config, and the stack is sized to the total.
+//!
+//! Sockets are claimed while they're open, so the console's `dump` shows how many are in use (and
+//! the most there have been), and a user going over its share is logged rather than quietly
+//! starving someone else.
+
+use defmt::{warn, Format};
+use portable_atomic::{AtomicU8, Ordering};
+use serde::Serialize;
+
+use crate::{config, http_server, portal};
+
+/// Everything that opens a socket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum User {
+    /// The stack's own DHCP client.
+    Dhcp = 0,
+    /// The stack's own DNS resolver.
+    Dns,
+    Mqtt,
+    /// The multicast that takes MQTT's place without a broker, see `lan`.
+    Lan,
+    Syslog,
+    HttpPush,
+    /// Rules calling their webhooks, see `rules`.
+    Webhook,
+    HttpServer,
+    /// Round trip probes, see `latency`.
+    Latency,
+    /// The Wi-Fi setup form and the DHCP and DNS servers behind it, see `portal`.
+    Portal,
+    Sntp,
+    /// Checking for and downloading firmware updates, see `ota`.
+    Ota,
+}
+
+pub const USER_COUNT: usize = 12;
+pub const USERS: [User; USER_COUNT] = [
+    User::Dhcp,
+    User::Dns,
+    User::Mqtt,
+    User::Lan,
+    User::Syslog,
+    User::HttpPush,
+    User::Webhook,
+    User::HttpServer,
+    User::Latency,
+    User::Portal,
+    User::Sntp,
+    User::Ota,
+];
+
+impl User {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            User::Dhcp => "dhcp",
+            User::Dns => "dns",
+            User::Mqtt => "mqtt",
+            User::Lan => "lan",
+            User::Syslog => "syslog",
+            User::HttpPush => "http_push",
+            User::Webhook => "webhook",
+            User::HttpServer => "http_server",
+            User::Latency => "latency",
+            User::Portal => "portal",
+            User::Sntp => "sntp",
+            User::Ota => "ota",
+        }
+    }
+
+    /// How many sockets this can have open at once, given the config.
+    pub const fn budget(&self) -> usize {
+        match self {
+            User::Dhcp | User::Dns | User::Webhook => 1,
+            // Only one of them runs, but which is down to the stored config, so both get a socket.
+            User::Mqtt | User::Lan => 1,
+            User::Syslog => config::SYSLOG_HOST.is_some() as usize,
+            User::HttpPush => config::HTTP_PUSH_URL.is_some() as usize,
+            User::HttpServer if config::HTTP_SERVER => http_server::CONNECTIONS,
+            User::HttpServer => 0,
+            User::Latency => config::LATENCY_MONITOR as usize,
+            User::Portal if portal::ENABLED => portal::SOCKETS,
+            User::Portal => 0,
+            User::Sntp => config::NTP_SERVER.is_some() as usize,
+            User::Ota => config::OTA_URL.is_some() as usize,
+        }
+    }
+
+    /// The stack's own sockets are open the whole time, and never claimed.
+    pub const fn permanent(&self) -> bool {
+        matches!(self, User::Dhcp | User::Dns)
+    }
+}
+
+/// The number of sockets the stack is created with.
+pub const BUDGET: usize = {
+    let mut total = 0;
+    let mut i = 0;
+    while i < USER_COUNT {
+        total += USERS[i].budget();
+        i += 1;
+    }
+    total
+};
+
+static IN_USE: [AtomicU8; USER_COUNT] = [const { AtomicU8::new(0) }; USER_COUNT];
+static PEAK: AtomicU8 = AtomicU8::new(0);
+
+/// A socket in use, counted until this is dropped. Hold it for as long as the socket's open.
+pub struct Claim {
+    user: User,
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        IN_USE[self.user as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Count a socket `user` is about to open.
+pub fn claim(user: User) -> Claim {
+    let held = IN_USE[user as usize].fetch_add(1, Ordering::Relaxed) + 1;
+    if usize::from(held) > user.budget() {
+        warn!(
+            "{} has {} sockets open, more than its {}",
+            user.name(),
+            held,
+            user.budget()
+        );
+    }
+
+    PEAK.fetch_max(in_use(), Ordering::Relaxed);
+    Claim { user }
+}
+
+fn held(user: User) -> u8 {
+    match user.permanent() {
+        true => user.budget() as u8,
+        false => IN_USE[user as usize].load(Ordering::Relaxed),
+    }
+}
+
+fn in_use() -> u8 {
+    USERS.into_iter().map(held).sum()
+}
+
+/// How many sockets are in use, and by whom.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SocketSnapshot {
+    pub budget: usize,
+    pub in_use: u8,
+    pub peak: u8,
+    pub users: [UserUsage; USER_COUNT],
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UserUsage {
+    pub name: &'static str,
+    pub in_use: u8,
+    pub budget: usize,
+}
+
+pub fn snapshot() -> SocketSnapshot {
+    SocketSnapshot {
+        budget: BUDGET,
+        in_use: in_use(),
+        peak: PEAK.load(Ordering::Relaxed).max(in_use()),
+        users: USERS.map(|user| UserUsage {
+            name: user.name(),
+            in_use: held(user),
+            budget: user.budget(),
+        }),
+    }
+}
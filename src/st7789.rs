@@ -0,0 +1,299 @@
+//! Driver for the ST7789V2 panel on the Waveshare 1.69" module, written around what the UI needs.
+//!
+//! Everything's drawn through `embedded-graphics`, which is synchronous, so drawing blocks on the
+//! bus like it always has. Each window is set once and then streamed a chunk at a time, so a
+//! background or a batch of text (see `batch`) is one transaction rather than one per pixel. The
+//! slower full-screen jobs, like waking the panel up and clearing it, are async instead and go out
+//! over DMA, leaving the executor free for the sensor and network in the meantime.
+//!
+//! If the panel's tearing-effect output is wired up, it's turned on at init and
+//! `wait_for_blanking` waits for the panel to finish a refresh, for drawing in between.
+//!
+//! The bytes go out over whatever [`Bus`] the panel's on: SPI, or with the `parallel-display`
+//! feature, an 8080-style parallel bus (see `parallel`).
+
+use embassy_rp::gpio::{Input, Output};
+use embassy_rp::peripherals::SPI0;
+use embassy_rp::spi::{self, Async, Spi};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_graphics::pixelcolor::raw::ToBytes;
+use embedded_graphics::pixelcolor::Rgb565;
+use embedded_graphics::prelude::{Dimensions, DrawTarget, Point, Size};
+use embedded_graphics::primitives::Rectangle;
+use embedded_graphics::Pixel;
+
+pub const WIDTH: u32 = 240;
+pub const HEIGHT: u32 = 280;
+
+/// The controller has room for 320 rows, and the panel shows the middle 280 of them.
+const ROW_OFFSET: u16 = 20;
+
+/// A couple of refreshes at the panel's ~53 Hz frame rate, after which the tearing-effect output
+/// is assumed to be missing.
+const BLANKING_TIMEOUT: Duration = Duration::from_millis(40);
+
+/// Pixels are sent this many bytes at a time.
+const CHUNK: usize = 512;
+
+const SWRESET: u8 = 0x01;
+const SLPOUT: u8 = 0x11;
+const INVON: u8 = 0x21;
+const DISPON: u8 = 0x29;
+const CASET: u8 = 0x2A;
+const RASET: u8 = 0x2B;
+const RAMWR: u8 = 0x2C;
+const TEON: u8 = 0x35;
+const MADCTL: u8 = 0x36;
+const COLMOD: u8 = 0x3A;
+
+/// How the panel's laid out (portrait, RGB order, 16 bits a pixel), then Waveshare's power, porch
+/// and gamma settings for it.
+const INIT_SEQUENCE: [(u8, &[u8]); 15] = [
+    (MADCTL, &[0x00]),
+    (COLMOD, &[0x05]),
+    (0xB2, &[0x0B, 0x0B, 0x00, 0x33, 0x35]),
+    (0xB7, &[0x11]),
+    (0xBB, &[0x35]),
+    (0xC0, &[0x2C]),
+    (0xC2, &[0x01]),
+    (0xC3, &[0x0D]),
+    (0xC4, &[0x20]),
+    (0xC6, &[0x13]),
+    (0xD0, &[0xA4, 0xA1]),
+    (0xD6, &[0xA1]),
+    (
+        0xE0,
+        &[
+            0xF0, 0x06, 0x0B, 0x0A, 0x09, 0x26, 0x29, 0x33, 0x41, 0x18, 0x16, 0x15, 0x29, 0x2D,
+        ],
+    ),
+    (
+        0xE1,
+        &[
+            0xF0, 0x04, 0x08, 0x08, 0x07, 0x03, 0x28, 0x32, 0x40, 0x3B, 0x19, 0x18, 0x2A, 0x2E,
+        ],
+    ),
+    (0xE4, &[0x25, 0x00, 0x00]),
+];
+
+/// Carries commands, parameters and pixels to the panel. DC and CS are up to the driver, and
+/// writes only return once the last byte's gone out, so they can be changed straight after.
+pub trait Bus {
+    type Error: core::fmt::Debug;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// The same, over DMA.
+    async fn write_async(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+impl Bus for Spi<'static, SPI0, Async> {
+    type Error = spi::Error;
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), spi::Error> {
+        self.blocking_write(bytes)
+    }
+
+    async fn write_async(&mut self, bytes: &[u8]) -> Result<(), spi::Error> {
+        Spi::write(self, bytes).await
+    }
+}
+
+pub struct St7789<B> {
+    bus: B,
+    /// Low for a command, high for its parameters or pixels.
+    dc: Output<'static>,
+    cs: Output<'static>,
+    rst: Output<'static>,
+    /// The panel's tearing-effect output, if it's wired up.
+    te: Option<Input<'static>>,
+}
+
+impl<B: Bus> St7789<B> {
+    pub fn new(bus: B, dc: Output<'static>, cs: Output<'static>, rst: Output<'static>) -> Self {
+        Self {
+            bus,
+            dc,
+            cs,
+            rst,
+            te: None,
+        }
+    }
+
+    /// Use the panel's tearing-effect output, see `wait_for_blanking`.
+    pub fn with_tearing_effect(mut self, te: Input<'static>) -> Self {
+        self.te = Some(te);
+        self
+    }
+
+    /// Reset the panel and wake it up, ready to draw on.
+    pub async fn init(&mut self) -> Result<(), B::Error> {
+        self.rst.set_low();
+        Timer::after_millis(10).await;
+        self.rst.set_high();
+        Timer::after_millis(120).await;
+
+        self.command(SWRESET, &[])?;
+        Timer::after_millis(150).await;
+
+        for (command, params) in INIT_SEQUENCE {
+            self.command(command, params)?;
+        }
+        if self.te.is_some() {
+            // V-blank only.
+            self.command(TEON, &[0x00])?;
+        }
+        self.command(INVON, &[])?;
+
+        self.command(SLPOUT, &[])?;
+        Timer::after_millis(120).await;
+        self.command(DISPON, &[])
+    }
+
+    /// Fill `area` with `color` over DMA.
+    pub async fn fill(&mut self, area: &Rectangle, color: Rgb565) -> Result<(), B::Error> {
+        let area = area.intersection(&self.bounding_box());
+        if area.is_zero_sized() {
+            return Ok(());
+        }
+
+        let mut chunk = [0u8; CHUNK];
+        for pixel in chunk.chunks_exact_mut(2) {
+            pixel.copy_from_slice(&color.to_be_bytes());
+        }
+
+        self.set_window(&area)?;
+        let mut remaining = area.size.width as usize * area.size.height as usize * 2;
+
+        self.dc.set_high();
+        self.cs.set_low();
+        let mut sent = Ok(());
+        while remaining > 0 && sent.is_ok() {
+            let len = remaining.min(CHUNK);
+            sent = self.bus.write_async(&chunk[..len]).await;
+            remaining -= len;
+        }
+        self.cs.set_high();
+
+        sent
+    }
+
+    /// Wait until the panel's between refreshes, so what's drawn next doesn't shear. Returns
+    /// straight away without the tearing-effect output, and gives up after `BLANKING_TIMEOUT` in
+    /// case it's set but not actually connected.
+    pub async fn wait_for_blanking(&mut self) {
+        if let Some(te) = &mut self.te {
+            let _ = with_timeout(BLANKING_TIMEOUT, te.wait_for_rising_edge()).await;
+        }
+    }
+
+    fn command(&mut self, command: u8, params: &[u8]) -> Result<(), B::Error> {
+        self.cs.set_low();
+        self.dc.set_low();
+        let mut sent = self.bus.write(&[command]);
+        if sent.is_ok() && !params.is_empty() {
+            self.dc.set_high();
+            sent = self.bus.write(params);
+        }
+        self.cs.set_high();
+        sent
+    }
+
+    /// Point the panel's memory writes at `area`, which must be on screen.
+    fn set_window(&mut self, area: &Rectangle) -> Result<(), B::Error> {
+        let x = area.top_left.x as u16;
+        let y = area.top_left.y as u16 + ROW_OFFSET;
+        let x_end = x + area.size.width as u16 - 1;
+        let y_end = y + area.size.height as u16 - 1;
+
+        let [x0, x1] = x.to_be_bytes();
+        let [x2, x3] = x_end.to_be_bytes();
+        self.command(CASET, &[x0, x1, x2, x3])?;
+        let [y0, y1] = y.to_be_bytes();
+        let [y2, y3] = y_end.to_be_bytes();
+        self.command(RASET, &[y0, y1, y2, y3])?;
+        self.command(RAMWR, &[])
+    }
+
+    /// Send the pixels for the window that's been set, a chunk at a time.
+    fn write_pixels(&mut self, colors: impl IntoIterator<Item = Rgb565>) -> Result<(), B::Error> {
+        let mut chunk = [0u8; CHUNK];
+        let mut len = 0;
+
+        self.dc.set_high();
+        self.cs.set_low();
+        let mut sent = Ok(());
+        for color in colors {
+            chunk[len..len + 2].copy_from_slice(&color.to_be_bytes());
+            len += 2;
+            if len == CHUNK {
+                sent = self.bus.write(&chunk);
+                len = 0;
+                if sent.is_err() {
+                    break;
+                }
+            }
+        }
+        if sent.is_ok() && len > 0 {
+            sent = self.bus.write(&chunk[..len]);
+        }
+        self.cs.set_high();
+
+        sent
+    }
+}
+
+impl<B> Dimensions for St7789<B> {
+    fn bounding_box(&self) -> Rectangle {
+        Rectangle::new(Point::zero(), Size::new(WIDTH, HEIGHT))
+    }
+}
+
+impl<B: Bus> DrawTarget for St7789<B> {
+    type Color = Rgb565;
+    type Error = B::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Rgb565>>,
+    {
+        let bounds = self.bounding_box();
+        for Pixel(point, color) in pixels.into_iter().filter(|p| bounds.contains(p.0)) {
+            self.set_window(&Rectangle::new(point, Size::new(1, 1)))?;
+            self.write_pixels([color])?;
+        }
+        Ok(())
+    }
+
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Rgb565>,
+    {
+        if area.is_zero_sized() {
+            return Ok(());
+        }
+        if area.intersection(&self.bounding_box()) == *area {
+            self.set_window(area)?;
+            let count = area.size.width as usize * area.size.height as usize;
+            return self.write_pixels(colors.into_iter().take(count));
+        }
+
+        // Partly off screen, so only what's on it is drawn, a pixel at a time.
+        self.draw_iter(
+            area.points()
+                .zip(colors)
+                .map(|(point, color)| Pixel(point, color)),
+        )
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Rgb565) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        if area.is_zero_sized() {
+            return Ok(());
+        }
+
+        self.set_window(&area)?;
+        let count = area.size.width as usize * area.size.height as usize;
+        self.write_pixels(core::iter::repeat(color).take(count))
+    }
+}
@@ -0,0 +1,207 @@
+//! A shared snapshot of what the device is currently doing, for anything that needs to report on it
+//! without being in the path of the readings channels.
+
+use core::cell::RefCell;
+
+use embassy_net::IpAddress;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+
+use crate::sen55::Readings;
+use crate::sensirion::SensorInfo;
+use crate::syslog::{self, Severity};
+
+/// How far through bringing up the network (and MQTT) we are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ConnectionState {
+    Starting,
+    JoiningWifi,
+    /// Serving the Wi-Fi setup form on an access point of its own, see `portal`.
+    Provisioning,
+    WaitingForDhcp,
+    ConnectingMqtt,
+    Online,
+    /// Connected, but the broker won't take what we publish (its ACL denies it), so nothing's
+    /// being published for now, see `mqtt`.
+    ReadOnly,
+    /// The Wi-Fi dropped after boot and is being rejoined, see `wifi`.
+    Reconnecting,
+    /// There's no Wi-Fi firmware in flash to bring the chip up with, so the device is running
+    /// without a network, see `wifi_blobs`.
+    NoWifiFirmware,
+}
+
+impl ConnectionState {
+    /// Whether there's a broker connection, even one that's only good for listening.
+    pub const fn connected(&self) -> bool {
+        matches!(self, ConnectionState::Online | ConnectionState::ReadOnly)
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            ConnectionState::Starting => "starting",
+            ConnectionState::JoiningWifi => "joining_wifi",
+            ConnectionState::Provisioning => "provisioning",
+            ConnectionState::WaitingForDhcp => "waiting_for_dhcp",
+            ConnectionState::ConnectingMqtt => "connecting_mqtt",
+            ConnectionState::Online => "online",
+            ConnectionState::ReadOnly => "read_only",
+            ConnectionState::Reconnecting => "reconnecting",
+            ConnectionState::NoWifiFirmware => "no_wifi_firmware",
+        }
+    }
+}
+
+/// What the SEN55 is up to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SensorActivity {
+    /// Measurement has (re)started, but the readings aren't trusted yet.
+    WarmingUp,
+    Measuring,
+    /// Stopped between measurements to save the fan and laser.
+    Idle,
+}
+
+impl SensorActivity {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            SensorActivity::WarmingUp => "warming_up",
+            SensorActivity::Measuring => "measuring",
+            SensorActivity::Idle => "idle",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DeviceState {
+    /// The most recent set of (averaged) readings.
+    pub readings: Option<Readings>,
+    pub connection: ConnectionState,
+    pub sensor_activity: SensorActivity,
+    /// When the sensor was last (re)initialised, which restarts its fan cleaning timer.
+    pub sensor_started: Option<Instant>,
+    /// The sensor's product name and versions, once they've been read.
+    pub sensor_info: Option<SensorInfo>,
+    /// How many state messages have been published since boot.
+    pub published: u32,
+    /// How many sets of readings were dropped since boot because the publisher fell behind.
+    pub dropped: u32,
+    /// The sequence number of the last state message, counting every one sent, so consumers can
+    /// spot gaps.
+    pub sequence: u32,
+    /// How many state messages failed to publish since boot.
+    pub publish_failures: u32,
+    /// The broker's address, once DNS has resolved it.
+    pub broker_address: Option<IpAddress>,
+    /// The broker in the list that was last connected to.
+    pub broker_host: Option<&'static str>,
+    /// How many times the MQTT worker has tried to connect since boot.
+    pub mqtt_attempts: u32,
+    /// Why the broker last disconnected us or refused to let us connect, if it ever has.
+    pub broker_disconnect: Option<&'static str>,
+}
+
+static STATE: Mutex<ThreadModeRawMutex, RefCell<DeviceState>> =
+    Mutex::new(RefCell::new(DeviceState {
+        readings: None,
+        connection: ConnectionState::Starting,
+        sensor_activity: SensorActivity::WarmingUp,
+        sensor_started: None,
+        sensor_info: None,
+        published: 0,
+        dropped: 0,
+        sequence: 0,
+        publish_failures: 0,
+        broker_address: None,
+        broker_host: None,
+        mqtt_attempts: 0,
+        broker_disconnect: None,
+    }));
+
+/// A copy of the current state.
+pub fn get() -> DeviceState {
+    STATE.lock(|s| *s.borrow())
+}
+
+pub fn set_readings(readings: Readings) {
+    STATE.lock(|s| s.borrow_mut().readings = Some(readings));
+}
+
+pub fn set_connection(connection: ConnectionState) {
+    let changed = STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        let changed = s.connection != connection;
+        s.connection = connection;
+        changed
+    });
+
+    if changed {
+        syslog::event(
+            Severity::Notice,
+            "network",
+            format_args!("connection {}", connection.name()),
+        );
+    }
+}
+
+pub fn set_sensor_activity(activity: SensorActivity) {
+    STATE.lock(|s| s.borrow_mut().sensor_activity = activity);
+}
+
+pub fn set_sensor_started(at: Instant) {
+    STATE.lock(|s| s.borrow_mut().sensor_started = Some(at));
+}
+
+pub fn set_sensor_info(info: SensorInfo) {
+    STATE.lock(|s| s.borrow_mut().sensor_info = Some(info));
+}
+
+pub fn set_broker_address(address: IpAddress) {
+    STATE.lock(|s| s.borrow_mut().broker_address = Some(address));
+}
+
+pub fn set_broker_host(host: &'static str) {
+    STATE.lock(|s| s.borrow_mut().broker_host = Some(host));
+}
+
+pub fn set_broker_disconnect(reason: &'static str) {
+    STATE.lock(|s| s.borrow_mut().broker_disconnect = Some(reason));
+}
+
+pub fn count_mqtt_attempt() {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.mqtt_attempts = s.mqtt_attempts.wrapping_add(1);
+    });
+}
+
+pub fn count_dropped() {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.dropped = s.dropped.wrapping_add(1);
+    });
+}
+
+/// The sequence number for the next state message.
+pub fn next_sequence() -> u32 {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.sequence = s.sequence.wrapping_add(1);
+        s.sequence
+    })
+}
+
+pub fn count_publish_failure() {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.publish_failures = s.publish_failures.wrapping_add(1);
+    });
+}
+
+pub fn count_published() {
+    STATE.lock(|s| {
+        let mut s = s.borrow_mut();
+        s.published = s.published.wrapping_add(1);
+    });
+}
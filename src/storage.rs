@@ -0,0 +1,186 @@
+use defmt::{error, info, warn, Format};
+use embassy_rp::flash::{Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::mutex::Mutex;
+
+/// Total size of the Pico W's flash chip.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Size of the region at the end of flash reserved for persistent records.
+/// Must match the gap left at the end of FLASH in memory.x.
+const STORAGE_SIZE: usize = 64 * 1024;
+
+/// Offset (from the start of flash) of the first storage sector.
+const STORAGE_START: u32 = (FLASH_SIZE - STORAGE_SIZE) as u32;
+
+/// Marks a sector as containing a valid record, so blank (all 0xFF) flash is never mistaken for one.
+const RECORD_MAGIC: u32 = 0x5644_5352; // "VDSR"
+
+/// Magic (4) + payload length (2) + reserved (2) + checksum (4).
+const HEADER_LEN: usize = 12;
+
+/// Largest payload a single record can hold.
+pub const MAX_PAYLOAD: usize = ERASE_SIZE - HEADER_LEN;
+
+pub type StorageFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+/// Each slot gets its own erase sector so updating one never risks another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Slot {
+    /// The SEN55's VOC algorithm state, so it doesn't have to re-learn after every reboot.
+    VocState,
+    /// Runtime settings changed from Home Assistant.
+    Settings,
+    /// The last week of daily air scores.
+    ScoreHistory,
+    /// The components last announced to Home Assistant, see `announced`.
+    Announced,
+    /// The long-run VOC index histogram, see `voc_baseline`.
+    VocBaseline,
+    /// The rules set over MQTT, see `rules`.
+    Rules,
+    /// The last few alarms, see `alarm_log`.
+    AlarmLog,
+    /// The Wi-Fi network, broker and device identity, see `device_config`.
+    DeviceConfig,
+}
+
+impl Slot {
+    const fn offset(&self) -> u32 {
+        let index = match self {
+            Slot::VocState => 0,
+            Slot::Settings => 1,
+            Slot::ScoreHistory => 2,
+            Slot::Announced => 3,
+            Slot::VocBaseline => 4,
+            Slot::Rules => 5,
+            Slot::AlarmLog => 6,
+            Slot::DeviceConfig => 7,
+        };
+
+        STORAGE_START + (index * ERASE_SIZE as u32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum StorageError {
+    /// `init` hasn't been called yet.
+    NotReady,
+    /// The payload won't fit in one sector.
+    TooLarge,
+    /// Nothing (valid) has been stored in this slot.
+    Empty,
+    /// The stored record is a different size to the one asked for.
+    WrongSize,
+    /// The flash driver returned an error.
+    Flash,
+}
+
+static STORAGE: Mutex<ThreadModeRawMutex, Option<StorageFlash>> = Mutex::new(None);
+
+/// Hand the flash peripheral over to the storage module. Must be called before any loads or saves.
+pub async fn init(flash: StorageFlash) {
+    *STORAGE.lock().await = Some(flash);
+    info!("Storage ready, {} bytes reserved", STORAGE_SIZE);
+}
+
+/// Load the record in `slot` into `out`, which must be exactly the size it was saved with.
+pub async fn load(slot: Slot, out: &mut [u8]) -> Result<(), StorageError> {
+    let mut guard = STORAGE.lock().await;
+    let flash = guard.as_mut().ok_or(StorageError::NotReady)?;
+
+    let mut header = [0u8; HEADER_LEN];
+    flash
+        .blocking_read(slot.offset(), &mut header)
+        .map_err(|_| StorageError::Flash)?;
+
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    if magic != RECORD_MAGIC {
+        return Err(StorageError::Empty);
+    }
+
+    let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+    if len != out.len() {
+        warn!("{} record is {} bytes, expected {}", slot, len, out.len());
+        return Err(StorageError::WrongSize);
+    }
+
+    flash
+        .blocking_read(slot.offset() + HEADER_LEN as u32, out)
+        .map_err(|_| StorageError::Flash)?;
+
+    let checksum = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if checksum != crc32(out) {
+        warn!("{} record failed its checksum, ignoring it", slot);
+        return Err(StorageError::Empty);
+    }
+
+    Ok(())
+}
+
+/// Replace the record in `slot` with `payload`.
+///
+/// This erases and rewrites a whole sector, so don't call it more often than necessary.
+pub async fn save(slot: Slot, payload: &[u8]) -> Result<(), StorageError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(StorageError::TooLarge);
+    }
+
+    let mut guard = STORAGE.lock().await;
+    let flash = guard.as_mut().ok_or(StorageError::NotReady)?;
+
+    // Flash writes have to be whole pages, so build the record in RAM first.
+    let mut sector = [0xFFu8; ERASE_SIZE];
+    sector[0..4].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    sector[4..6].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    sector[6..8].copy_from_slice(&[0, 0]);
+    sector[8..12].copy_from_slice(&crc32(payload).to_le_bytes());
+    sector[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+
+    let offset = slot.offset();
+    if let Err(e) = flash.blocking_erase(offset, offset + ERASE_SIZE as u32) {
+        error!("Couldn't erase {} sector: {}", slot, e);
+        return Err(StorageError::Flash);
+    }
+
+    if let Err(e) = flash.blocking_write(offset, &sector) {
+        error!("Couldn't write {} sector: {}", slot, e);
+        return Err(StorageError::Flash);
+    }
+
+    Ok(())
+}
+
+/// Wipe the record in `slot` so the next load reports it as empty.
+pub async fn clear(slot: Slot) -> Result<(), StorageError> {
+    let mut guard = STORAGE.lock().await;
+    let flash = guard.as_mut().ok_or(StorageError::NotReady)?;
+
+    let offset = slot.offset();
+    flash
+        .blocking_erase(offset, offset + ERASE_SIZE as u32)
+        .map_err(|_| StorageError::Flash)
+}
+
+/// Lend the flash to `f`, for `ota` to write an update without getting in the way of the records.
+pub async fn borrow<R>(f: impl FnOnce(&mut StorageFlash) -> R) -> Result<R, StorageError> {
+    let mut guard = STORAGE.lock().await;
+    let flash = guard.as_mut().ok_or(StorageError::NotReady)?;
+    Ok(f(flash))
+}
+
+/// Plain bitwise CRC-32 (IEEE). Records are small and rarely read so a lookup table isn't worth the flash.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
@@ -0,0 +1,139 @@
+//! Sends notable events and errors to a syslog server (RFC 5424 over UDP), for anyone who already
+//! collects logs that way.
+//!
+//! Events are queued from anywhere with `event` and sent by the syslog worker once the network is
+//! up. If the queue is full, or no server is configured, they're dropped; syslog is a nice to have
+//! and never holds anything else up.
+
+use core::fmt::{Arguments, Write};
+
+use embassy_net::dns::DnsQueryType;
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Timer;
+use heapless::String;
+use log::{error, info};
+use static_cell::StaticCell;
+
+use crate::config;
+use crate::device_config;
+use crate::sockets::{self, User};
+use crate::traffic;
+
+/// Used when `SYSLOG_PORT` isn't set.
+const DEFAULT_PORT: u16 = 514;
+
+/// `local0`, used when `SYSLOG_FACILITY` isn't set.
+const DEFAULT_FACILITY: u8 = 16;
+
+/// Longest message text kept; anything after is cut off.
+const MAX_MESSAGE: usize = 96;
+
+/// Room for the header (priority, version, hostname, app name, message ID) plus the message.
+const MAX_DATAGRAM: usize = MAX_MESSAGE + 128;
+
+/// The RFC 5424 severities we use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+#[allow(unused)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+struct Event {
+    severity: Severity,
+    /// Which part of the firmware it's from, sent as the message ID.
+    source: &'static str,
+    message: String<MAX_MESSAGE>,
+}
+
+static EVENTS: Channel<ThreadModeRawMutex, Event, 8> = Channel::new();
+
+/// Queue an event for the syslog server, if there is one.
+pub fn event(severity: Severity, source: &'static str, message: Arguments) {
+    if config::SYSLOG_HOST.is_none() {
+        return;
+    }
+
+    let mut text = String::new();
+    // Too long just means truncated, which is fine for a log line.
+    _ = text.write_fmt(message);
+
+    _ = EVENTS.try_send(Event {
+        severity,
+        source,
+        message: text,
+    });
+}
+
+/// Sends queued events to `host` for as long as the device is up.
+#[embassy_executor::task]
+pub async fn worker(stack: Stack<'static>, host: &'static str) {
+    info!("started syslog worker");
+
+    static RX_META: StaticCell<[PacketMetadata; 1]> = StaticCell::new();
+    static RX_BUFFER: StaticCell<[u8; 16]> = StaticCell::new();
+    static TX_META: StaticCell<[PacketMetadata; 4]> = StaticCell::new();
+    static TX_BUFFER: StaticCell<[u8; MAX_DATAGRAM * 4]> = StaticCell::new();
+
+    let _claim = sockets::claim(User::Syslog);
+    let mut socket = UdpSocket::new(
+        stack,
+        RX_META.init([PacketMetadata::EMPTY; 1]),
+        RX_BUFFER.init([0; 16]),
+        TX_META.init([PacketMetadata::EMPTY; 4]),
+        TX_BUFFER.init([0; MAX_DATAGRAM * 4]),
+    );
+
+    if let Err(e) = socket.bind(0) {
+        error!("Couldn't bind syslog socket: {:?}", e);
+        return;
+    }
+
+    let port = parse_or(config::SYSLOG_PORT, DEFAULT_PORT);
+    let facility = parse_or(config::SYSLOG_FACILITY, DEFAULT_FACILITY).min(23);
+
+    // Keep trying to find the server, in case DNS isn't answering yet.
+    let address = loop {
+        match stack.dns_query(host, DnsQueryType::A).await {
+            Ok(addresses) if !addresses.is_empty() => break addresses[0],
+            Ok(_) | Err(_) => {
+                error!("Couldn't resolve syslog host {}", host);
+                Timer::after_secs(30).await;
+            }
+        }
+    };
+    let destination = IpEndpoint::new(address, port);
+    info!("Sending syslog to {}:{}", host, port);
+
+    let mut datagram = String::<MAX_DATAGRAM>::new();
+
+    loop {
+        let event = EVENTS.receive().await;
+
+        // <PRI>VERSION TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA MSG. There's no
+        // clock to timestamp with, so that's left as the nil value for the server to fill in.
+        datagram.clear();
+        _ = write!(
+            datagram,
+            "<{}>1 - {} vindskrivare - {} - {}",
+            facility as u16 * 8 + event.severity as u16,
+            device_config::get().identifier,
+            event.source,
+            event.message
+        );
+
+        match socket.send_to(datagram.as_bytes(), destination).await {
+            Ok(()) => traffic::sent_datagram(User::Syslog, datagram.len()),
+            Err(e) => error!("Syslog send failed: {:?}", e),
+        }
+    }
+}
+
+fn parse_or<T: core::str::FromStr>(value: Option<&str>, default: T) -> T {
+    value.and_then(|v| v.trim().parse().ok()).unwrap_or(default)
+}
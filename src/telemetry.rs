@@ -0,0 +1,319 @@
+//! Crash telemetry that survives watchdog and panic resets.
+//!
+//! Every task checks in with a heartbeat, and the watchdog worker only keeps feeding the hardware
+//! watchdog while they all keep doing so. The heartbeats, the last error and the reason for the
+//! reset are kept in RAM that isn't cleared at boot, and published over MQTT on the next connection
+//! so there's something to go on when a device on the wall has been quietly rebooting.
+
+use core::cell::Cell;
+use core::mem::MaybeUninit;
+use core::ptr::addr_of_mut;
+
+use defmt::{error, info, Format};
+use embassy_rp::watchdog::{ResetReason, Watchdog};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use serde::Serialize;
+
+use crate::syslog::{self, Severity};
+
+/// The hardware watchdog resets the board if it isn't fed within this time.
+/// The RP2040 can't do much more than 8s.
+const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(8);
+
+/// How often the watchdog worker checks the heartbeats and feeds the watchdog.
+const FEED_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Marks the block as written by us rather than left over from power-up. Change it whenever the
+/// block's layout does, so one left by older firmware isn't misread.
+const TELEMETRY_MARKER: u32 = 0x5445_4C32; // "TEL2"
+
+/// Tasks that report heartbeats to the watchdog.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum Task {
+    Main,
+    Sen55,
+    Mqtt,
+    Ui,
+}
+
+const TASK_COUNT: usize = 4;
+const TASKS: [Task; TASK_COUNT] = [Task::Main, Task::Sen55, Task::Mqtt, Task::Ui];
+
+impl Task {
+    const fn index(&self) -> usize {
+        match self {
+            Task::Main => 0,
+            Task::Sen55 => 1,
+            Task::Mqtt => 2,
+            Task::Ui => 3,
+        }
+    }
+
+    fn from_index(index: u8) -> Option<Task> {
+        TASKS.get(index as usize).copied()
+    }
+
+    /// How long the task may go without a heartbeat before it's considered stuck.
+    const fn max_silence(&self) -> Duration {
+        match self {
+            // Main only wakes up once a minute.
+            Task::Main => Duration::from_secs(120),
+            // A sensor reinit takes about 10s, plus backoff.
+            Task::Sen55 => Duration::from_secs(60),
+            // Reconnecting (DNS, TCP and MQTT timeouts) can take a while.
+            Task::Mqtt => Duration::from_secs(120),
+            Task::Ui => Duration::from_secs(120),
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Task::Main => "main",
+            Task::Sen55 => "sen55",
+            Task::Mqtt => "mqtt",
+            Task::Ui => "ui",
+        }
+    }
+}
+
+/// Why the last reset happened, as far as we can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum ErrorCode {
+    None = 0,
+    Panic = 1,
+    SensorInit = 2,
+    DhcpTimeout = 3,
+    LinkTimeout = 4,
+    TaskStalled = 5,
+}
+
+impl ErrorCode {
+    fn from_u8(code: u8) -> ErrorCode {
+        match code {
+            1 => ErrorCode::Panic,
+            2 => ErrorCode::SensorInit,
+            3 => ErrorCode::DhcpTimeout,
+            4 => ErrorCode::LinkTimeout,
+            5 => ErrorCode::TaskStalled,
+            _ => ErrorCode::None,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            ErrorCode::None => "none",
+            ErrorCode::Panic => "panic",
+            ErrorCode::SensorInit => "sensor_init",
+            ErrorCode::DhcpTimeout => "dhcp_timeout",
+            ErrorCode::LinkTimeout => "link_timeout",
+            ErrorCode::TaskStalled => "task_stalled",
+        }
+    }
+}
+
+/// Lives in RAM that isn't zeroed at boot. Plain integers only, so any bit pattern is valid.
+#[derive(Clone, Copy)]
+struct TelemetryBlock {
+    marker: u32,
+    marker_check: u32,
+    /// Uptime (ms) of each task's last heartbeat, or 0 if it hasn't checked in yet.
+    heartbeats: [u32; TASK_COUNT],
+    /// An ErrorCode.
+    last_error: u8,
+    /// Index of the task the watchdog worker gave up on, or 0xFF.
+    stalled_task: u8,
+    /// Source line of the last panic, or 0.
+    panic_line: u32,
+    /// Non-zero if the board's being reset because of a panic, which the hardware can't tell apart
+    /// from a power cycle.
+    panicked: u8,
+    /// Uptime (ms) when the last error was recorded.
+    error_at: u32,
+}
+
+// Nothing in .retained is touched at startup, or by the bootloader, see memory.x.
+#[link_section = ".retained.telemetry"]
+static mut TELEMETRY: MaybeUninit<TelemetryBlock> = MaybeUninit::uninit();
+
+/// What happened before the last reset, published on the next MQTT connection.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CrashReport {
+    pub reset_cause: &'static str,
+    pub last_error: &'static str,
+    pub error_at_ms: u32,
+    pub panic_line: Option<u32>,
+    pub stalled_task: Option<&'static str>,
+    /// Uptime (ms) of each task's last heartbeat, in the order main, sen55, mqtt, ui.
+    pub heartbeats_ms: [u32; TASK_COUNT],
+}
+
+static LAST_CRASH: Mutex<ThreadModeRawMutex, Cell<Option<CrashReport>>> =
+    Mutex::new(Cell::new(None));
+
+fn block() -> *mut TelemetryBlock {
+    // SAFETY: only used to build raw pointers, never references.
+    unsafe { addr_of_mut!(TELEMETRY).cast() }
+}
+
+fn uptime_ms() -> u32 {
+    Instant::now().as_millis() as u32
+}
+
+/// Collect whatever the previous boot left behind, then start a fresh block for this one.
+///
+/// Must be called once at boot, before any heartbeats or errors are recorded.
+pub fn init(watchdog: &Watchdog) {
+    let mut reset_cause = match watchdog.reset_reason() {
+        Some(ResetReason::TimedOut) => "watchdog",
+        Some(ResetReason::Forced) => "forced",
+        None => "power_on",
+    };
+
+    critical_section::with(|_| {
+        // SAFETY: the block is plain integers, so reading it uninitialised is harmless, and we
+        // only trust the contents if the markers say we wrote them.
+        let previous = unsafe { block().read_volatile() };
+
+        if previous.marker == TELEMETRY_MARKER && previous.marker_check == !TELEMETRY_MARKER {
+            if previous.panicked != 0 && reset_cause == "power_on" {
+                reset_cause = "panic";
+            }
+
+            let report = CrashReport {
+                reset_cause,
+                last_error: ErrorCode::from_u8(previous.last_error).name(),
+                error_at_ms: previous.error_at,
+                panic_line: (previous.panic_line != 0).then_some(previous.panic_line),
+                stalled_task: Task::from_index(previous.stalled_task).map(|t| t.name()),
+                heartbeats_ms: previous.heartbeats,
+            };
+
+            // Only worth reporting if something actually went wrong.
+            if matches!(reset_cause, "watchdog" | "panic")
+                || previous.last_error != ErrorCode::None as u8
+            {
+                LAST_CRASH.lock(|c| c.set(Some(report)));
+            }
+        }
+
+        // SAFETY: raw pointer write, never reads the old contents.
+        unsafe {
+            block().write_volatile(TelemetryBlock {
+                marker: TELEMETRY_MARKER,
+                marker_check: !TELEMETRY_MARKER,
+                heartbeats: [0; TASK_COUNT],
+                last_error: ErrorCode::None as u8,
+                stalled_task: 0xFF,
+                panic_line: 0,
+                panicked: 0,
+                error_at: 0,
+            })
+        }
+    });
+
+    info!("Reset cause: {}", reset_cause);
+}
+
+/// Take the report from before the last reset, if there was anything worth reporting.
+pub fn crash_report() -> Option<CrashReport> {
+    LAST_CRASH.lock(|c| c.get())
+}
+
+/// Forget the crash report once it's been published.
+pub fn clear_crash_report() {
+    LAST_CRASH.lock(|c| c.set(None));
+}
+
+/// Tell the watchdog this task is still alive.
+pub fn heartbeat(task: Task) {
+    let now = uptime_ms().max(1);
+
+    critical_section::with(|_| {
+        // SAFETY: init has written the block, and this is a raw pointer write.
+        unsafe { addr_of_mut!((*block()).heartbeats[task.index()]).write_volatile(now) }
+    });
+}
+
+/// Record an error that's about to cause a reset, so it shows up in the next crash report.
+pub fn record_error(code: ErrorCode) {
+    let now = uptime_ms();
+    syslog::event(
+        Severity::Error,
+        "telemetry",
+        format_args!("{}", code.name()),
+    );
+
+    critical_section::with(|_| {
+        // SAFETY: as above.
+        unsafe {
+            addr_of_mut!((*block()).last_error).write_volatile(code as u8);
+            addr_of_mut!((*block()).error_at).write_volatile(now);
+        }
+    });
+}
+
+/// Record a panic, keeping any more specific error code recorded just before it. The reset that
+/// follows is reported as one.
+pub fn record_panic(line: Option<u32>) {
+    critical_section::with(|_| {
+        // SAFETY: as above.
+        unsafe {
+            if addr_of_mut!((*block()).last_error).read_volatile() == ErrorCode::None as u8 {
+                addr_of_mut!((*block()).last_error).write_volatile(ErrorCode::Panic as u8);
+                addr_of_mut!((*block()).error_at).write_volatile(uptime_ms());
+            }
+            addr_of_mut!((*block()).panic_line).write_volatile(line.unwrap_or(0));
+            addr_of_mut!((*block()).panicked).write_volatile(1);
+        }
+    });
+}
+
+/// The first task that's gone quiet for longer than it's allowed to, if any.
+fn stalled_task() -> Option<Task> {
+    let now = uptime_ms();
+
+    // SAFETY: init has written the block, and u32 arrays are always valid.
+    let heartbeats = unsafe { addr_of_mut!((*block()).heartbeats).read_volatile() };
+
+    TASKS.into_iter().find(|task| {
+        let last = heartbeats[task.index()];
+
+        // Tasks that haven't started yet can't be stuck.
+        last != 0 && now.wrapping_sub(last) > task.max_silence().as_millis() as u32
+    })
+}
+
+/// Feeds the hardware watchdog for as long as every task keeps checking in.
+///
+/// If one goes quiet, it's recorded in the telemetry block and the watchdog is left to reset the
+/// board. If the executor itself is wedged this never runs at all, with the same result.
+#[embassy_executor::task]
+pub async fn watchdog_worker(mut watchdog: Watchdog) {
+    info!("started watchdog worker");
+
+    watchdog.pause_on_debug(true);
+    watchdog.start(WATCHDOG_TIMEOUT);
+
+    loop {
+        if let Some(task) = stalled_task() {
+            error!(
+                "{} task has stalled, letting the watchdog reset the board",
+                task
+            );
+
+            record_error(ErrorCode::TaskStalled);
+            critical_section::with(|_| {
+                // SAFETY: as above.
+                unsafe { addr_of_mut!((*block()).stalled_task).write_volatile(task.index() as u8) }
+            });
+
+            core::future::pending::<()>().await;
+        }
+
+        watchdog.feed();
+        Timer::after(FEED_INTERVAL).await;
+    }
+}
@@ -0,0 +1,96 @@
+//! Short status lines shown one at a time along the bottom of the readings page.
+//!
+//! Lines come from providers: plain functions, each owned by the module that knows the answer,
+//! that write a line (or nothing, if there's nothing to say right now) into the buffer they're
+//! given. To add a line, write a provider and list it in `PROVIDERS`.
+//!
+//! A fired banner rule (see `rules`) takes over the ticker until it's released.
+
+use core::fmt::{self, Display, Formatter, Write};
+
+use embassy_time::{Duration, Instant};
+use heapless::String;
+
+use crate::state::{self, ConnectionState};
+use crate::{report, rules, sen55};
+
+/// One line of the ticker. Anything longer won't fit across the screen anyway.
+pub type Line = String<32>;
+
+/// Writes a line into the buffer, or leaves it empty to be skipped.
+pub type Provider = fn(&mut Line);
+
+/// Every line, in the order they take turns.
+const PROVIDERS: [Provider; 4] = [
+    uptime,
+    connection,
+    sen55::fan_clean_line,
+    report::last_spike_line,
+];
+
+/// How long each line stays up.
+const LINE_TIME: Duration = Duration::from_secs(5);
+
+/// The line that should be showing now, if any provider has something to say. Providers with
+/// nothing to say give up their turn to the next one.
+pub fn current() -> Option<Line> {
+    let mut banner = Line::new();
+    rules::banner(&mut banner);
+    if !banner.is_empty() {
+        return Some(banner);
+    }
+
+    let turn = (Instant::now().as_secs() / LINE_TIME.as_secs()) as usize;
+
+    (0..PROVIDERS.len()).find_map(|offset| {
+        let mut line = Line::new();
+        PROVIDERS[(turn + offset) % PROVIDERS.len()](&mut line);
+        (!line.is_empty()).then_some(line)
+    })
+}
+
+/// A duration in the largest two units that matter, e.g. `3d 4h`, `4h 12m` or `12m`.
+pub struct Elapsed(pub Duration);
+
+impl Display for Elapsed {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let minutes = self.0.as_secs() / 60;
+        let (days, hours, minutes) = (minutes / (24 * 60), minutes / 60 % 24, minutes % 60);
+
+        match (days, hours) {
+            (0, 0) => write!(f, "{}m", minutes),
+            (0, _) => write!(f, "{}h {}m", hours, minutes),
+            _ => write!(f, "{}d {}h", days, hours),
+        }
+    }
+}
+
+fn uptime(line: &mut Line) {
+    _ = write!(
+        line,
+        "Up {}",
+        Elapsed(Instant::now().duration_since(Instant::MIN))
+    );
+}
+
+/// Only says anything when the device isn't online, as that's the only time it's news.
+fn connection(line: &mut Line) {
+    match state::get().connection {
+        ConnectionState::Online => {}
+        ConnectionState::ReadOnly => {
+            _ = line.push_str("Broker read-only");
+        }
+        ConnectionState::ConnectingMqtt => {
+            _ = line.push_str("Broker offline");
+        }
+        ConnectionState::Reconnecting => {
+            _ = line.push_str("Reconnecting Wi-Fi");
+        }
+        ConnectionState::NoWifiFirmware => {
+            _ = line.push_str("No Wi-Fi firmware");
+        }
+        _ => {
+            _ = line.push_str("Network offline");
+        }
+    }
+}
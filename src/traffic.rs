@@ -0,0 +1,178 @@
+//! Counts what each part of the firmware sends and receives over the network, for anyone on a
+//! metered or constrained link wanting to know where it goes. Totals are published once a day, to
+//! the traffic topic, and announced to Home Assistant as diagnostic sensors.
+//!
+//! TCP sockets are wrapped in a [`Counted`], which counts the bytes read and written through it.
+//! That's the payload only: the stack doesn't say how it splits a stream into segments, or what
+//! headers and retransmissions it adds, so packets are only counted for UDP, where each send is
+//! one datagram. The stack's own DHCP and DNS traffic isn't seen at all.
+//!
+//! As with the report, "daily" means every 24 hours since boot.
+
+use core::cell::RefCell;
+use core::ops::{Deref, DerefMut};
+
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::Vec;
+use portable_atomic::{AtomicU32, Ordering};
+use serde::Serialize;
+
+use crate::report::REPORT_PERIOD;
+use crate::sockets::{User, USERS, USER_COUNT};
+
+static SENT_BYTES: [AtomicU32; USER_COUNT] = [const { AtomicU32::new(0) }; USER_COUNT];
+static RECEIVED_BYTES: [AtomicU32; USER_COUNT] = [const { AtomicU32::new(0) }; USER_COUNT];
+static SENT_PACKETS: [AtomicU32; USER_COUNT] = [const { AtomicU32::new(0) }; USER_COUNT];
+
+struct Day {
+    /// When the current day's counting started.
+    started: Instant,
+    /// The last day's totals, until they're published.
+    ready: Option<DailyTraffic>,
+}
+
+static DAY: Mutex<ThreadModeRawMutex, RefCell<Day>> = Mutex::new(RefCell::new(Day {
+    started: Instant::from_ticks(0),
+    ready: None,
+}));
+
+/// Whether `user` sends datagrams, so its packets can be counted.
+const fn datagrams(user: User) -> bool {
+    matches!(user, User::Lan | User::Syslog | User::Sntp)
+}
+
+/// Count `bytes` sent by `user`.
+pub fn sent(user: User, bytes: usize) {
+    SENT_BYTES[user as usize].fetch_add(bytes as u32, Ordering::Relaxed);
+}
+
+/// Count `bytes` received by `user`.
+pub fn received(user: User, bytes: usize) {
+    RECEIVED_BYTES[user as usize].fetch_add(bytes as u32, Ordering::Relaxed);
+}
+
+/// Count a datagram of `bytes` sent by `user`.
+pub fn sent_datagram(user: User, bytes: usize) {
+    sent(user, bytes);
+    SENT_PACKETS[user as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// A socket that counts what goes through it as `user`'s traffic. Everything other than reading
+/// and writing goes straight to the socket.
+pub struct Counted<S> {
+    socket: S,
+    user: User,
+}
+
+impl<S> Counted<S> {
+    pub const fn new(socket: S, user: User) -> Self {
+        Self { socket, user }
+    }
+}
+
+impl<S> Deref for Counted<S> {
+    type Target = S;
+
+    fn deref(&self) -> &S {
+        &self.socket
+    }
+}
+
+impl<S> DerefMut for Counted<S> {
+    fn deref_mut(&mut self) -> &mut S {
+        &mut self.socket
+    }
+}
+
+impl<S: ErrorType> ErrorType for Counted<S> {
+    type Error = S::Error;
+}
+
+impl<S: Read> Read for Counted<S> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = self.socket.read(buf).await?;
+        received(self.user, len);
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let len = self.socket.write(buf).await?;
+        sent(self.user, len);
+        Ok(len)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.socket.flush().await
+    }
+}
+
+/// A day's traffic, as published.
+#[derive(Debug, Clone, Serialize)]
+pub struct DailyTraffic {
+    /// How many hours the totals cover.
+    pub hours: f32,
+    pub sent_bytes: u32,
+    pub received_bytes: u32,
+    /// Each subsystem that can use the network under the current config.
+    pub subsystems: Vec<SubsystemTraffic, USER_COUNT>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SubsystemTraffic {
+    pub name: &'static str,
+    pub sent_bytes: u32,
+    pub received_bytes: u32,
+    /// Datagrams sent, or `None` for TCP, where they aren't known.
+    pub sent_packets: Option<u32>,
+}
+
+/// The last day's totals, once there's a day to report and until they're published.
+pub fn ready() -> Option<DailyTraffic> {
+    DAY.lock(|day| {
+        let mut day = day.borrow_mut();
+        let now = Instant::now();
+        if day.ready.is_none() && now - day.started >= REPORT_PERIOD {
+            day.ready = Some(roll(now - day.started));
+            day.started = now;
+        }
+        day.ready.clone()
+    })
+}
+
+/// Drop the last day's totals, once they've been published.
+pub fn clear_ready() {
+    DAY.lock(|day| day.borrow_mut().ready = None);
+}
+
+/// Take the totals so far, starting again from zero.
+fn roll(elapsed: Duration) -> DailyTraffic {
+    let mut traffic = DailyTraffic {
+        hours: elapsed.as_secs() as f32 / 3600.0,
+        sent_bytes: 0,
+        received_bytes: 0,
+        subsystems: Vec::new(),
+    };
+
+    for user in USERS {
+        let sent_packets = SENT_PACKETS[user as usize].swap(0, Ordering::Relaxed);
+        let subsystem = SubsystemTraffic {
+            name: user.name(),
+            sent_bytes: SENT_BYTES[user as usize].swap(0, Ordering::Relaxed),
+            received_bytes: RECEIVED_BYTES[user as usize].swap(0, Ordering::Relaxed),
+            sent_packets: datagrams(user).then_some(sent_packets),
+        };
+        if user.permanent() || user.budget() == 0 {
+            continue;
+        }
+        traffic.sent_bytes += subsystem.sent_bytes;
+        traffic.received_bytes += subsystem.received_bytes;
+        _ = traffic.subsystems.push(subsystem);
+    }
+
+    traffic
+}
@@ -1,148 +1,172 @@
-use core::fmt::Write;
-use core::mem::discriminant;
-
 use defmt::info;
-use embassy_time::Timer;
-use embedded_graphics::image::{ImageDrawable, ImageDrawableExt, ImageRaw};
-use embedded_graphics::pixelcolor::raw::LittleEndian;
-use embedded_graphics::prelude::{DrawTarget, IntoStorage, Point, RgbColor, Size};
-use embedded_graphics::primitives::Rectangle;
-use embedded_graphics::{image::Image, image::ImageRawLE, pixelcolor::Rgb565, Drawable};
-
-use embassy_rp::gpio::Output;
+use embassy_futures::select::{select4, Either4};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_graphics::prelude::{Dimensions, DrawTarget, Point, Primitive, RgbColor, Size};
+use embedded_graphics::primitives::{PrimitiveStyle, Rectangle};
+use embedded_graphics::{image::Image, pixelcolor::Rgb565, Drawable};
+
+#[cfg(not(feature = "parallel-display"))]
 use embassy_rp::peripherals::SPI0;
-use embassy_rp::spi::{Blocking, Spi};
-use heapless::String;
+use embassy_rp::pwm::{self, Pwm};
+#[cfg(not(feature = "parallel-display"))]
+use embassy_rp::spi::{Async, Spi};
+use heapless::Vec;
 use u8g2_fonts::types::HorizontalAlignment;
 use u8g2_fonts::FontRenderer;
 
-use crate::sen55::{Health, Readings};
-use crate::{DelayWrapper, UI_READING_CHANNEL};
+use crate::alarms;
+use crate::asset::{compressed_asset, CompressedImage};
+use crate::batch::Batched;
+use crate::buttons::ButtonEvent;
+use crate::haptics;
+use crate::icons::{self, Icon, ICON_SIZE};
+use crate::kiosk::{self, Fault};
+use crate::menu::{Menu, Outcome};
+use crate::mirror;
+use crate::mode;
+use crate::pages::{FallbackPage, PageId, Pages, MAX_PAGES};
+#[cfg(feature = "parallel-display")]
+use crate::parallel::Parallel;
+use crate::power;
+use crate::profile::{self, Job};
+use crate::qr::{self, QrError};
+use crate::sen55::{self, Readings};
+use crate::st7789::St7789;
+use crate::telemetry::{self, Task};
+use crate::{config, settings, UI_BUTTON_CHANNEL, UI_READINGS};
 
 use defmt_rtt as _;
 
-use st7789v2_driver::{FrameBuffer, Region, ST7789V2};
+/// The display, with what's drawn on it a pixel at a time batched into windows (see `batch`).
+#[cfg(not(feature = "parallel-display"))]
+pub type Display = Batched<St7789<Spi<'static, SPI0, Async>>>;
+#[cfg(feature = "parallel-display")]
+pub type Display = Batched<St7789<Parallel>>;
 
-pub type Display =
-    ST7789V2<Spi<'static, SPI0, Blocking>, Output<'static>, Output<'static>, Output<'static>>;
+pub const DISPLAY_W: u32 = 240;
+pub const DISPLAY_H: u32 = 280;
 
-const DISPLAY_W: u32 = 240;
-const DISPLAY_H: u32 = 280;
-const READING_WIDTH: u32 = 70;
-const READING_HEIGHT: u32 = 24;
+/// The backlight's PWM counter wraps at this, so brightness percentages map onto it evenly.
+const BACKLIGHT_TOP: u16 = 10_000;
 
-const READING_SEP: i32 = 66;
-const FIRST_READING_Y: i32 = 28;
+// Status text on the connecting screens sits in a strip along the bottom.
+const STATUS_LINES: usize = 3;
+const STATUS_LINE_HEIGHT: i32 = 14;
+const STATUS_Y: i32 = DISPLAY_H as i32 - (STATUS_LINE_HEIGHT * STATUS_LINES as i32) - 4;
 
-const PM1_POS: Point = reading_pos(60, 0);
-const PM25_POS: Point = reading_pos(60, 1);
-const PM4_POS: Point = reading_pos(60, 2);
-const PM10_POS: Point = reading_pos(60, 3);
+const RAW_BG_STARTUP: CompressedImage =
+    CompressedImage::new(compressed_asset!("bg-startup"), DISPLAY_W, DISPLAY_H);
 
-const TVOC_POS: Point = reading_pos(168, 0);
-const TNOX_POS: Point = reading_pos(168, 1);
-const TEMP_POS: Point = reading_pos(168, 2);
-const HMTY_POS: Point = reading_pos(168, 3);
+const RAW_CONNECTING_WIFI: CompressedImage =
+    CompressedImage::new(compressed_asset!("connect-wifi"), DISPLAY_W, DISPLAY_H);
 
-const fn reading_pos(x: i32, index: u32) -> Point {
-    Point::new(x, FIRST_READING_Y + (READING_SEP * index as i32))
-}
+const RAW_CONNECTING_DHCP: CompressedImage =
+    CompressedImage::new(compressed_asset!("connect-dhcp"), DISPLAY_W, DISPLAY_H);
+
+const RAW_CONNECTING_MQTT: CompressedImage =
+    CompressedImage::new(compressed_asset!("connect-mqtt"), DISPLAY_W, DISPLAY_H);
 
-const READING_REGIONS: [Region; 8] = [
-    Region {
-        x: PM1_POS.x as u16,
-        y: PM1_POS.y as u16,
-        width: READING_WIDTH,
-        height: READING_HEIGHT,
-    },
-    Region {
-        x: PM25_POS.x as u16,
-        y: PM25_POS.y as u16,
-        width: READING_WIDTH,
-        height: READING_HEIGHT,
-    },
-    Region {
-        x: PM4_POS.x as u16,
-        y: PM4_POS.y as u16,
-        width: READING_WIDTH,
-        height: READING_HEIGHT,
-    },
-    Region {
-        x: PM10_POS.x as u16,
-        y: PM10_POS.y as u16,
-        width: READING_WIDTH,
-        height: READING_HEIGHT,
-    },
-    Region {
-        x: TVOC_POS.x as u16,
-        y: TVOC_POS.y as u16,
-        width: READING_WIDTH,
-        height: READING_HEIGHT,
-    },
-    Region {
-        x: TNOX_POS.x as u16,
-        y: TNOX_POS.y as u16,
-        width: READING_WIDTH,
-        height: READING_HEIGHT,
-    },
-    Region {
-        x: TEMP_POS.x as u16,
-        y: TEMP_POS.y as u16,
-        width: READING_WIDTH,
-        height: READING_HEIGHT,
-    },
-    Region {
-        x: HMTY_POS.x as u16,
-        y: HMTY_POS.y as u16,
-        width: READING_WIDTH,
-        height: READING_HEIGHT,
-    },
-];
-
-const RAW_BG_STARTUP: ImageRawLE<'static, Rgb565> =
-    ImageRawLE::new(include_bytes!("../ui/raw/bg-startup.bin"), DISPLAY_W);
-
-const RAW_CONNECTING_WIFI: ImageRawLE<'static, Rgb565> =
-    ImageRawLE::new(include_bytes!("../ui/raw/connect-wifi.bin"), DISPLAY_W);
-
-const RAW_CONNECTING_DHCP: ImageRawLE<'static, Rgb565> =
-    ImageRawLE::new(include_bytes!("../ui/raw/connect-dhcp.bin"), DISPLAY_W);
-
-const RAW_CONNECTING_MQTT: ImageRawLE<'static, Rgb565> =
-    ImageRawLE::new(include_bytes!("../ui/raw/connect-mqtt.bin"), DISPLAY_W);
-
-const RAW_CONNECTING_SEN55: ImageRawLE<'static, Rgb565> =
-    ImageRawLE::new(include_bytes!("../ui/raw/connect-sen55.bin"), DISPLAY_W);
-
-const RAW_CONNECTING_READY: ImageRawLE<'static, Rgb565> =
-    ImageRawLE::new(include_bytes!("../ui/raw/connect-ready.bin"), DISPLAY_W);
-
-const RAW_BG_READINGS_OK: ImageRawLE<'static, Rgb565> =
-    ImageRawLE::new(include_bytes!("../ui/raw/readings-default.bin"), DISPLAY_W);
-
-const RAW_BG_READINGS_UNHAPPY: ImageRawLE<'static, Rgb565> =
-    ImageRawLE::new(include_bytes!("../ui/raw/readings-unhappy.bin"), DISPLAY_W);
-
-const RAW_BG_READINGS_DANGEROUS: ImageRawLE<'static, Rgb565> = ImageRawLE::new(
-    include_bytes!("../ui/raw/readings-dangerous.bin"),
-    DISPLAY_W,
-);
+const RAW_CONNECTING_SEN55: CompressedImage =
+    CompressedImage::new(compressed_asset!("connect-sen55"), DISPLAY_W, DISPLAY_H);
+
+const RAW_CONNECTING_READY: CompressedImage =
+    CompressedImage::new(compressed_asset!("connect-ready"), DISPLAY_W, DISPLAY_H);
 
 pub struct UiController {
     display: Display,
 
-    /// Provides the ability to delay for a certain amount of time.
-    delay: DelayWrapper,
+    backlight: Pwm<'static>,
+
+    /// Whether the backlight is on. Nothing's drawn while it's off.
+    screen_on: bool,
+
+    /// Set by the `display_off` command: the screen stays off, whatever the mode, until the button
+    /// is next used.
+    blanked: bool,
+
+    /// Which layout the readings are drawn with.
+    mode: DisplayMode,
+
+    /// The readings currently on screen, so they can be redrawn straight away after a page change.
+    last_readings: Option<Readings>,
+
+    /// Whether the readings on screen were drawn as stale, to redraw them when that changes.
+    stale: bool,
+
+    pages: Pages,
+
+    /// The pages to cycle through, in order.
+    carousel: Vec<PageId, MAX_PAGES>,
 
-    // The previous health of the readings, used to determine if the background needs to be redrawn
-    last_health: Option<Health>,
+    /// Index into `carousel` of the page on screen.
+    current: usize,
 
-    // We only show every 5th reading to reduce flicker.
-    // This counter is used to keep track.
-    reading_skip: u8,
+    /// Whether `PAGES` has the alarms page, so it stays in the carousel. Otherwise it's only there
+    /// while `alarms::wanted` says so.
+    alarms_listed: bool,
+
+    /// When the current page went up, to know when it's been there long enough.
+    shown_at: Instant,
+
+    /// While a page has its graph frozen: which, and when it was frozen or last stepped through.
+    frozen: Option<(PageId, Instant)>,
+
+    /// Set when the screen has something else on it, so the next page drawn has to start afresh.
+    needs_full_redraw: bool,
+
+    /// Set when there's something new to draw that hasn't made it to the screen yet.
+    dirty: bool,
+
+    /// When the last frame was drawn, to pace the next one.
+    last_frame: Instant,
+
+    /// The on-device menu, drawn instead of the current page while it's open.
+    menu: Option<Menu>,
+
+    /// Watches the sensor and network in kiosk mode, or `None` if `KIOSK` isn't set.
+    kiosk: Option<kiosk::Watch>,
+
+    /// Whatever kiosk mode has found wrong, in which case the fallback page is shown instead of
+    /// the carousel.
+    fault: Option<Fault>,
+
+    fallback: FallbackPage,
+}
+
+/// A frozen graph carries on by itself if it's left alone this long.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How often kiosk mode checks on the sensor and network, and ticks the fallback page's timer.
+const KIOSK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// While the menu's open, never redraw more often than this, whatever the power profile. Anything
+/// that comes in sooner is folded into the next frame.
+const MENU_FRAME_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Signalled to turn the screen off until the button is next used.
+pub static SCREEN_OFF: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+/// How the readings page is laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DisplayMode {
+    /// Cycle through the configured pages.
+    Standard,
+    /// Stay on the large text page, for anyone who finds the other pages hard to read.
+    HighContrast,
+}
+
+impl DisplayMode {
+    /// Set `DISPLAY_MODE=high_contrast` at build time to start in high contrast mode.
+    fn from_config() -> Self {
+        match config::DISPLAY_MODE {
+            Some("high_contrast") => DisplayMode::HighContrast,
+            _ => DisplayMode::Standard,
+        }
+    }
 }
 
-#[allow(unused)]
 pub enum ConnectionStage {
     Wifi,
     Dhcp,
@@ -152,26 +176,63 @@ pub enum ConnectionStage {
 }
 
 impl UiController {
-    pub fn new(display: Display, delay: DelayWrapper) -> Self {
+    pub fn new(display: Display, backlight: Pwm<'static>) -> Self {
+        let carousel = PageId::parse_list(config::PAGES);
         Self {
             display,
-            delay,
-            last_health: None,
-            reading_skip: 0,
+            backlight,
+            screen_on: false,
+            blanked: false,
+            mode: DisplayMode::from_config(),
+            last_readings: None,
+            stale: false,
+            pages: Pages::new(),
+            alarms_listed: carousel.contains(&PageId::Alarms),
+            carousel,
+            current: 0,
+            shown_at: Instant::now(),
+            frozen: None,
+            needs_full_redraw: true,
+            dirty: false,
+            last_frame: Instant::MIN,
+            menu: None,
+            kiosk: config::KIOSK.then(kiosk::Watch::new),
+            fault: None,
+            fallback: FallbackPage::new(),
         }
     }
 
     pub async fn init(&mut self) {
-        self.display.hard_reset(&mut self.delay).unwrap();
-        Timer::after_millis(500).await;
-        self.display.init(&mut self.delay).unwrap();
-        self.display
-            .clear_screen(Rgb565::BLACK.into_storage())
-            .unwrap();
-
-        // Set up the regions for the readings
-        for region in READING_REGIONS.iter() {
-            self.display.store_region(*region).unwrap();
+        self.display.init().await.unwrap();
+        let screen = self.display.bounding_box();
+        self.display.fill(&screen, Rgb565::BLACK).await.unwrap();
+    }
+
+    /// Turn the backlight on (at the brightness in the settings) or off.
+    pub fn set_backlight(&mut self, on: bool) {
+        let mut pwm_config = pwm::Config::default();
+        pwm_config.top = BACKLIGHT_TOP;
+        pwm_config.compare_a = if on {
+            BACKLIGHT_TOP / 100 * u16::from(settings::get().brightness)
+        } else {
+            0
+        };
+        self.backlight.set_config(&pwm_config);
+        self.screen_on = on;
+    }
+
+    /// Turn the screen on or off to suit the current mode. When it comes back on, whatever was on
+    /// screen before is stale, so the current page is redrawn from scratch.
+    pub fn apply_mode(&mut self) {
+        let on = mode::get().display_on() && !self.blanked;
+        if on == self.screen_on {
+            return;
+        }
+
+        info!("Screen {}", if on { "on" } else { "off" });
+        self.set_backlight(on);
+        if on {
+            self.show_page_now();
         }
     }
 
@@ -192,119 +253,516 @@ impl UiController {
         img.draw(&mut self.display).unwrap();
     }
 
-    pub fn render_readings(&mut self, readings: Readings) {
-        // Skip some readings to reduce flicker
-        if self.reading_skip < 5 {
-            self.reading_skip += 1;
+    /// Draw a few lines of status text along the bottom of a connecting screen, replacing
+    /// whatever was there before.
+    pub fn render_connecting_status(&mut self, lines: &[&str]) {
+        Rectangle::new(
+            Point::new(0, STATUS_Y),
+            Size::new(DISPLAY_W, DISPLAY_H - STATUS_Y as u32),
+        )
+        .into_styled(PrimitiveStyle::with_fill(Rgb565::BLACK))
+        .draw(&mut self.display)
+        .unwrap();
+
+        let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_6x13_tf>();
+
+        for (i, line) in lines.iter().take(STATUS_LINES).enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            font.render_aligned(
+                *line,
+                Point::new(
+                    DISPLAY_W as i32 / 2,
+                    STATUS_Y + 2 + (STATUS_LINE_HEIGHT * i as i32),
+                ),
+                u8g2_fonts::types::VerticalPosition::Top,
+                HorizontalAlignment::Center,
+                u8g2_fonts::types::FontColor::Transparent(Rgb565::WHITE),
+                &mut self.display,
+            )
+            .expect("couldn't render status");
+        }
+    }
+
+    /// Draw an icon centred just above the status text, standing in for details that shouldn't be
+    /// shown in guest mode.
+    pub fn render_connecting_icon(&mut self, icon: Icon) {
+        let pos = Point::new(
+            (DISPLAY_W - ICON_SIZE) as i32 / 2,
+            STATUS_Y - ICON_SIZE as i32 - 4,
+        );
+        icons::draw(&mut self.display, icon, pos, Rgb565::WHITE).unwrap();
+    }
+
+    /// Fill the screen with a QR code for `text`, with a few short lines underneath telling the
+    /// user what scanning it will do.
+    pub fn render_qr(&mut self, text: &str, caption: &[&str]) -> Result<(), QrError> {
+        self.display.clear(Rgb565::BLACK).unwrap();
+
+        let qr_size = DISPLAY_W - 20;
+        qr::draw(
+            &mut self.display,
+            text,
+            Point::new(DISPLAY_W as i32 / 2, 10 + qr_size as i32 / 2),
+            qr_size,
+        )?;
+
+        self.render_connecting_status(caption);
+
+        Ok(())
+    }
+
+    /// Switch between the standard and high contrast layouts, redrawing straight away.
+    pub fn toggle_display_mode(&mut self) {
+        self.mode = match self.mode {
+            DisplayMode::Standard => DisplayMode::HighContrast,
+            DisplayMode::HighContrast => DisplayMode::Standard,
+        };
+        info!("Display mode now {}", self.mode);
+
+        self.unfreeze();
+        self.show_page_now();
+    }
+
+    /// A short press: the next menu item if the menu's open, otherwise the next page. On the
+    /// alarms page, it's dismissed as well, and on a frozen graph it steps back through it.
+    pub fn press(&mut self) {
+        match &mut self.menu {
+            Some(menu) => {
+                menu.next();
+                self.dirty = true;
+            }
+            None if self.frozen.is_some() => self.step_frozen(true),
+            None if self.current_page() == PageId::Alarms && !self.alarms_listed => {
+                alarms::dismiss();
+                self.update_alarms_page();
+            }
+            None => self.next_page(),
+        }
+    }
+
+    /// A double press: freeze the graph on screen, or let it carry on. Anywhere else, it's two
+    /// presses.
+    pub fn double_press(&mut self) {
+        if self.menu.is_none() && self.fault.is_none() {
+            if self.frozen.is_some() {
+                self.unfreeze();
+                return;
+            }
+
+            let id = self.current_page();
+            if self.pages.get(id).set_frozen(true) {
+                info!("Page {} frozen", id);
+                self.frozen = Some((id, Instant::now()));
+                self.show_page_now();
+                return;
+            }
+        }
+
+        self.press();
+        self.press();
+    }
+
+    /// Move a frozen graph back through its history, or forward again.
+    fn step_frozen(&mut self, back: bool) {
+        let Some((id, at)) = &mut self.frozen else {
             return;
-        } else {
-            self.reading_skip = 0;
+        };
+        self.pages.get(*id).step_frozen(back);
+        *at = Instant::now();
+        self.needs_full_redraw = true;
+        self.dirty = true;
+    }
+
+    /// Let a frozen graph carry on, before it's replaced or left alone too long.
+    fn unfreeze(&mut self) {
+        if let Some((id, _)) = self.frozen.take() {
+            info!("Page {} unfrozen", id);
+            self.pages.get(id).set_frozen(false);
+            self.needs_full_redraw = true;
+            self.dirty = true;
         }
+    }
 
-        // Work out the health of the readings
-        let new_health = readings.health();
-        let bg = match new_health {
-            Health::Ok => &RAW_BG_READINGS_OK,
-            Health::Warning => &RAW_BG_READINGS_UNHAPPY,
-            Health::Dangerous => &RAW_BG_READINGS_DANGEROUS,
+    /// A long press: pick the menu item if the menu's open, otherwise open it.
+    pub async fn long_press(&mut self) {
+        let Some(menu) = &mut self.menu else {
+            self.menu = Some(Menu::new());
+            self.dirty = true;
+            return;
         };
 
-        let mut this_frame_raw = [0; 240 * 280 * 2];
-        let mut this_frame_buffer = FrameBuffer::new(&mut this_frame_raw, DISPLAY_W, DISPLAY_H);
+        match menu.select().await {
+            Outcome::Redraw => {}
+            Outcome::ToggleContrast => self.toggle_display_mode(),
+            Outcome::Close => {
+                self.close_menu();
+                return;
+            }
+        }
 
-        // Last health is different (or unset), redraw the background
-        let img = Image::new(bg, Point::zero());
-        bg.draw(&mut this_frame_buffer).unwrap();
+        // Brightness may have changed.
+        if self.screen_on {
+            self.set_backlight(true);
+        }
+        self.dirty = true;
+    }
 
-        if self.last_health.is_none() {
-            // First time rendering, draw background directly to display
-            img.draw(&mut self.display).unwrap();
+    /// The page (or menu) deadline has passed: close the menu, or move on to the next page.
+    pub fn deadline_reached(&mut self) {
+        match self.menu {
+            Some(_) => self.close_menu(),
+            None if self.frozen.is_some() => {
+                self.unfreeze();
+                self.shown_at = Instant::now();
+            }
+            None => self.next_page(),
         }
+    }
 
-        if let Some(last_health) = &self.last_health {
-            if discriminant(&new_health) != discriminant(last_health) {
-                // Health hasn changed, draw background directly to display
-                img.draw(&mut self.display).unwrap();
+    fn close_menu(&mut self) {
+        info!("Menu closed");
+        self.menu = None;
+        // The pinned page may be another one now.
+        self.unfreeze();
+        // Units or the pinned page may have changed, so start the page afresh.
+        self.show_page_now();
+    }
+
+    /// A step of the rotary encoder: through (or adjusting) the menu if it's open, otherwise back
+    /// and forth through the pages.
+    pub async fn turn(&mut self, clockwise: bool) {
+        match &mut self.menu {
+            Some(menu) => {
+                menu.turn(clockwise).await;
+
+                // Brightness may have changed.
+                if self.screen_on {
+                    self.set_backlight(true);
+                }
+                self.dirty = true;
             }
+            None if self.frozen.is_some() => self.step_frozen(!clockwise),
+            None => self.step_page(clockwise),
         }
+    }
 
-        // Draw the readings
-        draw_reading(&mut self.display, bg, PM1_POS, &readings.pm1_0);
-        draw_reading(&mut self.display, bg, TVOC_POS, &readings.voc_index);
-        draw_reading(&mut self.display, bg, PM10_POS, &readings.pm10_0);
-        draw_reading(&mut self.display, bg, HMTY_POS, &readings.humidity);
-        draw_reading(&mut self.display, bg, PM25_POS, &readings.pm2_5);
-        draw_reading(&mut self.display, bg, TNOX_POS, &readings.nox_index);
-        draw_reading(&mut self.display, bg, PM4_POS, &readings.pm4_0);
-        draw_reading(&mut self.display, bg, TEMP_POS, &readings.temperature);
+    /// Move on to the next page in the carousel, if there is one.
+    pub fn next_page(&mut self) {
+        self.step_page(true);
+    }
+
+    /// Move to the next page in the carousel, or back to the previous one.
+    fn step_page(&mut self, forward: bool) {
+        if self.mode == DisplayMode::HighContrast
+            || self.fault.is_some()
+            || settings::get().pinned_page.is_some()
+            || self.carousel.len() < 2
+        {
+            // Nothing to move to, but restart the timer so we don't keep getting asked.
+            self.shown_at = Instant::now();
+            return;
+        }
+
+        self.unfreeze();
 
-        self.last_health = Some(new_health);
+        let len = self.carousel.len();
+        self.current = match forward {
+            true => (self.current + 1) % len,
+            false => (self.current + len - 1) % len,
+        };
+        info!("Showing page {}", self.current_page());
+
+        self.show_page_now();
     }
-}
 
-fn draw_reading<D>(
-    display: &mut D,
-    bg: &ImageRaw<'static, Rgb565, LittleEndian>,
-    pos: Point,
-    value: &Option<f32>,
-) where
-    D: DrawTarget<Color = Rgb565>,
-    <D as DrawTarget>::Error: core::fmt::Debug,
-{
-    let font = FontRenderer::new::<u8g2_fonts::fonts::u8g2_font_logisoso24_tn>();
-
-    let mut buf = String::<8>::new();
-
-    // Differente decimal places based on the value, so its always fewer than 4 characters
-    let content = match value {
-        Some(v) if *v >= 100.0 => {
-            write!(&mut buf, "{:.0}", v).unwrap();
-            buf.as_str()
+    /// Put the alarms page in the carousel, straight after the page on screen, while there are
+    /// alarms worth seeing, and take it out again once they've cleared or it's been dismissed.
+    fn update_alarms_page(&mut self) {
+        if self.alarms_listed {
+            return;
         }
-        Some(v) if *v >= 10.0 => {
-            write!(&mut buf, "{:.1}", v).unwrap();
-            buf.as_str()
+
+        let position = self.carousel.iter().position(|id| *id == PageId::Alarms);
+        match (alarms::wanted(), position) {
+            (true, None) => {
+                if self
+                    .carousel
+                    .insert(self.current + 1, PageId::Alarms)
+                    .is_ok()
+                {
+                    info!("Alarms page added");
+                }
+            }
+            (false, Some(i)) => {
+                info!("Alarms page removed");
+                self.carousel.remove(i);
+                if self.current > i {
+                    self.current -= 1;
+                } else if self.current == i {
+                    // It was on screen, so move on to whatever came after it.
+                    self.current %= self.carousel.len();
+                    self.show_page_now();
+                }
+            }
+            _ => {}
         }
-        Some(v) => {
-            write!(&mut buf, "{:.2}", v).unwrap();
-            buf.as_str()
+    }
+
+    /// When the current page has been up long enough to move on, or the menu should close.
+    pub fn page_deadline(&mut self) -> Instant {
+        if let Some(menu) = &self.menu {
+            return menu.closes_at();
+        }
+
+        if let Some((_, frozen_at)) = self.frozen {
+            return frozen_at + FREEZE_TIMEOUT;
         }
-        None => "...",
-    };
-
-    // Render background plate
-    Image::new(
-        &bg.sub_image(&Rectangle {
-            top_left: pos,
-            size: Size {
-                width: 71,
-                height: 26,
-            },
-        }),
-        pos,
-    )
-    .draw(display)
-    .unwrap();
-
-    font.render_aligned(
-        content,
-        pos,
-        u8g2_fonts::types::VerticalPosition::Top,
-        HorizontalAlignment::Left,
-        u8g2_fonts::types::FontColor::Transparent(Rgb565::WHITE),
-        display,
-    )
-    .expect("couldn't render time");
+
+        let id = self.current_page();
+        self.shown_at + self.pages.get(id).dwell_time()
+    }
+
+    /// When the readings on screen will go stale, if they haven't already.
+    pub fn stale_at(&self) -> Option<Instant> {
+        let readings = self.last_readings.filter(|_| !self.stale)?;
+        Some(readings.taken_at + sen55::stale_after())
+    }
+
+    /// Redraw the page if the readings have gone stale, or fresh ones have arrived since, so the
+    /// values are greyed out only while they're stale.
+    pub fn check_stale(&mut self) {
+        let stale = self.last_readings.is_some_and(|r| r.is_stale());
+        if stale != self.stale {
+            info!("Readings {}", if stale { "stale" } else { "fresh again" });
+            self.stale = stale;
+            self.needs_full_redraw = true;
+            self.dirty = true;
+        }
+    }
+
+    /// When kiosk mode next wants to check on the sensor and network, if it's on.
+    pub fn next_check_at(&self) -> Option<Instant> {
+        self.kiosk
+            .as_ref()
+            .map(|_| Instant::now() + KIOSK_CHECK_INTERVAL)
+    }
+
+    /// In kiosk mode, check on the sensor and network, switching to or from the fallback page as
+    /// they go and come back.
+    pub fn check_kiosk(&mut self) {
+        let Some(watch) = &mut self.kiosk else {
+            return;
+        };
+
+        let fault = watch.check(self.last_readings.as_ref());
+        if fault.map(|f| f.kind) != self.fault.map(|f| f.kind) {
+            match fault {
+                Some(fault) => info!("Kiosk fallback: {}", fault.kind),
+                None => info!("Kiosk fallback cleared"),
+            }
+            self.fault = fault;
+            self.fallback.invalidate();
+            self.show_page_now();
+            return;
+        }
+
+        self.fault = fault;
+        if let Some(fault) = fault {
+            if self.menu.is_none()
+                && self
+                    .fallback
+                    .wants_refresh(self.last_readings.as_ref(), fault)
+            {
+                self.dirty = true;
+            }
+        }
+    }
+
+    /// Take note of new readings. They're drawn on the next frame, if the page wants them.
+    pub fn queue_readings(&mut self, readings: Readings) {
+        self.last_readings = Some(readings);
+        haptics::health(&readings.health());
+        self.update_alarms_page();
+
+        // The menu doesn't show readings, and the page is redrawn in full once it closes.
+        if self.menu.is_some() {
+            return;
+        }
+
+        // The fallback page's checked along with the sensor and network.
+        if self.fault.is_some() {
+            return;
+        }
+
+        let id = self.current_page();
+        if self.pages.get(id).wants_refresh(&readings) {
+            self.dirty = true;
+        }
+    }
+
+    /// When the next frame should be drawn, or `None` if there's nothing waiting to be drawn (or
+    /// the screen is off).
+    pub fn next_frame_at(&self) -> Option<Instant> {
+        let interval = match self.menu {
+            Some(_) => MENU_FRAME_INTERVAL,
+            None => power::get().frame_interval(),
+        };
+        (self.dirty && self.screen_on).then(|| self.last_frame + interval)
+    }
+
+    /// Wait for the panel to finish a refresh, if it says when (see `config::DISPLAY_TE`), so the
+    /// next frame goes up between two rather than across one.
+    pub async fn wait_for_blanking(&mut self) {
+        self.display.wait_for_blanking().await;
+    }
+
+    /// Draw everything that's changed since the last frame, in one go.
+    pub fn draw_frame(&mut self) {
+        if let Some(menu) = &self.menu {
+            mirror::set_page("menu");
+            menu.render(&mut self.display, self.mode == DisplayMode::HighContrast);
+            self.dirty = false;
+            self.last_frame = Instant::now();
+            return;
+        }
+
+        if let Some(fault) = self.fault {
+            mirror::set_page("fallback");
+            if self.needs_full_redraw {
+                self.fallback.invalidate();
+            }
+            self.fallback
+                .render(&mut self.display, self.last_readings.as_ref(), fault);
+            self.needs_full_redraw = false;
+            self.dirty = false;
+            self.last_frame = Instant::now();
+            return;
+        }
+
+        let Some(readings) = self.last_readings else {
+            return;
+        };
+
+        let full = self.needs_full_redraw;
+        let id = self.current_page();
+        mirror::set_page(id.key());
+        self.pages
+            .get(id)
+            .render(&mut self.display, &readings, full);
+
+        self.needs_full_redraw = false;
+        self.dirty = false;
+        self.last_frame = Instant::now();
+    }
+
+    fn current_page(&self) -> PageId {
+        match self.mode {
+            DisplayMode::HighContrast => PageId::Large,
+            DisplayMode::Standard => settings::get()
+                .pinned_page
+                .unwrap_or(self.carousel[self.current]),
+        }
+    }
+
+    /// Whatever's on screen belongs to another page, so the next frame has to redraw everything.
+    /// Pages cover the whole screen when drawn from scratch, so there's no need to clear it first.
+    fn show_page_now(&mut self) {
+        self.shown_at = Instant::now();
+        self.needs_full_redraw = true;
+        self.dirty = true;
+    }
 }
 
 /// Consumes a UiController and draws readings to it whenever
 /// new ones are recieved on the UI channel.
+///
+/// Pages rotate in the order set by `PAGES`, each staying up for its own dwell time, unless one
+/// has been pinned from the menu. A press of the button skips to the next page, and a long press
+/// opens the menu (see `menu`), which then takes the presses until it closes. Turning the rotary
+/// encoder, if there is one, steps through the pages (or the menu) either way. A double press
+/// freezes the live graph, after which presses and turns step through its history instead, until
+/// another double press or `FREEZE_TIMEOUT` without one.
+///
+/// Drawing is paced to at most one frame per the power profile's frame interval: readings and page
+/// changes that arrive in between are coalesced, and only the latest state is drawn. With the
+/// panel's tearing-effect output wired up, each frame starts as the panel finishes a refresh.
+///
+/// The screen is turned off while the device is in away mode.
+///
+/// Once the readings are stale (see `sen55::stale_after`) the page is redrawn with them greyed
+/// out, and again when fresh ones arrive.
+///
+/// In kiosk mode the sensor and network are checked every second, and the pages make way for a
+/// fallback page while either is gone (see `kiosk`).
 #[embassy_executor::task]
-pub async fn worker(mut ui: UiController) {
+pub async fn worker(ui: UiController) {
+    profile::track(Job::Ui, run(ui)).await
+}
+
+async fn run(mut ui: UiController) {
     info!("started ui worker");
 
     loop {
-        let readings = UI_READING_CHANNEL.receive().await;
-        ui.render_readings(readings);
+        // Readings stop for minutes at a time while the sensor is duty cycling, but the page
+        // deadline still wakes us up regularly.
+        telemetry::heartbeat(Task::Ui);
+
+        let page_deadline = ui.page_deadline();
+        let wake_at = [ui.next_frame_at(), ui.stale_at(), ui.next_check_at()]
+            .into_iter()
+            .flatten()
+            .fold(page_deadline, Instant::min);
+
+        match select4(
+            UI_READINGS.wait(),
+            UI_BUTTON_CHANNEL.receive(),
+            Timer::at(wake_at),
+            SCREEN_OFF.wait(),
+        )
+        .await
+        {
+            Either4::First(readings) => ui.queue_readings(readings),
+            // While the screen's been turned off, using the button only brings it back.
+            Either4::Second(_) if ui.blanked => {
+                haptics::button();
+                info!("Screen no longer blanked");
+                ui.blanked = false;
+            }
+            Either4::Second(ButtonEvent::LongPress) => {
+                haptics::button();
+                ui.long_press().await;
+            }
+            Either4::Second(ButtonEvent::Press) => {
+                haptics::button();
+                ui.press();
+            }
+            Either4::Second(ButtonEvent::DoublePress) => {
+                haptics::button();
+                ui.double_press();
+            }
+            Either4::Second(ButtonEvent::Clockwise) => ui.turn(true).await,
+            Either4::Second(ButtonEvent::CounterClockwise) => ui.turn(false).await,
+            Either4::Third(()) => {
+                if Instant::now() >= page_deadline {
+                    ui.deadline_reached();
+                }
+            }
+            Either4::Fourth(()) => {
+                info!("Screen blanked");
+                ui.blanked = true;
+            }
+        }
+
+        ui.apply_mode();
+        ui.check_stale();
+        ui.check_kiosk();
+
+        if ui.next_frame_at().is_some_and(|at| Instant::now() >= at) {
+            ui.wait_for_blanking().await;
+            ui.draw_frame();
+        }
     }
 }
@@ -0,0 +1,135 @@
+//! Learns what the VOC index is normally like in this particular home, so the current value can be
+//! given relative to it.
+//!
+//! The SEN55's index is already relative (100 is the average of the last day or so), but a home
+//! that always smells of something sits at the same 100 as one that never does, and the index
+//! drifts back towards 100 during a long episode. Here a histogram of the index is built up over
+//! weeks, one sample a minute, and the current value is given as the percentage of the time it's
+//! been lower: 95 means the air's worse than it is 95% of the time, whatever the index says.
+//!
+//! Older samples count for less as new ones arrive, so the baseline follows the seasons (windows
+//! open in summer, heating on in winter). The histogram is saved to flash every hour.
+
+use core::cell::RefCell;
+
+use defmt::{error, info};
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+
+use crate::storage::{self, Slot};
+
+/// The VOC index runs from 1 to 500. Each bin covers 10 of it.
+const BINS: usize = 50;
+const BIN_WIDTH: f32 = 10.0;
+
+/// How often a sample's taken from the readings.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Once there are this many samples (two weeks' worth) every bin's halved, so each week counts for
+/// roughly half as much as the one after it.
+const DECAY_AT: u32 = 14 * 24 * 60;
+
+/// No relative value is given until there's a day's worth of samples to compare against.
+const MIN_SAMPLES: u32 = 24 * 60;
+
+/// Bump this whenever the layout of the stored bytes changes.
+const BASELINE_VERSION: u8 = 1;
+
+const STORED_LEN: usize = 1 + BINS * 2;
+
+struct Baseline {
+    bins: [u16; BINS],
+    last_sample: Option<Instant>,
+}
+
+impl Baseline {
+    fn total(&self) -> u32 {
+        self.bins.iter().map(|count| *count as u32).sum()
+    }
+}
+
+static BASELINE: Mutex<ThreadModeRawMutex, RefCell<Baseline>> =
+    Mutex::new(RefCell::new(Baseline {
+        bins: [0; BINS],
+        last_sample: None,
+    }));
+
+fn bin(voc: f32) -> usize {
+    ((voc / BIN_WIDTH) as usize).min(BINS - 1)
+}
+
+/// Take a sample from the latest readings, if one's due.
+pub fn learn(voc: Option<f32>) {
+    let Some(voc) = voc else {
+        return;
+    };
+
+    BASELINE.lock(|b| {
+        let mut b = b.borrow_mut();
+        let now = Instant::now();
+        if b.last_sample.is_some_and(|at| now - at < SAMPLE_INTERVAL) {
+            return;
+        }
+        b.last_sample = Some(now);
+
+        b.bins[bin(voc)] += 1;
+        if b.total() >= DECAY_AT {
+            b.bins.iter_mut().for_each(|count| *count /= 2);
+        }
+    });
+}
+
+/// The percentage of the time the VOC index has been lower than `voc`, or `None` until there's
+/// enough of a baseline.
+pub fn relative(voc: Option<f32>) -> Option<f32> {
+    let voc = voc?;
+
+    BASELINE.lock(|b| {
+        let b = b.borrow();
+        let total = b.total();
+        if total < MIN_SAMPLES {
+            return None;
+        }
+
+        // Everything in the lower bins, and the part of this one below the value.
+        let index = bin(voc);
+        let below: u32 = b.bins[..index].iter().map(|count| *count as u32).sum();
+        let within = ((voc - index as f32 * BIN_WIDTH) / BIN_WIDTH).clamp(0.0, 1.0);
+        let below = below as f32 + b.bins[index] as f32 * within;
+
+        Some((100.0 * below / total as f32).clamp(0.0, 100.0))
+    })
+}
+
+/// Load the baseline from flash. Storage must be initialised first.
+pub async fn load() {
+    let mut bytes = [0u8; STORED_LEN];
+
+    match storage::load(Slot::VocBaseline, &mut bytes).await {
+        Ok(()) if bytes[0] == BASELINE_VERSION => {
+            let mut bins = [0; BINS];
+            for (count, pair) in bins.iter_mut().zip(bytes[1..].chunks_exact(2)) {
+                *count = u16::from_le_bytes([pair[0], pair[1]]);
+            }
+            BASELINE.lock(|b| b.borrow_mut().bins = bins);
+        }
+        Ok(()) => info!("Ignoring VOC baseline from an old version"),
+        Err(e) => info!("No VOC baseline ({})", e),
+    }
+}
+
+/// Save the baseline to flash.
+pub async fn save() {
+    let bins = BASELINE.lock(|b| b.borrow().bins);
+
+    let mut bytes = [BASELINE_VERSION; STORED_LEN];
+    for (pair, count) in bytes[1..].chunks_exact_mut(2).zip(bins) {
+        pair.copy_from_slice(&count.to_le_bytes());
+    }
+
+    match storage::save(Slot::VocBaseline, &bytes).await {
+        Ok(()) => info!("Saved VOC baseline"),
+        Err(e) => error!("Couldn't save VOC baseline: {}", e),
+    }
+}
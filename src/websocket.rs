@@ -0,0 +1,113 @@
+//! Streams the readings to a WebSocket client as they arrive, one JSON text frame (the same as the
+//! MQTT state message) per set, for live dashboards (Grafana Live, or a page of your own) without
+//! a broker or polling. Served at `/live` by the HTTP server.
+//!
+//! Only the latest readings are ever waiting to go out, so a client that can't keep up just misses
+//! the ones in between rather than holding anything else up. One that stops reading altogether is
+//! dropped once a frame's been stuck for `SEND_TIMEOUT`.
+
+use embassy_futures::select::{select, Either};
+use embassy_net::tcp::TcpSocket;
+use embassy_time::{Duration, WithTimeout};
+use embedded_io_async::{Read, Write};
+use log::{info, warn};
+use protocol::websocket::{accept_key, FrameReader, OPCODE_CLOSE, OPCODE_TEXT};
+
+use crate::hass::StateMessage;
+use crate::traffic::Counted;
+use crate::WEBSOCKET_READINGS;
+
+/// How long a frame can take to go out before the client's given up on.
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Room for the state message, plus the frame header.
+const FRAME_SIZE: usize = 1024;
+
+/// Complete the handshake for a client that sent `key`, then stream readings to it until it goes
+/// away.
+pub async fn serve(socket: &mut Counted<TcpSocket<'_>>, key: &str) {
+    let mut accept = [0u8; 60];
+    let Some(accept) = accept_key(key, &mut accept) else {
+        warn!("WebSocket key too long");
+        return;
+    };
+
+    let head = [
+        "HTTP/1.1 101 Switching Protocols\r\n",
+        "Upgrade: websocket\r\n",
+        "Connection: Upgrade\r\n",
+        "Sec-WebSocket-Accept: ",
+        accept,
+        "\r\n\r\n",
+    ];
+    for part in head {
+        if socket.write_all(part.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+    info!("WebSocket client connected");
+
+    // Anything in the buffer is from before the client connected.
+    WEBSOCKET_READINGS.reset();
+
+    let mut incoming = [0u8; 128];
+    let mut frames = FrameReader::new();
+    let mut frame = [0u8; FRAME_SIZE];
+    loop {
+        // Clients hardly ever say anything, but watching for it is how a close is noticed.
+        let readings = match select(WEBSOCKET_READINGS.wait(), socket.read(&mut incoming)).await {
+            Either::First(readings) => readings,
+            Either::Second(Ok(0)) | Either::Second(Err(_)) => break,
+            Either::Second(Ok(n)) => {
+                // Pings and anything else are ignored. Browsers don't ping.
+                let mut closed = false;
+                frames.feed(&incoming[..n], |opcode| closed |= opcode == OPCODE_CLOSE);
+                if closed {
+                    _ = send(socket, OPCODE_CLOSE, &[]).await;
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let Ok(len) = serde_json_core::to_slice(&StateMessage::current(readings), &mut frame)
+        else {
+            warn!("State message too big for a WebSocket frame");
+            continue;
+        };
+
+        match send(socket, OPCODE_TEXT, &frame[..len])
+            .with_timeout(SEND_TIMEOUT)
+            .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(())) => break,
+            Err(_) => {
+                warn!("WebSocket client isn't keeping up, dropping it");
+                break;
+            }
+        }
+    }
+
+    info!("WebSocket client gone");
+}
+
+/// Send one unmasked, unfragmented frame, as a server does.
+async fn send(socket: &mut Counted<TcpSocket<'_>>, opcode: u8, payload: &[u8]) -> Result<(), ()> {
+    let mut header = [0x80 | opcode, 0, 0, 0];
+    let header = match payload.len() {
+        len @ 0..=125 => {
+            header[1] = len as u8;
+            &header[..2]
+        }
+        len => {
+            header[1] = 126;
+            header[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            &header[..4]
+        }
+    };
+
+    socket.write_all(header).await.map_err(|_| ())?;
+    socket.write_all(payload).await.map_err(|_| ())?;
+    socket.flush().await.map_err(|_| ())
+}
@@ -0,0 +1,189 @@
+//! Keeps the device on the Wi-Fi once it's joined at boot (see `wait_for_network` in `main`).
+//!
+//! The CYW43 doesn't rejoin by itself, so if the access point restarts or the signal drops the link
+//! stays down. The supervisor checks the link every few seconds, and once it's gone it rejoins,
+//! backing off between attempts so a network that's down for a while isn't hammered. The connection
+//! state says `reconnecting` in the meantime, for the screen and the debug console.
+//!
+//! It also owns the chip's control channel, so the power profile and the rules' LED (which hangs off
+//! the chip) are set from here too, and the signal strength is read from here with each check.
+
+use core::cell::Cell;
+use core::fmt;
+
+use cyw43::{Control, JoinOptions};
+use defmt::{info, warn};
+use embassy_futures::select::{select3, Either3};
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Timer, WithTimeout};
+
+use crate::power::WifiPower;
+use crate::state::{self, ConnectionState};
+use crate::{config, device_config, power, rules};
+
+/// How often the link is checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long one attempt to join is given.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long DHCP is given after rejoining before trying again from the start.
+const DHCP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The wait after the first failed attempt to rejoin, doubling after each one up to `MAX_BACKOFF`.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// The power saving the chip was last told to use.
+static POWER: Mutex<ThreadModeRawMutex, Cell<Option<WifiPower>>> = Mutex::new(Cell::new(None));
+
+/// The signal strength at the last check, in dBm, while the link's up.
+static RSSI: Mutex<ThreadModeRawMutex, Cell<Option<i32>>> = Mutex::new(Cell::new(None));
+
+/// The signal strength at the last check, in dBm, or `None` while the link's down.
+pub fn rssi() -> Option<i32> {
+    RSSI.lock(|rssi| rssi.get())
+}
+
+/// The power saving the chip's using, once it's been set.
+pub fn power() -> Option<WifiPower> {
+    POWER.lock(|power| power.get())
+}
+
+/// Have the chip use `power` from now on.
+pub async fn set_power(control: &mut Control<'_>, power: WifiPower) {
+    control.set_power_management(power.mode()).await;
+    POWER.lock(|current| current.set(Some(power)));
+}
+
+/// Why joining the network didn't work.
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub enum JoinError {
+    /// The chip's status code for the failure.
+    Status(u32),
+    TimedOut,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Status(status) => write!(f, "status {}", status),
+            JoinError::TimedOut => f.write_str("timed out"),
+        }
+    }
+}
+
+/// Tell the chip which country it's in (see `WIFI_COUNTRY`), so it uses that country's channels and
+/// power limits rather than the worldwide set. Needs doing once, straight after `Control::init`.
+pub async fn set_country(control: &mut Control<'_>) {
+    let Some(country) = config::WIFI_COUNTRY.map(str::trim) else {
+        return;
+    };
+    let code = country.as_bytes();
+    if code.len() != 2 || !code.iter().all(u8::is_ascii_alphabetic) {
+        warn!(
+            "Ignoring WIFI_COUNTRY {}, it should be two letters",
+            country
+        );
+        return;
+    }
+
+    // The chip's country info: the code, the CLM revision (-1 for the latest it has), and the code
+    // again as the regulatory domain.
+    let code = [
+        code[0].to_ascii_uppercase(),
+        code[1].to_ascii_uppercase(),
+        0,
+        0,
+    ];
+    let mut info = [0; 12];
+    info[0..4].copy_from_slice(&code);
+    info[4..8].copy_from_slice(&(-1i32).to_le_bytes());
+    info[8..12].copy_from_slice(&code);
+    control.set_iovar("country", &info).await;
+
+    info!("Wi-Fi country set to {}", country);
+}
+
+/// Join the network in the device config, once.
+pub async fn join(control: &mut Control<'_>) -> Result<(), JoinError> {
+    let config = device_config::get();
+    match control
+        .join(
+            &config.wifi_network,
+            JoinOptions::new(config.wifi_password.as_bytes()),
+        )
+        .with_timeout(JOIN_TIMEOUT)
+        .await
+    {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(err)) => Err(JoinError::Status(err.status)),
+        Err(_) => Err(JoinError::TimedOut),
+    }
+}
+
+/// Watches the link and rejoins when it drops, and passes power profile and LED changes on to the
+/// chip.
+#[embassy_executor::task]
+pub async fn worker(mut control: Control<'static>, stack: Stack<'static>) -> ! {
+    info!("started wifi worker");
+
+    loop {
+        match select3(
+            Timer::after(CHECK_INTERVAL),
+            power::CHANGED.wait(),
+            rules::LED.wait(),
+        )
+        .await
+        {
+            Either3::First(()) => {
+                if !stack.is_link_up() {
+                    RSSI.lock(|rssi| rssi.set(None));
+                    rejoin(&mut control, stack).await;
+                }
+                let reading = control.get_rssi().await;
+                RSSI.lock(|rssi| rssi.set(Some(reading)));
+            }
+            Either3::Second(profile) => {
+                let power = profile.wifi_power();
+                info!("Wi-Fi power saving now {} ({})", power.key(), profile.key());
+                set_power(&mut control, power).await;
+            }
+            Either3::Third(on) => control.gpio_set(0, on).await,
+        }
+    }
+}
+
+/// Rejoin the network and wait for DHCP, for as long as it takes.
+async fn rejoin(control: &mut Control<'_>, stack: Stack<'static>) {
+    warn!("Wi-Fi link lost, rejoining");
+    let before = state::get().connection;
+    state::set_connection(ConnectionState::Reconnecting);
+
+    let mut backoff = MIN_BACKOFF;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+
+        // Start from scratch, in case the chip still thinks it's partly associated.
+        control.leave().await;
+        match join(control).await {
+            Ok(()) => match stack.wait_config_up().with_timeout(DHCP_TIMEOUT).await {
+                Ok(()) => break,
+                Err(_) => warn!("DHCP didn't come up after rejoining"),
+            },
+            Err(err) => warn!("Wi-Fi rejoin attempt {} failed: {}", attempt, err),
+        }
+
+        Timer::after(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+
+    info!("Rejoined the Wi-Fi after {} attempts", attempt);
+
+    // Back to how things were. If the broker connection didn't survive, the MQTT worker notices and
+    // says it's connecting again.
+    state::set_connection(before);
+}
@@ -0,0 +1,110 @@
+//! The CYW43's firmware and CLM blobs, kept in a flash partition of their own instead of in the
+//! application image. Together they're over 200K and never change, so leaving them out makes every
+//! update that much smaller. `cargo xtask wifi-firmware` writes the partition, once per device.
+//!
+//! The partition sits just below the storage at the end of flash (see `memory.x`) and is read in
+//! place through XIP, so nothing is copied into RAM. It starts with a header:
+//!
+//! magic (4) + firmware length (4) + CLM length (4) + CRC-32 of everything after the header (4)
+//!
+//! followed by the firmware, then the CLM from the next 4-byte boundary (padded with zeroes). All
+//! little endian.
+//!
+//! With the `bundled-wifi-firmware` feature the blobs are built into the image as they used to be,
+//! for devices that are only ever flashed by copying a `.uf2` across.
+
+use defmt::Format;
+
+#[cfg(not(feature = "bundled-wifi-firmware"))]
+use crate::storage;
+
+/// Where the partition starts, from the start of flash. Must match the gap left below the storage
+/// in memory.x, and `WIFI_FIRMWARE_OFFSET` in the xtask.
+#[cfg(not(feature = "bundled-wifi-firmware"))]
+const PARTITION_OFFSET: usize = 0x1B_0000;
+
+/// Size of the partition, header included.
+#[cfg(not(feature = "bundled-wifi-firmware"))]
+const PARTITION_SIZE: usize = 256 * 1024;
+
+/// Where flash appears in the address space.
+#[cfg(not(feature = "bundled-wifi-firmware"))]
+const XIP_BASE: usize = 0x1000_0000;
+
+/// Marks a written partition, so blank (all 0xFF) flash is never mistaken for one.
+#[cfg(not(feature = "bundled-wifi-firmware"))]
+const PARTITION_MAGIC: u32 = 0x5644_5746; // "VDWF"
+
+#[cfg(not(feature = "bundled-wifi-firmware"))]
+const HEADER_LEN: usize = 16;
+
+/// The blobs `cyw43` needs to bring the chip up.
+pub struct Blobs {
+    pub firmware: &'static [u8],
+    pub clm: &'static [u8],
+}
+
+/// Why the blobs couldn't be found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BlobError {
+    /// The partition has never been written.
+    Missing,
+    /// Something's there, but the lengths don't fit or the checksum doesn't match, e.g. the write
+    /// was interrupted.
+    Corrupt,
+}
+
+#[cfg(feature = "bundled-wifi-firmware")]
+pub fn load() -> Result<Blobs, BlobError> {
+    Ok(Blobs {
+        firmware: include_bytes!("../cyw43-firmware/43439A0.bin"),
+        clm: include_bytes!("../cyw43-firmware/43439A0_clm.bin"),
+    })
+}
+
+/// Find the blobs in their partition and check they're intact.
+#[cfg(not(feature = "bundled-wifi-firmware"))]
+pub fn load() -> Result<Blobs, BlobError> {
+    // SAFETY: the partition is mapped through XIP for as long as the program runs, and nothing
+    // writes to it: it's outside both the image and the storage.
+    let partition: &'static [u8] = unsafe {
+        core::slice::from_raw_parts((XIP_BASE + PARTITION_OFFSET) as *const u8, PARTITION_SIZE)
+    };
+
+    let word = |at: usize| {
+        u32::from_le_bytes([
+            partition[at],
+            partition[at + 1],
+            partition[at + 2],
+            partition[at + 3],
+        ])
+    };
+
+    if word(0) != PARTITION_MAGIC {
+        return Err(BlobError::Missing);
+    }
+
+    let firmware_len = word(4) as usize;
+    let clm_len = word(8) as usize;
+    if firmware_len > PARTITION_SIZE || clm_len > PARTITION_SIZE {
+        return Err(BlobError::Corrupt);
+    }
+    let clm_start = (HEADER_LEN + firmware_len).next_multiple_of(4);
+    let (Some(firmware), Some(clm)) = (
+        partition.get(HEADER_LEN..HEADER_LEN + firmware_len),
+        partition.get(clm_start..clm_start + clm_len),
+    ) else {
+        return Err(BlobError::Corrupt);
+    };
+
+    if storage::crc32(&partition[HEADER_LEN..clm_start + clm_len]) != word(12) {
+        return Err(BlobError::Corrupt);
+    }
+
+    defmt::info!(
+        "Wi-Fi firmware {} bytes and CLM {} bytes from flash",
+        firmware.len(),
+        clm.len()
+    );
+    Ok(Blobs { firmware, clm })
+}
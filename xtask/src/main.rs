@@ -0,0 +1,592 @@
+//! Build, flash and provision a Vindskrivare in one go.
+//!
+//! Run it from anywhere in the repo with `cargo xtask <command>`; `cargo xtask help` lists the
+//! commands. Flashing needs `probe-rs` installed and a debug probe attached.
+//!
+//! Provisioning builds with the right environment, then writes the settings to the device's
+//! stored config over the USB debug console (a stored config takes precedence over the build's,
+//! so one left over from before would otherwise win), restarts it, and checks it came up with
+//! them.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{self, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::{Signer, SigningKey};
+use rand_core::OsRng;
+use sha2::{Digest, Sha512};
+
+const CHIP: &str = "RP2040";
+
+/// Where `cargo build --release` puts the firmware, relative to the repo root.
+const FIRMWARE: &str = "target/thumbv6m-none-eabi/release/Vindskrivare";
+
+/// The bootloader's crate, and where `cargo build --release` puts it, relative to the repo root.
+const BOOTLOADER_CRATE: &str = "bootloader";
+const BOOTLOADER: &str = "bootloader/target/thumbv6m-none-eabi/release/vindskrivare-bootloader";
+
+/// Where the update image and its manifest are written, relative to the repo root. Upload both to
+/// the same directory on the server, see `ota.rs`.
+const OTA_DIR: &str = "target/ota";
+const OTA_IMAGE: &str = "vindskrivare.bin";
+
+/// The Wi-Fi chip's blobs, relative to the repo root.
+const WIFI_FIRMWARE: &str = "cyw43-firmware/43439A0.bin";
+const WIFI_CLM: &str = "cyw43-firmware/43439A0_clm.bin";
+
+/// Where the partition image for the blobs is written, relative to the repo root.
+const WIFI_PARTITION: &str = "target/wifi-firmware.bin";
+
+/// The blobs' flash partition, as an address. Must match `PARTITION_OFFSET` (and the header
+/// layout) in `wifi_blobs.rs`, and memory.x.
+const WIFI_FIRMWARE_ADDRESS: u32 = 0x1000_0000 + 0x1B_0000;
+const WIFI_FIRMWARE_SIZE: usize = 256 * 1024;
+const WIFI_FIRMWARE_MAGIC: u32 = 0x5644_5746; // "VDWF"
+
+/// The USB IDs the firmware's debug console shows up with. Must match `main.rs`.
+const USB_VID: u16 = 0xc0de;
+const USB_PID: u16 = 0xcafe;
+
+/// How long to wait for the console to appear after flashing.
+const CONSOLE_WAIT: Duration = Duration::from_secs(20);
+
+/// Command line options that stand in for the firmware's build-time environment variables.
+const OPTIONS: [(&str, &str); 9] = [
+    ("--id", "HASS_DEVICE_IDENTIFIER"),
+    ("--name", "HASS_DEVICE_NAME"),
+    ("--mqtt-host", "MQTT_HOST"),
+    ("--mqtt-username", "MQTT_USERNAME"),
+    ("--mqtt-password", "MQTT_PASSWORD"),
+    ("--mqtt-client-id", "MQTT_CLIENT_ID"),
+    ("--discovery-base", "MQTT_HASS_DISCOVERY_BASE"),
+    ("--ssid", "WF_SSID"),
+    ("--password", "WF_PASS"),
+];
+
+/// The device config's keys (see `device_config::KEYS` in the firmware), and the build-time
+/// variable each one defaults to.
+const DEVICE_CONFIG: [(&str, &str); 8] = [
+    ("wifi_network", "WF_SSID"),
+    ("wifi_password", "WF_PASS"),
+    ("mqtt_host", "MQTT_HOST"),
+    ("mqtt_username", "MQTT_USERNAME"),
+    ("mqtt_password", "MQTT_PASSWORD"),
+    ("mqtt_client_id", "MQTT_CLIENT_ID"),
+    ("identifier", "HASS_DEVICE_IDENTIFIER"),
+    ("name", "HASS_DEVICE_NAME"),
+];
+
+/// The firmware won't build without these.
+const REQUIRED: [&str; 6] = [
+    "WF_SSID",
+    "WF_PASS",
+    "MQTT_CLIENT_ID",
+    "MQTT_HASS_DISCOVERY_BASE",
+    "HASS_DEVICE_NAME",
+    "HASS_DEVICE_IDENTIFIER",
+];
+
+type Result<T> = std::result::Result<T, String>;
+
+/// Environment overrides from the command line, plus the serial port to check the device on.
+struct Settings {
+    env: BTreeMap<&'static str, String>,
+    port: Option<String>,
+}
+
+impl Settings {
+    fn parse(args: &[String]) -> Result<Settings> {
+        let mut settings = Settings {
+            env: BTreeMap::new(),
+            port: None,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{arg} needs a value"))?
+                .clone();
+
+            if arg == "--port" {
+                settings.port = Some(value);
+                continue;
+            }
+
+            let (_, var) = OPTIONS
+                .iter()
+                .find(|(option, _)| option == arg)
+                .ok_or_else(|| format!("unknown option {arg}"))?;
+            settings.env.insert(var, value);
+        }
+
+        // The client ID only has to be unique, so the identifier will do if it isn't given.
+        if !settings.env.contains_key("MQTT_CLIENT_ID") && env::var("MQTT_CLIENT_ID").is_err() {
+            if let Some(id) = settings.get("HASS_DEVICE_IDENTIFIER") {
+                settings.env.insert("MQTT_CLIENT_ID", id);
+            }
+        }
+
+        Ok(settings)
+    }
+
+    /// A setting from the command line, falling back to the environment.
+    fn get(&self, var: &str) -> Option<String> {
+        self.env.get(var).cloned().or_else(|| env::var(var).ok())
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let rest = args.get(1..).unwrap_or_default();
+
+    let result = match args.first().map(String::as_str) {
+        Some("build") => Settings::parse(rest).and_then(|s| build(&s)),
+        Some("flash") => Settings::parse(rest).and_then(|s| {
+            build(&s)?;
+            flash()
+        }),
+        Some("provision") => Settings::parse(rest).and_then(|s| {
+            build(&s)?;
+            flash_bootloader()?;
+            flash_wifi_firmware()?;
+            flash()?;
+            store_settings(&s)?;
+            verify(&s)
+        }),
+        Some("wifi-firmware") => flash_wifi_firmware(),
+        Some("bootloader") => flash_bootloader(),
+        Some("ota") => Settings::parse(rest).and_then(|mut s| {
+            let key = signing_key()?;
+            // The new firmware takes updates signed the same way as this one.
+            s.env
+                .insert("OTA_PUBLIC_KEY", hex(key.verifying_key().as_bytes()));
+            build(&s)?;
+            ota_image(&key)
+        }),
+        Some("ota-key") => match rest {
+            [path] => ota_key(path),
+            _ => Err("usage: cargo xtask ota-key <file to keep the signing key in>".into()),
+        },
+        Some("verify") => Settings::parse(rest).and_then(|s| verify(&s)),
+        _ => {
+            help();
+            Ok(())
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        process::exit(1);
+    }
+}
+
+fn help() {
+    println!("usage: cargo xtask <command> [options]");
+    println!();
+    println!("commands:");
+    println!("  build      build the release firmware");
+    println!("  flash      build, then flash it over a debug probe");
+    println!(
+        "  provision  build, flash, store the settings, then check the device came up with them"
+    );
+    println!("  wifi-firmware  write the Wi-Fi firmware to its own partition, once per device");
+    println!("  bootloader build and flash the bootloader, once per device");
+    println!("  ota        build, then write a signed update image and its manifest to {OTA_DIR}");
+    println!("  ota-key    make a key pair to sign updates with");
+    println!("  verify     just check a running device's settings over USB");
+    println!();
+    println!("options (each falls back to the environment variable of the same name):");
+    for (option, var) in OPTIONS {
+        println!("  {option:<18} {var}");
+    }
+    println!(
+        "  {:<18} serial port of the device's console, found automatically if not given",
+        "--port"
+    );
+}
+
+fn repo_root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .parent()
+        .unwrap()
+        .to_path_buf()
+}
+
+fn build(settings: &Settings) -> Result<()> {
+    let missing: Vec<_> = REQUIRED
+        .iter()
+        .filter(|var| settings.get(var).is_none())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("missing settings: {missing:?}"));
+    }
+
+    println!(
+        "Building firmware for {}",
+        settings.get("HASS_DEVICE_IDENTIFIER").unwrap()
+    );
+
+    run(
+        Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+            .args(["build", "--release"])
+            .current_dir(repo_root())
+            .envs(&settings.env),
+    )
+}
+
+fn flash() -> Result<()> {
+    println!("Flashing");
+
+    run(Command::new("probe-rs")
+        .args(["download", "--chip", CHIP, FIRMWARE])
+        .current_dir(repo_root()))?;
+
+    run(Command::new("probe-rs").args(["reset", "--chip", CHIP]))
+}
+
+/// Build the bootloader and flash it to the start of flash, where it boots the firmware (and swaps
+/// in updates). It never changes, so this only has to be done once per device.
+fn flash_bootloader() -> Result<()> {
+    println!("Building bootloader");
+    run(
+        Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+            .args(["build", "--release"])
+            .current_dir(repo_root().join(BOOTLOADER_CRATE)),
+    )?;
+
+    println!("Flashing bootloader");
+    run(Command::new("probe-rs")
+        .args(["download", "--chip", CHIP, BOOTLOADER])
+        .current_dir(repo_root()))
+}
+
+/// Turn the built firmware into a raw image for `ota.rs` to download, with a manifest describing
+/// it and `key`'s signature of it. Needs `rust-objcopy`, from `cargo install cargo-binutils`.
+fn ota_image(key: &SigningKey) -> Result<()> {
+    let dir = repo_root().join(OTA_DIR);
+    std::fs::create_dir_all(&dir).map_err(|e| format!("couldn't create {OTA_DIR}: {e}"))?;
+
+    // The second stage bootloader is the bootloader's, and leaving it in would pad the image out
+    // from the start of flash rather than the firmware's partition.
+    let path = dir.join(OTA_IMAGE);
+    run(Command::new("rust-objcopy")
+        .args(["-O", "binary", "--remove-section", ".boot2", FIRMWARE])
+        .arg(&path)
+        .current_dir(repo_root()))?;
+
+    let image = std::fs::read(&path).map_err(|e| format!("couldn't read {OTA_IMAGE}: {e}"))?;
+    // What embassy-boot checks before marking an update to be swapped in: the SHA-512 of the
+    // image, signed.
+    let signature = key.sign(&Sha512::digest(&image));
+    let manifest = serde_json::json!({
+        "version": firmware_version()?,
+        "image": OTA_IMAGE,
+        "size": image.len(),
+        "crc32": crc32(&image),
+        "signature": hex(&signature.to_bytes()),
+    });
+    std::fs::write(dir.join("manifest.json"), manifest.to_string())
+        .map_err(|e| format!("couldn't write the manifest: {e}"))?;
+
+    println!(
+        "Wrote {OTA_DIR}/{OTA_IMAGE} ({} bytes) and {OTA_DIR}/manifest.json",
+        image.len()
+    );
+    Ok(())
+}
+
+/// The key to sign updates with, from the file `OTA_SIGNING_KEY` names.
+fn signing_key() -> Result<SigningKey> {
+    let path = env::var("OTA_SIGNING_KEY")
+        .map_err(|_| "set OTA_SIGNING_KEY to the key file from `cargo xtask ota-key`")?;
+    let contents =
+        std::fs::read_to_string(&path).map_err(|e| format!("couldn't read {path}: {e}"))?;
+    let secret = from_hex(contents.trim())
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| format!("{path} isn't a signing key"))?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// Make a key pair to sign updates with, keeping the private half in `path` and printing the
+/// public half for the firmware's `OTA_PUBLIC_KEY`.
+fn ota_key(path: &str) -> Result<()> {
+    let key = SigningKey::generate(&mut OsRng);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", hex(key.as_bytes())))
+        .map_err(|e| format!("couldn't write {path}: {e}"))?;
+
+    println!("Wrote the signing key to {path}, keep it safe and out of the repo.");
+    println!("Set OTA_SIGNING_KEY={path} to run `cargo xtask ota`, and build every device that");
+    println!(
+        "should take its updates with OTA_PUBLIC_KEY={}",
+        hex(key.verifying_key().as_bytes())
+    );
+    Ok(())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// The firmware's version, from its Cargo.toml, which is what devices compare the manifest's with.
+fn firmware_version() -> Result<String> {
+    let manifest = std::fs::read_to_string(repo_root().join("Cargo.toml"))
+        .map_err(|e| format!("couldn't read Cargo.toml: {e}"))?;
+    manifest
+        .lines()
+        .find_map(|line| line.strip_prefix("version = "))
+        .map(|version| version.trim_matches('"').to_string())
+        .ok_or_else(|| "no version in Cargo.toml".into())
+}
+
+/// Write the Wi-Fi chip's firmware and CLM to their own flash partition, where the firmware reads
+/// them from at boot. They never change, so this only has to be done once per device, and keeping
+/// them out of the image keeps every update smaller.
+fn flash_wifi_firmware() -> Result<()> {
+    let read = |path: &str| {
+        std::fs::read(repo_root().join(path)).map_err(|e| format!("couldn't read {path}: {e}"))
+    };
+    let firmware = read(WIFI_FIRMWARE)?;
+    let clm = read(WIFI_CLM)?;
+
+    // Header, firmware, then the CLM from the next 4-byte boundary. See `wifi_blobs.rs`.
+    let mut blobs = firmware.clone();
+    blobs.resize(blobs.len().next_multiple_of(4), 0);
+    blobs.extend_from_slice(&clm);
+
+    let mut image = Vec::with_capacity(16 + blobs.len());
+    for word in [
+        WIFI_FIRMWARE_MAGIC,
+        firmware.len() as u32,
+        clm.len() as u32,
+        crc32(&blobs),
+    ] {
+        image.extend_from_slice(&word.to_le_bytes());
+    }
+    image.extend_from_slice(&blobs);
+    if image.len() > WIFI_FIRMWARE_SIZE {
+        return Err(format!(
+            "Wi-Fi firmware is {} bytes, more than the {WIFI_FIRMWARE_SIZE} its partition holds",
+            image.len()
+        ));
+    }
+
+    let path = repo_root().join(WIFI_PARTITION);
+    std::fs::write(&path, &image).map_err(|e| format!("couldn't write {WIFI_PARTITION}: {e}"))?;
+
+    println!("Flashing Wi-Fi firmware ({} bytes)", image.len());
+    run(Command::new("probe-rs")
+        .args(["download", "--chip", CHIP, "--binary-format", "bin"])
+        .args(["--base-address", &format!("{WIFI_FIRMWARE_ADDRESS:#x}")])
+        .arg(&path))
+}
+
+/// CRC-32 (IEEE), the same as `storage::crc32` in the firmware.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Write the settings to the device's stored config over the USB console, then restart it to use
+/// them. Any that aren't given are stored blank, the same as leaving them out of the build.
+fn store_settings(settings: &Settings) -> Result<()> {
+    // It's still using whatever identifier it had before, so take any console unless told.
+    let port = match &settings.port {
+        Some(port) => port.clone(),
+        None => wait_for_console(None)?,
+    };
+    println!("Storing settings on {port}");
+
+    let mut serial = open_console(&port)?;
+    let mut reader = BufReader::new(serial.try_clone().map_err(|e| e.to_string())?);
+
+    for (key, var) in DEVICE_CONFIG {
+        let value = settings.get(var).unwrap_or_default();
+        serial
+            .write_all(format!("set {key} {value}\r\n").as_bytes())
+            .map_err(|e| format!("couldn't write to {port}: {e}"))?;
+
+        let reply = read_reply(&mut reader, &port, |line| {
+            line.starts_with("saved") || line.starts_with("error")
+        })?;
+        if !reply.starts_with("saved") {
+            return Err(format!("couldn't store {key}: {reply}"));
+        }
+    }
+
+    serial
+        .write_all(b"reboot\r\n")
+        .map_err(|e| format!("couldn't write to {port}: {e}"))?;
+    // Give it a moment to go, so the console isn't found again before it's restarted.
+    thread::sleep(Duration::from_secs(2));
+
+    Ok(())
+}
+
+/// Ask the device for its state over the USB console and check it matches the settings.
+fn verify(settings: &Settings) -> Result<()> {
+    let id = settings
+        .get("HASS_DEVICE_IDENTIFIER")
+        .ok_or("need --id to know which device to check")?;
+
+    let port = match &settings.port {
+        Some(port) => port.clone(),
+        None => wait_for_console(Some(&id))?,
+    };
+    println!("Checking {id} on {port}");
+
+    let dump = read_dump(&port)?;
+    let config = &dump["config"];
+
+    let checks = [
+        ("identifier", "HASS_DEVICE_IDENTIFIER"),
+        ("name", "HASS_DEVICE_NAME"),
+        ("mqtt_host", "MQTT_HOST"),
+        ("mqtt_username", "MQTT_USERNAME"),
+        ("mqtt_client_id", "MQTT_CLIENT_ID"),
+        ("wifi_network", "WF_SSID"),
+    ];
+
+    let mut ok = true;
+    for (key, var) in checks {
+        let Some(expected) = settings.get(var) else {
+            continue;
+        };
+
+        let actual = config[key].as_str().unwrap_or("<missing>");
+        if actual == expected {
+            println!("  {key}: {actual}");
+        } else {
+            println!("  {key}: {actual} (expected {expected})");
+            ok = false;
+        }
+    }
+
+    println!(
+        "  connection: {}",
+        dump["connection"].as_str().unwrap_or("?")
+    );
+
+    if ok {
+        println!("Device is provisioned");
+        Ok(())
+    } else {
+        Err("device doesn't match the settings".into())
+    }
+}
+
+/// Find the serial port of the console for the device with identifier `id` (or any device, if
+/// `None`), waiting for it to show up if the device has just been reset.
+fn wait_for_console(id: Option<&str>) -> Result<String> {
+    let started = Instant::now();
+
+    loop {
+        let ports = serialport::available_ports().map_err(|e| e.to_string())?;
+        let found = ports.into_iter().find(|port| match &port.port_type {
+            serialport::SerialPortType::UsbPort(usb) => {
+                usb.vid == USB_VID
+                    && usb.pid == USB_PID
+                    && id.is_none_or(|id| usb.serial_number.as_deref() == Some(id))
+            }
+            _ => false,
+        });
+
+        if let Some(port) = found {
+            return Ok(port.port_name);
+        }
+
+        if started.elapsed() > CONSOLE_WAIT {
+            return Err(match id {
+                Some(id) => format!("no console for {id} showed up, try --port"),
+                None => "no console showed up, try --port".into(),
+            });
+        }
+
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Send `dump` and wait for the JSON reply.
+fn read_dump(port: &str) -> Result<serde_json::Value> {
+    let mut serial = open_console(port)?;
+    serial
+        .write_all(b"dump\r\n")
+        .map_err(|e| format!("couldn't write to {port}: {e}"))?;
+
+    let mut reader = BufReader::new(serial);
+    let reply = read_reply(&mut reader, port, |line| {
+        serde_json::from_str::<serde_json::Value>(line).is_ok()
+    })
+    .map_err(|_| "device didn't answer the dump command".to_string())?;
+
+    serde_json::from_str(&reply).map_err(|e| e.to_string())
+}
+
+fn open_console(port: &str) -> Result<Box<dyn serialport::SerialPort>> {
+    serialport::new(port, 115_200)
+        .timeout(Duration::from_secs(1))
+        .open()
+        .map_err(|e| format!("couldn't open {port}: {e}"))
+}
+
+/// Read lines from the console until one `is_reply`, giving up after a few seconds.
+fn read_reply(
+    reader: &mut impl BufRead,
+    port: &str,
+    is_reply: impl Fn(&str) -> bool,
+) -> Result<String> {
+    let started = Instant::now();
+    let mut line = String::new();
+
+    while started.elapsed() < Duration::from_secs(5) {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(_) if is_reply(line.trim()) => return Ok(line.trim().to_string()),
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+            Err(e) => return Err(format!("couldn't read from {port}: {e}")),
+        }
+    }
+
+    Err(format!("no answer from {port}"))
+}
+
+fn run(command: &mut Command) -> Result<()> {
+    let status = command
+        .status()
+        .map_err(|e| format!("couldn't run {:?}: {e}", command.get_program()))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{:?} failed: {status}", command.get_program()))
+    }
+}